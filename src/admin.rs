@@ -0,0 +1,175 @@
+use axum::{
+    extract::{Path, Query},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::{stream, Stream};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::{
+    audit::{AuditEntry, AuditQuery},
+    batch::BatchReport,
+    error::ChetterError,
+    metrics::EventCount,
+    scheduler::DeadLetter,
+    tracker::{HookPing, PrState},
+    State,
+};
+
+/// Create the router for the administrative API.
+///
+/// Every route requires an `Authorization: Bearer <admin_token>` header, or basic authentication
+/// with `admin_token` as password for browsers, and the API is disabled entirely unless
+/// `admin_token` is configured.
+pub fn router(state: State) -> Router<State> {
+    Router::new()
+        .route("/admin/repos/:org/:repo/resync", post(resync))
+        .route("/admin/repos/:org/:repo/gc", post(gc))
+        .route("/admin/repos/:org/:repo/cutover", post(cutover))
+        .route("/admin/repos/:org/:repo/prs/:num/state", get(pr_state))
+        .route("/admin/dead-letters", get(dead_letters))
+        .route("/admin/hook", get(hook))
+        .route("/admin/events", get(events))
+        .route("/admin/events/stream", get(event_stream))
+        .route("/admin/audit", get(audit))
+        .route_layer(middleware::from_fn_with_state(state, authorize))
+}
+
+pub(crate) async fn authorize<B>(
+    axum::extract::State(state): axum::extract::State<State>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(ref token) = state.config.admin_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(credential);
+
+    match provided {
+        Some(p) if constant_time_eq(p.as_bytes(), token.expose().as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            warn!("Unauthorized request for {}", request.uri().path());
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, r#"Basic realm="chetter""#)],
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Token of a bearer `Authorization` header, or the password of a basic one.
+fn credential(authorization: &str) -> Option<String> {
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return Some(token.into());
+    }
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded).ok()?).ok()?;
+    decoded.split_once(':').map(|(_, password)| password.into())
+}
+
+/// Compare without returning early so that timing does not reveal the matching prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn resync(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((org, repo)): Path<(String, String)>,
+) -> Result<BatchReport, ChetterError> {
+    state.resync_repo(&org, &repo).await
+}
+
+async fn gc(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((org, repo)): Path<(String, String)>,
+) -> Result<BatchReport, ChetterError> {
+    state.gc_repo(&org, &repo).await
+}
+
+async fn cutover(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((org, repo)): Path<(String, String)>,
+) -> Result<BatchReport, ChetterError> {
+    state.cutover_repo(&org, &repo).await
+}
+
+async fn pr_state(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((org, repo, num)): Path<(String, String, u64)>,
+) -> Result<Json<PrState>, ChetterError> {
+    Ok(Json(state.pr_state(&org, &repo, num).await?))
+}
+
+async fn dead_letters(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> Json<Vec<DeadLetter>> {
+    Json(state.dead_letters())
+}
+
+async fn events(axum::extract::State(state): axum::extract::State<State>) -> Json<Vec<EventCount>> {
+    Json(state.event_counts())
+}
+
+/// Stream webhook events as they finish processing, noting events that were dropped because the
+/// client fell behind.
+async fn event_stream(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = stream::unfold(state.subscribe_processed(), |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(processed) => Event::default()
+                .event("processed")
+                .json_data(processed)
+                .unwrap_or_default(),
+            Err(RecvError::Lagged(n)) => Event::default().event("lagged").data(n.to_string()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), rx))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn audit(
+    axum::extract::State(state): axum::extract::State<State>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, ChetterError> {
+    Ok(Json(state.audit_entries(&query)?))
+}
+
+async fn hook(axum::extract::State(state): axum::extract::State<State>) -> Json<Option<HookPing>> {
+    Json(state.last_ping())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials() {
+        assert_eq!(credential("Bearer s3cret").as_deref(), Some("s3cret"));
+        // admin:s3cret
+        assert_eq!(
+            credential("Basic YWRtaW46czNjcmV0").as_deref(),
+            Some("s3cret")
+        );
+        assert_eq!(credential("Basic !!!"), None);
+        assert_eq!(credential("Digest s3cret"), None);
+    }
+}
@@ -0,0 +1,20 @@
+use axum::{extract::Path, middleware, routing::get, Json, Router};
+
+use crate::{admin::authorize, error::ChetterError, history::PrHistory, State};
+
+/// Create the router for the read-only query API.
+///
+/// The API is meant for tools building on the references chetter maintains and shares the
+/// `admin_token` authorization of the administrative API.
+pub fn router(state: State) -> Router<State> {
+    Router::new()
+        .route("/api/:org/:repo/pulls/:num/versions", get(versions))
+        .route_layer(middleware::from_fn_with_state(state, authorize))
+}
+
+async fn versions(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((org, repo, num)): Path<(String, String, u64)>,
+) -> Result<Json<PrHistory>, ChetterError> {
+    Ok(Json(state.pr_history(&org, &repo, num).await?))
+}
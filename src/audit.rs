@@ -0,0 +1,261 @@
+#[cfg(feature = "audit")]
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "audit")]
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+#[cfg(feature = "audit")]
+use tracing::error;
+
+#[cfg(feature = "audit")]
+use crate::{error::ChetterError, hooks::RefHook};
+
+/// Entries kept in memory when no audit log file is configured.
+#[cfg(feature = "audit")]
+const MAX_RECENT: usize = 10000;
+
+/// Kind of change made to a reference.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Create,
+    Update,
+    Delete,
+}
+
+/// What caused references to change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trigger {
+    /// Login of the user whose webhook event caused the change, `admin` for the administrative
+    /// API, `cli` for commands, `poll` for polling or `chetter` for scheduled work
+    pub actor: String,
+
+    /// GitHub delivery id of the webhook event, if any
+    pub delivery: Option<String>,
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Self {
+            actor: "chetter".into(),
+            delivery: None,
+        }
+    }
+}
+
+/// A change made to a reference.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the change was made
+    pub at: DateTime<Utc>,
+
+    /// Repository as `<org>/<repo>`
+    pub repo: String,
+
+    /// Full name of the reference, such as `refs/heads/pr/12/v1`
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+
+    /// Kind of change
+    pub operation: Operation,
+
+    /// SHA-1 the reference pointed to before the change, if known
+    pub old_sha: Option<String>,
+
+    /// SHA-1 the reference points to after the change, None if deleted
+    pub new_sha: Option<String>,
+
+    /// What caused the change
+    #[serde(flatten)]
+    pub trigger: Trigger,
+}
+
+/// Filter of audit log entries.
+#[cfg(feature = "audit")]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuditQuery {
+    /// Only entries of repository `<org>/<repo>`
+    pub repo: Option<String>,
+
+    /// Only entries of references starting with this prefix
+    #[serde(rename = "ref")]
+    pub ref_prefix: Option<String>,
+
+    /// Return at most this many of the most recent matching entries
+    pub limit: Option<usize>,
+}
+
+#[cfg(feature = "audit")]
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.repo.as_ref().map_or(true, |r| *r == entry.repo)
+            && self
+                .ref_prefix
+                .as_ref()
+                .map_or(true, |p| entry.ref_name.starts_with(p.as_str()))
+    }
+}
+
+/// Append-only log of every change made to references.
+///
+/// Entries are appended to a file of JSON lines if one is configured and otherwise only the most
+/// recent ones are kept in memory.  Installed as a [RefHook] by [State::from_config].
+///
+/// [State::from_config]: crate::State::from_config
+#[cfg(feature = "audit")]
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    file: Option<(PathBuf, Arc<Mutex<File>>)>,
+    recent: Arc<Mutex<VecDeque<AuditEntry>>>,
+}
+
+#[cfg(feature = "audit")]
+impl AuditLog {
+    /// Create an audit log appending to the file at `path`.
+    pub fn open(path: &Path) -> Result<Self, ChetterError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some((path.into(), Arc::new(Mutex::new(file)))),
+            recent: Arc::default(),
+        })
+    }
+
+    /// Record `entry`.
+    ///
+    /// Failing to write to the file is logged rather than failing the change, which was already
+    /// made.
+    pub fn record(&self, entry: AuditEntry) {
+        let Some((ref path, ref file)) = self.file else {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() >= MAX_RECENT {
+                recent.pop_front();
+            }
+            recent.push_back(entry);
+            return;
+        };
+
+        let line = match serde_json::to_string(&entry) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to serialize audit entry {entry:?}: {e}");
+                return;
+            }
+        };
+        if let Err(e) = writeln!(file.lock().unwrap(), "{line}") {
+            error!("{}: failed to append {line}: {e}", path.display());
+        }
+    }
+
+    /// Get the most recent entries matching `query`, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, ChetterError> {
+        let mut entries: VecDeque<AuditEntry> = VecDeque::new();
+        let mut push = |entry: AuditEntry| {
+            if !query.matches(&entry) {
+                return;
+            }
+            if query.limit.is_some_and(|l| entries.len() >= l) {
+                entries.pop_front();
+            }
+            if query.limit != Some(0) {
+                entries.push_back(entry);
+            }
+        };
+
+        match self.file {
+            Some((ref path, _)) => {
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let line = line?;
+                    match serde_json::from_str(&line) {
+                        Ok(entry) => push(entry),
+                        Err(e) => error!("{}: skipping {line}: {e}", path.display()),
+                    }
+                }
+            }
+            None => self.recent.lock().unwrap().iter().cloned().for_each(push),
+        }
+        Ok(entries.into())
+    }
+}
+
+#[cfg(feature = "audit")]
+#[async_trait]
+impl RefHook for AuditLog {
+    async fn after(&self, change: &AuditEntry) {
+        self.record(change.clone());
+    }
+}
+
+#[cfg(all(test, feature = "audit"))]
+mod tests {
+    use super::*;
+
+    fn entry(repo: &str, ref_name: &str, operation: Operation) -> AuditEntry {
+        AuditEntry {
+            at: Utc::now(),
+            repo: repo.into(),
+            ref_name: ref_name.into(),
+            operation,
+            old_sha: None,
+            new_sha: Some("c1".into()),
+            trigger: Trigger {
+                actor: "alice".into(),
+                delivery: Some("abc".into()),
+            },
+        }
+    }
+
+    fn record_and_query(log: &AuditLog) {
+        log.record(entry("org/a", "refs/heads/pr/1/v1", Operation::Create));
+        log.record(entry("org/a", "refs/heads/pr/2/v1", Operation::Create));
+        log.record(entry("org/b", "refs/heads/pr/1/v1", Operation::Update));
+        log.record(entry("org/a", "refs/heads/pr/1/v1", Operation::Delete));
+
+        assert_eq!(log.query(&AuditQuery::default()).unwrap().len(), 4);
+
+        let query = AuditQuery {
+            repo: Some("org/a".into()),
+            ref_prefix: Some("refs/heads/pr/1/".into()),
+            limit: None,
+        };
+        let ops: Vec<Operation> = log
+            .query(&query)
+            .unwrap()
+            .iter()
+            .map(|e| e.operation)
+            .collect();
+        assert_eq!(ops, [Operation::Create, Operation::Delete]);
+
+        let query = AuditQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let entries = log.query(&query).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, Operation::Delete);
+        assert_eq!(entries[0].trigger.actor, "alice");
+    }
+
+    #[test]
+    fn in_memory() {
+        record_and_query(&AuditLog::default());
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("chetter-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        record_and_query(&AuditLog::open(&path).unwrap());
+
+        // Entries survive reopening
+        let log = AuditLog::open(&path).unwrap();
+        assert_eq!(log.query(&AuditQuery::default()).unwrap().len(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "server")]
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+#[cfg(feature = "server")]
+use tracing::warn;
+
+#[cfg(feature = "server")]
+use crate::State;
+
+/// Approximate width of a character of the badge font, in pixels.
+const CHAR_WIDTH: usize = 7;
+
+/// Horizontal padding around each half of a badge, in pixels.
+const PADDING: usize = 10;
+
+/// How long a badge is served before looking up its pull request again.
+const BADGE_TTL: Duration = Duration::from_secs(60);
+
+/// When a badge was rendered, and the badge if its repository serves them.
+type CachedBadge = (Instant, Option<String>);
+
+/// Badges served recently, so that embedding them doesn't cost an API request per view.
+///
+/// `None` is cached for repositories whose badges are not public.
+#[derive(Clone, Default)]
+pub struct BadgeCache {
+    badges: Arc<Mutex<HashMap<(String, u64), CachedBadge>>>,
+}
+
+impl BadgeCache {
+    /// Get the badge of pull request `pr` in `repo` if it was cached less than a TTL ago.
+    pub fn get(&self, repo: &str, pr: u64) -> Option<Option<String>> {
+        let badges = self.badges.lock().unwrap();
+        match badges.get(&(repo.into(), pr)) {
+            Some((at, badge)) if at.elapsed() < BADGE_TTL => Some(badge.clone()),
+            _ => None,
+        }
+    }
+
+    /// Cache `badge` of pull request `pr` in `repo`, dropping expired badges.
+    pub fn insert(&self, repo: &str, pr: u64, badge: Option<String>) {
+        let mut badges = self.badges.lock().unwrap();
+        badges.retain(|_, (at, _)| at.elapsed() < BADGE_TTL);
+        badges.insert((repo.into(), pr), (Instant::now(), badge));
+    }
+}
+
+/// Render a flat badge reading `label` on the left and `message` on a `color` background on the
+/// right, in the style of shields.io.
+pub fn render(label: &str, message: &str, color: &str) -> String {
+    let label_width = label.chars().count() * CHAR_WIDTH + PADDING;
+    let message_width = message.chars().count() * CHAR_WIDTH + PADDING;
+    let width = label_width + message_width;
+    let label_x = label_width / 2;
+    let message_x = label_width + message_width / 2;
+    let label = escape(label);
+    let message = escape(message);
+    let color = escape(color);
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{label_x}" y="14">{label}</text><text x="{message_x}" y="14">{message}</text></g></svg>"##
+    )
+}
+
+/// Escape `s` for use in XML or HTML text and attributes.
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Create the router serving badges of pull requests.
+///
+/// Badges are public so that they can be embedded anywhere, they are only served for public
+/// repositories and those opted in with `public_badges`.
+#[cfg(feature = "server")]
+pub fn router() -> Router<State> {
+    Router::new().route("/badge/:org/:repo/:file", get(versions))
+}
+
+/// Badge with the number of versions recorded for a pull request, `<number>.svg`.
+#[cfg(feature = "server")]
+async fn versions(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((org, repo, file)): Path<(String, String, String)>,
+) -> Response {
+    let Some(pr) = file
+        .strip_suffix(".svg")
+        .and_then(|n| n.parse::<u64>().ok())
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let full_name = format!("{org}/{repo}");
+    let badge = match state.badges.get(&full_name, pr) {
+        Some(badge) => badge,
+        None => {
+            let badge = match state.public_badges(&org, &repo).await {
+                Ok(true) => state
+                    .pr_state(&org, &repo, pr)
+                    .await
+                    .map(|s| Some(render("versions", &s.version.to_string(), "#007ec6"))),
+                Ok(false) => Ok(None),
+                Err(e) => Err(e),
+            };
+            match badge {
+                Ok(badge) => {
+                    state.badges.insert(&full_name, pr, badge.clone());
+                    badge
+                }
+                // Not cached, the next view tries again
+                Err(e) => {
+                    warn!("{full_name}#{pr}: failed to render badge: {e}");
+                    Some(render("versions", "unknown", "#9f9f9f"))
+                }
+            }
+        }
+    };
+    let Some(svg) = badge else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            // Proxies such as GitHub's camo would otherwise keep showing an old count
+            (header::CACHE_CONTROL, "no-cache, max-age=0"),
+        ],
+        svg,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_badge() {
+        let svg = render("versions", "3", "#007ec6");
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains(r#"width="83""#));
+        assert!(svg.contains(">versions</text>"));
+        assert!(svg.contains(">3</text>"));
+
+        let svg = render("<a&b>", "\"", "red");
+        assert!(svg.contains(">&lt;a&amp;b&gt;</text>"));
+        assert!(svg.contains(">&quot;</text>"));
+    }
+
+    #[test]
+    fn cache() {
+        let cache = BadgeCache::default();
+        assert_eq!(cache.get("o/r", 1), None);
+        cache.insert("o/r", 1, Some("svg".into()));
+        cache.insert("o/private", 1, None);
+        assert_eq!(cache.get("o/r", 1), Some(Some("svg".into())));
+        assert_eq!(cache.get("o/private", 1), Some(None));
+        assert_eq!(cache.get("o/r", 2), None);
+
+        let expired = Instant::now() - BADGE_TTL;
+        cache
+            .badges
+            .lock()
+            .unwrap()
+            .insert(("o/r".into(), 2), (expired, Some("old".into())));
+        assert_eq!(cache.get("o/r", 2), None);
+        cache.insert("o/r", 3, None);
+        assert!(!cache
+            .badges
+            .lock()
+            .unwrap()
+            .contains_key(&("o/r".into(), 2)));
+    }
+}
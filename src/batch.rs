@@ -0,0 +1,125 @@
+#[cfg(feature = "server")]
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::error::ChetterError;
+
+/// Outcome of an operation on a single pull request within a batch.
+#[derive(Debug)]
+pub struct BatchItem {
+    /// Pull request number
+    pub pr: u64,
+
+    /// Description of the action taken or the error that prevented it
+    pub result: Result<String, ChetterError>,
+}
+
+/// Results of an operation applied to many pull requests.
+///
+/// A failure on one pull request does not prevent the others from being processed, so the report
+/// may describe a partial success.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub items: Vec<BatchItem>,
+}
+
+impl BatchReport {
+    /// Record the outcome for pull request `pr`.
+    pub fn push(&mut self, pr: u64, result: Result<String, ChetterError>) {
+        self.items.push(BatchItem { pr, result });
+    }
+
+    /// True if the operation succeeded on every pull request.
+    pub fn is_ok(&self) -> bool {
+        self.items.iter().all(|i| i.result.is_ok())
+    }
+
+    /// Convert the report into a single result, combining all failures.
+    pub fn into_result(self) -> Result<(), ChetterError> {
+        let errors: Vec<ChetterError> = self
+            .items
+            .into_iter()
+            .filter_map(|i| i.result.err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ChetterError::Multiple(errors))
+        }
+    }
+
+    /// Summarize the outcome of every item as JSON.
+    pub fn to_json(&self) -> serde_json::Value {
+        let results: Vec<serde_json::Value> = self
+            .items
+            .iter()
+            .map(|i| match &i.result {
+                Ok(detail) => json!({"pr": i.pr, "ok": true, "detail": detail}),
+                Err(e) => json!({"pr": i.pr, "ok": false, "error": e.to_string()}),
+            })
+            .collect();
+        json!({ "results": results })
+    }
+}
+
+#[cfg(feature = "server")]
+impl IntoResponse for BatchReport {
+    /// Respond with 200 if every item succeeded, otherwise 207 Multi-Status.
+    fn into_response(self) -> Response {
+        let status = if self.is_ok() {
+            StatusCode::OK
+        } else {
+            StatusCode::MULTI_STATUS
+        };
+        (status, Json(self.to_json())).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_success() {
+        let mut report = BatchReport::default();
+        report.push(1, Ok("opened".into()));
+        report.push(2, Err(ChetterError::GithubParseError("bad".into())));
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.to_json(),
+            json!({"results": [
+                {"pr": 1, "ok": true, "detail": "opened"},
+                {"pr": 2, "ok": false, "error": "bad"},
+            ]})
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn multi_status() {
+        let mut report = BatchReport::default();
+        report.push(1, Ok("opened".into()));
+        assert_eq!(report.into_response().status(), StatusCode::OK);
+
+        let mut report = BatchReport::default();
+        report.push(1, Ok("opened".into()));
+        report.push(2, Err(ChetterError::GithubParseError("bad".into())));
+        assert_eq!(report.into_response().status(), StatusCode::MULTI_STATUS);
+    }
+
+    #[test]
+    fn into_result() {
+        let mut report = BatchReport::default();
+        report.push(1, Ok("opened".into()));
+        assert!(report.into_result().is_ok());
+
+        let mut report = BatchReport::default();
+        report.push(1, Err(ChetterError::GithubParseError("a".into())));
+        report.push(2, Err(ChetterError::GithubParseError("b".into())));
+        assert_eq!(report.into_result().unwrap_err().to_string(), "a | b");
+    }
+}
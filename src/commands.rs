@@ -0,0 +1,177 @@
+use std::fmt;
+
+/// Commands understood in pull request comments, as listed in replies to those that are not.
+pub const USAGE: &str = "\
+- `/chetter disable`: delete the references of this pull request and stop recording them
+- `/chetter enable`: record this pull request again, starting from `v1`
+- `/chetter snapshot <name>`: record the current head and base as `snapshot-<name>`, such as to \
+mark the version that was benchmarked
+- `/chetter diff <from> <to>`: link to the changes between two recorded versions, such as `v1` \
+and `v3`, and show how to compare them with `git range-diff`
+- `/chetter subscribe`: move `<login>-head` to every new version, without having to review, for \
+the author and requested reviewers
+- `/chetter unsubscribe`: stop moving `<login>-head` to new versions";
+
+/// A command given to chetter-app in a pull request comment, on a line of its own starting with
+/// `/chetter`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Delete the references of the pull request and stop recording it
+    Disable,
+
+    /// Record the pull request again after it was disabled
+    Enable,
+
+    /// Record the current head and base under a name
+    Snapshot(String),
+
+    /// Compare two recorded versions, such as `v1` and `v3`
+    Diff(String, String),
+
+    /// Move the reference of the commenter's last review to every new version
+    Subscribe,
+
+    /// Stop moving the commenter's reference to new versions
+    Unsubscribe,
+}
+
+impl Command {
+    /// Commands given in the lines of comment `body`, or why they are not understood.
+    ///
+    /// Lines in fenced code blocks are skipped, so that commands can be quoted.
+    pub fn parse_all(body: &str) -> Vec<Result<Self, String>> {
+        let mut fenced = false;
+        body.lines()
+            .filter(|line| {
+                if line.trim_start().starts_with("```") {
+                    fenced = !fenced;
+                    return false;
+                }
+                !fenced
+            })
+            .filter_map(Self::parse)
+            .collect()
+    }
+
+    /// Parse `line`, None if it is not a command.
+    pub fn parse(line: &str) -> Option<Result<Self, String>> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("/chetter") {
+            return None;
+        }
+        let args: Vec<&str> = words.collect();
+        Some(match args[..] {
+            ["disable"] => Ok(Self::Disable),
+            ["enable"] => Ok(Self::Enable),
+            ["snapshot", name] => Ok(Self::Snapshot(name.to_string())),
+            ["snapshot"] => Err(format!("`{}` needs a name", line.trim())),
+            ["diff", from, to] => Ok(Self::Diff(from.to_string(), to.to_string())),
+            ["diff", ..] => Err(format!("`{}` needs two versions", line.trim())),
+            ["subscribe"] => Ok(Self::Subscribe),
+            ["unsubscribe"] => Ok(Self::Unsubscribe),
+            _ => Err(format!("`{}` is not a command", line.trim())),
+        })
+    }
+
+    /// Whether only those who can push to the repository may give the command.
+    pub fn requires_push(&self) -> bool {
+        match self {
+            Self::Disable | Self::Enable | Self::Snapshot(_) | Self::Subscribe => true,
+            Self::Diff(..) | Self::Unsubscribe => false,
+        }
+    }
+
+    /// Whether the author of the pull request may give the command without being able to push.
+    pub fn open_to_author(&self) -> bool {
+        matches!(self, Self::Snapshot(_) | Self::Subscribe)
+    }
+
+    /// Whether those a review was requested from may give the command without being able to
+    /// push.
+    pub fn open_to_reviewers(&self) -> bool {
+        matches!(self, Self::Subscribe)
+    }
+
+    /// Who may give the command, as told to those who may not.
+    pub fn allowed(&self) -> &'static str {
+        match (self.open_to_author(), self.open_to_reviewers()) {
+            (true, true) => {
+                "the author, requested reviewers and those who can push to the repository"
+            }
+            (true, false) => "the author and those who can push to the repository",
+            (false, true) => "requested reviewers and those who can push to the repository",
+            (false, false) => "those who can push to the repository",
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Disable => write!(f, "/chetter disable"),
+            Self::Enable => write!(f, "/chetter enable"),
+            Self::Snapshot(name) => write!(f, "/chetter snapshot {name}"),
+            Self::Diff(from, to) => write!(f, "/chetter diff {from} {to}"),
+            Self::Subscribe => write!(f, "/chetter subscribe"),
+            Self::Unsubscribe => write!(f, "/chetter unsubscribe"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsed() {
+        let body = "Thanks!\n\n/chetter disable\n  /chetter   enable  \n/chetterdisable\n";
+        assert_eq!(
+            Command::parse_all(body),
+            [Ok(Command::Disable), Ok(Command::Enable)]
+        );
+        assert_eq!(Command::Disable.to_string(), "/chetter disable");
+
+        assert_eq!(
+            Command::parse_all("/chetter frobnicate"),
+            [Err("`/chetter frobnicate` is not a command".into())]
+        );
+        assert_eq!(
+            Command::parse_all("/chetter disable now"),
+            [Err("`/chetter disable now` is not a command".into())]
+        );
+        assert_eq!(
+            Command::parse_all("/chetter snapshot rc1\n/chetter snapshot\n"),
+            [
+                Ok(Command::Snapshot("rc1".into())),
+                Err("`/chetter snapshot` needs a name".into())
+            ]
+        );
+        assert_eq!(
+            Command::Snapshot("rc1".into()).to_string(),
+            "/chetter snapshot rc1"
+        );
+        assert!(Command::Snapshot("rc1".into()).open_to_author());
+        assert!(!Command::Disable.open_to_author());
+
+        assert_eq!(
+            Command::parse_all("/chetter diff v1 v3\n/chetter diff v1\n"),
+            [
+                Ok(Command::Diff("v1".into(), "v3".into())),
+                Err("`/chetter diff v1` needs two versions".into())
+            ]
+        );
+        assert!(!Command::Diff("v1".into(), "v3".into()).requires_push());
+
+        assert_eq!(
+            Command::parse_all("/chetter subscribe\n/chetter unsubscribe"),
+            [Ok(Command::Subscribe), Ok(Command::Unsubscribe)]
+        );
+        assert!(Command::Subscribe.requires_push());
+        assert!(Command::Subscribe.open_to_reviewers());
+        assert!(!Command::Unsubscribe.requires_push());
+
+        assert!(Command::parse_all("> /chetter disable").is_empty());
+        assert!(Command::parse_all("```\n/chetter disable\n```").is_empty());
+        assert!(Command::parse_all("see `/chetter disable`").is_empty());
+    }
+}
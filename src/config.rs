@@ -0,0 +1,1003 @@
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::{
+    crypto::Envelope,
+    error::ChetterError,
+    filter::EventFilters,
+    github::REF_NS,
+    notifications::{NatsSink, Notifications, OutboundWebhook},
+    refname::RefLayout,
+    secrets::SecretSource,
+};
+
+/// Chetter Application configuration
+///
+/// Repository behavior is configured in the `[defaults]` table and may be overridden per
+/// repository in a `[repos."<org>/<repo>"]` table.  Any setting not overridden for a repository is
+/// inherited from `[defaults]`.  In a configuration built in code, a repository setting left at
+/// its built-in default is inherited from `defaults` once the configuration is finalized.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AppConfig {
+    /// GitHub Application id
+    pub app_id: u64,
+
+    /// GitHub Application private key in PEM format
+    ///
+    /// Like every secret it may instead be fetched from a provider, see [SecretSource].
+    pub private_key: Secret,
+
+    /// Bearer token required to use the administrative API, which is disabled when unset
+    pub admin_token: Option<Secret>,
+
+    /// Secret of the GitHub App webhook, every event must be signed with it when set
+    pub webhook_secret: Option<Secret>,
+
+    /// Only accept webhook events from the networks GitHub publishes for hooks
+    #[serde(default)]
+    pub restrict_webhook_sources: bool,
+
+    /// Path under which all routes are served, such as `/chetter` when behind an ingress routing
+    /// `/chetter/*`
+    pub path_prefix: Option<String>,
+
+    /// Hours between scheduled garbage collection of closed pull requests in every installed
+    /// repository, disabled when unset
+    pub gc_interval_hours: Option<u64>,
+
+    /// Minutes between checks for webhook events GitHub failed to deliver, such as while
+    /// chetter-app was down, which are then processed.  Also checked on startup, disabled when
+    /// unset
+    pub delivery_check_minutes: Option<u64>,
+
+    /// Minutes between fetching `private_key` and `webhook_secret` again from their providers,
+    /// to pick up rotated credentials, disabled when unset
+    pub secret_refresh_minutes: Option<u64>,
+
+    /// Poll installed repositories instead of relying on webhook events, disabled when unset
+    pub polling: Option<PollingConfig>,
+
+    /// Background tasks, such as deleting references of closed pull requests, run at once
+    #[serde(default = "default_background_workers")]
+    pub background_workers: usize,
+
+    /// Local storage settings
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    /// Serve HTTPS instead of HTTP, optionally requiring client certificates
+    pub tls: Option<TlsConfig>,
+
+    /// Report errors to Sentry, requires the `sentry` feature
+    pub sentry: Option<SentryConfig>,
+
+    /// Share delivery deduplication and pull request locks between replicas, requires the
+    /// `redis` feature
+    pub redis: Option<RedisConfig>,
+
+    /// GitHub API usage settings
+    #[serde(default)]
+    pub github: GithubConfig,
+
+    /// Rules deciding which webhook events are processed
+    #[serde(default)]
+    pub filters: EventFilters,
+
+    /// Endpoints every processed webhook event is posted to, requires the `notifications`
+    /// feature
+    #[serde(default)]
+    pub outbound_webhooks: Vec<OutboundWebhook>,
+
+    /// NATS subjects every processed webhook event is published to, requires the
+    /// `notifications` feature
+    #[serde(default)]
+    pub outbound_nats: Vec<NatsSink>,
+
+    /// Settings applied to repositories without an override
+    #[serde(default)]
+    pub defaults: RepoConfig,
+
+    /// Per-repository settings, keyed by `<org>/<repo>`
+    #[serde(default)]
+    pub repos: HashMap<String, RepoConfig>,
+
+    /// Repositories that already inherited from `defaults`
+    #[serde(skip)]
+    inherited: HashSet<String>,
+}
+
+/// Settings for serving HTTPS
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// Path of the PEM encoded certificate chain
+    pub certificate: String,
+
+    /// Path of the PEM encoded private key
+    pub private_key: String,
+
+    /// Path of PEM encoded certificate authorities, clients must present a certificate signed by
+    /// one of them when set
+    pub client_ca: Option<String>,
+}
+
+/// Settings for coordinating replicas through Redis
+#[derive(Deserialize, Debug, Clone)]
+pub struct RedisConfig {
+    /// Server URL, such as `redis://redis:6379/0`
+    pub url: Secret,
+
+    /// Prefix of every key, to share a server between deployments
+    #[serde(default = "default_redis_prefix")]
+    pub prefix: String,
+
+    /// Hours deliveries are remembered as processed
+    #[serde(default = "default_delivery_ttl_hours")]
+    pub delivery_ttl_hours: u64,
+
+    /// Seconds to wait for another replica working on the same pull request, which is also how
+    /// long a lock outlives a replica that went away while holding it
+    #[serde(default = "default_lock_timeout_secs")]
+    pub lock_timeout_secs: u64,
+
+    /// Seconds the lease of the replica running scheduled jobs lasts without being renewed, which
+    /// is also how long it takes another replica to take over should it go away
+    #[serde(default = "default_leader_lease_secs")]
+    pub leader_lease_secs: u64,
+}
+
+/// Settings for polling pull requests and reviews
+#[derive(Deserialize, Debug, Clone)]
+pub struct PollingConfig {
+    /// Minutes between polls of every installed repository
+    #[serde(default = "default_poll_minutes")]
+    pub interval_minutes: u64,
+}
+
+/// Settings for reporting errors to Sentry or a compatible service
+#[derive(Deserialize, Debug, Clone)]
+pub struct SentryConfig {
+    /// Client key of the project errors are reported to
+    pub dsn: Secret,
+
+    /// Environment errors are reported in, such as `production`
+    pub environment: Option<String>,
+}
+
+/// Settings for data chetter-app persists locally
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Base64 encoded 32 byte key used to encrypt persisted data, stored unencrypted when unset
+    pub encryption_key: Option<Secret>,
+
+    /// File every change to references is appended to, kept in memory only when unset
+    pub audit_log: Option<PathBuf>,
+
+    /// Record every accepted webhook payload, disabled when unset
+    pub payloads: Option<PayloadRecording>,
+
+    /// File the newest webhook delivery checked for missed events is kept in, so that checks
+    /// resume from it after restarting rather than looking back a day
+    pub delivery_cursor: Option<PathBuf>,
+}
+
+/// Settings for recording webhook payloads
+#[derive(Deserialize, Debug, Clone)]
+pub struct PayloadRecording {
+    /// Directory payloads are written to, one file per delivery
+    pub directory: PathBuf,
+
+    /// Recorded payloads kept, older ones are deleted
+    #[serde(default = "default_max_payloads")]
+    pub max_files: usize,
+}
+
+/// Configuration value that is kept out of Debug output.
+///
+/// The value is either given in the configuration or fetched from a provider when it is loaded,
+/// see [SecretSource].
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(from = "SecretSource")]
+pub struct Secret {
+    source: SecretSource,
+    value: String,
+}
+
+impl Secret {
+    /// Get the secret value.
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    /// Whether the value is fetched from a provider, and may be rotated.
+    pub fn is_provided(&self) -> bool {
+        self.source.is_provided()
+    }
+
+    /// Fetch the value from the provider of the secret, if any.
+    pub fn resolve(&mut self) -> Result<(), ChetterError> {
+        self.value = self.source.fetch()?;
+        Ok(())
+    }
+
+    /// Fetch the value from the provider of the secret again, such as after it was rotated.
+    pub fn refetch(&self) -> Result<Self, ChetterError> {
+        let mut secret = self.clone();
+        secret.resolve()?;
+        Ok(secret)
+    }
+}
+
+impl From<SecretSource> for Secret {
+    fn from(source: SecretSource) -> Self {
+        let value = match source {
+            SecretSource::Value(ref v) => v.clone(),
+            _ => String::new(),
+        };
+        Self { source, value }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        SecretSource::Value(value.into()).into()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        SecretSource::Value(value).into()
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+/// Settings for how chetter-app uses the GitHub API
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GithubConfig {
+    /// Base URL of the GitHub API, `https://api.github.com` when unset, such as a fake GitHub in
+    /// tests
+    pub api_url: Option<String>,
+
+    /// Number of GraphQL mutations deleting references of a single pull request run at once
+    pub delete_concurrency: usize,
+
+    /// Number of references requested per page when listing them, at most 100
+    pub per_page: u8,
+
+    /// Requests left to an installation below which work that can wait, such as deleting
+    /// references and garbage collection, is deferred until the rate limit resets
+    pub rate_limit_floor: u32,
+}
+
+impl Default for GithubConfig {
+    fn default() -> Self {
+        Self {
+            api_url: None,
+            delete_concurrency: 2,
+            per_page: 100,
+            rate_limit_floor: 500,
+        }
+    }
+}
+
+fn default_background_workers() -> usize {
+    4
+}
+
+fn default_redis_prefix() -> String {
+    "chetter".into()
+}
+
+fn default_delivery_ttl_hours() -> u64 {
+    72
+}
+
+fn default_lock_timeout_secs() -> u64 {
+    300
+}
+
+fn default_leader_lease_secs() -> u64 {
+    30
+}
+
+fn default_poll_minutes() -> u64 {
+    5
+}
+
+fn default_max_payloads() -> usize {
+    1000
+}
+
+impl StorageConfig {
+    /// Get the Envelope used to encrypt persisted data, if encryption is enabled.
+    pub fn envelope(&self) -> Result<Option<Envelope>, ChetterError> {
+        self.encryption_key
+            .as_ref()
+            .map(|key| Envelope::from_base64(key.expose()))
+            .transpose()
+    }
+}
+
+impl AppConfig {
+    /// Configuration of GitHub App `app_id` with `private_key`, leaving everything else to its
+    /// default.
+    pub fn new(app_id: u64, private_key: impl Into<Secret>) -> Self {
+        Self {
+            app_id,
+            private_key: private_key.into(),
+            admin_token: None,
+            webhook_secret: None,
+            restrict_webhook_sources: false,
+            path_prefix: None,
+            gc_interval_hours: None,
+            delivery_check_minutes: None,
+            secret_refresh_minutes: None,
+            polling: None,
+            background_workers: default_background_workers(),
+            storage: StorageConfig::default(),
+            tls: None,
+            sentry: None,
+            redis: None,
+            github: GithubConfig::default(),
+            filters: EventFilters::default(),
+            outbound_webhooks: vec![],
+            outbound_nats: vec![],
+            defaults: RepoConfig::default(),
+            repos: HashMap::new(),
+            inherited: HashSet::new(),
+        }
+    }
+
+    /// Load the configuration from a TOML file.
+    pub fn from_file(config_path: &str) -> Result<Self, ChetterError> {
+        let config_str = std::fs::read_to_string(config_path)?;
+        Self::from_toml(&config_str)
+    }
+
+    /// Parse the configuration from a TOML string.
+    pub fn from_toml(config_str: &str) -> Result<Self, ChetterError> {
+        let mut table: toml::Table = toml::from_str(config_str)?;
+
+        if let Some(toml::Value::Table(defaults)) = table.get("defaults").cloned() {
+            if let Some(toml::Value::Table(repos)) = table.get_mut("repos") {
+                for (_, repo) in repos.iter_mut() {
+                    if let toml::Value::Table(overrides) = repo {
+                        let mut merged = defaults.clone();
+                        merge_tables(&mut merged, std::mem::take(overrides));
+                        *overrides = merged;
+                    }
+                }
+            }
+        }
+
+        let mut config: Self = toml::Value::Table(table).try_into()?;
+        // Merging the tables tells overrides from settings left out, which finalize cannot.
+        config.inherited = config.repos.keys().cloned().collect();
+        config.finalize()?;
+        Ok(config)
+    }
+
+    /// Normalize and validate the settings, then fetch every secret given by a provider.
+    ///
+    /// Done when parsing the configuration, and by [State::from_config] for configurations built
+    /// otherwise.
+    ///
+    /// [State::from_config]: crate::State::from_config
+    pub fn finalize(&mut self) -> Result<(), ChetterError> {
+        self.path_prefix = match self.path_prefix.as_deref().map(|p| p.trim_end_matches('/')) {
+            None | Some("") => None,
+            Some(p) if p.starts_with('/') => Some(p.into()),
+            Some(p) => {
+                return Err(ChetterError::Config(format!(
+                    "path_prefix must start with '/': {p}"
+                )))
+            }
+        };
+        if !(1..=100).contains(&self.github.per_page) {
+            return Err(ChetterError::Config(format!(
+                "github.per_page must be between 1 and 100: {}",
+                self.github.per_page
+            )));
+        }
+        self.filters.validate().map_err(ChetterError::Config)?;
+        for (name, repo) in &mut self.repos {
+            if self.inherited.insert(name.clone()) {
+                repo.inherit(&self.defaults);
+            }
+        }
+        self.resolve_secrets()?;
+        for repo in std::iter::once(&self.defaults).chain(self.repos.values()) {
+            for ns in repo.namespace.iter().chain(repo.migrate_to.iter()) {
+                if !valid_namespace(ns) {
+                    return Err(ChetterError::Config(format!(
+                        "reference namespace must be below refs/heads/, refs/tags/, refs/notes/ or refs/guest/: {ns}"
+                    )));
+                }
+            }
+            // Already checked when deserialized, but not when built otherwise
+            repo.layout.validate().map_err(ChetterError::Config)?;
+        }
+        Ok(())
+    }
+
+    /// Fetch every secret given by a provider that was not fetched yet.
+    fn resolve_secrets(&mut self) -> Result<(), ChetterError> {
+        let secrets = std::iter::once(&mut self.private_key)
+            .chain(self.admin_token.as_mut())
+            .chain(self.webhook_secret.as_mut())
+            .chain(self.storage.encryption_key.as_mut())
+            .chain(self.sentry.as_mut().map(|s| &mut s.dsn))
+            .chain(self.redis.as_mut().map(|r| &mut r.url))
+            .chain(
+                std::iter::once(&mut self.defaults)
+                    .chain(self.repos.values_mut())
+                    .filter_map(|r| r.notifications.as_mut())
+                    .flat_map(|n| n.secrets_mut()),
+            )
+            .chain(
+                self.outbound_webhooks
+                    .iter_mut()
+                    .filter_map(|w| w.secret.as_mut()),
+            )
+            .chain(
+                self.outbound_nats
+                    .iter_mut()
+                    .filter_map(|n| n.token.as_mut()),
+            );
+        for secret in secrets.filter(|s| s.is_provided() && s.value.is_empty()) {
+            secret.resolve()?;
+        }
+        Ok(())
+    }
+
+    /// Get the path of `route` (starting with '/') including any configured `path_prefix`.
+    pub fn url_path(&self, route: &str) -> String {
+        format!("{}{route}", self.path_prefix.as_deref().unwrap_or(""))
+    }
+
+    /// Get the settings for the repository `full_name` (`<org>/<repo>`).
+    pub fn repo(&self, full_name: &str) -> &RepoConfig {
+        self.repos.get(full_name).unwrap_or(&self.defaults)
+    }
+}
+
+/// Check that `ns` is a reference namespace GitHub allows applications to write to.
+fn valid_namespace(ns: &str) -> bool {
+    ["refs/heads/", "refs/tags/", "refs/notes/", "refs/guest/"]
+        .iter()
+        .any(|p| ns.len() > p.len() && ns.starts_with(p) && !ns.ends_with('/'))
+}
+
+/// Recursively overlay `overrides` onto `base`.
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge_tables(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Per-repository settings
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RepoConfig {
+    /// How to record a push that only rebased the pull request onto a new base.
+    pub rebase: RebaseMode,
+
+    /// Handling of reviews that have been dismissed.
+    pub dismissed_review: ReviewPolicy,
+
+    /// Handling of reviews that are still pending.
+    pub pending_review: ReviewPolicy,
+
+    /// Handling of reviews that only comment, without approving or requesting changes.
+    pub commented_review: ReviewPolicy,
+
+    /// Only bookmark approvals, ignoring reviews that request changes.
+    pub approvals_only: bool,
+
+    /// How bookmarks of each review are named.
+    pub review_naming: ReviewNaming,
+
+    /// Do not record reviews submitted by bots, such as CI or code review applications.
+    pub ignore_bot_reviews: bool,
+
+    /// Logins whose reviews are not recorded.
+    pub ignored_reviewers: Vec<String>,
+
+    /// Comment on the pull request when a new version is created.
+    pub version_comment: CommentMode,
+
+    /// Set a `chetter/snapshot` commit status when a new version is created.
+    pub commit_status: bool,
+
+    /// Comment on the pull request with the differences from the prior version when a new
+    /// version is created.
+    pub range_diff_comment: bool,
+
+    /// Fire a `repository_dispatch` event of this type when a new version is created, so that
+    /// workflows of the repository can act on it.
+    pub dispatch_event: Option<String>,
+
+    /// Maintain `latest` and `latest-base` references pointing at the most recent version.
+    pub latest_refs: bool,
+
+    /// Record the merge-base of every version and its base as `v<N>-mergebase`.
+    pub merge_base_refs: bool,
+
+    /// Preserve references as tags when a pull request is merged instead of only deleting them.
+    pub archive_on_merge: bool,
+
+    /// Serve badges of pull requests to anyone even though the repository is private.
+    pub public_badges: bool,
+
+    /// Seconds to wait after a pull request is opened before recording `v1`, so that pushes made
+    /// immediately after opening are included in the first version.
+    pub open_delay_secs: u64,
+
+    /// Keep some references for a grace period after a pull request is closed instead of
+    /// deleting them all immediately.
+    pub retention: Option<Retention>,
+
+    /// Namespace references are recorded under, `refs/heads/pr` when unset.
+    pub namespace: Option<String>,
+
+    /// Namespace references are also recorded under while migrating away from `namespace`.
+    pub migrate_to: Option<String>,
+
+    /// Announce new versions and reviews, requires the `notifications` feature
+    pub notifications: Option<Notifications>,
+
+    /// Templates naming the references of each pull request
+    pub layout: RefLayout,
+}
+
+impl RepoConfig {
+    /// Take every setting left at its built-in default from `defaults` instead.
+    fn inherit(&mut self, defaults: &RepoConfig) {
+        let builtin = RepoConfig::default();
+        macro_rules! inherit {
+            ($($field:ident),* $(,)?) => {
+                // Listing every field makes adding one without inheriting it an error
+                let RepoConfig { $($field),* } = self;
+                $(
+                    if *$field == builtin.$field {
+                        *$field = defaults.$field.clone();
+                    }
+                )*
+            };
+        }
+        inherit!(
+            rebase,
+            dismissed_review,
+            pending_review,
+            commented_review,
+            approvals_only,
+            review_naming,
+            ignore_bot_reviews,
+            ignored_reviewers,
+            version_comment,
+            commit_status,
+            range_diff_comment,
+            dispatch_event,
+            latest_refs,
+            merge_base_refs,
+            archive_on_merge,
+            public_badges,
+            open_delay_secs,
+            retention,
+            namespace,
+            migrate_to,
+            notifications,
+            layout,
+        );
+    }
+
+    /// Get the namespace references are recorded under.
+    pub fn ref_namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(REF_NS)
+    }
+
+    /// Check if reviews submitted by `login` should not be recorded.
+    pub fn ignores_reviewer(&self, login: &str, is_bot: bool) -> bool {
+        (is_bot && self.ignore_bot_reviews)
+            || self
+                .ignored_reviewers
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(login))
+    }
+
+    /// Check if `full_ref` is recorded by chetter, either in its namespace or the migration
+    /// target.
+    pub fn owns_ref(&self, full_ref: &str) -> bool {
+        std::iter::once(self.ref_namespace())
+            .chain(self.migrate_to.as_deref())
+            .any(|ns| {
+                full_ref
+                    .strip_prefix(ns)
+                    .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+}
+
+/// References kept after a pull request is closed
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Retention {
+    /// Number of the most recent versions to keep along with `head` and `head-base`.
+    pub keep_versions: u32,
+
+    /// Days after the pull request was closed before scheduled garbage collection deletes the
+    /// kept references.
+    pub grace_days: u32,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            keep_versions: 1,
+            grace_days: 7,
+        }
+    }
+}
+
+/// Handling of pushes that rebase a pull request without changing its content.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RebaseMode {
+    /// Do not detect rebases, every push creates a new version.
+    #[default]
+    Off,
+
+    /// Create the new version along with a `v<N>-rebase` marker reference.
+    Mark,
+
+    /// Update `head` and `head-base`, but do not create a new version.
+    Skip,
+}
+
+/// Handling of reviews in states other than approved.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReviewPolicy {
+    /// Do nothing.
+    #[default]
+    Ignore,
+
+    /// Bookmark the review as if it were approved or requested changes.
+    Record,
+
+    /// Delete all references bookmarking the reviewer's prior reviews.
+    Cleanup,
+}
+
+/// Naming of the references bookmarking each review.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReviewNaming {
+    /// Number reviews by each reviewer, `<reviewer>-v<N>`.
+    #[default]
+    Counter,
+
+    /// Use the id of the review, `<reviewer>-r<id>`.
+    Id,
+
+    /// Use the time the review was submitted, `<reviewer>-t<YYYYMMDD>T<HHMMSS>Z`.
+    Timestamp,
+}
+
+/// How to announce a new version on the pull request.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommentMode {
+    /// Do not comment.
+    #[default]
+    Off,
+
+    /// Add a new comment for every version.
+    Post,
+
+    /// Maintain a single comment describing the latest version.
+    Update,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    const KEYS: &str = indoc! {r#"
+        app_id = 1234
+        private_key = "key"
+    "#};
+
+    #[test]
+    fn minimal() {
+        let config = AppConfig::from_toml(KEYS).unwrap();
+        assert_eq!(config.app_id, 1234);
+        assert_eq!(config.background_workers, 4);
+        assert_eq!(config.repo("org/repo"), &RepoConfig::default());
+    }
+
+    #[test]
+    fn built() {
+        let parsed = AppConfig::from_toml(KEYS).unwrap();
+        let mut config = AppConfig::new(1234, "key");
+        config.finalize().unwrap();
+        assert_eq!(format!("{config:?}"), format!("{parsed:?}"));
+
+        config.path_prefix = Some("/chetter/".into());
+        config.webhook_secret = Some(
+            SecretSource::Exec {
+                exec: vec!["echo".into(), "s3cret".into()],
+            }
+            .into(),
+        );
+        config.finalize().unwrap();
+        assert_eq!(config.path_prefix.as_deref(), Some("/chetter"));
+        assert_eq!(config.webhook_secret.unwrap().expose(), "s3cret");
+
+        let mut config = AppConfig::new(1234, "key");
+        config.github.per_page = 0;
+        assert!(config.finalize().is_err());
+    }
+
+    #[test]
+    fn secrets() {
+        let config = AppConfig::from_toml(indoc! {r#"
+            app_id = 1234
+            private_key = "private"
+            admin_token = "admin"
+            webhook_secret = "webhook"
+        "#})
+        .unwrap();
+        assert_eq!(config.private_key.expose(), "private");
+        assert_eq!(config.admin_token.as_ref().unwrap().expose(), "admin");
+
+        let debug = format!("{config:?}");
+        for secret in ["private", "admin", "webhook"] {
+            assert!(!debug.contains(&format!("\"{secret}\"")), "{debug}");
+        }
+        assert!(!config.private_key.is_provided());
+    }
+
+    #[test]
+    fn secret_providers() {
+        let config = AppConfig::from_toml(indoc! {r#"
+            app_id = 1234
+            private_key = { exec = ["echo", "private"] }
+            webhook_secret = { exec = ["sh", "-c", "echo webhook"] }
+        "#})
+        .unwrap();
+        assert_eq!(config.private_key.expose(), "private");
+        assert!(config.private_key.is_provided());
+        assert_eq!(config.webhook_secret.as_ref().unwrap().expose(), "webhook");
+        assert_eq!(
+            config.private_key.refetch().unwrap().expose(),
+            config.private_key.expose()
+        );
+
+        assert!(AppConfig::from_toml(indoc! {r#"
+            app_id = 1234
+            private_key = { exec = ["false"] }
+        "#})
+        .is_err());
+        assert!(AppConfig::from_toml(indoc! {r#"
+            app_id = 1234
+            private_key = { unknown = "provider" }
+        "#})
+        .is_err());
+    }
+
+    #[test]
+    fn payloads() {
+        let config = AppConfig::from_toml(KEYS).unwrap();
+        assert!(config.storage.payloads.is_none());
+
+        let config = AppConfig::from_toml(&format!(
+            "{KEYS}\n[storage.payloads]\ndirectory = \"/data/payloads\"\n"
+        ))
+        .unwrap();
+        let payloads = config.storage.payloads.unwrap();
+        assert_eq!(payloads.directory, PathBuf::from("/data/payloads"));
+        assert_eq!(payloads.max_files, 1000);
+    }
+
+    #[test]
+    fn polling() {
+        let config = AppConfig::from_toml(KEYS).unwrap();
+        assert!(config.polling.is_none());
+
+        let config = AppConfig::from_toml(&format!("{KEYS}\n[polling]\n")).unwrap();
+        assert_eq!(config.polling.unwrap().interval_minutes, 5);
+    }
+
+    #[test]
+    fn path_prefix() {
+        let config = AppConfig::from_toml(KEYS).unwrap();
+        assert_eq!(config.url_path("/admin"), "/admin");
+
+        let config = AppConfig::from_toml(&format!("path_prefix = \"/chetter/\"\n{KEYS}")).unwrap();
+        assert_eq!(config.path_prefix.as_deref(), Some("/chetter"));
+        assert_eq!(config.url_path("/admin"), "/chetter/admin");
+
+        let config = AppConfig::from_toml(&format!("path_prefix = \"/\"\n{KEYS}")).unwrap();
+        assert_eq!(config.path_prefix, None);
+
+        assert!(AppConfig::from_toml(&format!("path_prefix = \"chetter\"\n{KEYS}")).is_err());
+    }
+
+    #[test]
+    fn github() {
+        let config = AppConfig::from_toml(KEYS).unwrap();
+        assert_eq!(config.github.per_page, 100);
+
+        let config = AppConfig::from_toml(&format!("{KEYS}\n[github]\nper_page = 50\n")).unwrap();
+        assert_eq!(config.github.per_page, 50);
+        assert_eq!(config.github.delete_concurrency, 2);
+
+        assert!(AppConfig::from_toml(&format!("{KEYS}\n[github]\nper_page = 0\n")).is_err());
+        assert!(AppConfig::from_toml(&format!("{KEYS}\n[github]\nper_page = 101\n")).is_err());
+    }
+
+    #[test]
+    fn repo_overrides() {
+        let config = AppConfig::from_toml(&format!(
+            "{KEYS}{}",
+            indoc! {r#"
+                [defaults]
+                rebase = "mark"
+
+                [repos."org/skip"]
+                rebase = "skip"
+                dismissed_review = "cleanup"
+
+                [repos."org/inherit"]
+
+                [repos."org/retain".retention]
+                keep_versions = 3
+            "#}
+        ))
+        .unwrap();
+        assert_eq!(config.repo("org/other").rebase, RebaseMode::Mark);
+        assert_eq!(config.repo("org/skip").rebase, RebaseMode::Skip);
+        assert_eq!(config.repo("org/inherit").rebase, RebaseMode::Mark);
+        assert_eq!(
+            config.repo("org/skip").dismissed_review,
+            ReviewPolicy::Cleanup
+        );
+        assert_eq!(
+            config.repo("org/inherit").dismissed_review,
+            ReviewPolicy::Ignore
+        );
+        assert_eq!(config.repo("org/inherit").retention, None);
+        assert_eq!(
+            config.repo("org/retain").retention,
+            Some(Retention {
+                keep_versions: 3,
+                grace_days: 7
+            })
+        );
+    }
+
+    #[test]
+    fn built_repo_overrides() {
+        let mut config = AppConfig::new(1234, "key");
+        config.defaults.rebase = RebaseMode::Mark;
+        config.defaults.latest_refs = true;
+        config.repos.insert(
+            "org/skip".into(),
+            RepoConfig {
+                rebase: RebaseMode::Skip,
+                ..Default::default()
+            },
+        );
+        config
+            .repos
+            .insert("org/inherit".into(), RepoConfig::default());
+        config.finalize().unwrap();
+        assert_eq!(config.repo("org/skip").rebase, RebaseMode::Skip);
+        assert!(config.repo("org/skip").latest_refs);
+        assert_eq!(config.repo("org/inherit"), &config.defaults);
+
+        // Overrides back to a built-in default survive finalizing a parsed configuration again
+        let mut parsed = AppConfig::from_toml(&format!(
+            "{KEYS}{}",
+            indoc! {r#"
+                [defaults]
+                latest_refs = true
+
+                [repos."org/off"]
+                latest_refs = false
+            "#}
+        ))
+        .unwrap();
+        parsed.finalize().unwrap();
+        assert!(!parsed.repo("org/off").latest_refs);
+    }
+
+    #[test]
+    fn ignored_reviewers() {
+        let config = AppConfig::from_toml(&format!(
+            "{KEYS}{}",
+            indoc! {r#"
+                [defaults]
+                ignore_bot_reviews = true
+                ignored_reviewers = ["ci-user"]
+            "#}
+        ))
+        .unwrap();
+        let repo = config.repo("org/repo");
+        assert!(repo.ignores_reviewer("dependabot[bot]", true));
+        assert!(repo.ignores_reviewer("CI-User", false));
+        assert!(!repo.ignores_reviewer("nick", false));
+        assert!(!RepoConfig::default().ignores_reviewer("dependabot[bot]", true));
+    }
+
+    #[test]
+    fn layout() {
+        let config = AppConfig::from_toml(KEYS).unwrap();
+        assert_eq!(config.repo("org/repo").layout, RefLayout::default());
+
+        let toml = format!(
+            "{KEYS}{}",
+            indoc! {r#"
+                [defaults.layout]
+                version = "{pr}/snap-{version}"
+
+                [repos."org/other".layout]
+                head = "{pr}/tip"
+            "#}
+        );
+        let config = AppConfig::from_toml(&toml).unwrap();
+        let layout = &config.repo("org/repo").layout;
+        assert_eq!(layout.version, "{pr}/snap-{version}");
+        assert_eq!(layout.head, "{pr}/head");
+        let layout = &config.repo("org/other").layout;
+        assert_eq!(layout.version, "{pr}/snap-{version}");
+        assert_eq!(layout.head, "{pr}/tip");
+
+        for layout in ["version = \"{pr}/snap\"", "head = \"{pr}/disabled\""] {
+            let toml = format!("{KEYS}[defaults.layout]\n{layout}\n");
+            assert!(AppConfig::from_toml(&toml).is_err(), "{layout}");
+        }
+    }
+
+    #[test]
+    fn namespace() {
+        let config = AppConfig::from_toml(&format!(
+            "{KEYS}{}",
+            indoc! {r#"
+                [repos."org/migrating"]
+                migrate_to = "refs/heads/chetter"
+            "#}
+        ))
+        .unwrap();
+        let repo = config.repo("org/other");
+        assert_eq!(repo.ref_namespace(), REF_NS);
+        assert!(repo.owns_ref("refs/heads/pr/12/v1"));
+        assert!(!repo.owns_ref("refs/heads/chetter/12/v1"));
+        assert!(!repo.owns_ref("refs/heads/pre-release"));
+
+        let repo = config.repo("org/migrating");
+        assert!(repo.owns_ref("refs/heads/pr/12/v1"));
+        assert!(repo.owns_ref("refs/heads/chetter/12/v1"));
+
+        for ns in ["refs/pull", "refs/heads/", "pr", "refs/heads/pr/"] {
+            let toml = format!("{KEYS}[defaults]\nnamespace = \"{ns}\"\n");
+            assert!(AppConfig::from_toml(&toml).is_err(), "{ns}");
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{config::RedisConfig, error::ChetterError};
+
+/// Deliveries remembered by a single replica.
+const MAX_RECENT_DELIVERIES: usize = 10000;
+
+/// Keeps replicas from processing the same webhook delivery, or updating the references of a pull
+/// request, at the same time, and elects the one running scheduled jobs.
+///
+/// Without a Redis backend, deliveries are only deduplicated within this replica, work on pull
+/// requests runs concurrently as before and this replica is always the leader.
+#[derive(Clone)]
+pub struct Coordinator {
+    recent: Arc<Mutex<RecentDeliveries>>,
+    leader: Arc<AtomicBool>,
+
+    #[cfg(feature = "redis")]
+    redis: Option<backend::Redis>,
+}
+
+#[derive(Default)]
+struct RecentDeliveries {
+    guids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl RecentDeliveries {
+    /// Remember `guid`, false if it already was.
+    fn insert(&mut self, guid: &str) -> bool {
+        if !self.guids.insert(guid.into()) {
+            return false;
+        }
+        self.order.push_back(guid.into());
+        if self.order.len() > MAX_RECENT_DELIVERIES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.guids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Forget `guid`.
+    fn remove(&mut self, guid: &str) {
+        if self.guids.remove(guid) {
+            self.order.retain(|g| g != guid);
+        }
+    }
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Self {
+            recent: Arc::default(),
+            leader: Arc::new(AtomicBool::new(true)),
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+}
+
+impl Coordinator {
+    /// Create a coordinator using the Redis server of `config`, if any, which is connected to
+    /// when first used.
+    pub fn new(config: Option<&RedisConfig>) -> Result<Self, ChetterError> {
+        match config {
+            None => Ok(Self::default()),
+            #[cfg(feature = "redis")]
+            Some(config) => Ok(Self {
+                recent: Arc::default(),
+                leader: Arc::default(),
+                redis: Some(backend::Redis::new(config)?),
+            }),
+            #[cfg(not(feature = "redis"))]
+            Some(_) => Err(ChetterError::Config(
+                "[redis] is configured but chetter-app was built without it".into(),
+            )),
+        }
+    }
+
+    /// Claim webhook delivery `guid`, false if it was already claimed and must not be processed
+    /// again.
+    pub async fn claim_delivery(&self, guid: &str) -> Result<bool, ChetterError> {
+        if self.recent.lock().unwrap().guids.contains(guid) {
+            return Ok(false);
+        }
+        #[cfg(feature = "redis")]
+        if let Some(ref redis) = self.redis {
+            if !redis.claim_delivery(guid).await? {
+                return Ok(false);
+            }
+        }
+        Ok(self.recent.lock().unwrap().insert(guid))
+    }
+
+    /// Forget the claim on delivery `guid`, such as after failing to process it, so that it can
+    /// be redelivered.
+    pub async fn release_delivery(&self, guid: &str) -> Result<(), ChetterError> {
+        self.recent.lock().unwrap().remove(guid);
+        #[cfg(feature = "redis")]
+        if let Some(ref redis) = self.redis {
+            return redis.release_delivery(guid).await;
+        }
+        Ok(())
+    }
+
+    /// Run `work` on pull request `pr` of `repo` once no other replica is working on it.
+    pub async fn exclusive<F: Future>(
+        &self,
+        repo: &str,
+        pr: u64,
+        work: F,
+    ) -> Result<F::Output, ChetterError> {
+        #[cfg(feature = "redis")]
+        if let Some(ref redis) = self.redis {
+            return redis.exclusive(repo, pr, work).await;
+        }
+        let _ = (repo, pr);
+        Ok(work.await)
+    }
+
+    /// Whether this replica runs scheduled jobs, such as garbage collection.
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    /// Take part in electing the leader until `shutdown` is cancelled.
+    ///
+    /// The leader holds a lease that it renews, another replica takes over once it expires.  The
+    /// first election is over when this returns, so that scheduled jobs starting right away run on
+    /// the leader.
+    pub async fn start_election(&self, shutdown: CancellationToken) {
+        #[cfg(feature = "redis")]
+        if let Some(ref redis) = self.redis {
+            use tokio::time::interval;
+            use tracing::{info, warn};
+
+            let renew = redis.renew_interval();
+            let redis = redis.clone();
+            let leader = self.leader.clone();
+            let token = crate::crypto::random_token();
+            let campaign = move || {
+                let redis = redis.clone();
+                let leader = leader.clone();
+                let token = token.clone();
+                async move {
+                    let leading = leader.load(Ordering::Relaxed);
+                    // Step down when the lease cannot be renewed, it may expire meanwhile
+                    let elected = match redis.campaign(&token, leading).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("Leader election failed: {e}");
+                            false
+                        }
+                    };
+                    match (leading, elected) {
+                        (false, true) => info!("Elected leader, running scheduled jobs"),
+                        (true, false) => info!("No longer the leader"),
+                        _ => (),
+                    }
+                    leader.store(elected, Ordering::Relaxed);
+                }
+            };
+
+            campaign().await;
+            let mut ticker = interval(renew);
+            ticker.tick().await;
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = ticker.tick() => campaign().await,
+                    }
+                }
+            });
+            return;
+        }
+        let _ = shutdown;
+    }
+}
+
+#[cfg(feature = "redis")]
+mod backend {
+    use redis::{aio::ConnectionManager, Client, Script};
+    use std::{future::Future, pin::pin, sync::Arc};
+    use tokio::{
+        sync::OnceCell,
+        time::{interval_at, sleep, Duration, Instant},
+    };
+    use tracing::warn;
+
+    use crate::{config::RedisConfig, crypto::random_token, error::ChetterError};
+
+    /// How often a lock held by another replica is tried again.
+    const LOCK_RETRY: Duration = Duration::from_millis(250);
+
+    /// Extend the lease of the leader, or a lock, only if it is still held with the token it was
+    /// taken with.
+    const RENEW: &str = r#"
+        if redis.call("GET", KEYS[1]) == ARGV[1] then
+            return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+        end
+        return 0
+    "#;
+
+    /// Delete a lock only if it is still held with the token it was taken with.
+    const RELEASE: &str = r#"
+        if redis.call("GET", KEYS[1]) == ARGV[1] then
+            return redis.call("DEL", KEYS[1])
+        end
+        return 0
+    "#;
+
+    #[derive(Clone)]
+    pub(super) struct Redis {
+        client: Client,
+        conn: Arc<OnceCell<ConnectionManager>>,
+        prefix: String,
+        delivery_ttl: Duration,
+        lock_timeout: Duration,
+        leader_lease: Duration,
+    }
+
+    impl Redis {
+        pub(super) fn new(config: &RedisConfig) -> Result<Self, ChetterError> {
+            Ok(Self {
+                client: Client::open(config.url.expose())?,
+                conn: Arc::default(),
+                prefix: config.prefix.clone(),
+                delivery_ttl: Duration::from_secs(config.delivery_ttl_hours * 3600),
+                lock_timeout: Duration::from_secs(config.lock_timeout_secs),
+                leader_lease: Duration::from_secs(config.leader_lease_secs.max(3)),
+            })
+        }
+
+        /// Connection to the server, which reconnects on its own once established.
+        async fn conn(&self) -> Result<ConnectionManager, ChetterError> {
+            let conn = self
+                .conn
+                .get_or_try_init(|| self.client.get_connection_manager())
+                .await?;
+            Ok(conn.clone())
+        }
+
+        pub(super) async fn claim_delivery(&self, guid: &str) -> Result<bool, ChetterError> {
+            let claimed: Option<String> = redis::cmd("SET")
+                .arg(format!("{}:delivery:{guid}", self.prefix))
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(self.delivery_ttl.as_secs())
+                .query_async(&mut self.conn().await?)
+                .await?;
+            Ok(claimed.is_some())
+        }
+
+        pub(super) async fn release_delivery(&self, guid: &str) -> Result<(), ChetterError> {
+            let _: i64 = redis::cmd("DEL")
+                .arg(format!("{}:delivery:{guid}", self.prefix))
+                .query_async(&mut self.conn().await?)
+                .await?;
+            Ok(())
+        }
+
+        /// How often the leader renews its lease and other replicas try to take it over.
+        pub(super) fn renew_interval(&self) -> Duration {
+            self.leader_lease / 3
+        }
+
+        /// Renew the lease of the leader if `leading`, otherwise try to take it, returning whether
+        /// this replica holds it with `token`.
+        pub(super) async fn campaign(
+            &self,
+            token: &str,
+            leading: bool,
+        ) -> Result<bool, ChetterError> {
+            let key = format!("{}:leader", self.prefix);
+            let lease = self.leader_lease.as_millis() as u64;
+            let mut conn = self.conn().await?;
+            if leading {
+                let renewed: i32 = Script::new(RENEW)
+                    .key(&key)
+                    .arg(token)
+                    .arg(lease)
+                    .invoke_async(&mut conn)
+                    .await?;
+                if renewed == 1 {
+                    return Ok(true);
+                }
+            }
+            let taken: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg(token)
+                .arg("NX")
+                .arg("PX")
+                .arg(lease)
+                .query_async(&mut conn)
+                .await?;
+            Ok(taken.is_some())
+        }
+
+        /// Take the lock of pull request `pr` of `repo`, which expires after `lock_timeout` should
+        /// this replica go away while holding it, run `work` and release it.
+        ///
+        /// The lock is renewed while `work` runs, so that work taking longer than `lock_timeout`
+        /// keeps it.
+        pub(super) async fn exclusive<F: Future>(
+            &self,
+            repo: &str,
+            pr: u64,
+            work: F,
+        ) -> Result<F::Output, ChetterError> {
+            let key = format!("{}:lock:{repo}:{pr}", self.prefix);
+            let token = random_token();
+            let mut conn = self.conn().await?;
+            let deadline = Instant::now() + self.lock_timeout;
+            loop {
+                let locked: Option<String> = redis::cmd("SET")
+                    .arg(&key)
+                    .arg(&token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(self.lock_timeout.as_millis() as u64)
+                    .query_async(&mut conn)
+                    .await?;
+                if locked.is_some() {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(ChetterError::Coordination(format!(
+                        "timed out waiting for another replica to finish {repo}#{pr}"
+                    )));
+                }
+                sleep(LOCK_RETRY).await;
+            }
+
+            let timeout = self.lock_timeout.as_millis() as u64;
+            let renew = (self.lock_timeout / 3).max(LOCK_RETRY);
+            let mut ticker = interval_at(Instant::now() + renew, renew);
+            let mut work = pin!(work);
+            let r = loop {
+                tokio::select! {
+                    r = &mut work => break r,
+                    _ = ticker.tick() => {
+                        let renewed: Result<i32, _> = Script::new(RENEW)
+                            .key(&key)
+                            .arg(&token)
+                            .arg(timeout)
+                            .invoke_async(&mut conn)
+                            .await;
+                        match renewed {
+                            Ok(1) => (),
+                            Ok(_) => warn!("Lost {key}, another replica may work on {repo}#{pr}"),
+                            Err(e) => warn!("Failed to renew {key}: {e}"),
+                        }
+                    }
+                }
+            };
+
+            let released: Result<i32, _> = Script::new(RELEASE)
+                .key(&key)
+                .arg(&token)
+                .invoke_async(&mut conn)
+                .await;
+            match released {
+                Ok(1) => (),
+                Ok(_) => warn!("{key} expired or was taken by another replica before release"),
+                Err(e) => warn!("Failed to release {key}, it expires on its own: {e}"),
+            }
+            Ok(r)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local() {
+        let coordinator = Coordinator::default();
+        assert!(coordinator.claim_delivery("a").await.unwrap());
+        assert!(coordinator.claim_delivery("b").await.unwrap());
+        assert!(!coordinator.claim_delivery("a").await.unwrap());
+        coordinator.release_delivery("a").await.unwrap();
+        assert!(coordinator.claim_delivery("a").await.unwrap());
+
+        coordinator.start_election(CancellationToken::new()).await;
+        assert!(coordinator.is_leader());
+        assert_eq!(
+            coordinator
+                .exclusive("org/repo", 1, async { 2 })
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn forgets_oldest() {
+        let coordinator = Coordinator::default();
+        for i in 0..=MAX_RECENT_DELIVERIES {
+            assert!(coordinator.claim_delivery(&i.to_string()).await.unwrap());
+        }
+        assert!(coordinator.claim_delivery("0").await.unwrap());
+        assert!(!coordinator.claim_delivery("2").await.unwrap());
+    }
+}
@@ -0,0 +1,187 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::error::ChetterError;
+
+/// Identifies the format of sealed data.
+const MAGIC: &[u8; 4] = b"CHE1";
+const NONCE_LEN: usize = 24;
+/// Length of an encrypted data key, the key plus authentication tag
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN;
+
+/// Envelope encryption for data persisted to disk.
+///
+/// Each sealed record is encrypted with a random data key, which is itself encrypted with the
+/// configured master key and stored alongside the record.  Using XChaCha20-Poly1305 throughout
+/// makes random nonces safe and detects tampering.
+///
+/// Sealed layout: `MAGIC | key nonce | wrapped data key | data nonce | ciphertext`
+#[derive(Clone)]
+pub struct Envelope {
+    master: XChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for Envelope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Envelope(<redacted>)")
+    }
+}
+
+impl Envelope {
+    /// Create an Envelope from a base64 encoded 32 byte master key.
+    pub fn from_base64(key: &str) -> Result<Self, ChetterError> {
+        let key = BASE64
+            .decode(key.trim())
+            .map_err(|e| ChetterError::Encryption(format!("invalid encryption key: {e}")))?;
+        if key.len() != 32 {
+            return Err(ChetterError::Encryption(format!(
+                "encryption key must be 32 bytes, not {}",
+                key.len()
+            )));
+        }
+        Ok(Self {
+            master: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Generate a new random master key, base64 encoded.
+    pub fn generate_key() -> String {
+        BASE64.encode(XChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    /// Encrypt `plaintext`.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, ChetterError> {
+        let data_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let key_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped = self
+            .master
+            .encrypt(&key_nonce, data_key.as_slice())
+            .map_err(|e| ChetterError::Encryption(e.to_string()))?;
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&data_key)
+            .encrypt(&nonce, plaintext)
+            .map_err(|e| ChetterError::Encryption(e.to_string()))?;
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&key_nonce);
+        sealed.extend_from_slice(&wrapped);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt data previously encrypted by `seal()`.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, ChetterError> {
+        if sealed.len() < HEADER_LEN || &sealed[..MAGIC.len()] != MAGIC {
+            return Err(ChetterError::Encryption("not sealed data".into()));
+        }
+
+        let (key_nonce, rest) = sealed[MAGIC.len()..].split_at(NONCE_LEN);
+        let (wrapped, rest) = rest.split_at(WRAPPED_KEY_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let data_key = self
+            .master
+            .decrypt(XNonce::from_slice(key_nonce), wrapped)
+            .map_err(|_| ChetterError::Encryption("wrong key or corrupt data".into()))?;
+        XChaCha20Poly1305::new(Key::from_slice(&data_key))
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| ChetterError::Encryption("corrupt data".into()))
+    }
+}
+
+/// Verify the `X-Hub-Signature-256` header GitHub computed over `body` with the webhook `secret`.
+pub fn verify_signature(
+    secret: &str,
+    body: &[u8],
+    signature: Option<&str>,
+) -> Result<(), ChetterError> {
+    let Some(signature) = signature else {
+        return Err(ChetterError::Unauthorized(
+            "missing X-Hub-Signature-256".into(),
+        ));
+    };
+    let digest = signature
+        .strip_prefix("sha256=")
+        .and_then(|s| hex::decode(s).ok())
+        .ok_or(ChetterError::Unauthorized(
+            "malformed X-Hub-Signature-256".into(),
+        ))?;
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+        .map_err(|e| ChetterError::Unauthorized(e.to_string()))?;
+    mac.update(body);
+    // Constant time, so that timing does not reveal how much of the signature matched
+    mac.verify_slice(&digest)
+        .map_err(|_| ChetterError::Unauthorized("invalid X-Hub-Signature-256".into()))
+}
+
+/// Compute the `X-Hub-Signature-256` header of `body` signed with the webhook `secret`, as GitHub
+/// does when delivering it.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Generate a random hex encoded token that cannot be guessed, such as an OAuth `state`.
+pub fn random_token() -> String {
+    hex::encode(XChaCha20Poly1305::generate_nonce(&mut OsRng))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // Example from the GitHub documentation on validating webhook deliveries
+        let secret = "It's a Secret to Everybody";
+        let header = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify_signature(secret, b"Hello, World!", Some(header)).is_ok());
+        assert!(verify_signature(secret, b"Hello, World?", Some(header)).is_err());
+        assert!(verify_signature("wrong", b"Hello, World!", Some(header)).is_err());
+        assert!(verify_signature(secret, b"Hello, World!", Some("sha256=zz")).is_err());
+        assert!(verify_signature(secret, b"Hello, World!", None).is_err());
+        assert_eq!(sign(secret, b"Hello, World!"), header);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let envelope = Envelope::from_base64(&Envelope::generate_key()).unwrap();
+        let sealed = envelope.seal(b"private metadata").unwrap();
+        assert!(!sealed.windows(7).any(|w| w == b"private"));
+        assert_eq!(envelope.open(&sealed).unwrap(), b"private metadata");
+    }
+
+    #[test]
+    fn tampered() {
+        let envelope = Envelope::from_base64(&Envelope::generate_key()).unwrap();
+        let mut sealed = envelope.seal(b"data").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(envelope.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let a = Envelope::from_base64(&Envelope::generate_key()).unwrap();
+        let b = Envelope::from_base64(&Envelope::generate_key()).unwrap();
+        assert!(b.open(&a.seal(b"data").unwrap()).is_err());
+    }
+
+    #[test]
+    fn bad_key() {
+        assert!(Envelope::from_base64("c2hvcnQ=").is_err());
+        assert!(Envelope::from_base64("not base64!").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use axum::{middleware, response::Html, routing::get, Router};
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use std::collections::BTreeMap;
+
+use crate::{admin::authorize, badge::escape, tracker::TrackedPr, State};
+
+/// Pull requests listed by the dashboard.
+const LIMIT: usize = 100;
+
+/// Create the router serving the dashboard.
+///
+/// The dashboard is protected like the administrative API, browsers prompt for `admin_token` as
+/// password.
+pub fn router(state: State) -> Router<State> {
+    Router::new()
+        .route("/dashboard", get(dashboard))
+        .route_layer(middleware::from_fn_with_state(state, authorize))
+}
+
+/// A pull request listed by the dashboard.
+struct Row {
+    pr: TrackedPr,
+
+    /// Latest recorded version, None if it could not be listed
+    version: Option<u32>,
+}
+
+async fn dashboard(axum::extract::State(state): axum::extract::State<State>) -> Html<String> {
+    let prs = state.recent_prs(LIMIT);
+    let versions = join_all(prs.iter().map(|p| {
+        let state = &state;
+        async move {
+            let (org, repo) = p.repo.split_once('/')?;
+            state
+                .pr_state(org, repo, p.pr)
+                .await
+                .ok()
+                .map(|s| s.version)
+        }
+    }))
+    .await;
+
+    let mut repos: BTreeMap<String, Vec<Row>> = BTreeMap::new();
+    for (pr, version) in prs.into_iter().zip(versions) {
+        repos
+            .entry(pr.repo.clone())
+            .or_default()
+            .push(Row { pr, version });
+    }
+    Html(render(&repos, Utc::now()))
+}
+
+fn render(repos: &BTreeMap<String, Vec<Row>>, now: DateTime<Utc>) -> String {
+    let mut body = String::new();
+    if repos.is_empty() {
+        body.push_str("<p>No pull requests were processed since starting.</p>\n");
+    }
+    for (repo, rows) in repos {
+        let repo = escape(repo);
+        body.push_str(&format!(
+            "<h2>{repo}</h2>\n<table>\n<tr><th>Pull request</th><th>Versions</th><th>Last event</th><th>Last active</th><th>Status</th></tr>\n"
+        ));
+        for row in rows {
+            let activity = &row.pr.activity;
+            let pr = row.pr.pr;
+            let version = row.version.map_or_else(|| "?".into(), |v| v.to_string());
+            let event = activity
+                .last_processed
+                .as_ref()
+                .map(|r| escape(&r.event))
+                .unwrap_or_default();
+            let active = activity
+                .last_active()
+                .map(|t| format!("{} ago", elapsed(now, t)))
+                .unwrap_or_default();
+            let status = match (&activity.in_flight[..], &activity.last_processed) {
+                ([_, ..], _) => "processing".into(),
+                (_, Some(r)) => r
+                    .error
+                    .as_deref()
+                    .map_or_else(|| "ok".into(), |e| format!("failed: {}", escape(e))),
+                _ => String::new(),
+            };
+            body.push_str(&format!(
+                "<tr><td><a href=\"https://github.com/{repo}/pull/{pr}\">#{pr}</a></td><td>{version}</td><td>{event}</td><td>{active}</td><td>{status}</td></tr>\n"
+            ));
+        }
+        body.push_str("</table>\n");
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>chetter</title>\n<style>body {{ font-family: sans-serif; }} td, th {{ padding: 0 1em; text-align: left; }}</style>\n</head>\n<body>\n<h1>Recently processed pull requests</h1>\n{body}</body>\n</html>\n"
+    )
+}
+
+/// Time elapsed from `then` to `now`, rounded down to the largest unit.
+fn elapsed(now: DateTime<Utc>, then: DateTime<Utc>) -> String {
+    let secs = (now - then).num_seconds().max(0);
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::PrTracker;
+    use chrono::Duration;
+
+    #[test]
+    fn render_dashboard() {
+        let tracker = PrTracker::default();
+        tracker
+            .start("org/<repo>", 7, None, "pull_request.opened")
+            .finish::<()>(&Ok(()));
+        let _pending = tracker.start("org/other", 8, None, "pull_request.synchronize");
+
+        let mut repos: BTreeMap<String, Vec<Row>> = BTreeMap::new();
+        for (pr, version) in tracker.recent(10).into_iter().zip([Some(2), None]) {
+            repos
+                .entry(pr.repo.clone())
+                .or_default()
+                .push(Row { pr, version });
+        }
+        let html = render(&repos, Utc::now() + Duration::minutes(5));
+        assert!(html.contains("<h2>org/&lt;repo&gt;</h2>"));
+        assert!(html.contains("<h2>org/other</h2>"));
+        assert!(html.contains("<td>processing</td>"));
+        assert!(html.contains("<td>5m ago</td>"));
+
+        let html = render(&BTreeMap::new(), Utc::now());
+        assert!(html.contains("No pull requests"));
+    }
+
+    #[test]
+    fn elapsed_units() {
+        let now = Utc::now();
+        assert_eq!(elapsed(now, now - Duration::seconds(5)), "5s");
+        assert_eq!(elapsed(now, now - Duration::minutes(90)), "1h");
+        assert_eq!(elapsed(now, now - Duration::days(3)), "3d");
+        assert_eq!(elapsed(now, now + Duration::seconds(5)), "0s");
+    }
+}
@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+use crate::{error::ChetterError, recorder::RecordedPayload};
+
+/// How far back deliveries are checked when no prior check is known, GitHub keeps them for 3 days.
+pub const LOOKBACK_HOURS: i64 = 24;
+
+/// An attempt of GitHub to deliver a webhook event to the app.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HookDelivery {
+    /// Id of the attempt, used to fetch its payload
+    pub id: u64,
+
+    /// Id of the event, shared by every attempt to deliver it and sent as `X-GitHub-Delivery`
+    pub guid: String,
+
+    /// When the attempt was made
+    pub delivered_at: DateTime<Utc>,
+
+    /// Whether the attempt was a redelivery
+    #[serde(default)]
+    pub redelivery: bool,
+
+    /// HTTP status the app responded with, 0 if it could not be reached
+    pub status_code: u16,
+
+    /// Event name, such as `pull_request`
+    pub event: String,
+
+    /// Event action, such as `opened`
+    pub action: Option<String>,
+}
+
+impl HookDelivery {
+    /// Whether the app accepted the event.
+    pub fn succeeded(&self) -> bool {
+        (200..300).contains(&self.status_code)
+    }
+}
+
+/// A delivery attempt including the request GitHub made.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HookDeliveryDetails {
+    /// The attempt
+    #[serde(flatten)]
+    pub delivery: HookDelivery,
+
+    /// Request that was sent
+    pub request: HookDeliveryRequest,
+}
+
+/// Request GitHub made to deliver an event.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HookDeliveryRequest {
+    /// Request headers
+    pub headers: BTreeMap<String, String>,
+
+    /// Parsed request body
+    pub payload: serde_json::Value,
+}
+
+impl From<HookDeliveryDetails> for RecordedPayload {
+    fn from(details: HookDeliveryDetails) -> Self {
+        Self {
+            received_at: details.delivery.delivered_at,
+            headers: details
+                .request
+                .headers
+                .into_iter()
+                .map(|(k, v)| (k.to_lowercase(), v))
+                .collect(),
+            body: details.request.payload.to_string(),
+        }
+    }
+}
+
+/// Deliveries among `deliveries`, listed newest first, of events that were never accepted, oldest
+/// first with one attempt per event.
+pub fn missed(deliveries: &[HookDelivery]) -> Vec<&HookDelivery> {
+    let accepted: HashSet<&str> = deliveries
+        .iter()
+        .filter(|d| d.succeeded())
+        .map(|d| d.guid.as_str())
+        .collect();
+    let mut seen = HashSet::new();
+    let mut missed: Vec<&HookDelivery> = deliveries
+        .iter()
+        .filter(|d| !accepted.contains(d.guid.as_str()) && seen.insert(d.guid.as_str()))
+        .collect();
+    missed.reverse();
+    missed
+}
+
+/// Newest delivery checked for missed events, kept in a file if configured so that checking
+/// resumes from it after restarting.
+#[derive(Clone, Default)]
+pub struct DeliveryCursor {
+    path: Option<PathBuf>,
+    guid: Arc<Mutex<Option<String>>>,
+}
+
+impl DeliveryCursor {
+    /// Create a cursor kept in the file at `path`, reading its last value if it exists.
+    pub fn open(path: PathBuf) -> Result<Self, ChetterError> {
+        let guid = match fs::read_to_string(&path) {
+            Ok(v) => Some(v.trim().to_string()).filter(|g| !g.is_empty()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            guid: Arc::new(Mutex::new(guid)),
+        })
+    }
+
+    /// Event id of the newest delivery checked, None if none was.
+    pub fn get(&self) -> Option<String> {
+        self.guid.lock().unwrap().clone()
+    }
+
+    /// Set the newest delivery checked.
+    ///
+    /// Failing to write the file is logged, the cursor is only lost on restart.
+    pub fn set(&self, guid: &str) {
+        *self.guid.lock().unwrap() = Some(guid.into());
+        if let Some(ref path) = self.path {
+            if let Err(e) = fs::write(path, guid) {
+                warn!("{}: failed to write delivery cursor: {e}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(id: u64, guid: &str, status_code: u16) -> HookDelivery {
+        HookDelivery {
+            id,
+            guid: guid.into(),
+            delivered_at: Utc::now(),
+            redelivery: false,
+            status_code,
+            event: "pull_request".into(),
+            action: Some("synchronize".into()),
+        }
+    }
+
+    #[test]
+    fn missed_deliveries() {
+        // Newest first: b failed and was redelivered, c failed twice, d was never reachable
+        let deliveries = [
+            delivery(6, "e", 200),
+            delivery(5, "b", 202),
+            delivery(4, "c", 500),
+            delivery(3, "d", 0),
+            delivery(2, "c", 502),
+            delivery(1, "b", 500),
+        ];
+        let ids: Vec<u64> = missed(&deliveries).iter().map(|d| d.id).collect();
+        assert_eq!(ids, [3, 4]);
+    }
+
+    #[test]
+    fn details() {
+        let details: HookDeliveryDetails = serde_json::from_value(serde_json::json!({
+            "id": 12,
+            "guid": "0b989ba4",
+            "delivered_at": "2024-01-02T03:04:05Z",
+            "redelivery": false,
+            "duration": 0.27,
+            "status": "Invalid HTTP Response: 503",
+            "status_code": 503,
+            "event": "ping",
+            "action": null,
+            "installation_id": null,
+            "repository_id": null,
+            "request": {
+                "headers": {"X-GitHub-Event": "ping", "X-GitHub-Delivery": "0b989ba4"},
+                "payload": {"zen": "Design for failure."}
+            },
+            "response": {"headers": {}, "payload": null}
+        }))
+        .unwrap();
+        assert!(!details.delivery.succeeded());
+
+        let payload = RecordedPayload::from(details);
+        assert_eq!(payload.headers["x-github-delivery"], "0b989ba4");
+        assert_eq!(payload.body, r#"{"zen":"Design for failure."}"#);
+    }
+
+    #[test]
+    fn cursor() {
+        let path = std::env::temp_dir().join(format!("chetter-cursor-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let cursor = DeliveryCursor::open(path.clone()).unwrap();
+        assert_eq!(cursor.get(), None);
+        cursor.set("abc");
+        assert_eq!(
+            DeliveryCursor::open(path.clone()).unwrap().get().as_deref(),
+            Some("abc")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(feature = "server")]
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
@@ -7,6 +8,10 @@ use serde::Deserialize;
 #[derive(Deserialize, Debug)]
 pub struct GraphqlError {
     pub message: String,
+
+    /// Path to the field that failed, starting with the alias of the mutation
+    #[serde(default)]
+    pub path: Vec<serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -20,9 +25,26 @@ pub enum ChetterError {
     IOError(std::io::Error),
     JSONWebTokenError(jsonwebtoken::errors::Error),
     Octocrab(octocrab::Error),
+    GithubRequest {
+        error: octocrab::Error,
+        request_id: String,
+    },
     TOMLParseError(toml::de::Error),
     JoinError(tokio::task::JoinError),
     GithubGraphqlError(GraphqlErrors),
+    Multiple(Vec<ChetterError>),
+    PermissionDenied(String),
+    Encryption(String),
+    Config(String),
+    Unauthorized(String),
+    Coordination(String),
+    Vetoed(String),
+    #[cfg(feature = "redis")]
+    Redis(redis::RedisError),
+    #[cfg(feature = "git2")]
+    Git(git2::Error),
+    #[cfg(feature = "notifications")]
+    Http(reqwest::Error),
 }
 
 impl From<std::io::Error> for ChetterError {
@@ -49,6 +71,27 @@ impl From<octocrab::Error> for ChetterError {
     }
 }
 
+#[cfg(feature = "redis")]
+impl From<redis::RedisError> for ChetterError {
+    fn from(error: redis::RedisError) -> Self {
+        Self::Redis(error)
+    }
+}
+
+#[cfg(feature = "git2")]
+impl From<git2::Error> for ChetterError {
+    fn from(error: git2::Error) -> Self {
+        Self::Git(error)
+    }
+}
+
+#[cfg(feature = "notifications")]
+impl From<reqwest::Error> for ChetterError {
+    fn from(error: reqwest::Error) -> Self {
+        Self::Http(error)
+    }
+}
+
 impl From<tokio::task::JoinError> for ChetterError {
     fn from(error: tokio::task::JoinError) -> Self {
         Self::JoinError(error)
@@ -57,6 +100,89 @@ impl From<tokio::task::JoinError> for ChetterError {
 
 impl std::error::Error for ChetterError {}
 
+impl ChetterError {
+    /// The error of a failed GitHub API request, if that is what this is.
+    pub fn octocrab(&self) -> Option<&octocrab::Error> {
+        match self {
+            ChetterError::Octocrab(e) | ChetterError::GithubRequest { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Combine the errors of partially failed work, None if there were none.
+    pub fn from_errors(mut errors: Vec<ChetterError>) -> Option<ChetterError> {
+        match errors.len() {
+            0 => None,
+            1 => errors.pop(),
+            _ => Some(ChetterError::Multiple(errors)),
+        }
+    }
+
+    /// Whether work that failed with this error may succeed when tried again, such as after a
+    /// rate limit, timeout or server error, as opposed to being denied or rejected by GitHub.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ChetterError::Octocrab(e) | ChetterError::GithubRequest { error: e, .. } => match e {
+                octocrab::Error::GitHub { source, .. } => {
+                    let status = source.status_code.as_u16();
+                    status >= 500 || is_throttled(status, &source.message)
+                }
+                octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. } => true,
+                _ => false,
+            },
+            ChetterError::GithubGraphqlError(e) => e.errors.iter().any(|e| {
+                let message = e.message.to_lowercase();
+                message.contains("timeout")
+                    || message.contains("timed out")
+                    || message.contains("something went wrong")
+            }),
+            ChetterError::IOError(e) => e.kind() == std::io::ErrorKind::TimedOut,
+            #[cfg(feature = "redis")]
+            ChetterError::Redis(e) => e.is_timeout() || e.is_connection_dropped(),
+            #[cfg(feature = "git2")]
+            ChetterError::Git(e) => {
+                matches!(e.class(), git2::ErrorClass::Net | git2::ErrorClass::Ssh)
+            }
+            #[cfg(feature = "notifications")]
+            ChetterError::Http(e) => {
+                e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error())
+            }
+            ChetterError::Multiple(errors) => errors.iter().all(|e| e.is_retryable()),
+            _ => false,
+        }
+    }
+
+    /// Whether GitHub refused the request for lack of permission, or hid what it was about, as
+    /// opposed to failing for reasons that may pass.
+    pub fn is_denied(&self) -> bool {
+        match self {
+            ChetterError::Octocrab(e) | ChetterError::GithubRequest { error: e, .. } => match e {
+                octocrab::Error::GitHub { source, .. } => {
+                    is_denied(source.status_code.as_u16(), &source.message)
+                }
+                _ => false,
+            },
+            ChetterError::GithubGraphqlError(e) => e
+                .errors
+                .iter()
+                .any(|e| e.message.contains("Resource not accessible")),
+            ChetterError::PermissionDenied(_) => true,
+            ChetterError::Multiple(errors) => errors.iter().all(|e| e.is_denied()),
+            _ => false,
+        }
+    }
+}
+
+/// Whether GitHub refused a request with `status` for lack of permission.
+fn is_denied(status: u16, message: &str) -> bool {
+    status == 404 || (status == 403 && !is_throttled(status, message))
+}
+
+/// Whether GitHub refused a request with `status` because it is unavailable or rate limiting us.
+fn is_throttled(status: u16, message: &str) -> bool {
+    matches!(status, 429 | 503) || (status == 403 && message.contains("rate limit"))
+}
+
 impl std::fmt::Display for ChetterError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -64,19 +190,81 @@ impl std::fmt::Display for ChetterError {
             ChetterError::IOError(e) => write!(f, "{}", e),
             ChetterError::JSONWebTokenError(e) => write!(f, "{}", e),
             ChetterError::Octocrab(e) => write!(f, "{}", e),
+            ChetterError::GithubRequest { error, request_id } => {
+                write!(f, "{} (x-github-request-id: {})", error, request_id)
+            }
             ChetterError::TOMLParseError(e) => write!(f, "{}", e),
             ChetterError::JoinError(e) => write!(f, "{}", e),
             ChetterError::GithubGraphqlError(e) => {
                 let errs: Vec<&str> = e.errors.iter().map(|e| e.message.as_ref()).collect();
                 write!(f, "GraphQL Errors: {}", errs.join(" | "))
             }
+            ChetterError::PermissionDenied(e) => write!(f, "{}", e),
+            ChetterError::Encryption(e) => write!(f, "{}", e),
+            ChetterError::Config(e) => write!(f, "{}", e),
+            ChetterError::Unauthorized(e) => write!(f, "{}", e),
+            ChetterError::Coordination(e) => write!(f, "{}", e),
+            ChetterError::Vetoed(e) => write!(f, "{}", e),
+            #[cfg(feature = "redis")]
+            ChetterError::Redis(e) => write!(f, "{}", e),
+            #[cfg(feature = "git2")]
+            ChetterError::Git(e) => write!(f, "{}", e),
+            #[cfg(feature = "notifications")]
+            ChetterError::Http(e) => write!(f, "{}", e),
+            ChetterError::Multiple(e) => {
+                let errs: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errs.join(" | "))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl ChetterError {
+    /// HTTP status reported to the client, so that GitHub only retries deliveries which failed for
+    /// reasons other than the event itself.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ChetterError::GithubParseError(_) => StatusCode::BAD_REQUEST,
+            ChetterError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ChetterError::Vetoed(_) => StatusCode::FORBIDDEN,
+            ChetterError::Octocrab(e) | ChetterError::GithubRequest { error: e, .. } => {
+                upstream_status(e)
+            }
+            ChetterError::GithubGraphqlError(_) => StatusCode::BAD_GATEWAY,
+            ChetterError::Multiple(errors) => {
+                let mut statuses = errors.iter().map(|e| e.status_code());
+                let first = statuses.next().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                if statuses.all(|s| s == first) {
+                    first
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// 503 when GitHub is unavailable or rate limiting us, 502 for any other failed request.
+#[cfg(feature = "server")]
+fn upstream_status(error: &octocrab::Error) -> StatusCode {
+    match error {
+        octocrab::Error::GitHub { source, .. } => {
+            match is_throttled(source.status_code.as_u16(), &source.message) {
+                true => StatusCode::SERVICE_UNAVAILABLE,
+                false => StatusCode::BAD_GATEWAY,
+            }
         }
+        octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. } => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
+#[cfg(feature = "server")]
 impl IntoResponse for ChetterError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+        (self.status_code(), self.to_string()).into_response()
     }
 }
 
@@ -103,4 +291,94 @@ mod tests {
         let err = ChetterError::GithubGraphqlError(serde_json::from_value(j).unwrap());
         assert_eq!("GraphQL Errors: msg1 | msg2", err.to_string());
     }
+
+    #[test]
+    fn multiple_errors() {
+        let err = ChetterError::Multiple(vec![
+            ChetterError::GithubParseError("first".into()),
+            ChetterError::GithubParseError("second".into()),
+        ]);
+        assert_eq!("first | second", err.to_string());
+
+        assert!(ChetterError::from_errors(vec![]).is_none());
+        let err = ChetterError::from_errors(vec![ChetterError::GithubParseError("only".into())]);
+        assert!(matches!(err, Some(ChetterError::GithubParseError(_))));
+        let err = ChetterError::from_errors(vec![
+            ChetterError::GithubParseError("first".into()),
+            ChetterError::GithubParseError("second".into()),
+        ]);
+        assert!(matches!(err, Some(ChetterError::Multiple(ref e)) if e.len() == 2));
+    }
+
+    #[test]
+    fn retryable() {
+        let graphql = |message: &str| {
+            ChetterError::GithubGraphqlError(GraphqlErrors {
+                errors: vec![GraphqlError {
+                    message: message.into(),
+                    path: vec![],
+                }],
+            })
+        };
+        let timeout = || graphql("This may be the result of a timeout");
+        let denied = || graphql("Resource not accessible by integration");
+
+        assert!(timeout().is_retryable());
+        assert!(!denied().is_retryable());
+        assert!(!ChetterError::PermissionDenied("no contents: write".into()).is_retryable());
+        assert!(!ChetterError::GithubParseError("bad event".into()).is_retryable());
+        assert!(ChetterError::Multiple(vec![timeout(), timeout()]).is_retryable());
+        assert!(!ChetterError::Multiple(vec![timeout(), denied()]).is_retryable());
+
+        assert!(is_throttled(429, ""));
+        assert!(is_throttled(
+            403,
+            "API rate limit exceeded for installation"
+        ));
+        assert!(!is_throttled(403, "Resource not accessible by integration"));
+    }
+
+    #[test]
+    fn denied() {
+        let graphql = |message: &str| {
+            ChetterError::GithubGraphqlError(GraphqlErrors {
+                errors: vec![GraphqlError {
+                    message: message.into(),
+                    path: vec![],
+                }],
+            })
+        };
+        assert!(graphql("Resource not accessible by integration").is_denied());
+        assert!(!graphql("This may be the result of a timeout").is_denied());
+        assert!(!ChetterError::GithubParseError("bad gateway".into()).is_denied());
+
+        assert!(is_denied(404, "Not Found"));
+        assert!(is_denied(403, "Resource not accessible by integration"));
+        assert!(!is_denied(403, "You have exceeded a secondary rate limit"));
+        assert!(!is_denied(502, "Bad Gateway"));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn status_codes() {
+        let parse = || ChetterError::GithubParseError("bad event".into());
+        let graphql = || ChetterError::GithubGraphqlError(GraphqlErrors { errors: vec![] });
+        let io = || ChetterError::IOError(std::io::Error::from_raw_os_error(2));
+
+        assert_eq!(parse().status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            ChetterError::Unauthorized("bad signature".into()).status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(graphql().status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(io().status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            ChetterError::Multiple(vec![graphql(), graphql()]).status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            ChetterError::Multiple(vec![graphql(), io()]).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
@@ -0,0 +1,242 @@
+use octocrab::models::webhook_events::{WebhookEvent, WebhookEventPayload};
+use serde::Deserialize;
+
+/// Rules deciding which webhook events are processed, in the `[filters]` table.
+///
+/// An event is processed when it matches any rule in `only`, or there are none, and no rule in
+/// `skip`:
+///
+/// ```toml
+/// [[filters.only]]
+/// repos = ["org/*"]
+///
+/// [[filters.skip]]
+/// authors = ["dependabot[bot]", "renovate[bot]"]
+///
+/// [[filters.skip]]
+/// repos = ["org/legacy-*"]
+/// events = ["pull_request_review"]
+/// ```
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct EventFilters {
+    /// Process only events matching any of these rules, every event when empty
+    pub only: Vec<EventRule>,
+
+    /// Never process events matching any of these rules
+    pub skip: Vec<EventRule>,
+}
+
+/// A rule matching events for which every condition given holds.
+///
+/// Patterns may use `*` for any characters and `?` for a single one.  Conditions on the author,
+/// base branch or labels only hold for events about a pull request.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventRule {
+    /// Patterns of the repository as `<org>/<repo>`
+    pub repos: Vec<String>,
+
+    /// Patterns of the login of the pull request author, ignoring case
+    pub authors: Vec<String>,
+
+    /// Patterns of the branch the pull request is based on
+    pub base_branches: Vec<String>,
+
+    /// Labels, any of which the pull request has
+    pub labels: Vec<String>,
+
+    /// Patterns of the event as `<kind>` or `<kind>.<action>`, such as `push` or
+    /// `pull_request.synchronize`
+    pub events: Vec<String>,
+}
+
+/// What filters know of a webhook event.
+#[derive(Debug, Default)]
+pub struct EventFacts<'a> {
+    /// Event as `<kind>.<action>`, or `<kind>` for events without actions
+    pub event: String,
+
+    /// Repository as `<org>/<repo>`
+    pub repo: &'a str,
+
+    /// Login of the pull request author
+    pub author: Option<&'a str>,
+
+    /// Branch the pull request is based on
+    pub base_branch: Option<&'a str>,
+
+    /// Labels of the pull request
+    pub labels: Vec<&'a str>,
+}
+
+impl<'a> EventFacts<'a> {
+    /// Gather what is known of `event`.
+    pub fn of(event: &'a WebhookEvent) -> Self {
+        let pull = match event.specific {
+            WebhookEventPayload::PullRequest(ref p) => Some(&p.pull_request),
+            WebhookEventPayload::PullRequestReview(ref p) => Some(&p.pull_request),
+            _ => None,
+        };
+        Self {
+            event: crate::event_name(event),
+            repo: event
+                .repository
+                .as_ref()
+                .and_then(|r| r.full_name.as_deref())
+                .unwrap_or_default(),
+            author: pull.and_then(|p| p.user.as_ref()).map(|u| u.login.as_str()),
+            base_branch: pull.map(|p| p.base.ref_field.as_str()),
+            labels: pull
+                .and_then(|p| p.labels.as_ref())
+                .map(|l| l.iter().map(|l| l.name.as_str()).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl EventFilters {
+    /// Whether an event with `facts` is processed.
+    pub fn accepts(&self, facts: &EventFacts) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|r| r.matches(facts)))
+            && !self.skip.iter().any(|r| r.matches(facts))
+    }
+
+    /// Check that every rule has a condition, an empty one matching every event is most likely a
+    /// mistake.
+    pub fn validate(&self) -> Result<(), String> {
+        for (key, rules) in [("only", &self.only), ("skip", &self.skip)] {
+            if rules.iter().any(|r| *r == EventRule::default()) {
+                return Err(format!("filters.{key} rules must have a condition"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EventRule {
+    /// Whether every condition of the rule holds for an event with `facts`.
+    pub fn matches(&self, facts: &EventFacts) -> bool {
+        let kind = facts.event.split('.').next().unwrap_or_default();
+        let any = |patterns: &[String], value: Option<&str>, fold: bool| {
+            patterns.is_empty()
+                || value.is_some_and(|v| patterns.iter().any(|p| glob_match(p, v, fold)))
+        };
+        any(&self.repos, Some(facts.repo), false)
+            && any(&self.authors, facts.author, true)
+            && any(&self.base_branches, facts.base_branch, false)
+            && (self.labels.is_empty()
+                || self.labels.iter().any(|l| facts.labels.contains(&&l[..])))
+            && (any(&self.events, Some(&facts.event), false)
+                || any(&self.events, Some(kind), false))
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any characters and `?` a single one,
+/// ignoring ASCII case if `fold`.
+fn glob_match(pattern: &str, text: &str, fold: bool) -> bool {
+    let eq = |p: char, t: char| p == '?' || p == t || (fold && p.eq_ignore_ascii_case(&t));
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was seen and the text it was last tried to match up to
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && eq(pattern[p], text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            backtrack = Some((star, matched + 1));
+            p = star + 1;
+            t = matched + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{
+        Fixture, PullRequestEvent, PullRequestFixture, PullRequestReviewEvent,
+    };
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("org/*", "org/repo", false));
+        assert!(glob_match("*/repo", "org/repo", false));
+        assert!(glob_match("org/re?o", "org/repo", false));
+        assert!(glob_match("*", "", false));
+        assert!(glob_match("o*g/*-*", "org/legacy-app", false));
+        assert!(!glob_match("org/*", "other/repo", false));
+        assert!(!glob_match("org/re?o", "org/reo", false));
+        assert!(!glob_match("Org/*", "org/repo", false));
+        assert!(glob_match("Dependabot[bot]", "dependabot[bot]", true));
+    }
+
+    #[test]
+    fn rules() {
+        let pull = PullRequestFixture::new(1)
+            .repo("org/app")
+            .author("dependabot[bot]")
+            .base_ref("main")
+            .label("deps");
+        let opened = PullRequestEvent::new("opened", pull.clone()).event();
+        let facts = EventFacts::of(&opened);
+        assert_eq!(facts.event, "pull_request.opened");
+        assert_eq!(facts.author, Some("dependabot[bot]"));
+        assert_eq!(facts.base_branch, Some("main"));
+
+        let rule = |toml: &str| toml::from_str::<EventRule>(toml).unwrap();
+        assert!(rule("repos = ['org/*']").matches(&facts));
+        assert!(rule("authors = ['*[BOT]']").matches(&facts));
+        assert!(rule("base_branches = ['release/*', 'main']").matches(&facts));
+        assert!(rule("labels = ['wip', 'deps']").matches(&facts));
+        assert!(rule("events = ['pull_request']").matches(&facts));
+        assert!(rule("events = ['pull_request.opened']").matches(&facts));
+        assert!(!rule("events = ['pull_request.closed']").matches(&facts));
+        assert!(!rule("repos = ['org/*']\nlabels = ['wip']").matches(&facts));
+
+        let review = PullRequestReviewEvent::new(pull, "reviewer").event();
+        let facts = EventFacts::of(&review);
+        assert!(rule("events = ['pull_request_review']").matches(&facts));
+        assert!(rule("labels = ['deps']").matches(&facts));
+    }
+
+    #[test]
+    fn filters() {
+        let filters: EventFilters = toml::from_str(
+            r#"
+            [[only]]
+            repos = ["org/*"]
+
+            [[skip]]
+            authors = ["dependabot[bot]"]
+            "#,
+        )
+        .unwrap();
+        filters.validate().unwrap();
+        let accepts = |pull: PullRequestFixture| {
+            filters.accepts(&EventFacts::of(
+                &PullRequestEvent::new("opened", pull).event(),
+            ))
+        };
+        assert!(accepts(PullRequestFixture::new(1).repo("org/app")));
+        assert!(!accepts(PullRequestFixture::new(1).repo("other/app")));
+        assert!(!accepts(
+            PullRequestFixture::new(1)
+                .repo("org/app")
+                .author("dependabot[bot]")
+        ));
+        assert!(EventFilters::default().accepts(&EventFacts::default()));
+
+        let empty: EventFilters = toml::from_str("[[skip]]").unwrap();
+        assert!(empty.validate().is_err());
+        assert!(toml::from_str::<EventFilters>("[[skip]]\nauthor = ['typo']").is_err());
+    }
+}
@@ -0,0 +1,27 @@
+use async_trait::async_trait;
+use octocrab::models::webhook_events::WebhookEvent;
+
+use crate::{error::ChetterError, github::RepositoryController};
+
+/// A service hosting repositories and delivering webhook events about them, such as GitHub.
+///
+/// Handling pull requests only needs a [RepositoryController] acting on the repository an event
+/// is about, which the forge provides.  Events are represented with the GitHub webhook models,
+/// another forge translates its own when parsing them.
+#[async_trait]
+pub trait Forge: Send + Sync {
+    /// Client acting on a single repository
+    type Client: RepositoryController + Clone + Send + Sync + 'static;
+
+    /// Parse the `body` of a webhook event of type `kind`, such as `pull_request`.
+    fn parse_event(&self, kind: &str, body: &str) -> Result<WebhookEvent, ChetterError>;
+
+    /// Create a client for the repository event `ev` is about.
+    async fn client_for_event(&self, ev: &WebhookEvent) -> Result<Self::Client, ChetterError>;
+
+    /// Create a client for `org/repo`.
+    async fn client_for(&self, org: &str, repo: &str) -> Result<Self::Client, ChetterError>;
+
+    /// Create a client for every repository the app acts on.
+    async fn installed_clients(&self) -> Result<Vec<Self::Client>, ChetterError>;
+}
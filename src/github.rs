@@ -1,27 +1,83 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
 use indoc::formatdoc;
+use ipnet::IpNet;
 use octocrab::{
     models::{
+        issues::Comment,
+        pulls::{PullRequest, Review},
         webhook_events::{EventInstallation, WebhookEvent},
-        InstallationToken,
     },
-    params::repos::Reference,
-    Octocrab,
+    FromResponse, Octocrab, Page,
 };
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
 use tracing::{error, info, warn};
 
 #[cfg(test)]
 use mockall::automock;
 
-use crate::error::{ChetterError, GraphqlErrors};
+use crate::{
+    audit::{AuditEntry, Operation, Trigger},
+    config::{AppConfig, GithubConfig},
+    deliveries::{HookDelivery, HookDeliveryDetails},
+    error::{ChetterError, GraphqlError, GraphqlErrors},
+    forge::Forge,
+    hooks::{RefHook, RefHooks},
+    metrics::timed,
+    ratelimit::RateBudget,
+};
 
 /// Namespace under which all references will be created.
 // This has to be under refs/heads, refs/tags, refs/notes or refs/guest in order to use GraphQL per
 // https://github.com/orgs/community/discussions/83980.  GraphQL is important so that we can delete
 // hundreds of references with a single API call when a PR is closed.
-const REF_NS: &str = "refs/heads/pr";
+pub const REF_NS: &str = "refs/heads/pr";
+
+/// Namespace under which references of merged pull requests are archived as tags.
+pub const ARCHIVE_NS: &str = "refs/tags/chetter";
+
+/// Response header identifying a request to GitHub support.
+const REQUEST_ID: &str = "x-github-request-id";
+
+/// Response header with the number of requests left until the rate limit resets.
+const RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+
+/// Response header with when the rate limit resets, in seconds since the epoch.
+const RATELIMIT_RESET: &str = "x-ratelimit-reset";
+
+/// Get header `name` of `response` as a str, if it is set.
+macro_rules! header {
+    ($response:expr, $name:expr) => {
+        $response.headers().get($name).and_then(|v| v.to_str().ok())
+    };
+}
+
+/// Send `request` with `client`, recording the rate limit GitHub reports and keeping the id it
+/// assigned to the request if it fails; `read` turns a successful `response` into the result.
+macro_rules! send {
+    ($client:expr, $request:expr, |$response:ident| $read:expr) => {{
+        let response = $request.await?;
+        let request_id = $client.observe(
+            header!(response, REQUEST_ID),
+            header!(response, RATELIMIT_REMAINING),
+            header!(response, RATELIMIT_RESET),
+        );
+        match octocrab::map_github_error(response).await {
+            Ok($response) => Ok($read),
+            Err(error) => Err(request_error(error, request_id)),
+        }
+    }};
+}
 
 /// Git reference
 #[derive(Debug, Clone, PartialEq)]
@@ -36,36 +92,313 @@ pub struct Ref {
     pub node_id: String,
 }
 
+/// Changes to a single file between two commits
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FilePatch {
+    /// Path of the file
+    pub filename: String,
+
+    /// Unified diff of the file, not available for binary or very large files
+    pub patch: Option<String>,
+}
+
+/// Pull request state relevant to references
+#[derive(Debug, Clone, PartialEq)]
+pub struct PullRequestInfo {
+    /// Pull request number
+    pub number: u64,
+
+    /// True if the pull request is open
+    pub open: bool,
+
+    /// Full SHA-1 object name of the pull request head
+    pub head: String,
+
+    /// Full SHA-1 object name of the pull request base
+    pub base: String,
+
+    /// Name of the branch the pull request will be merged into
+    pub base_ref: String,
+
+    /// When the pull request was closed, if it has been
+    pub closed_at: Option<DateTime<Utc>>,
+
+    /// True if the author opted out of chetter-app, see [opted_out]
+    pub opted_out: bool,
+
+    /// Logins of the users a review was requested from, who have not submitted it yet
+    pub requested_reviewers: Vec<String>,
+}
+
+impl From<PullRequest> for PullRequestInfo {
+    fn from(pr: PullRequest) -> Self {
+        Self {
+            opted_out: opted_out(&pr),
+            number: pr.number,
+            open: pr.state == Some(octocrab::models::IssueState::Open),
+            head: pr.head.sha,
+            base: pr.base.sha,
+            base_ref: pr.base.ref_field,
+            closed_at: pr.closed_at,
+            requested_reviewers: pr
+                .requested_reviewers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|user| user.login)
+                .collect(),
+        }
+    }
+}
+
+/// Comparison between two commits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Comparison {
+    /// Files changed between the two commits
+    pub files: Vec<FilePatch>,
+
+    /// Position of head relative to base: `ahead`, `behind`, `identical` or `diverged`
+    pub status: String,
+
+    /// Full SHA-1 object name of the best common ancestor of the two commits
+    pub merge_base: String,
+}
+
+impl Comparison {
+    /// True if base is reachable from head.
+    pub fn base_is_ancestor(&self) -> bool {
+        matches!(self.status.as_str(), "ahead" | "identical")
+    }
+}
+
+/// Access token of an installation.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InstallationToken {
+    /// Token to send as `Authorization: Bearer <token>`
+    pub token: String,
+
+    /// When the token expires, an hour after it was minted
+    pub expires_at: DateTime<Utc>,
+}
+
 /// GitHub Application Client.
 ///
 /// A GitHub client authenticated as a 'Github App' as opposed to an 'OAuth 2' application.  This
 /// client is mostly useful for creating a `RepositoryClient`, which can get an installation access
 /// token and then take actions on GitHub repositories where it has been installed.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct AppClient {
-    crab: Octocrab,
+    /// Client authorized as the app, replaced when its private key is rotated
+    crab: Arc<RwLock<Octocrab>>,
+    app_id: u64,
+    settings: GithubConfig,
+    budget: RateBudget,
+    hooks: RefHooks,
 }
 
 impl AppClient {
-    /// Create a new AppClient from a configuration file.
-    pub fn new(config_path: String) -> Result<Self, ChetterError> {
-        #[derive(Deserialize, Debug)]
-        struct Config {
-            app_id: u64,
-            private_key: String,
+    /// Create a new AppClient from the application configuration.
+    pub fn new(config: &AppConfig) -> Result<Self, ChetterError> {
+        let crab = app_crab(
+            config.app_id,
+            config.private_key.expose(),
+            config.github.api_url.as_deref(),
+        )?;
+        Ok(Self {
+            crab: Arc::new(RwLock::new(crab)),
+            app_id: config.app_id,
+            settings: config.github.clone(),
+            budget: RateBudget::new(config.github.rate_limit_floor),
+            hooks: RefHooks::default(),
+        })
+    }
+
+    /// Authorize as the app with private key `pem` from now on, such as after it was rotated.
+    ///
+    /// Installation tokens are obtained with the new key as they expire.
+    pub fn set_private_key(&self, pem: &str) -> Result<(), ChetterError> {
+        *self.crab.write().unwrap() = app_crab(self.app_id, pem, self.settings.api_url.as_deref())?;
+        Ok(())
+    }
+
+    /// Client authorized as the app.
+    fn crab(&self) -> Octocrab {
+        self.crab.read().unwrap().clone()
+    }
+
+    /// Call `hook` around every change made to references by clients created from now on.
+    pub fn register_ref_hook(&mut self, hook: impl RefHook + 'static) {
+        self.hooks.register(hook);
+    }
+
+    /// Networks GitHub delivers webhook events from, as published by the meta API.
+    pub async fn hook_networks(&self) -> Result<Vec<IpNet>, ChetterError> {
+        #[derive(Deserialize)]
+        struct Meta {
+            hooks: Vec<String>,
+        }
+
+        let meta: Meta = self.crab().get("/meta", None::<&()>).await?;
+        Ok(meta
+            .hooks
+            .iter()
+            .filter_map(|n| match n.parse() {
+                Ok(net) => Some(net),
+                Err(e) => {
+                    warn!("Skipping hook network {n}: {e}");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Attempts to deliver webhook events to the app made after `since`, newest first, stopping
+    /// at the first attempt to deliver event `until` if given.
+    pub async fn hook_deliveries(
+        &self,
+        since: DateTime<Utc>,
+        until: Option<&str>,
+    ) -> Result<Vec<HookDelivery>, ChetterError> {
+        let mut deliveries = vec![];
+        let mut page: octocrab::Page<HookDelivery> = self
+            .crab()
+            .get("/app/hook/deliveries", Some(&json!({"per_page": 100})))
+            .await?;
+        loop {
+            for delivery in page.take_items() {
+                if delivery.delivered_at < since || until == Some(delivery.guid.as_str()) {
+                    return Ok(deliveries);
+                }
+                deliveries.push(delivery);
+            }
+            match self.crab().get_page(&page.next).await? {
+                Some(next) => page = next,
+                None => return Ok(deliveries),
+            }
         }
+    }
+
+    /// Get a delivery attempt including the request that was made.
+    pub async fn hook_delivery(&self, id: u64) -> Result<HookDeliveryDetails, ChetterError> {
+        Ok(self
+            .crab()
+            .get(format!("/app/hook/deliveries/{id}"), None::<&()>)
+            .await?)
+    }
+
+    /// Get the id of the installation of this application on `org/repo`.
+    pub async fn installation_for(&self, org: &str, repo: &str) -> Result<u64, ChetterError> {
+        let installation: octocrab::models::Installation = self
+            .crab()
+            .get(format!("/repos/{org}/{repo}/installation"), None::<&()>)
+            .await?;
+        Ok(installation.id.0)
+    }
+
+    /// Mint a new access token for installation `id`, as used for every API call on its
+    /// repositories.
+    pub async fn installation_token(&self, id: u64) -> Result<InstallationToken, ChetterError> {
+        Ok(self
+            .crab()
+            .post(
+                format!("/app/installations/{id}/access_tokens"),
+                None::<&()>,
+            )
+            .await?)
+    }
+
+    /// Create a RepositoryClient for every repository of installation `id` that is not archived.
+    pub async fn installation_repo_clients(
+        &self,
+        id: u64,
+    ) -> Result<Vec<RepositoryClient>, ChetterError> {
+        #[derive(Deserialize)]
+        struct Owner {
+            login: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Repository {
+            name: String,
+            owner: Owner,
+            #[serde(default)]
+            archived: bool,
+        }
+
+        #[derive(Deserialize)]
+        struct Repositories {
+            repositories: Vec<Repository>,
+        }
+
+        let crab = self.installation_crab(id);
+        let mut clients = vec![];
+        for page in 1u32.. {
+            let resp: Repositories = crab
+                .get(
+                    "/installation/repositories",
+                    Some(&json!({"per_page": 100, "page": page})),
+                )
+                .await?;
+            let count = resp.repositories.len();
+            // Archived repositories are read-only
+            clients.extend(
+                resp.repositories
+                    .into_iter()
+                    .filter(|r| !r.archived)
+                    .map(|r| {
+                        RepositoryClient::new(
+                            crab.clone(),
+                            r.owner.login,
+                            r.name,
+                            id,
+                            self.budget.clone(),
+                            self.settings.clone(),
+                        )
+                        .with_hooks(self.hooks.clone())
+                    }),
+            );
+            if count < 100 {
+                break;
+            }
+        }
+        Ok(clients)
+    }
+
+    /// Create a new RepositoryClient for `org/repo` using installation `id`.
+    pub async fn installation_client(
+        &self,
+        id: u64,
+        org: String,
+        repo: String,
+    ) -> Result<RepositoryClient, ChetterError> {
+        let crab = self.installation_crab(id);
+        Ok(RepositoryClient::new(
+            crab,
+            org,
+            repo,
+            id,
+            self.budget.clone(),
+            self.settings.clone(),
+        )
+        .with_hooks(self.hooks.clone()))
+    }
 
-        let config_str = std::fs::read_to_string(config_path)?;
-        let config: Config = toml::from_str(&config_str)?;
-        let key = jsonwebtoken::EncodingKey::from_rsa_pem(config.private_key.as_bytes())?;
+    /// Client authorized as installation `id`, refreshing its token as it expires.
+    fn installation_crab(&self, id: u64) -> Octocrab {
+        self.crab().installation(id.into())
+    }
+}
 
-        let crab = Octocrab::builder().app(config.app_id.into(), key).build()?;
+#[async_trait]
+impl Forge for AppClient {
+    type Client = RepositoryClient;
 
-        Ok(Self { crab })
+    fn parse_event(&self, kind: &str, body: &str) -> Result<WebhookEvent, ChetterError> {
+        WebhookEvent::try_from_header_and_body(kind, body)
+            .map_err(|e| ChetterError::GithubParseError(format!("Failed to parse event: {e}")))
     }
 
     /// Create a new RepositoryClient using the `.installation` data in a webhook event.
-    pub async fn repo_client(&self, ev: &WebhookEvent) -> Result<RepositoryClient, ChetterError> {
+    async fn client_for_event(&self, ev: &WebhookEvent) -> Result<RepositoryClient, ChetterError> {
         let repo = ev
             .repository
             .as_ref()
@@ -80,42 +413,703 @@ impl AppClient {
             .login
             .clone();
 
-        let id = match ev.installation.as_ref() {
-            Some(EventInstallation::Minimal(v)) => v.id.0,
-            Some(EventInstallation::Full(v)) => v.id.0,
-            None => {
-                return Err(ChetterError::GithubParseError(
-                    "missing event.installation.id".into(),
-                ));
+        let id = installation_id(ev).ok_or(ChetterError::GithubParseError(
+            "missing event.installation.id".into(),
+        ))?;
+        self.installation_client(id, org, repo.name.clone()).await
+    }
+
+    /// Create a new RepositoryClient for `org/repo` by looking up the installation of this
+    /// application on the repository.
+    async fn client_for(&self, org: &str, repo: &str) -> Result<RepositoryClient, ChetterError> {
+        let id = self.installation_for(org, repo).await?;
+        self.installation_client(id, org.into(), repo.into()).await
+    }
+
+    /// Create a RepositoryClient for every repository this application is installed on, skipping
+    /// suspended installations.
+    async fn installed_clients(&self) -> Result<Vec<RepositoryClient>, ChetterError> {
+        #[derive(Deserialize)]
+        struct Installation {
+            id: u64,
+            suspended_at: Option<DateTime<Utc>>,
+        }
+
+        let mut clients = vec![];
+        for page in 1u32.. {
+            let installations: Vec<Installation> = self
+                .crab()
+                .get(
+                    "/app/installations",
+                    Some(&json!({"per_page": 100, "page": page})),
+                )
+                .await?;
+            for installation in &installations {
+                if installation.suspended_at.is_some() {
+                    info!("skipping suspended installation {}", installation.id);
+                    continue;
+                }
+                clients.extend(self.installation_repo_clients(installation.id).await?);
             }
-        };
-        let url = format!("/app/installations/{}/access_tokens", id);
-        let token: InstallationToken = self.crab.post(url, None::<&()>).await?;
-        let crab = octocrab::OctocrabBuilder::new()
-            .personal_token(token.token)
-            .build()?;
+            if installations.len() < 100 {
+                break;
+            }
+        }
+        Ok(clients)
+    }
+}
 
-        Ok(RepositoryClient {
-            crab,
-            org,
-            repo: repo.name.clone(),
-        })
+/// Client authorized as GitHub App `app_id` with private key `pem`, using the API at `api_url`
+/// if set.
+fn app_crab(app_id: u64, pem: &str, api_url: Option<&str>) -> Result<Octocrab, ChetterError> {
+    let key = jsonwebtoken::EncodingKey::from_rsa_pem(pem.as_bytes())?;
+    let mut builder = Octocrab::builder().app(app_id.into(), key);
+    if let Some(url) = api_url {
+        builder = builder.base_uri(url)?;
+    }
+    Ok(builder.build()?)
+}
+
+// The clients hold credentials, leave them out.
+impl std::fmt::Debug for AppClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AppClient")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
     }
 }
 
 /// GitHub client authorized to act on behalf of a 'GitHub App' using the granted permissions on a
 /// specific repository.
+#[derive(Clone)]
 pub struct RepositoryClient {
     crab: Octocrab,
     org: String,
     repo: String,
+
+    /// Namespace references are read from and written to
+    ns: String,
+
+    /// Namespace references are also written to while migrating
+    migrate_to: Option<String>,
+
+    /// GraphQL node_id of the repository, fetched when first needed
+    repo_id: OnceLock<String>,
+
+    /// Installation the client is authorized as
+    installation: u64,
+
+    /// Rate limit budget shared with every other client
+    budget: RateBudget,
+
+    /// Called around every change to references
+    hooks: RefHooks,
+
+    /// What changes made by this client are attributed to
+    trigger: Trigger,
+
+    settings: GithubConfig,
 }
 
 impl RepositoryClient {
+    fn new(
+        crab: Octocrab,
+        org: String,
+        repo: String,
+        installation: u64,
+        budget: RateBudget,
+        settings: GithubConfig,
+    ) -> Self {
+        Self {
+            crab,
+            org,
+            repo,
+            ns: REF_NS.into(),
+            migrate_to: None,
+            repo_id: OnceLock::new(),
+            installation,
+            budget,
+            hooks: RefHooks::default(),
+            trigger: Trigger::default(),
+            settings,
+        }
+    }
+
+    /// Attribute changes to references to `actor` and webhook `delivery` in the audit log.
+    pub fn triggered_by(mut self, actor: &str, delivery: Option<&str>) -> Self {
+        self.trigger = Trigger {
+            actor: actor.into(),
+            delivery: delivery.map(String::from),
+        };
+        self
+    }
+
+    /// Call `hooks` around every change to references.
+    fn with_hooks(mut self, hooks: RefHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Describe a change to reference `name` as it is recorded in the audit log.
+    fn change(
+        &self,
+        name: String,
+        operation: Operation,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> AuditEntry {
+        AuditEntry {
+            at: Utc::now(),
+            repo: self.full_name(),
+            ref_name: name,
+            operation,
+            old_sha: old.map(String::from),
+            new_sha: new.map(String::from),
+            trigger: self.trigger.clone(),
+        }
+    }
+
+    /// Ask the hooks whether `change` may be made.
+    async fn allowed(&self, change: &AuditEntry) -> Result<(), ChetterError> {
+        self.hooks.before(change).await.map_err(|e| {
+            warn!("{:?} of {} vetoed: {e}", change.operation, change.ref_name);
+            e
+        })
+    }
+
+    /// Split `items` into those the hooks allow changing as described by `change` and the
+    /// vetoes of the others.
+    async fn allowed_batch<T: Clone>(
+        &self,
+        items: &[T],
+        change: impl Fn(&T) -> AuditEntry,
+    ) -> (Vec<T>, Vec<ChetterError>) {
+        if self.hooks.is_empty() {
+            return (items.to_vec(), vec![]);
+        }
+        let mut allowed = vec![];
+        let mut vetoes = vec![];
+        for item in items {
+            match self.allowed(&change(item)).await {
+                Ok(()) => allowed.push(item.clone()),
+                Err(e) => vetoes.push(e),
+            }
+        }
+        (allowed, vetoes)
+    }
+
+    /// Tell the hooks, such as the audit log, about `change` once it was made.
+    async fn made(&self, mut change: AuditEntry) {
+        change.at = Utc::now();
+        self.hooks.after(&change).await;
+    }
+
+    /// Wait for the rate limit to reset if few requests are left, before work that can wait.
+    pub async fn throttle(&self) {
+        self.budget.reserve(self.installation).await
+    }
+
+    /// Use `ns` instead of {REF_NS}, additionally writing every change to `migrate_to` if set.
+    pub fn with_namespace(mut self, ns: &str, migrate_to: Option<&str>) -> Self {
+        self.ns = ns.into();
+        self.migrate_to = migrate_to.map(String::from);
+        self
+    }
+
     /// Get the full name for the target repository.
     pub fn full_name(&self) -> String {
         format!("{}/{}", self.org, self.repo)
     }
+
+    /// Get the namespace references are read from.
+    pub fn namespace(&self) -> &str {
+        &self.ns
+    }
+
+    /// Get the namespace references are being migrated to, if any.
+    pub fn migration_target(&self) -> Option<&str> {
+        self.migrate_to.as_deref()
+    }
+
+    /// Create a new reference rooted at `ns`.
+    pub async fn create_ref_in(
+        &self,
+        ns: &str,
+        ref_name: &str,
+        sha: &str,
+    ) -> Result<(), ChetterError> {
+        let change = self.change(
+            format!("{ns}/{ref_name}"),
+            Operation::Create,
+            None,
+            Some(sha),
+        );
+        self.allowed(&change).await?;
+        let req = json!({"ref": format!("{}/{}", ns, ref_name), "sha": &sha});
+        let url = format!("/repos/{}/{}/git/refs", self.org, self.repo);
+        let short_sha = sha.get(..8).unwrap_or(sha);
+        match self.post("create_ref", &url, &req).await {
+            Ok::<octocrab::models::repos::Ref, _>(_) => {
+                info!("created {}/{} as {}", ns, ref_name, short_sha);
+                self.made(change).await;
+                Ok(())
+            }
+            // Redelivered events and races with other deliveries create the same references
+            Err(error) if is_already_exists(&error) => {
+                info!("{}/{} already exists, updating it instead", ns, ref_name);
+                self.update_ref_in(ns, ref_name, None, sha).await
+            }
+            Err(error) => {
+                error!("Failed to create {} as {}", ref_name, short_sha);
+                Err(error)
+            }
+        }
+    }
+
+    /// Update an existing reference rooted at `ns`, which pointed to `old_sha` if known.
+    pub async fn update_ref_in(
+        &self,
+        ns: &str,
+        ref_name: &str,
+        old_sha: Option<&str>,
+        sha: &str,
+    ) -> Result<(), ChetterError> {
+        let change = self.change(
+            format!("{ns}/{ref_name}"),
+            Operation::Update,
+            old_sha,
+            Some(sha),
+        );
+        self.allowed(&change).await?;
+        let req = json!({"sha": &sha, "force": true});
+        let url = format!("/repos/{}/{}/git/{}/{}", self.org, self.repo, ns, ref_name);
+        let short_sha = sha.get(..8).unwrap_or(sha);
+        match self.post("update_ref", &url, &req).await {
+            Ok::<octocrab::models::repos::Ref, _>(_) => {
+                info!("updated {}/{} as {}", ns, ref_name, short_sha);
+                self.made(change).await;
+                Ok(())
+            }
+            Err(error) => {
+                error!("Failed to update {}/{} to {}", ns, ref_name, short_sha);
+                Err(error)
+            }
+        }
+    }
+
+    async fn delete_ref_in(&self, ns: &str, ref_name: &str) -> Result<(), ChetterError> {
+        let change = self.change(format!("{ns}/{ref_name}"), Operation::Delete, None, None);
+        self.allowed(&change).await?;
+        let short_ns = &ns[5..]; // Strip 'refs/'
+        let url = format!(
+            "/repos/{}/{}/git/refs/{}/{}",
+            self.org, self.repo, short_ns, ref_name
+        );
+        match self.delete("delete_ref", &url).await {
+            Ok(_) => {
+                info!("deleted {}/{}", ns, ref_name);
+                self.made(change).await;
+                Ok(())
+            }
+            Err(error) => {
+                error!("Failed to delete {}/{}", ns, ref_name);
+                Err(error)
+            }
+        }
+    }
+
+    /// Record the rate limit reported in a response, returning its request id.
+    fn observe(
+        &self,
+        request_id: Option<&str>,
+        remaining: Option<&str>,
+        reset: Option<&str>,
+    ) -> Option<String> {
+        if let (Some(remaining), Some(reset)) = (remaining, reset) {
+            self.budget.record(self.installation, remaining, reset);
+        }
+        request_id.map(String::from)
+    }
+
+    /// GET `route` as `call`, keeping the id GitHub assigned to the request if it fails.
+    async fn get<R: FromResponse>(&self, call: &str, route: &str) -> Result<R, ChetterError> {
+        timed(call, async {
+            send!(self, self.crab._get(route), |response| {
+                R::from_response(response).await?
+            })
+        })
+        .await
+    }
+
+    /// GET every page of the list at `route` as `call`.
+    async fn get_all<T: DeserializeOwned>(
+        &self,
+        call: &str,
+        route: &str,
+    ) -> Result<Vec<T>, ChetterError> {
+        let mut page: Page<T> = self.get(call, route).await?;
+        let mut results = page.take_items();
+        while let Some(next) = page.next.take() {
+            page = self.get(call, &next.to_string()).await?;
+            results.extend(page.take_items());
+        }
+        Ok(results)
+    }
+
+    /// POST `body` to `route` as `call`, keeping the id GitHub assigned to the request if it
+    /// fails.
+    async fn post<R: FromResponse>(
+        &self,
+        call: &str,
+        route: &str,
+        body: &impl Serialize,
+    ) -> Result<R, ChetterError> {
+        timed(call, async {
+            send!(self, self.crab._post(route, Some(body)), |response| {
+                R::from_response(response).await?
+            })
+        })
+        .await
+    }
+
+    /// POST GraphQL `query` as `call`.
+    ///
+    /// GitHub answers with a success even when the query failed, which is turned into
+    /// [ChetterError::GithubGraphqlError] before the outcome of the call is recorded.
+    async fn graphql(
+        &self,
+        call: &str,
+        query: &serde_json::Value,
+    ) -> Result<serde_json::Value, ChetterError> {
+        timed(call, async {
+            let resp: serde_json::Value =
+                send!(self, self.crab._post("/graphql", Some(query)), |response| {
+                    serde_json::Value::from_response(response).await?
+                })?;
+            // graphql errors are ignored
+            // https://github.com/XAMPPRocky/octocrab/issues/78
+            if resp.get("errors").is_some() {
+                if let Ok(e) = serde_json::from_value::<GraphqlErrors>(resp.clone()) {
+                    return Err(ChetterError::GithubGraphqlError(e));
+                }
+            }
+            Ok(resp)
+        })
+        .await
+    }
+
+    /// PATCH `route` with `body` as `call`, keeping the id GitHub assigned to the request if it
+    /// fails.
+    async fn patch<R: FromResponse>(
+        &self,
+        call: &str,
+        route: &str,
+        body: &impl Serialize,
+    ) -> Result<R, ChetterError> {
+        timed(call, async {
+            send!(self, self.crab._patch(route, Some(body)), |response| {
+                R::from_response(response).await?
+            })
+        })
+        .await
+    }
+
+    /// DELETE `route` as `call`, keeping the id GitHub assigned to the request if it fails.
+    async fn delete(&self, call: &str, route: &str) -> Result<(), ChetterError> {
+        timed(call, async {
+            send!(self, self.crab._delete(route, None::<&()>), |_response| ())
+        })
+        .await
+    }
+
+    /// Get the repository.
+    async fn repository(&self) -> Result<octocrab::models::Repository, ChetterError> {
+        self.get("get_repo", &format!("/repos/{}/{}", self.org, self.repo))
+            .await
+    }
+
+    /// Whether the repository is public.
+    pub async fn is_public(&self) -> Result<bool, ChetterError> {
+        let repo = self.repository().await?;
+        Ok(repo.private == Some(false))
+    }
+
+    /// Get the GraphQL node_id of the repository.
+    async fn repo_id(&self) -> Result<String, ChetterError> {
+        if let Some(id) = self.repo_id.get() {
+            return Ok(id.clone());
+        }
+        let Some(id) = self.repository().await?.node_id else {
+            return Err(ChetterError::GithubParseError(format!(
+                "{}: missing node_id",
+                self.full_name()
+            )));
+        };
+        Ok(self.repo_id.get_or_init(|| id).clone())
+    }
+
+    /// Create references rooted at `ns` with a single GraphQL mutation, leaving out those vetoed
+    /// by the hooks.
+    async fn create_refs_in(
+        &self,
+        ns: &str,
+        refs: &[(String, String)],
+    ) -> Result<(), ChetterError> {
+        let create = |(name, sha): &(String, String)| {
+            self.change(format!("{ns}/{name}"), Operation::Create, None, Some(sha))
+        };
+        let (refs, mut errors) = self.allowed_batch(refs, create).await;
+        if let Err(e) = self.create_allowed_refs_in(ns, &refs).await {
+            errors.push(e);
+        }
+        match ChetterError::from_errors(errors) {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Create references rooted at `ns` with a single GraphQL mutation.
+    async fn create_allowed_refs_in(
+        &self,
+        ns: &str,
+        refs: &[(String, String)],
+    ) -> Result<(), ChetterError> {
+        if refs.is_empty() {
+            return Ok(());
+        }
+        let repo_id = self.repo_id().await?;
+        let mutations: String = refs
+            .iter()
+            .enumerate()
+            .map(|(i, (name, sha))| {
+                formatdoc!(
+                    r#"
+                    create_{i}: createRef(input: {{
+                            repositoryId: "{repo_id}",
+                            name: "{ns}/{name}",
+                            oid: "{sha}",
+                            clientMutationId: "{name}"
+                        }}) {{
+                        clientMutationId
+                    }}
+                    "#,
+                )
+            })
+            .collect();
+        let query = json!({"query": format!("mutation {{\n{}\n}}", mutations)});
+
+        let graphql_errors = match self.graphql("create_refs", &query).await {
+            Ok(_) => vec![],
+            Err(ChetterError::GithubGraphqlError(e)) => e.errors,
+            Err(error) => {
+                error!("failed to create references: {}", &error);
+                return Err(error);
+            }
+        };
+
+        // Each mutation succeeds or fails on its own.  Redelivered events and retries after
+        // a partial batch create the same references, which are updated instead.
+        let mut existing: HashSet<usize> = HashSet::new();
+        let mut failed: HashSet<usize> = HashSet::new();
+        let mut unattributed = false;
+        let mut errors = vec![];
+        for e in graphql_errors {
+            match mutation_index(&e, "create_").filter(|i| *i < refs.len()) {
+                Some(i) if e.message.contains("already exists") => {
+                    existing.insert(i);
+                    continue;
+                }
+                Some(i) => {
+                    failed.insert(i);
+                }
+                None => unattributed = true,
+            }
+            error!("error: {}", e.message);
+            errors.push(e);
+        }
+
+        let mut results = vec![];
+        for (i, (name, sha)) in refs.iter().enumerate() {
+            if existing.contains(&i) {
+                info!("{}/{} already exists, updating it instead", ns, name);
+                if let Err(e) = self.update_ref_in(ns, name, None, sha).await {
+                    results.push(e);
+                }
+            } else if !unattributed && !failed.contains(&i) {
+                info!("created {}/{} as {}", ns, name, sha.get(..8).unwrap_or(sha));
+                self.made(self.change(format!("{ns}/{name}"), Operation::Create, None, Some(sha)))
+                    .await;
+            }
+        }
+        if !errors.is_empty() {
+            results.push(ChetterError::GithubGraphqlError(GraphqlErrors { errors }));
+        }
+        match ChetterError::from_errors(results) {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Delete references rooted at `ns` by their GraphQL node_id, leaving out those vetoed by the
+    /// hooks.
+    async fn delete_refs_in(&self, ns: &str, refs: &[Ref]) -> Result<(), ChetterError> {
+        let delete = |r: &Ref| {
+            let name = format!("{ns}/{}", r.full_name);
+            self.change(name, Operation::Delete, Some(&r.sha), None)
+        };
+        let (refs, mut errors) = self.allowed_batch(refs, delete).await;
+
+        // Chunks are taken from a shared queue by a few concurrent workers.
+        let queue = Mutex::new(&refs[..]);
+        let workers =
+            (0..self.settings.delete_concurrency.max(1)).map(|_| self.delete_worker(ns, &queue));
+        errors.extend(join_all(workers).await.into_iter().flatten());
+
+        match ChetterError::from_errors(errors) {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Delete chunks of references from `queue` until it is empty.
+    async fn delete_worker(&self, ns: &str, queue: &Mutex<&[Ref]>) -> Vec<ChetterError> {
+        let mut errors: Vec<ChetterError> = vec![];
+
+        // Github GraphQL takes a ridiculous amount of time to delete references and will cut us
+        // off after 90s of CPU time or 60s of real time.  How long varies from day to day, so the
+        // chunk size adapts to how long recent mutations took and chunks that time out are
+        // retried in smaller pieces.
+        loop {
+            self.throttle().await;
+            let mut rest = {
+                let mut queue = queue.lock().unwrap();
+                if queue.is_empty() {
+                    break;
+                }
+                let size = DELETE_CHUNK.size().min(queue.len());
+                let (chunk, remaining) = queue.split_at(size);
+                *queue = remaining;
+                chunk
+            };
+
+            let mut limit = rest.len();
+            while !rest.is_empty() {
+                let size = DELETE_CHUNK.size().min(limit).min(rest.len());
+                let (chunk, remaining) = rest.split_at(size);
+                let started = Instant::now();
+                let r = self.delete_chunk_in(ns, chunk).await;
+                let elapsed = started.elapsed();
+                match r {
+                    Ok(()) => {
+                        DELETE_CHUNK.succeeded(size, elapsed);
+                        rest = remaining;
+                    }
+                    Err(e) if is_timeout(&e) && size > ChunkSizer::MIN => {
+                        warn!(
+                            "deleting {size} refs failed after {}s, retrying in smaller chunks: {e}",
+                            elapsed.as_secs()
+                        );
+                        DELETE_CHUNK.failed(size);
+                        limit = size / 2;
+                    }
+                    Err(e) => {
+                        if is_timeout(&e) {
+                            DELETE_CHUNK.failed(size);
+                        }
+                        errors.push(e);
+                        rest = remaining;
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    /// Delete a chunk of references rooted at `ns` with a single GraphQL mutation.
+    async fn delete_chunk_in(&self, ns: &str, chunk: &[Ref]) -> Result<(), ChetterError> {
+        let mutations: String = chunk
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                formatdoc!(
+                    r#"
+                    delete_{i}: deleteRef(input: {{
+                            refId: "{node_id}",
+                            clientMutationId: "{full_name}"
+                        }}) {{
+                        clientMutationId
+                    }}
+                    "#,
+                    node_id = r.node_id,
+                    full_name = r.full_name,
+                )
+            })
+            .collect();
+        let query = json!({"query": format!("mutation {{\n{}\n}}", mutations)});
+        info!("Sending mutation to delete {} refs", chunk.len());
+
+        match self.graphql("delete_refs", &query).await {
+            Ok(_) => {
+                for r in chunk {
+                    info!("deleted {}/{}", ns, r.full_name);
+                    self.made(self.change(
+                        format!("{ns}/{}", r.full_name),
+                        Operation::Delete,
+                        Some(&r.sha),
+                        None,
+                    ))
+                    .await;
+                }
+                Ok(())
+            }
+            Err(ChetterError::GithubGraphqlError(e)) => {
+                e.errors.iter().for_each(|e| {
+                    error!("error: {}", e.message);
+                });
+                Err(ChetterError::GithubGraphqlError(e))
+            }
+            Err(error) => {
+                error!("failed to delete references: {}", &error);
+                Err(error)
+            }
+        }
+    }
+
+    /// Get the references rooted at `ns` that begin with `search`, named relative to `ns`.
+    pub async fn matching_refs_in(&self, ns: &str, search: &str) -> Result<Vec<Ref>, ChetterError> {
+        let short_ns = &ns[5..]; // Strip 'refs/'
+        let url = format!(
+            "/repos/{}/{}/git/matching-refs/{}/{}?per_page={}",
+            self.org, self.repo, short_ns, search, self.settings.per_page
+        );
+        let results: Vec<octocrab::models::repos::Ref> =
+            self.get_all("matching_refs", &url).await?;
+        Ok(results.into_iter().filter_map(|r| to_ref(ns, r)).collect())
+    }
+
+    /// Get the reviews of pull request `pr`, oldest first.
+    pub async fn pull_reviews(&self, pr: u64) -> Result<Vec<Review>, ChetterError> {
+        let url = format!(
+            "/repos/{}/{}/pulls/{pr}/reviews?per_page=100",
+            self.org, self.repo
+        );
+        self.get_all("pull_reviews", &url).await
+    }
+
+    /// Get the reference `name` rooted at `ns`, None if it does not exist.
+    pub async fn get_ref_in(&self, ns: &str, name: &str) -> Result<Option<Ref>, ChetterError> {
+        let short_ns = &ns[5..]; // Strip 'refs/'
+        let url = format!(
+            "/repos/{}/{}/git/ref/{}/{}",
+            self.org, self.repo, short_ns, name
+        );
+        let r: octocrab::models::repos::Ref = match self.get("get_ref", &url).await {
+            Ok(r) => r,
+            Err(e) if e.octocrab().and_then(github_status) == Some(404) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(to_ref(ns, r))
+    }
 }
 
 #[cfg_attr(test, automock)]
@@ -130,7 +1124,7 @@ impl RepositoryClient {
 /// use async_trait::async_trait;
 /// use chetter_app::{
 ///     error::ChetterError,
-///     github::{Ref, RepositoryController}
+///     github::{Comparison, PullRequestInfo, Ref, RepositoryController}
 /// };
 ///
 /// struct NullClient;
@@ -138,9 +1132,37 @@ impl RepositoryClient {
 /// #[async_trait]
 /// impl RepositoryController for NullClient {
 ///     async fn create_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError> { Ok(()) }
-///     async fn update_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError> { Ok(()) }
+///     async fn create_refs(&self, refs: &[(String, String)]) -> Result<(), ChetterError> { Ok(()) }
+///     async fn update_ref<'a>(
+///         &self,
+///         ref_name: &str,
+///         old_sha: Option<&'a str>,
+///         sha: &str,
+///     ) -> Result<(), ChetterError> {
+///         Ok(())
+///     }
+///     async fn delete_ref(&self, ref_name: &str) -> Result<(), ChetterError> { Ok(()) }
+///     async fn archive_ref(&self, r: &Ref) -> Result<(), ChetterError> { Ok(()) }
 ///     async fn delete_refs(&self, ref_names: &[Ref]) -> Result<(), ChetterError> { Ok(()) }
+///     async fn get_ref(&self, name: &str) -> Result<Option<Ref>, ChetterError> { Ok(None) }
 ///     async fn matching_refs(&self, search: &str) -> Result<Vec<Ref>, ChetterError> { Ok(vec![]) }
+///     async fn compare(&self, base: &str, head: &str) -> Result<Comparison, ChetterError> {
+///         Ok(Comparison::default())
+///     }
+///     async fn post_comment(&self, pr: u64, body: &str) -> Result<(), ChetterError> { Ok(()) }
+///     async fn upsert_comment(&self, pr: u64, marker: &str, body: &str)
+///         -> Result<(), ChetterError> { Ok(()) }
+///     async fn get_pull(&self, pr: u64) -> Result<PullRequestInfo, ChetterError> {
+///         Err(ChetterError::GithubParseError("no pull requests".into()))
+///     }
+///     async fn open_pulls<'a>(&self, base: Option<&'a str>)
+///         -> Result<Vec<PullRequestInfo>, ChetterError> { Ok(vec![]) }
+///     fn compare_url(&self, base: &str, head: &str) -> String { String::new() }
+///     async fn set_status(&self, sha: &str, context: &str, description: &str, target_url: &str)
+///         -> Result<(), ChetterError> { Ok(()) }
+///     async fn repository_dispatch(&self, event_type: &str, payload: &serde_json::Value)
+///         -> Result<(), ChetterError> { Ok(()) }
+///     async fn can_push(&self, login: &str) -> Result<bool, ChetterError> { Ok(false) }
 /// }
 ///
 /// async fn foo() {
@@ -150,17 +1172,41 @@ impl RepositoryClient {
 ///     assert!(client.create_ref("1234/existing-ref", "abc1234").await.is_ok());
 /// }
 /// ```
-
 pub trait RepositoryController {
     /// Create a new reference (rooted at {REF_NS}/*) to the specified sha.
+    ///
+    /// An existing reference is updated instead.
     async fn create_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError>;
 
+    /// Create several new references (rooted at *{REF_NS}/*), given as pairs of name and sha, in
+    /// a single request.
+    async fn create_refs(&self, refs: &[(String, String)]) -> Result<(), ChetterError>;
+
     /// Update an existing reference (rooted at *{REF_NS}/*) to the specified sha.
-    async fn update_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError>;
+    ///
+    /// `old_sha` is what the reference pointed to when it was last read, if it was.
+    async fn update_ref<'a>(
+        &self,
+        ref_name: &str,
+        old_sha: Option<&'a str>,
+        sha: &str,
+    ) -> Result<(), ChetterError>;
+
+    /// Delete an existing reference (rooted at *{REF_NS}/*).
+    async fn delete_ref(&self, ref_name: &str) -> Result<(), ChetterError>;
+
+    /// Copy a reference (rooted at *{REF_NS}/*) to a tag with the same name rooted at
+    /// *{ARCHIVE_NS}/*.
+    ///
+    /// An existing tag is updated instead.
+    async fn archive_ref(&self, r: &Ref) -> Result<(), ChetterError>;
 
     /// Delete existing references (rooted at *{REF_NS}/*).
     async fn delete_refs(&self, ref_names: &[Ref]) -> Result<(), ChetterError>;
 
+    /// Get a single reference (rooted at *{REF_NS}/*), None if it does not exist.
+    async fn get_ref(&self, name: &str) -> Result<Option<Ref>, ChetterError>;
+
     /// Get a vector of references (rooted at *{REF_NS}/*) that end with the specified search
     /// string.
     ///
@@ -172,138 +1218,601 @@ pub trait RepositoryController {
     ///     - {REF_NS}/other/abc/d
     ///     - {REF_NS}/ab
     async fn matching_refs(&self, search: &str) -> Result<Vec<Ref>, ChetterError>;
+
+    /// Compare two commits, `base` and `head`, by sha or reference name.
+    async fn compare(&self, base: &str, head: &str) -> Result<Comparison, ChetterError>;
+
+    /// Add a new comment to a pull request.
+    async fn post_comment(&self, pr: u64, body: &str) -> Result<(), ChetterError>;
+
+    /// Replace the body of the pull request comment containing `marker`, or add a new comment if
+    /// there is none.
+    async fn upsert_comment(&self, pr: u64, marker: &str, body: &str) -> Result<(), ChetterError>;
+
+    /// Get the current state of a pull request.
+    async fn get_pull(&self, pr: u64) -> Result<PullRequestInfo, ChetterError>;
+
+    /// Get all open pull requests, or only those targeting branch `base`.
+    async fn open_pulls<'a>(
+        &self,
+        base: Option<&'a str>,
+    ) -> Result<Vec<PullRequestInfo>, ChetterError>;
+
+    /// Get the URL of the web view comparing two references (rooted at *{REF_NS}/*).
+    fn compare_url(&self, base: &str, head: &str) -> String;
+
+    /// Set a successful commit status for `context` on the specified sha.
+    async fn set_status(
+        &self,
+        sha: &str,
+        context: &str,
+        description: &str,
+        target_url: &str,
+    ) -> Result<(), ChetterError>;
+
+    /// Fire a `repository_dispatch` event of `event_type` with `payload` as its client payload,
+    /// so that workflows of the repository can act on it.
+    async fn repository_dispatch(
+        &self,
+        event_type: &str,
+        payload: &serde_json::Value,
+    ) -> Result<(), ChetterError>;
+
+    /// Whether user `login` can push to the repository, as required of maintainers.
+    async fn can_push(&self, login: &str) -> Result<bool, ChetterError>;
 }
 
 #[async_trait]
 impl RepositoryController for RepositoryClient {
     async fn create_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError> {
-        // We use Commit so that we can use a full refspec, refs/..., that won't get
-        // modified by ref_url() or full_ref_url().
-        let full_ref = Reference::Commit(format!("{}/{}", REF_NS, ref_name));
-        match self
-            .crab
-            .repos(&self.org, &self.repo)
-            .create_ref(&full_ref, sha)
-            .await
-        {
-            Ok(_) => {
-                info!("created {}/{} as {}", REF_NS, ref_name, &sha[0..8]);
-                Ok(())
+        let r = self.create_ref_in(&self.ns, ref_name, sha).await;
+        if let Some(ref ns) = self.migrate_to {
+            if let Err(e) = self.create_ref_in(ns, ref_name, sha).await {
+                warn!("Failed to create {ref_name} in migration target {ns}: {e}");
             }
-            Err(error) => {
-                error!("Failed to create {} as {}", ref_name, &sha[0..8]);
-                Err(ChetterError::Octocrab(error))
+        }
+        r
+    }
+
+    async fn create_refs(&self, refs: &[(String, String)]) -> Result<(), ChetterError> {
+        let r = self.create_refs_in(&self.ns, refs).await;
+        if let Some(ref ns) = self.migrate_to {
+            if let Err(e) = self.create_refs_in(ns, refs).await {
+                warn!("Failed to create references in migration target {ns}: {e}");
             }
         }
+        r
     }
 
-    async fn update_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError> {
-        let req = json!({"sha": &sha, "force": true});
-        let url = format!(
-            "/repos/{}/{}/git/{}/{}",
-            self.org, self.repo, REF_NS, ref_name
-        );
-        match self.crab.post(&url, Some(&req)).await {
+    async fn update_ref<'a>(
+        &self,
+        ref_name: &str,
+        old_sha: Option<&'a str>,
+        sha: &str,
+    ) -> Result<(), ChetterError> {
+        let r = self.update_ref_in(&self.ns, ref_name, old_sha, sha).await;
+        if let Some(ref ns) = self.migrate_to {
+            // The reference may predate the migration
+            if self.update_ref_in(ns, ref_name, None, sha).await.is_err() {
+                if let Err(e) = self.create_ref_in(ns, ref_name, sha).await {
+                    warn!("Failed to update {ref_name} in migration target {ns}: {e}");
+                }
+            }
+        }
+        r
+    }
+
+    async fn delete_ref(&self, ref_name: &str) -> Result<(), ChetterError> {
+        let r = self.delete_ref_in(&self.ns, ref_name).await;
+        if let Some(ref ns) = self.migrate_to {
+            if let Err(e) = self.delete_ref_in(ns, ref_name).await {
+                warn!("Failed to delete {ref_name} in migration target {ns}: {e}");
+            }
+        }
+        r
+    }
+
+    async fn archive_ref(&self, r: &Ref) -> Result<(), ChetterError> {
+        let full_ref = format!("{}/{}", ARCHIVE_NS, r.full_name);
+        let change = self.change(full_ref.clone(), Operation::Create, None, Some(&r.sha));
+        self.allowed(&change).await?;
+        let req = json!({"ref": &full_ref, "sha": &r.sha});
+        let url = format!("/repos/{}/{}/git/refs", self.org, self.repo);
+        match self.post("archive_ref", &url, &req).await {
             Ok::<octocrab::models::repos::Ref, _>(_) => {
-                info!("updated {}/{} as {}", REF_NS, ref_name, &sha[0..8]);
+                info!("archived {}/{} as {}", self.ns, r.full_name, full_ref);
+                self.made(change).await;
                 Ok(())
             }
+            // Left behind by an earlier attempt, such as for a redelivered event
+            Err(error) if is_already_exists(&error) => {
+                info!("{} already exists, updating it instead", full_ref);
+                self.update_ref_in(ARCHIVE_NS, &r.full_name, None, &r.sha)
+                    .await
+            }
             Err(error) => {
-                error!("Failed to update {}/{} to {}", REF_NS, ref_name, &sha[0..8]);
-                Err(ChetterError::Octocrab(error))
+                error!("Failed to archive {}/{}", self.ns, r.full_name);
+                Err(error)
             }
         }
     }
 
     async fn delete_refs(&self, refs: &[Ref]) -> Result<(), ChetterError> {
-        let mut errors: Vec<ChetterError> = vec![];
-
-        // Github GraphQL takes a ridiculous amount of time to delete references and will cut us
-        // off after 90s of CPU time or 60s of real time.
-        for chunk in refs.chunks(100) {
-            let mutations: String = chunk
-                .iter()
-                .enumerate()
-                .map(|(i, r)| {
-                    formatdoc!(
-                        r#"
-                        delete_{i}: deleteRef(input: {{
-                                refId: "{node_id}",
-                                clientMutationId: "{full_name}"
-                            }}) {{
-                            clientMutationId
-                        }}
-                        "#,
-                        node_id = r.node_id,
-                        full_name = r.full_name,
-                    )
-                })
-                .collect();
-            let query = json!({"query": format!("mutation {{\n{}\n}}", mutations)});
-            info!("Sending mutation to delete {} refs", chunk.len());
-
-            match self.crab.graphql(&query).await {
-                // graphql errors are ignored
-                // https://github.com/XAMPPRocky/octocrab/issues/78
-                Ok::<serde_json::Value, _>(resp) => {
-                    if let Ok(e) = serde_json::from_value::<GraphqlErrors>(resp) {
-                        e.errors.iter().for_each(|e| {
-                            error!("error: {}", e.message);
-                        });
-                        errors.push(ChetterError::GithubGraphqlError(e));
-                    } else {
-                        chunk.iter().for_each(|r| {
-                            info!("deleted {}/{}", REF_NS, r.full_name);
-                        });
-                    }
-                }
-                Err(error) => {
-                    error!("failed to delete references: {:?}", &error);
-                    errors.push(ChetterError::Octocrab(error));
+        let r = self.delete_refs_in(&self.ns, refs).await;
+        if let (Some(ns), false) = (&self.migrate_to, refs.is_empty()) {
+            // Node ids differ between namespaces, so look up the matching references in the
+            // target.  They usually all belong to one pull request, search by the common prefix.
+            let names: HashSet<&str> = refs.iter().map(|r| r.full_name.as_str()).collect();
+            let mirrored = match self.matching_refs_in(ns, common_prefix(&names)).await {
+                Ok(v) => v
+                    .into_iter()
+                    .filter(|r| names.contains(r.full_name.as_str()))
+                    .collect(),
+                Err(e) => {
+                    warn!("Failed to find references in migration target {ns}: {e}");
+                    vec![]
                 }
             };
+            if let Err(e) = self.delete_refs_in(ns, &mirrored).await {
+                warn!("Failed to delete references in migration target {ns}: {e}");
+            }
         }
+        r
+    }
 
-        match errors.pop() {
-            None => Ok(()),
-            Some(e) => Err(e),
-        }
+    async fn get_ref(&self, name: &str) -> Result<Option<Ref>, ChetterError> {
+        self.get_ref_in(&self.ns, name).await
     }
 
     async fn matching_refs(&self, search: &str) -> Result<Vec<Ref>, ChetterError> {
-        let short_ns = &REF_NS[5..]; // Strip 'refs/'
-        let page = self
-            .crab
-            .get(
-                format!(
-                    "/repos/{}/{}/git/matching-refs/{}/{}",
-                    self.org, self.repo, short_ns, search
-                ),
-                None::<&()>,
-            )
-            .await?;
-        let results = self
-            .crab
-            .all_pages::<octocrab::models::repos::Ref>(page)
+        self.matching_refs_in(&self.ns, search).await
+    }
+
+    async fn compare(&self, base: &str, head: &str) -> Result<Comparison, ChetterError> {
+        #[derive(Deserialize, Debug)]
+        struct Commit {
+            sha: String,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct CompareResponse {
+            #[serde(default)]
+            files: Vec<FilePatch>,
+            status: String,
+            merge_base_commit: Commit,
+        }
+
+        let url = format!(
+            "/repos/{}/{}/compare/{}...{}",
+            self.org, self.repo, base, head
+        );
+        let resp: CompareResponse = self.get("compare", &url).await?;
+
+        Ok(Comparison {
+            files: resp.files,
+            status: resp.status,
+            merge_base: resp.merge_base_commit.sha,
+        })
+    }
+    async fn post_comment(&self, pr: u64, body: &str) -> Result<(), ChetterError> {
+        let url = format!("/repos/{}/{}/issues/{pr}/comments", self.org, self.repo);
+        let _: serde_json::Value = self
+            .post("post_comment", &url, &json!({"body": body}))
             .await?;
-        Ok(results
+        info!("commented on #{}", pr);
+        Ok(())
+    }
+
+    async fn upsert_comment(&self, pr: u64, marker: &str, body: &str) -> Result<(), ChetterError> {
+        let url = format!(
+            "/repos/{}/{}/issues/{pr}/comments?per_page=100",
+            self.org, self.repo
+        );
+        let existing = self
+            .get_all::<Comment>("list_comments", &url)
+            .await?
             .into_iter()
-            .filter_map(|r| {
-                let sha = match r.object {
-                    octocrab::models::repos::Object::Commit { sha, .. } => sha,
-                    octocrab::models::repos::Object::Tag { sha, .. } => sha,
-                    _ => {
-                        warn!("Skipping unmatched: {:?}", r);
-                        return None;
-                    }
-                };
+            .find(|c| c.body.as_ref().is_some_and(|b| b.contains(marker)));
+
+        match existing {
+            Some(comment) => {
+                let url = format!(
+                    "/repos/{}/{}/issues/comments/{}",
+                    self.org, self.repo, comment.id
+                );
+                let _: serde_json::Value = self
+                    .patch("update_comment", &url, &json!({"body": body}))
+                    .await?;
+                info!("updated comment {} on #{}", comment.id, pr);
+                Ok(())
+            }
+            None => self.post_comment(pr, body).await,
+        }
+    }
+    async fn get_pull(&self, pr: u64) -> Result<PullRequestInfo, ChetterError> {
+        let url = format!("/repos/{}/{}/pulls/{pr}", self.org, self.repo);
+        let pull: PullRequest = self.get("get_pull", &url).await?;
+        Ok(pull.into())
+    }
+
+    async fn open_pulls<'a>(
+        &self,
+        base: Option<&'a str>,
+    ) -> Result<Vec<PullRequestInfo>, ChetterError> {
+        let mut url = format!(
+            "/repos/{}/{}/pulls?state=open&per_page=100",
+            self.org, self.repo
+        );
+        if let Some(base) = base {
+            url.push_str(&format!("&base={}", query_value(base)));
+        }
+        let pulls: Vec<PullRequest> = self.get_all("open_pulls", &url).await?;
+        Ok(pulls.into_iter().map(PullRequestInfo::from).collect())
+    }
+    fn compare_url(&self, base: &str, head: &str) -> String {
+        let short_ns = self.ns.trim_start_matches("refs/heads/");
+        format!(
+            "https://github.com/{}/{}/compare/{short_ns}/{base}...{short_ns}/{head}",
+            self.org, self.repo
+        )
+    }
+
+    async fn set_status(
+        &self,
+        sha: &str,
+        context: &str,
+        description: &str,
+        target_url: &str,
+    ) -> Result<(), ChetterError> {
+        let req = json!({
+            "state": "success",
+            "context": context,
+            "description": description,
+            "target_url": target_url,
+        });
+        let url = format!("/repos/{}/{}/statuses/{}", self.org, self.repo, sha);
+        let short_sha = sha.get(..8).unwrap_or(sha);
+        match self.post("set_status", &url, &req).await {
+            Ok::<serde_json::Value, _>(_) => {
+                info!("set {} status on {}", context, short_sha);
+                Ok(())
+            }
+            Err(error) => {
+                error!("Failed to set {} status on {}", context, short_sha);
+                Err(error)
+            }
+        }
+    }
+
+    async fn repository_dispatch(
+        &self,
+        event_type: &str,
+        payload: &serde_json::Value,
+    ) -> Result<(), ChetterError> {
+        let req = json!({"event_type": event_type, "client_payload": payload});
+        let url = format!("/repos/{}/{}/dispatches", self.org, self.repo);
+        // GitHub answers with no content
+        let r = timed("repository_dispatch", async {
+            send!(self, self.crab._post(url, Some(&req)), |_response| ())
+        })
+        .await;
+        match r {
+            Ok(()) => {
+                info!("dispatched {event_type} to {}", self.full_name());
+                Ok(())
+            }
+            Err(error) => {
+                error!("Failed to dispatch {event_type} to {}", self.full_name());
+                Err(error)
+            }
+        }
+    }
+
+    async fn can_push(&self, login: &str) -> Result<bool, ChetterError> {
+        #[derive(Deserialize)]
+        struct Permission {
+            /// `admin`, `write`, `read` or `none`, where `maintain` is reported as `write`
+            permission: String,
+        }
 
-                Some(Ref {
-                    full_name: r.ref_field.replace(&format!("{REF_NS}/"), ""),
-                    sha,
-                    node_id: r.node_id,
-                })
+        let url = format!(
+            "/repos/{}/{}/collaborators/{login}/permission",
+            self.org, self.repo
+        );
+        let r: Permission = self.get("can_push", &url).await?;
+        Ok(matches!(r.permission.as_str(), "admin" | "write"))
+    }
+}
+
+/// Id of the installation a webhook event was delivered for.
+pub fn installation_id(ev: &WebhookEvent) -> Option<u64> {
+    match ev.installation.as_ref()? {
+        EventInstallation::Minimal(v) => Some(v.id.0),
+        EventInstallation::Full(v) => Some(v.id.0),
+    }
+}
+
+/// Whether references should not be recorded for `pull`, as its title contains `[no-chetter]`
+/// or its body `<!-- chetter: off -->`, ignoring case and whitespace within the comment.
+pub fn opted_out(pull: &PullRequest) -> bool {
+    let title = pull.title.as_deref().unwrap_or_default().to_lowercase();
+    let body: String = pull
+        .body
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<String>()
+        .to_lowercase();
+    title.contains("[no-chetter]") || body.contains("<!--chetter:off-->")
+}
+
+/// Convert a reference rooted at `ns` from the REST API.
+fn to_ref(ns: &str, r: octocrab::models::repos::Ref) -> Option<Ref> {
+    let sha = match r.object {
+        octocrab::models::repos::Object::Commit { sha, .. } => sha,
+        octocrab::models::repos::Object::Tag { sha, .. } => sha,
+        _ => {
+            warn!("Skipping unmatched: {:?}", r);
+            return None;
+        }
+    };
+
+    Some(Ref {
+        full_name: r.ref_field.replace(&format!("{ns}/"), ""),
+        sha,
+        node_id: r.node_id,
+    })
+}
+
+/// HTTP status of an error response from GitHub, if any.
+fn github_status(error: &octocrab::Error) -> Option<u16> {
+    match error {
+        octocrab::Error::GitHub { source, .. } => Some(source.status_code.as_u16()),
+        _ => None,
+    }
+}
+
+/// Index of the mutation aliased `<prefix><index>` that failed with `error`, if it names one.
+fn mutation_index(error: &GraphqlError, prefix: &str) -> Option<usize> {
+    error
+        .path
+        .first()?
+        .as_str()?
+        .strip_prefix(prefix)?
+        .parse()
+        .ok()
+}
+
+/// Whether creating a reference failed because it already exists.
+fn is_already_exists(error: &ChetterError) -> bool {
+    match error.octocrab() {
+        Some(e @ octocrab::Error::GitHub { source, .. }) => {
+            github_status(e) == Some(422) && source.message.contains("already exists")
+        }
+        _ => false,
+    }
+}
+
+/// Wrap `error` of a failed request with the id GitHub assigned to it, if any.
+fn request_error(error: octocrab::Error, request_id: Option<String>) -> ChetterError {
+    match request_id {
+        Some(request_id) => {
+            warn!("GitHub request {request_id} failed: {error}");
+            ChetterError::GithubRequest { error, request_id }
+        }
+        None => ChetterError::Octocrab(error),
+    }
+}
+
+/// Longest common prefix of `names` ending in '/', or an empty string.
+fn common_prefix<'a>(names: &HashSet<&'a str>) -> &'a str {
+    let mut iter = names.iter();
+    let Some(first) = iter.next() else {
+        return "";
+    };
+    let mut len = first.len();
+    for name in iter {
+        len = first
+            .bytes()
+            .zip(name.bytes())
+            .take(len)
+            .take_while(|(a, b)| a == b)
+            .count();
+    }
+    match first[..len].rfind('/') {
+        Some(i) => &first[..=i],
+        None => "",
+    }
+}
+
+/// Percent-encode `value` for the query of a request URL.
+fn query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                char::from(b).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Check if `e` may be the result of GitHub giving up on a slow GraphQL mutation.
+///
+/// Besides GraphQL errors saying so, that is a bad or timed out gateway, or the request itself
+/// failing on the way; other REST errors, like being denied, are not.
+fn is_timeout(e: &ChetterError) -> bool {
+    match e {
+        ChetterError::Octocrab(e) | ChetterError::GithubRequest { error: e, .. } => match e {
+            octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. } => true,
+            e => matches!(github_status(e), Some(502 | 504)),
+        },
+        ChetterError::GithubGraphqlError(e) => e.errors.iter().any(|e| {
+            let message = e.message.to_lowercase();
+            message.contains("timeout") || message.contains("timed out")
+        }),
+        _ => false,
+    }
+}
+
+/// Size of the chunks GraphQL deletions are split into, shared by every client.
+static DELETE_CHUNK: ChunkSizer = ChunkSizer::new();
+
+/// Chunk size adapted to how long recent mutations took.
+struct ChunkSizer {
+    size: AtomicUsize,
+}
+
+impl ChunkSizer {
+    const MIN: usize = 5;
+    const MAX: usize = 100;
+
+    /// Mutations are sized to take about this long, well below GitHub's 60s limit.
+    const TARGET: Duration = Duration::from_secs(20);
+
+    const fn new() -> Self {
+        Self {
+            size: AtomicUsize::new(Self::MAX),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Scale towards the size that would have taken `TARGET` to delete, growing by at most half.
+    fn succeeded(&self, size: usize, elapsed: Duration) {
+        let millis = elapsed.as_millis().max(1);
+        let ideal = size as u128 * Self::TARGET.as_millis() / millis;
+        let next = (ideal as usize).clamp(Self::MIN, Self::MAX.min(size + size / 2 + 1));
+        // Only adjust sizes that were not limited by the number of references left
+        if next < size || size >= self.size() {
+            self.size.store(next, Ordering::Relaxed);
+        }
+    }
+
+    /// Halve the size after a mutation of `size` references timed out.
+    fn failed(&self, size: usize) {
+        self.size
+            .store((size / 2).max(Self::MIN), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::PullRequestFixture;
+
+    #[test]
+    fn opt_out() {
+        let pull = |fixture: PullRequestFixture| {
+            serde_json::from_value::<PullRequest>(fixture.to_json()).unwrap()
+        };
+        assert!(!opted_out(&pull(PullRequestFixture::new(1))));
+        assert!(opted_out(&pull(
+            PullRequestFixture::new(1).title("WIP [No-Chetter] experiment")
+        )));
+        assert!(opted_out(&pull(
+            PullRequestFixture::new(1).body("Testing CI\n\n<!--  Chetter:\toff -->\n")
+        )));
+        assert!(!opted_out(&pull(
+            PullRequestFixture::new(1).body("chetter: off")
+        )));
+        assert!(
+            PullRequestInfo::from(pull(PullRequestFixture::new(1).title("[no-chetter]"))).opted_out
+        );
+    }
+
+    #[test]
+    fn chunk_sizer() {
+        let sizer = ChunkSizer::new();
+        assert_eq!(sizer.size(), ChunkSizer::MAX);
+
+        sizer.failed(100);
+        assert_eq!(sizer.size(), 50);
+        sizer.succeeded(50, Duration::from_secs(40));
+        assert_eq!(sizer.size(), 25);
+        sizer.succeeded(25, Duration::from_secs(1));
+        assert_eq!(sizer.size(), 38);
+
+        // A small final chunk that was fast says little about larger ones
+        sizer.succeeded(3, Duration::from_millis(100));
+        assert_eq!(sizer.size(), 38);
+
+        for _ in 0..10 {
+            sizer.failed(sizer.size());
+        }
+        assert_eq!(sizer.size(), ChunkSizer::MIN);
+        for _ in 0..20 {
+            sizer.succeeded(sizer.size(), Duration::ZERO);
+        }
+        assert_eq!(sizer.size(), ChunkSizer::MAX);
+    }
+
+    #[test]
+    fn timeouts() {
+        let graphql = |message: &str| {
+            ChetterError::GithubGraphqlError(GraphqlErrors {
+                errors: vec![crate::error::GraphqlError {
+                    message: message.into(),
+                    path: vec![],
+                }],
             })
-            .collect())
+        };
+        assert!(is_timeout(&graphql(
+            "Something went wrong while executing your query. This may be the result of a timeout"
+        )));
+        assert!(!is_timeout(&graphql("Could not resolve to a node")));
+        assert!(!is_timeout(&ChetterError::Config("bad".into())));
+    }
+
+    #[test]
+    fn query_values() {
+        assert_eq!(query_value("main"), "main");
+        assert_eq!(query_value("release/1.0+fix&x"), "release/1.0%2Bfix%26x");
+    }
+
+    #[tokio::test]
+    async fn timeouts_by_status() {
+        use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+        let github = MockServer::start().await;
+        let crab = Octocrab::builder()
+            .base_uri(github.uri())
+            .unwrap()
+            .build()
+            .unwrap();
+        for (status, timeout) in [(401, false), (403, false), (502, true), (504, true)] {
+            Mock::given(path(format!("/status/{status}")))
+                .respond_with(
+                    ResponseTemplate::new(status)
+                        .set_body_json(serde_json::json!({"message": "failed"})),
+                )
+                .mount(&github)
+                .await;
+            let e = crab
+                .get::<serde_json::Value, _, ()>(format!("/status/{status}"), None)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                is_timeout(&ChetterError::GithubRequest {
+                    error: e,
+                    request_id: "1".into(),
+                }),
+                timeout,
+                "{status}"
+            );
+        }
+    }
+
+    #[test]
+    fn common_prefixes() {
+        let names = HashSet::from(["1/v1", "1/v2-base", "1/head"]);
+        assert_eq!(common_prefix(&names), "1/");
+        let names = HashSet::from(["1/v1", "12/v1"]);
+        assert_eq!(common_prefix(&names), "");
+        let names = HashSet::from(["1/nick-v1"]);
+        assert_eq!(common_prefix(&names), "1/");
     }
 }
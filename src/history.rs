@@ -0,0 +1,297 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    github::Ref,
+    refname::{ParsedRef, RefLayout},
+};
+
+/// Versions and review bookmarks recorded for a pull request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PrHistory {
+    /// Pull request number
+    pub pr: u64,
+
+    /// Most recent head, None if nothing was recorded
+    pub head: Option<String>,
+
+    /// Recorded versions, oldest first
+    pub versions: Vec<Version>,
+
+    /// Review bookmarks, ordered by reviewer
+    pub reviews: Vec<Review>,
+
+    /// Snapshots taken on demand, ordered by name
+    pub snapshots: Vec<Snapshot>,
+}
+
+/// A recorded version of a pull request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Version {
+    /// Version number, starting at 1
+    pub number: u32,
+
+    /// Head of the version
+    pub sha: String,
+
+    /// Base of the version, if recorded
+    pub base: Option<String>,
+
+    /// Merge-base of the version and its base, if recorded
+    pub merge_base: Option<String>,
+
+    /// Whether the version only rebased the prior version
+    pub rebase: bool,
+}
+
+/// Head of a pull request when a reviewer completed a review.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Review {
+    /// Login of the reviewer, escaped as in reference names
+    pub reviewer: String,
+
+    /// Name of the review, such as `v2` or `r123456`
+    pub review: String,
+
+    /// Head that was reviewed
+    pub sha: String,
+
+    /// Base that was reviewed, if recorded
+    pub base: Option<String>,
+}
+
+/// Head and base of a pull request recorded under a name with `/chetter snapshot`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Snapshot {
+    /// Name of the snapshot, such as `rc1`
+    pub name: String,
+
+    /// Head when the snapshot was taken
+    pub sha: String,
+
+    /// Base when the snapshot was taken, if recorded
+    pub base: Option<String>,
+}
+
+impl PrHistory {
+    /// Collect the history of pull request `pr` from its references, named according to `layout`.
+    pub fn from_refs(layout: &RefLayout, pr: u64, refs: &[Ref]) -> Self {
+        let mut history = PrHistory {
+            pr,
+            ..Default::default()
+        };
+        let mut versions: BTreeMap<u32, Version> = BTreeMap::new();
+        let mut reviews: BTreeMap<(String, String), Review> = BTreeMap::new();
+        let mut snapshots: BTreeMap<String, Snapshot> = BTreeMap::new();
+
+        for r in refs {
+            let sha = r.sha.clone();
+            match ParsedRef::parse_full(layout, pr, &r.full_name) {
+                ParsedRef::Head => history.head = Some(sha),
+                ParsedRef::Version(n) => versions.entry(n).or_default().sha = sha,
+                ParsedRef::VersionBase(n) => versions.entry(n).or_default().base = Some(sha),
+                ParsedRef::VersionMergeBase(n) => {
+                    versions.entry(n).or_default().merge_base = Some(sha)
+                }
+                ParsedRef::VersionRebase(n) => versions.entry(n).or_default().rebase = true,
+                ParsedRef::ReviewerVersion(reviewer, n) => {
+                    reviews.entry((reviewer, format!("v{n}"))).or_default().sha = sha
+                }
+                ParsedRef::ReviewerVersionBase(reviewer, n) => {
+                    reviews.entry((reviewer, format!("v{n}"))).or_default().base = Some(sha)
+                }
+                ParsedRef::ReviewerReview(reviewer, key) => {
+                    reviews.entry((reviewer, key)).or_default().sha = sha
+                }
+                ParsedRef::ReviewerReviewBase(reviewer, key) => {
+                    reviews.entry((reviewer, key)).or_default().base = Some(sha)
+                }
+                ParsedRef::Snapshot(name) => snapshots.entry(name).or_default().sha = sha,
+                ParsedRef::SnapshotBase(name) => {
+                    snapshots.entry(name).or_default().base = Some(sha)
+                }
+                _ => (),
+            }
+        }
+
+        // Bases and rebase markers without their version are left over from cleanups
+        history.versions = versions
+            .into_iter()
+            .filter(|(_, v)| !v.sha.is_empty())
+            .map(|(number, v)| Version { number, ..v })
+            .collect();
+        history.reviews = reviews
+            .into_iter()
+            .filter(|(_, r)| !r.sha.is_empty())
+            .map(|((reviewer, review), r)| Review {
+                reviewer,
+                review,
+                ..r
+            })
+            .collect();
+        history.snapshots = snapshots
+            .into_iter()
+            .filter(|(_, s)| !s.sha.is_empty())
+            .map(|(name, s)| Snapshot { name, ..s })
+            .collect();
+        history
+    }
+
+    /// Render the history as aligned plain text tables.
+    pub fn to_table(&self) -> String {
+        let short = |sha: &str| sha.chars().take(12).collect::<String>();
+        let short_opt = |sha: &Option<String>| sha.as_deref().map_or("-".into(), short);
+
+        let mut out = format!(
+            "pull request #{}, head {}\n\n",
+            self.pr,
+            short_opt(&self.head)
+        );
+        out.push_str(&format!(
+            "{:<8} {:<12} {:<12} {:<12} {}\n",
+            "VERSION", "SHA", "BASE", "MERGE-BASE", "REBASE"
+        ));
+        for v in &self.versions {
+            out.push_str(&format!(
+                "{:<8} {:<12} {:<12} {:<12} {}\n",
+                format!("v{}", v.number),
+                short(&v.sha),
+                short_opt(&v.base),
+                short_opt(&v.merge_base),
+                if v.rebase { "yes" } else { "no" },
+            ));
+        }
+
+        let width = self
+            .reviews
+            .iter()
+            .map(|r| r.reviewer.len())
+            .max()
+            .unwrap_or(0)
+            .max("REVIEWER".len());
+        out.push_str(&format!(
+            "\n{:<width$} {:<16} {:<12} {}\n",
+            "REVIEWER", "REVIEW", "SHA", "BASE"
+        ));
+        for r in &self.reviews {
+            out.push_str(&format!(
+                "{:<width$} {:<16} {:<12} {}\n",
+                r.reviewer,
+                r.review,
+                short(&r.sha),
+                short_opt(&r.base),
+            ));
+        }
+
+        if !self.snapshots.is_empty() {
+            let width = self
+                .snapshots
+                .iter()
+                .map(|s| s.name.len())
+                .max()
+                .unwrap_or(0)
+                .max("SNAPSHOT".len());
+            out.push_str(&format!(
+                "\n{:<width$} {:<12} {}\n",
+                "SNAPSHOT", "SHA", "BASE"
+            ));
+            for s in &self.snapshots {
+                out.push_str(&format!(
+                    "{:<width$} {:<12} {}\n",
+                    s.name,
+                    short(&s.sha),
+                    short_opt(&s.base),
+                ));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn make_ref(name: &str, sha: &str) -> Ref {
+        Ref {
+            full_name: name.into(),
+            sha: sha.into(),
+            node_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn from_refs() {
+        let refs = [
+            make_ref("1/head", "c2"),
+            make_ref("1/v1", "c1"),
+            make_ref("1/v1-base", "b1"),
+            make_ref("1/v2", "c2"),
+            make_ref("1/v2-base", "b1"),
+            make_ref("1/v2-rebase", "c2"),
+            make_ref("1/v3-base", "b1"),
+            make_ref("1/alice-v1", "c1"),
+            make_ref("1/alice-v1-base", "b1"),
+            make_ref("1/bob-r77", "c2"),
+            make_ref("1/bob-head", "c2"),
+            make_ref("1/snapshot-benchmarked", "c1"),
+            make_ref("1/snapshot-benchmarked-base", "b1"),
+        ];
+        let history = PrHistory::from_refs(&RefLayout::default(), 1, &refs);
+        assert_eq!(history.head.as_deref(), Some("c2"));
+        assert_eq!(
+            history.to_table(),
+            indoc! {"
+                pull request #1, head c2
+
+                VERSION  SHA          BASE         MERGE-BASE   REBASE
+                v1       c1           b1           -            no
+                v2       c2           b1           -            yes
+
+                REVIEWER REVIEW           SHA          BASE
+                alice    v1               c1           b1
+                bob      r77              c2           -
+
+                SNAPSHOT    SHA          BASE
+                benchmarked c1           b1
+            "}
+        );
+        assert_eq!(
+            history.versions,
+            [
+                Version {
+                    number: 1,
+                    sha: "c1".into(),
+                    base: Some("b1".into()),
+                    merge_base: None,
+                    rebase: false,
+                },
+                Version {
+                    number: 2,
+                    sha: "c2".into(),
+                    base: Some("b1".into()),
+                    merge_base: None,
+                    rebase: true,
+                },
+            ]
+        );
+        assert_eq!(
+            history.reviews,
+            [
+                Review {
+                    reviewer: "alice".into(),
+                    review: "v1".into(),
+                    sha: "c1".into(),
+                    base: Some("b1".into()),
+                },
+                Review {
+                    reviewer: "bob".into(),
+                    review: "r77".into(),
+                    sha: "c2".into(),
+                    base: None,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::{audit::AuditEntry, error::ChetterError};
+
+/// Called around every change chetter-app makes to a reference, such as to refuse touching
+/// references of some repositories or to mirror changes to an external system.
+///
+/// Changes are described as they are recorded in the audit log, with the time they are about to
+/// be or were made.  Install hooks with [State::with_ref_hook].
+///
+/// ```
+/// use async_trait::async_trait;
+/// use chetter_app::{audit::AuditEntry, error::ChetterError, hooks::RefHook};
+///
+/// /// Leave the references of a repository alone.
+/// struct Hands(String);
+///
+/// #[async_trait]
+/// impl RefHook for Hands {
+///     async fn before(&self, change: &AuditEntry) -> Result<(), ChetterError> {
+///         if change.repo == self.0 {
+///             return Err(ChetterError::Vetoed(format!("hands off {}", self.0)));
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+///
+/// [State::with_ref_hook]: crate::State::with_ref_hook
+#[async_trait]
+pub trait RefHook: Send + Sync {
+    /// `change` is about to be made, which is vetoed by returning an error.
+    ///
+    /// Vetoed changes fail with that error, while the rest of a batch still goes ahead.
+    async fn before(&self, _change: &AuditEntry) -> Result<(), ChetterError> {
+        Ok(())
+    }
+
+    /// `change` was made.
+    async fn after(&self, _change: &AuditEntry) {}
+}
+
+/// Hooks shared with other parts of an application.
+#[async_trait]
+impl<T: RefHook + ?Sized> RefHook for Arc<T> {
+    async fn before(&self, change: &AuditEntry) -> Result<(), ChetterError> {
+        (**self).before(change).await
+    }
+
+    async fn after(&self, change: &AuditEntry) {
+        (**self).after(change).await
+    }
+}
+
+/// Hooks called around changes to references, in the order they were registered.
+#[derive(Clone, Default)]
+pub struct RefHooks(Arc<Vec<Arc<dyn RefHook>>>);
+
+impl RefHooks {
+    /// Call `hook` around every change from now on.
+    ///
+    /// Clones made earlier keep the hooks they were made with.
+    pub fn register(&mut self, hook: impl RefHook + 'static) {
+        Arc::make_mut(&mut self.0).push(Arc::new(hook));
+    }
+
+    /// Whether no hooks are registered.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Ask every hook whether `change` may be made, stopping at the first veto.
+    pub async fn before(&self, change: &AuditEntry) -> Result<(), ChetterError> {
+        for hook in self.0.iter() {
+            hook.before(change).await?;
+        }
+        Ok(())
+    }
+
+    /// Tell every hook `change` was made.
+    pub async fn after(&self, change: &AuditEntry) {
+        for hook in self.0.iter() {
+            hook.after(change).await;
+        }
+    }
+}
+
+impl std::fmt::Debug for RefHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("RefHooks").field(&self.0.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{Operation, Trigger};
+    use chrono::Utc;
+    use std::sync::Mutex;
+
+    fn change(ref_name: &str) -> AuditEntry {
+        AuditEntry {
+            at: Utc::now(),
+            repo: "org/repo".into(),
+            ref_name: ref_name.into(),
+            operation: Operation::Create,
+            old_sha: None,
+            new_sha: Some("c1".into()),
+            trigger: Trigger::default(),
+        }
+    }
+
+    /// Records what it is called with, vetoing references ending with `veto`.
+    #[derive(Default)]
+    struct Recording {
+        veto: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl RefHook for Recording {
+        async fn before(&self, change: &AuditEntry) -> Result<(), ChetterError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("before {}", change.ref_name));
+            match change.ref_name.ends_with(self.veto) {
+                true => Err(ChetterError::Vetoed(change.ref_name.clone())),
+                false => Ok(()),
+            }
+        }
+
+        async fn after(&self, change: &AuditEntry) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("after {}", change.ref_name));
+        }
+    }
+
+    #[tokio::test]
+    async fn registered() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut hooks = RefHooks::default();
+        assert!(hooks.is_empty());
+        let earlier = hooks.clone();
+        hooks.register(Recording {
+            veto: "/v1",
+            calls: calls.clone(),
+        });
+        hooks.register(Recording {
+            veto: "/head",
+            calls: calls.clone(),
+        });
+        assert!(earlier.is_empty());
+
+        hooks.before(&change("1/v2")).await.unwrap();
+        hooks.after(&change("1/v2")).await;
+        assert_eq!(
+            calls.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            ["before 1/v2", "before 1/v2", "after 1/v2", "after 1/v2"]
+        );
+
+        // The first veto stops asking the other hooks
+        let e = hooks.before(&change("1/v1")).await.unwrap_err();
+        assert_eq!(e.to_string(), "1/v1");
+        assert_eq!(*calls.lock().unwrap(), ["before 1/v1"]);
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+type Key = (String, u64);
+
+/// Cancellable work updating the references of each pull request.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    inner: Arc<Mutex<HashMap<Key, (CancellationToken, TaskTracker)>>>,
+}
+
+impl InFlight {
+    /// Run `work` for `repo` pull request `pr`, None if it was cancelled before finishing.
+    pub async fn run<F: Future>(&self, repo: &str, pr: u64, work: F) -> Option<F::Output> {
+        let key = (repo.to_string(), pr);
+        let (token, tracker) = self
+            .inner
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| (CancellationToken::new(), TaskTracker::new()))
+            .clone();
+
+        let r = tracker
+            .track_future(async move {
+                tokio::select! {
+                    _ = token.cancelled() => None,
+                    r = work => Some(r),
+                }
+            })
+            .await;
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.get(&key).is_some_and(|(_, t)| t.is_empty()) {
+            inner.remove(&key);
+        }
+        r
+    }
+
+    /// Cancel all work running for `repo` pull request `pr`.
+    ///
+    /// The returned future completes once the cancelled work has stopped.
+    pub fn cancel(&self, repo: &str, pr: u64) -> impl Future<Output = ()> {
+        let entry = self.inner.lock().unwrap().remove(&(repo.to_string(), pr));
+        let tracker = entry.map(|(token, tracker)| {
+            token.cancel();
+            tracker.close();
+            tracker
+        });
+        async move {
+            if let Some(tracker) = tracker {
+                tracker.wait().await;
+            }
+        }
+    }
+
+    /// Cancel all work running for any pull request of `repo`.
+    ///
+    /// The returned future completes once the cancelled work has stopped.
+    pub fn cancel_repo(&self, repo: &str) -> impl Future<Output = ()> {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<Key> = inner.keys().filter(|(r, _)| r == repo).cloned().collect();
+        let trackers: Vec<TaskTracker> = keys
+            .iter()
+            .filter_map(|k| inner.remove(k))
+            .map(|(token, tracker)| {
+                token.cancel();
+                tracker.close();
+                tracker
+            })
+            .collect();
+        async move {
+            for tracker in trackers {
+                tracker.wait().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{sleep, Duration};
+
+    #[tokio::test]
+    async fn cancel() {
+        let inflight = InFlight::default();
+        assert_eq!(inflight.run("org/repo", 1, async { 1 }).await, Some(1));
+        assert!(inflight.inner.lock().unwrap().is_empty());
+
+        let pending = tokio::spawn({
+            let inflight = inflight.clone();
+            async move {
+                inflight
+                    .run("org/repo", 1, sleep(Duration::from_secs(600)))
+                    .await
+            }
+        });
+        let other = tokio::spawn({
+            let inflight = inflight.clone();
+            async move { inflight.run("org/repo", 2, async { 2 }).await }
+        });
+        let key = ("org/repo".to_string(), 1);
+        while !inflight.inner.lock().unwrap().contains_key(&key) {
+            tokio::task::yield_now().await;
+        }
+
+        inflight.cancel("org/repo", 1).await;
+        assert_eq!(pending.await.unwrap(), None);
+        assert_eq!(other.await.unwrap(), Some(2));
+        assert!(inflight.inner.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_repo() {
+        let inflight = InFlight::default();
+        let pending: Vec<_> = [("org/repo", 1), ("org/repo", 2), ("org/other", 1)]
+            .into_iter()
+            .map(|(repo, pr)| {
+                let inflight = inflight.clone();
+                tokio::spawn(async move {
+                    let work = sleep(Duration::from_millis(100));
+                    inflight.run(repo, pr, work).await
+                })
+            })
+            .collect();
+        while inflight.inner.lock().unwrap().len() < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        inflight.cancel_repo("org/repo").await;
+        let results: Vec<_> = futures::future::join_all(pending)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(results, [None, None, Some(())]);
+    }
+}
@@ -1,45 +1,674 @@
+use audit::AuditEntry;
+#[cfg(feature = "audit")]
+use audit::{AuditLog, AuditQuery};
+#[cfg(feature = "server")]
+use badge::BadgeCache;
+use batch::BatchReport;
+use commands::Command;
+use config::{AppConfig, CommentMode, RebaseMode, RepoConfig, ReviewNaming, ReviewPolicy, Secret};
+use coordination::Coordinator;
+use crypto::Envelope;
+use deliveries::DeliveryCursor;
 use error::ChetterError;
-use github::{AppClient, RepositoryClient, RepositoryController};
+use filter::EventFacts;
+use forge::Forge;
+use github::{
+    AppClient, Comparison, InstallationToken, PullRequestInfo, Ref, RepositoryClient,
+    RepositoryController,
+};
+use history::PrHistory;
+use hooks::RefHook;
+use indoc::formatdoc;
+use inflight::InFlight;
+use ipnet::IpNet;
+#[cfg(feature = "metrics")]
+use metrics::{metrics, EventCount};
+use notifications::{Notification, NotificationKind};
 use octocrab::models::{
-    pulls::ReviewState,
+    pulls::{Review, ReviewState},
     webhook_events::{
         payload::{
-            PullRequestReviewWebhookEventPayload, PullRequestWebhookEventAction,
-            PullRequestWebhookEventPayload, WebhookEventPayload,
+            InstallationRepositoriesWebhookEventAction,
+            InstallationRepositoriesWebhookEventPayload, InstallationWebhookEventAction,
+            IssueCommentWebhookEventAction, IssueCommentWebhookEventPayload,
+            PingWebhookEventPayload, PullRequestWebhookEventAction, PullRequestWebhookEventPayload,
+            RepositoryWebhookEventAction, WebhookEventPayload,
         },
         WebhookEvent,
     },
 };
-use std::marker::{Send, Sync};
-use tokio_util::task::TaskTracker;
-use tracing::{debug, error, info, Instrument};
+use policy::{DefaultPolicy, Policy};
+use probe::PermissionProbes;
+use recorder::{PayloadRecorder, RecordedPayload};
+use refname::{ParsedRef, RefLayout};
+use scheduler::{Backoff, DeadLetter, Priority, Scheduler};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    marker::{Send, Sync},
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+use tokio::sync::broadcast;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info, warn, Instrument};
+use tracker::{
+    ChangedRef, DeliveryChanges, HookPing, PrState, PrTracker, ProcessedEvent, TrackedEvent,
+    TrackedPr,
+};
 
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "server")]
+pub mod api;
+pub mod audit;
+pub mod badge;
+pub mod batch;
+pub mod commands;
+pub mod config;
+pub mod coordination;
+pub mod crypto;
+#[cfg(feature = "server")]
+pub mod dashboard;
+pub mod deliveries;
 pub mod error;
+pub mod filter;
+pub mod forge;
 pub mod github;
+pub mod history;
+pub mod hooks;
+pub mod inflight;
+#[cfg(feature = "git2")]
+pub mod local;
+pub mod metrics;
+#[cfg(feature = "notifications")]
+pub mod nats;
+pub mod notifications;
+pub mod policy;
+pub mod probe;
+pub mod rangediff;
+pub mod ratelimit;
+pub mod recorder;
+pub mod refname;
+pub mod scheduler;
+pub mod secrets;
+#[cfg(feature = "server")]
+pub mod setup;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "server")]
+pub mod tls;
+pub mod tracker;
+pub mod version;
+#[cfg(feature = "server")]
+pub mod webhook;
 
 /// Chetter Application state
 #[derive(Clone)]
 pub struct State {
+    /// Application configuration
+    config: Arc<AppConfig>,
+
     /// Github Application Client
     app_client: AppClient,
 
     /// Background tasks
     tasks: TaskTracker,
+
+    /// Runs webhook handling ahead of background deletions
+    scheduler: Scheduler,
+
+    /// Repositories probed for write access
+    probes: PermissionProbes,
+
+    /// Badges served recently
+    #[cfg(feature = "server")]
+    badges: BadgeCache,
+
+    /// Cancelled when the application is shutting down
+    shutdown: CancellationToken,
+
+    /// Events being processed for each pull request
+    tracker: PrTracker,
+
+    /// Reference updates that are cancelled when their pull request closes
+    inflight: InFlight,
+
+    /// Installations suspended by their owner, whose events are ignored
+    suspended: Arc<Mutex<HashSet<u64>>>,
+
+    /// Last known `<org>/<repo>` of each repository id, to follow renames and transfers
+    repo_names: Arc<Mutex<HashMap<u64, String>>>,
+
+    /// The most recent ping from the webhook
+    ping: Arc<Mutex<Option<HookPing>>>,
+
+    /// Networks GitHub delivers webhook events from, once fetched
+    hook_networks: Arc<RwLock<Option<Vec<IpNet>>>>,
+
+    /// Webhook events that finished processing, for streaming subscribers
+    processed: broadcast::Sender<ProcessedEvent>,
+
+    /// Records accepted webhook payloads, if configured
+    recorder: Option<PayloadRecorder>,
+
+    /// Encrypts persisted data, if configured
+    envelope: Option<Envelope>,
+
+    /// Newest webhook delivery checked for missed events
+    delivery_cursor: DeliveryCursor,
+
+    /// Deduplicates deliveries and serializes work on pull requests across replicas
+    coordinator: Coordinator,
+
+    /// Private key the app client currently authorizes with
+    private_key: Arc<RwLock<Secret>>,
+
+    /// Secrets webhook events may be signed with, the current one first followed by the one it
+    /// replaced when rotated
+    webhook_secrets: Arc<RwLock<Vec<Secret>>>,
+
+    /// Decides which references are made of pull request events
+    policy: Arc<dyn Policy<RepositoryClient>>,
+
+    /// Changes made to references by the webhook deliveries being processed
+    changes: DeliveryChanges,
+
+    /// Sends notifications and processed events, if configured
+    #[cfg(feature = "notifications")]
+    notifier: Option<Arc<notifications::Notifier>>,
+
+    /// Log of every change made to references
+    #[cfg(feature = "audit")]
+    audit: AuditLog,
 }
 
+/// Processed events buffered for each subscriber that falls behind.
+const PROCESSED_BUFFER: usize = 256;
+
 impl State {
     /// Create a new State using the specified configuration file
     pub fn new(config_path: String) -> Result<Self, String> {
-        let app_client = match AppClient::new(config_path) {
-            Ok(v) => v,
-            Err(e) => return Err(format!("{e}")),
+        AppConfig::from_file(&config_path)
+            .and_then(Self::from_config)
+            .map_err(|e| format!("{config_path}: {e}"))
+    }
+
+    /// Create a new State using `config`, such as one built by an application embedding
+    /// chetter-app rather than read from a file.
+    ///
+    /// The configuration is finalized first, see [AppConfig::finalize].
+    pub fn from_config(mut config: AppConfig) -> Result<Self, ChetterError> {
+        config.finalize()?;
+        let config = Arc::new(config);
+        let envelope = config.storage.envelope()?;
+        let recorder = match config.storage.payloads {
+            Some(ref settings) => Some(PayloadRecorder::new(settings, envelope.clone()).map_err(
+                |e| ChetterError::Config(format!("{}: {e}", settings.directory.display())),
+            )?),
+            None => None,
         };
+        let delivery_cursor = match config.storage.delivery_cursor {
+            Some(ref path) => DeliveryCursor::open(path.clone())
+                .map_err(|e| ChetterError::Config(format!("{}: {e}", path.display())))?,
+            None => DeliveryCursor::default(),
+        };
+        let coordinator = Coordinator::new(config.redis.as_ref())?;
+        let mut app_client = AppClient::new(&config)?;
+        let private_key = Arc::new(RwLock::new(config.private_key.clone()));
+        let webhook_secrets =
+            Arc::new(RwLock::new(config.webhook_secret.iter().cloned().collect()));
+        if notifications::configured(&config) && cfg!(not(feature = "notifications")) {
+            return Err(ChetterError::Config(
+                "notifications are configured but chetter-app was built without them".into(),
+            ));
+        }
         let tasks = TaskTracker::new();
-        Ok(Self { app_client, tasks })
+        let scheduler = Scheduler::new(tasks.clone(), config.background_workers);
+        #[cfg(feature = "notifications")]
+        let notifier = match notifications::configured(&config) {
+            true => {
+                let notifier = Arc::new(notifications::Notifier::new(
+                    config.clone(),
+                    scheduler.clone(),
+                )?);
+                app_client.register_ref_hook(notifier.clone());
+                Some(notifier)
+            }
+            false => None,
+        };
+        let changes = DeliveryChanges::default();
+        app_client.register_ref_hook(changes.clone());
+        if config.storage.audit_log.is_some() && cfg!(not(feature = "audit")) {
+            return Err(ChetterError::Config(
+                "an audit log is configured but chetter-app was built without it".into(),
+            ));
+        }
+        #[cfg(feature = "audit")]
+        let audit = match config.storage.audit_log {
+            Some(ref path) => AuditLog::open(path)?,
+            None => AuditLog::default(),
+        };
+        #[cfg(feature = "audit")]
+        app_client.register_ref_hook(audit.clone());
+        Ok(Self {
+            scheduler,
+            config,
+            app_client,
+            tasks,
+            probes: PermissionProbes::default(),
+            #[cfg(feature = "server")]
+            badges: BadgeCache::default(),
+            shutdown: CancellationToken::new(),
+            tracker: PrTracker::holding(changes.clone()),
+            inflight: InFlight::default(),
+            suspended: Arc::default(),
+            repo_names: Arc::default(),
+            ping: Arc::default(),
+            hook_networks: Arc::default(),
+            processed: broadcast::channel(PROCESSED_BUFFER).0,
+            recorder,
+            envelope,
+            delivery_cursor,
+            coordinator,
+            private_key,
+            webhook_secrets,
+            policy: Arc::new(DefaultPolicy),
+            changes,
+            #[cfg(feature = "notifications")]
+            notifier,
+            #[cfg(feature = "audit")]
+            audit,
+        })
+    }
+
+    /// Handle pull request events with `policy` instead of the default behavior.
+    pub fn with_policy(mut self, policy: impl Policy<RepositoryClient> + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Call `hook` around every change made to references, in addition to hooks registered
+    /// before it.
+    pub fn with_ref_hook(mut self, hook: impl RefHook + 'static) -> Self {
+        self.app_client.register_ref_hook(hook);
+        self
+    }
+
+    /// HTTPS settings, if configured.
+    pub fn tls_config(&self) -> Option<&config::TlsConfig> {
+        self.config.tls.as_ref()
+    }
+
+    /// Build that is running and the GitHub App it acts as.
+    pub fn version(&self) -> version::VersionInfo {
+        version::VersionInfo::new(self.config.app_id)
+    }
+
+    /// Error reporting settings, if configured.
+    pub fn sentry_config(&self) -> Option<&config::SentryConfig> {
+        self.config.sentry.as_ref()
+    }
+
+    /// Path under which all routes are served, if any.
+    pub fn path_prefix(&self) -> Option<&str> {
+        self.config.path_prefix.as_deref()
+    }
+
+    /// Start scheduled garbage collection if `gc_interval_hours` is configured.
+    pub fn start_scheduled_gc(&self) {
+        let Some(hours) = self.config.gc_interval_hours else {
+            if self.config.defaults.retention.is_some()
+                || self.config.repos.values().any(|r| r.retention.is_some())
+            {
+                warn!("retention is configured but gc_interval_hours is not, kept references will not be deleted");
+            }
+            return;
+        };
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            use tokio::time::{interval, Duration};
+
+            let mut ticker = interval(Duration::from_secs(hours.max(1) * 3600));
+            loop {
+                tokio::select! {
+                    _ = state.shutdown.cancelled() => break,
+                    // Followers keep ticking so that they take over on schedule
+                    _ = ticker.tick() => if state.coordinator.is_leader() {
+                        state.gc_installed_repos().await
+                    },
+                }
+            }
+        });
+    }
+
+    /// Periodically fetch the networks GitHub delivers webhook events from if
+    /// `restrict_webhook_sources` is configured.
+    pub fn start_hook_network_refresh(&self) {
+        if !self.config.restrict_webhook_sources {
+            return;
+        }
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            use tokio::time::{sleep, Duration};
+
+            loop {
+                let wait = match state.app_client.hook_networks().await {
+                    Ok(networks) => {
+                        info!("accepting webhook events from {} networks", networks.len());
+                        *state.hook_networks.write().unwrap() = Some(networks);
+                        Duration::from_secs(6 * 3600)
+                    }
+                    Err(e) => {
+                        error!("failed to fetch GitHub webhook networks: {e}");
+                        Duration::from_secs(60)
+                    }
+                };
+                tokio::select! {
+                    _ = state.shutdown.cancelled() => break,
+                    _ = sleep(wait) => (),
+                }
+            }
+        });
+    }
+
+    /// Poll every installed repository every `polling.interval_minutes` if configured, handling
+    /// new and updated pull requests and new reviews as their webhook events would be.
+    pub fn start_polling(&self) {
+        let Some(ref polling) = self.config.polling else {
+            return;
+        };
+
+        let minutes = polling.interval_minutes.max(1);
+        let state = self.clone();
+        tokio::spawn(async move {
+            use tokio::time::{interval, Duration};
+
+            // Reviews submitted before starting cannot be told apart from recorded ones
+            let started = chrono::Utc::now();
+            let mut polled = HashMap::new();
+            let mut ticker = interval(Duration::from_secs(minutes * 60));
+            loop {
+                tokio::select! {
+                    _ = state.shutdown.cancelled() => break,
+                    _ = ticker.tick() => if state.coordinator.is_leader() {
+                        state.poll_installed_repos(&mut polled, started).await
+                    },
+                }
+            }
+        });
+    }
+
+    /// Poll every installed repository, `polled` tracking when the reviews of each pull request
+    /// were last listed.
+    async fn poll_installed_repos(
+        &self,
+        polled: &mut HashMap<String, HashMap<u64, chrono::DateTime<chrono::Utc>>>,
+        started: chrono::DateTime<chrono::Utc>,
+    ) {
+        let clients = match self.app_client.installed_clients().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("polling: failed to list repositories: {e}");
+                return;
+            }
+        };
+
+        for client in clients {
+            let client = self.namespaced(client).triggered_by("poll", None);
+            client.throttle().await;
+            let full_name = client.full_name();
+            let pulls = polled.entry(full_name.clone()).or_default();
+            match self
+                .poll_repo(client, pulls, started)
+                .await
+                .and_then(BatchReport::into_result)
+            {
+                Ok(()) => debug!("polling: {full_name}: ok"),
+                Err(e) => error!("polling: {full_name}: {e}"),
+            }
+        }
+    }
+
+    /// Bring the references of every open pull request of `client` up to date and record their
+    /// reviews submitted since they were last polled, or `started`.
+    async fn poll_repo(
+        &self,
+        client: RepositoryClient,
+        polled: &mut HashMap<u64, chrono::DateTime<chrono::Utc>>,
+        started: chrono::DateTime<chrono::Utc>,
+    ) -> Result<BatchReport, ChetterError> {
+        let config = self.config.repo(&client.full_name()).clone();
+        let pulls = client.open_pulls(None).await?;
+        if let Some(pull) = pulls.first() {
+            self.probes
+                .ensure_writable(&client, &client.full_name(), &pull.head)
+                .await?;
+        }
+
+        // Closed pull requests are left to garbage collection
+        polled.retain(|pr, _| pulls.iter().any(|p| p.number == *pr));
+        let mut report = BatchReport::default();
+        for pull in pulls {
+            client.throttle().await;
+            let until = chrono::Utc::now();
+            let since = polled.get(&pull.number).copied().unwrap_or(started);
+            let work = poll_pr(&client, &pull, &config, since, until);
+            let r = self
+                .coordinator
+                .exclusive(&client.full_name(), pull.number, work)
+                .await
+                .and_then(|r| r);
+            if r.is_ok() {
+                polled.insert(pull.number, until);
+            }
+            report.push(pull.number, r);
+        }
+        Ok(report)
+    }
+
+    /// Take part in electing the replica that runs scheduled jobs, which is always this one unless
+    /// `[redis]` is configured.
+    pub async fn start_leader_election(&self) {
+        self.coordinator.start_election(self.shutdown.clone()).await;
+    }
+
+    /// Check for missed webhook deliveries now and then every `delivery_check_minutes` if
+    /// configured.
+    pub fn start_delivery_recovery(&self) {
+        let Some(minutes) = self.config.delivery_check_minutes else {
+            return;
+        };
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            use tokio::time::{interval, Duration};
+
+            let mut ticker = interval(Duration::from_secs(minutes.max(1) * 60));
+            loop {
+                tokio::select! {
+                    _ = state.shutdown.cancelled() => break,
+                    _ = ticker.tick() => if state.coordinator.is_leader() {
+                        match state.recover_missed_deliveries().await {
+                            Ok(0) => debug!("no missed webhook deliveries"),
+                            Ok(n) => info!("processed {n} missed webhook deliveries"),
+                            Err(e) => error!("failed to check for missed webhook deliveries: {e}"),
+                        }
+                    },
+                }
+            }
+        });
+    }
+
+    /// Fetch the private key and webhook secret from their providers every
+    /// `secret_refresh_minutes` if configured, to pick up rotated credentials.
+    pub fn start_secret_rotation(&self) {
+        let Some(minutes) = self.config.secret_refresh_minutes else {
+            return;
+        };
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            use tokio::time::{interval, Duration};
+
+            let mut ticker = interval(Duration::from_secs(minutes.max(1) * 60));
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = state.shutdown.cancelled() => break,
+                    _ = ticker.tick() => state.rotate_secrets().await,
+                }
+            }
+        });
+    }
+
+    /// Fetch the private key and webhook secret from their providers again, switching to them if
+    /// they changed.
+    ///
+    /// Failures are logged and the current secrets are kept.  Events signed with the webhook
+    /// secret that was replaced are still accepted until it is rotated again, as GitHub may have
+    /// sent them before the webhook was updated.
+    pub async fn rotate_secrets(&self) {
+        let config = self.config.clone();
+        let fetched = tokio::task::spawn_blocking(move || {
+            let private_key = config
+                .private_key
+                .is_provided()
+                .then(|| config.private_key.refetch());
+            let webhook_secret = config
+                .webhook_secret
+                .as_ref()
+                .filter(|s| s.is_provided())
+                .map(Secret::refetch);
+            (private_key, webhook_secret)
+        })
+        .await;
+        let (private_key, webhook_secret) = match fetched {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to fetch secrets: {e}");
+                return;
+            }
+        };
+
+        match private_key {
+            Some(Ok(key)) if key != *self.private_key.read().unwrap() => {
+                match self.app_client.set_private_key(key.expose()) {
+                    Ok(()) => {
+                        info!("private_key was rotated");
+                        *self.private_key.write().unwrap() = key;
+                    }
+                    Err(e) => {
+                        error!("rotated private_key is invalid, keeping the current one: {e}")
+                    }
+                }
+            }
+            Some(Err(e)) => error!("failed to fetch private_key: {e}"),
+            _ => (),
+        }
+
+        match webhook_secret {
+            Some(Ok(secret)) => {
+                let mut secrets = self.webhook_secrets.write().unwrap();
+                if secrets.first() != Some(&secret) {
+                    info!("webhook_secret was rotated");
+                    secrets.insert(0, secret);
+                    secrets.truncate(2);
+                }
+            }
+            Some(Err(e)) => error!("failed to fetch webhook_secret: {e}"),
+            None => (),
+        }
+    }
+
+    /// Process webhook events that GitHub failed to deliver since the last check, returning how
+    /// many were processed.
+    ///
+    /// Deliveries are checked back to the newest one seen by the last check, or a day back if
+    /// there was none.  Events are processed once, failures are logged and not retried.
+    pub async fn recover_missed_deliveries(&self) -> Result<usize, ChetterError> {
+        let since = chrono::Utc::now() - chrono::Duration::hours(deliveries::LOOKBACK_HOURS);
+        let until = self.delivery_cursor.get();
+        let checked = self
+            .app_client
+            .hook_deliveries(since, until.as_deref())
+            .await?;
+
+        let mut processed = 0;
+        for delivery in deliveries::missed(&checked) {
+            // Another replica may be recovering the same delivery
+            if !self.claim_delivery(&delivery.guid).await? {
+                continue;
+            }
+            let r = match self.app_client.hook_delivery(delivery.id).await {
+                Ok(details) => self.replay(&details.into(), false).await,
+                Err(e) => Err(e),
+            };
+            if r.is_err() {
+                self.release_delivery(&delivery.guid).await;
+            }
+            match r {
+                Ok(summary) => {
+                    info!(delivery = delivery.guid, "processed missed {summary}");
+                    processed += 1;
+                }
+                Err(e) => error!(
+                    delivery = delivery.guid,
+                    "failed to process missed delivery: {e}"
+                ),
+            }
+        }
+        if let Some(newest) = checked.first() {
+            self.delivery_cursor.set(&newest.guid);
+        }
+        Ok(processed)
+    }
+
+    /// Whether webhook events are accepted from `ip`, None when the source is unknown.
+    ///
+    /// Until the networks of GitHub have been fetched, events are refused when
+    /// `restrict_webhook_sources` is configured, as are events from unknown sources.
+    pub fn accepts_webhook_source(&self, ip: Option<IpAddr>) -> bool {
+        if !self.config.restrict_webhook_sources {
+            return true;
+        }
+        match (self.hook_networks.read().unwrap().as_deref(), ip) {
+            (Some(networks), Some(ip)) => in_networks(networks, ip),
+            _ => false,
+        }
+    }
+
+    /// Delete expired references of closed pull requests in every installed repository.
+    async fn gc_installed_repos(&self) {
+        let clients = match self.app_client.installed_clients().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("scheduled gc: failed to list repositories: {e}");
+                return;
+            }
+        };
+
+        for client in clients.into_iter().map(|c| self.namespaced(c)) {
+            client.throttle().await;
+            let full_name = client.full_name();
+            let config = self.config.repo(&full_name);
+            match gc_refs(client, grace_period(config), false, &config.layout)
+                .await
+                .and_then(BatchReport::into_result)
+            {
+                Ok(()) => debug!("scheduled gc: {full_name}: ok"),
+                Err(e) => error!("scheduled gc: {full_name}: {e}"),
+            }
+        }
     }
 
     /// Close the application state, giving any background tasks a chance to finish.
     pub async fn close(&self) {
+        self.shutdown.cancel();
         if !self.tasks.is_empty() {
             use tokio::time::{timeout, Duration};
 
@@ -54,181 +683,2125 @@ impl State {
         }
     }
 
-    /// Dispatch GitHub Webhook Events
+    /// Bring the references of every open pull request in `org/repo` up to date.
     ///
-    /// Handles PullRequest and PullRequestReview events, ignores all others.
-    pub async fn webhook_dispatcher(&self, event: WebhookEvent) -> Result<(), ChetterError> {
-        // Early exit to astatevoid making a repo client when not necessary
-        match event.specific {
-            WebhookEventPayload::PullRequest(_) | WebhookEventPayload::PullRequestReview(_) => (),
-            _ => return Ok(()),
+    /// Useful for recovering from missed webhook events.
+    pub async fn resync_repo(&self, org: &str, repo: &str) -> Result<BatchReport, ChetterError> {
+        let client = self.app_client.client_for(org, repo).await?;
+        self.resync_client(client.triggered_by("admin", None)).await
+    }
+
+    /// Record the open pull requests of `org/repo` that were missed, or only pull request `pr`.
+    ///
+    /// Useful for onboarding repositories without waiting for their pull requests to change.
+    pub async fn backfill(
+        &self,
+        org: &str,
+        repo: &str,
+        pr: Option<u64>,
+    ) -> Result<BatchReport, ChetterError> {
+        let client = self
+            .app_client
+            .client_for(org, repo)
+            .await?
+            .triggered_by("cli", None);
+        let Some(pr) = pr else {
+            return self.resync_client(client).await;
+        };
+
+        let client = self.namespaced(client);
+        let config = self.config.repo(&client.full_name()).clone();
+        let pull = client.get_pull(pr).await?;
+        let mut report = BatchReport::default();
+        if !pull.open {
+            report.push(pr, Ok("not open".into()));
+            return Ok(report);
         }
+        self.probes
+            .ensure_writable(&client, &client.full_name(), &pull.head)
+            .await?;
+        report.push(pr, resync_pr(client, &pull, &config).await);
+        Ok(report)
+    }
 
-        let repo_client = self.app_client.repo_client(&event).await?;
-        match event.specific {
-            WebhookEventPayload::PullRequest(payload) => {
-                let span = tracing::span!(
-                    tracing::Level::WARN,
-                    "pr",
-                    repo = repo_client.full_name(),
-                    pr = payload.number
-                );
-                async move { on_pull_request(repo_client, self.tasks.clone(), payload).await }
-                    .instrument(span)
-                    .await?;
-            }
-            WebhookEventPayload::PullRequestReview(payload) => {
-                let Some(reviewer) = payload.review.user.as_ref() else {
-                    let msg = "Missing .review.user";
-                    error!(msg);
-                    return Err(ChetterError::GithubParseError(msg.into()));
-                };
-                let login = reviewer.login.clone();
+    /// Bring the references of every open pull request in the repository of `client` up to date.
+    async fn resync_client(&self, client: RepositoryClient) -> Result<BatchReport, ChetterError> {
+        let client = self.namespaced(client);
+        let config = self.config.repo(&client.full_name()).clone();
 
-                let span = tracing::span!(
-                    tracing::Level::WARN,
-                    "review",
-                    repo = repo_client.full_name(),
-                    pr = payload.pull_request.number,
-                    reviewer = login,
+        let pulls = client.open_pulls(None).await?;
+        if let Some(pull) = pulls.first() {
+            self.probes
+                .ensure_writable(&client, &client.full_name(), &pull.head)
+                .await?;
+        }
+
+        let mut report = BatchReport::default();
+        for pull in pulls {
+            client.throttle().await;
+            let r = resync_pr(client.clone(), &pull, &config).await;
+            report.push(pull.number, r);
+        }
+        Ok(report)
+    }
+
+    /// Delete the references of every closed pull request in `org/repo` whose retention grace
+    /// period has expired.
+    pub async fn gc_repo(&self, org: &str, repo: &str) -> Result<BatchReport, ChetterError> {
+        self.gc_repo_as("admin", org, repo, false).await
+    }
+
+    /// Garbage collect `org/repo` for the `gc` command, only reporting what would be deleted
+    /// with `dry_run`.
+    pub async fn gc_command(
+        &self,
+        org: &str,
+        repo: &str,
+        dry_run: bool,
+    ) -> Result<BatchReport, ChetterError> {
+        self.gc_repo_as("cli", org, repo, dry_run).await
+    }
+
+    async fn gc_repo_as(
+        &self,
+        actor: &str,
+        org: &str,
+        repo: &str,
+        dry_run: bool,
+    ) -> Result<BatchReport, ChetterError> {
+        let client = self
+            .namespaced(self.app_client.client_for(org, repo).await?)
+            .triggered_by(actor, None);
+        let config = self.config.repo(&client.full_name());
+        gc_refs(client, grace_period(config), dry_run, &config.layout).await
+    }
+
+    /// Get the processing state of pull request `pr` in `org/repo`.
+    pub async fn pr_state(&self, org: &str, repo: &str, pr: u64) -> Result<PrState, ChetterError> {
+        let client = self.namespaced(self.app_client.client_for(org, repo).await?);
+        let layout = &self.config.repo(&client.full_name()).layout;
+        let refs = client.matching_refs(&format!("{pr}/")).await?;
+        Ok(PrState {
+            pr,
+            version: last_version(pr, &refs, layout),
+            head: refs
+                .iter()
+                .find(|r| ParsedRef::parse_full(layout, pr, &r.full_name) == ParsedRef::Head)
+                .map(|r| r.sha.clone()),
+            activity: self.tracker.get(&client.full_name(), pr),
+        })
+    }
+
+    /// Get up to `limit` pull requests with recent webhook activity, most recent first.
+    pub fn recent_prs(&self, limit: usize) -> Vec<TrackedPr> {
+        self.tracker.recent(limit)
+    }
+
+    /// Whether anyone may see badges of `org/repo`, because it is public or opted in.
+    pub async fn public_badges(&self, org: &str, repo: &str) -> Result<bool, ChetterError> {
+        if self.config.repo(&format!("{org}/{repo}")).public_badges {
+            return Ok(true);
+        }
+        self.app_client
+            .client_for(org, repo)
+            .await?
+            .is_public()
+            .await
+    }
+
+    /// Get the versions and review bookmarks recorded for pull request `pr` in `org/repo`.
+    pub async fn pr_history(
+        &self,
+        org: &str,
+        repo: &str,
+        pr: u64,
+    ) -> Result<PrHistory, ChetterError> {
+        let client = self.namespaced(self.app_client.client_for(org, repo).await?);
+        let layout = &self.config.repo(&client.full_name()).layout;
+        let refs = client.matching_refs(&format!("{pr}/")).await?;
+        Ok(PrHistory::from_refs(layout, pr, &refs))
+    }
+
+    /// Track suspension of installation `id`, resyncing its repositories when it is unsuspended.
+    fn on_installation(&self, id: u64, action: &InstallationWebhookEventAction) {
+        match action {
+            InstallationWebhookEventAction::Suspend => {
+                info!("installation {id} suspended");
+                self.suspended.lock().unwrap().insert(id);
+            }
+            InstallationWebhookEventAction::Unsuspend => {
+                info!("installation {id} unsuspended");
+                self.suspended.lock().unwrap().remove(&id);
+                let state = self.clone();
+                self.scheduler.spawn(
+                    Priority::Low,
+                    async move { state.resync_installation(id).await },
                 );
-                async move { on_pull_request_review(repo_client, &login, payload).await }
-                    .instrument(span)
-                    .await?;
             }
-            _ => (),
+            _ => debug!("Ignoring installation action: {action:?}"),
         }
-        Ok(())
     }
-}
 
-async fn on_pull_request(
-    repo_client: RepositoryClient,
-    tasks: TaskTracker,
-    payload: Box<PullRequestWebhookEventPayload>,
-) -> Result<(), ChetterError> {
-    match payload.action {
-        PullRequestWebhookEventAction::Synchronize => {
-            let sub_span = tracing::span!(tracing::Level::INFO, "synchronize");
-            async move {
-                synchronize_pr(
-                    repo_client,
-                    payload.number,
-                    &payload.pull_request.head.sha,
-                    &payload.pull_request.base.sha,
-                )
-                .await
+    /// Stop all work on `repo` once it is deleted or archived, as every API call would fail.
+    async fn on_repository(&self, repo: &str, action: &RepositoryWebhookEventAction) {
+        match action {
+            RepositoryWebhookEventAction::Deleted | RepositoryWebhookEventAction::Archived => {
+                info!("{repo}: {action:?}, dropping pending work");
+                self.inflight.cancel_repo(repo).await;
+                self.probes.forget(repo).await;
+                self.tracker.forget_repo(repo);
             }
-            .instrument(sub_span)
-            .await
+            // Renames and transfers are noticed on every event, see on_repository_renamed
+            _ => debug!("Ignoring repository action: {action:?}"),
         }
-        PullRequestWebhookEventAction::Opened | PullRequestWebhookEventAction::Reopened => {
-            let sub_span = tracing::span!(tracing::Level::INFO, "open");
-            async move {
-                open_pr(
-                    repo_client,
-                    payload.number,
-                    &payload.pull_request.head.sha,
-                    &payload.pull_request.base.sha,
-                )
+    }
+
+    /// Carry state kept for `old` over to `new` after the repository was renamed or transferred.
+    ///
+    /// References move along with the repository and GitHub redirects requests made with the old
+    /// name, so work already in flight carries on.
+    async fn on_repository_renamed(&self, old: &str, new: &str) {
+        info!("{old} is now {new}");
+        self.probes.rename(old, new).await;
+        self.tracker.rename_repo(old, new);
+        if self.config.repos.contains_key(old) && !self.config.repos.contains_key(new) {
+            warn!("{new}: settings are still configured for {old}, defaults now apply");
+        }
+    }
+
+    /// Stop all work on repositories removed from the installation, as access to them is revoked.
+    ///
+    /// Their references are left behind, GitHub revokes access before delivering the event so
+    /// there is no opportunity to delete them.
+    async fn on_installation_repositories(
+        &self,
+        payload: &InstallationRepositoriesWebhookEventPayload,
+    ) {
+        if payload.action != InstallationRepositoriesWebhookEventAction::Removed {
+            return;
+        }
+        for full_name in payload.repositories_removed.iter().map(|r| &r.full_name) {
+            info!("{full_name}: removed from the installation, dropping pending work");
+            self.inflight.cancel_repo(full_name).await;
+            self.probes.forget(full_name).await;
+            self.tracker.forget_repo(full_name);
+        }
+    }
+
+    /// Bring every repository of installation `id` up to date after events may have been missed.
+    async fn resync_installation(&self, id: u64) {
+        let clients = match self.app_client.installation_repo_clients(id).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("installation {id}: failed to list repositories: {e}");
+                return;
+            }
+        };
+
+        for client in clients {
+            let full_name = client.full_name();
+            match self
+                .resync_client(client)
                 .await
+                .and_then(BatchReport::into_result)
+            {
+                Ok(()) => info!("installation {id}: {full_name}: resynced"),
+                Err(e) => error!("installation {id}: {full_name}: {e}"),
             }
-            .instrument(sub_span)
-            .await
         }
-        PullRequestWebhookEventAction::Closed => {
-            let sub_span = tracing::span!(tracing::Level::INFO, "close");
+    }
 
-            // We can end up with a lot of references to remove.  We can do that in a single API
-            // call using GraphQL, but it still takes over 10s to delete just 50 references.
-            // Given that, we have no real choice but to run this task in the background and
-            // report success to GitHub before it decides to hang up on us.
-            tasks.spawn(
-                async move { close_pr(repo_client, payload.number).await }.instrument(sub_span),
-            );
-            Ok(())
+    /// Verify that a webhook event `body` was signed with the configured `webhook_secret`, if
+    /// any.  `signature` is the `X-Hub-Signature-256` header of the event.
+    pub fn verify_signature(
+        &self,
+        body: &[u8],
+        signature: Option<&str>,
+    ) -> Result<(), ChetterError> {
+        let secrets = self.webhook_secrets.read().unwrap();
+        let mut verified = Ok(());
+        for secret in secrets.iter() {
+            verified = crypto::verify_signature(secret.expose(), body, signature);
+            if verified.is_ok() {
+                break;
+            }
         }
+        verified
+    }
 
-        _ => {
-            debug!("Ignoring PR action: {:?}", payload.action);
-            Ok(())
+    /// The most recent ping from the webhook, if any was received since starting.
+    pub fn last_ping(&self) -> Option<HookPing> {
+        self.ping.lock().unwrap().clone()
+    }
+
+    /// Record a ping from the webhook, sent when it is created or when redelivered.
+    fn on_ping(&self, payload: &PingWebhookEventPayload) {
+        let hook_id = payload.hook_id.map(|id| id.0);
+        let zen = payload.zen.as_deref().unwrap_or_default();
+        info!("ping from webhook {hook_id:?}: {zen}");
+
+        let signed = self.config.webhook_secret.is_some();
+        if !signed {
+            warn!("webhook_secret is not configured, events are not authenticated");
         }
+        let events = payload
+            .hook
+            .iter()
+            .flat_map(|h| &h.events)
+            .map(action_name)
+            .collect();
+        *self.ping.lock().unwrap() = Some(HookPing {
+            hook_id,
+            events,
+            zen: payload.zen.clone(),
+            signed,
+            received_at: chrono::Utc::now(),
+        });
     }
-}
 
-async fn on_pull_request_review(
-    repo_client: RepositoryClient,
-    reviewer: &str,
-    payload: Box<PullRequestReviewWebhookEventPayload>,
-) -> Result<(), ChetterError> {
-    let Some(ref sha) = payload.review.commit_id else {
-        let msg = "missing .review.commit_id";
-        error!(msg);
-        return Err(ChetterError::GithubParseError(msg.into()));
-    };
+    /// Background work that failed every attempt, oldest first.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.scheduler.dead_letters()
+    }
 
-    match payload.review.state {
-        Some(ReviewState::Approved | ReviewState::ChangesRequested) => {
+    /// Apply the configured reference namespace of the repository to `client`.
+    fn namespaced(&self, client: RepositoryClient) -> RepositoryClient {
+        let config = self.config.repo(&client.full_name());
+        client.with_namespace(config.ref_namespace(), config.migrate_to.as_deref())
+    }
+
+    /// Copy every reference recorded in `org/repo` to the configured migration target.
+    ///
+    /// References missing from the target or pointing elsewhere are created or updated, grouped
+    /// by pull request in the report.  Run once the dual-write mode has been enabled, afterwards
+    /// `namespace` may be switched to the target.
+    pub async fn cutover_repo(&self, org: &str, repo: &str) -> Result<BatchReport, ChetterError> {
+        let client = self
+            .namespaced(self.app_client.client_for(org, repo).await?)
+            .triggered_by("admin", None);
+        let Some(target) = client.migration_target().map(String::from) else {
+            return Err(ChetterError::Config(format!(
+                "{}: migrate_to is not configured",
+                client.full_name()
+            )));
+        };
+
+        let existing: HashMap<String, String> = client
+            .matching_refs_in(&target, "")
+            .await?
+            .into_iter()
+            .map(|r| (r.full_name, r.sha))
+            .collect();
+
+        let mut by_pr: BTreeMap<u64, Vec<Ref>> = BTreeMap::new();
+        for r in client.matching_refs("").await? {
+            if existing.get(&r.full_name) == Some(&r.sha) {
+                continue;
+            }
+            match refname::pr_number(&r.full_name) {
+                Some(pr) => by_pr.entry(pr).or_default().push(r),
+                None => warn!("cutover: skipping {}", r.full_name),
+            }
+        }
+
+        let mut report = BatchReport::default();
+        for (pr, refs) in by_pr {
+            let mut errors: Vec<ChetterError> = vec![];
+            let copied = refs.len();
+            for r in refs {
+                let res = match existing.get(&r.full_name) {
+                    Some(old) => {
+                        client
+                            .update_ref_in(&target, &r.full_name, Some(old), &r.sha)
+                            .await
+                    }
+                    None => client.create_ref_in(&target, &r.full_name, &r.sha).await,
+                };
+                if let Err(e) = res {
+                    errors.push(e);
+                }
+            }
+            let r = match ChetterError::from_errors(errors) {
+                None => Ok(format!("copied {copied} references")),
+                Some(e) => Err(e),
+            };
+            report.push(pr, r);
+        }
+        Ok(report)
+    }
+
+    /// Dispatch GitHub Webhook Events
+    ///
+    /// Handles Ping, Installation, InstallationRepositories, Repository, PullRequest,
+    /// PullRequestReview and Push events, ignores all others.  `delivery` is
+    /// the `X-GitHub-Delivery` id of the event, if known.
+    pub async fn webhook_dispatcher(
+        &self,
+        event: WebhookEvent,
+        delivery: Option<&str>,
+    ) -> Result<(), ChetterError> {
+        let repo = event
+            .repository
+            .as_ref()
+            .and_then(|r| r.full_name.clone())
+            .unwrap_or_default();
+        let name = event_name(&event);
+        let pr = event_pr(&event);
+        let started = Instant::now();
+        let config = self.config.repo(&repo).clone();
+        let sender = self.processed.clone();
+        #[cfg(feature = "notifications")]
+        let (notifier, scheduler) = (self.notifier.clone(), self.scheduler.clone());
+        let (repo_name, event_name, delivery_id) =
+            (repo.clone(), name.clone(), delivery.map(String::from));
+        // Sent once the event and any work it left running in the background finished
+        let report = move |changes: Vec<AuditEntry>, error: Option<String>| {
+            let version =
+                changes
+                    .iter()
+                    .find_map(|c| match Notification::of(c, &config).map(|n| n.kind) {
+                        Some(NotificationKind::Version(n)) => Some(n),
+                        _ => None,
+                    });
+            #[cfg(feature = "metrics")]
+            metrics().observe_event(&repo_name, &event_name, error.is_none());
+            let processed = ProcessedEvent {
+                repo: repo_name,
+                pr,
+                event: event_name,
+                delivery: delivery_id,
+                error,
+                duration_ms: started.elapsed().as_millis() as u64,
+                version,
+                refs: changes.iter().map(ChangedRef::from).collect(),
+            };
+            #[cfg(feature = "notifications")]
+            if let Some(notifier) = notifier {
+                let processed = processed.clone();
+                scheduler.spawn(
+                    Priority::High,
+                    async move { notifier.deliver(&processed).await },
+                );
+            }
+            // Nobody may be subscribed, which is fine
+            let _ = sender.send(processed);
+        };
+        // Changes can only be attributed to deliveries with an id
+        let report = match delivery {
+            Some(delivery) => {
+                self.changes.start(delivery, report);
+                None
+            }
+            None => Some(report),
+        };
+        // Boxed, handling any event makes for a large future that would otherwise be moved around
+        // the stack of whoever awaits this.
+        let r = Box::pin(self.dispatch(event, delivery)).await;
+        if let Err(ref e) = r {
+            error!(repo, event = name, "Failed to process event: {e}");
+        }
+        let error = r.as_ref().err().map(ToString::to_string);
+        if let Some(report) = report {
+            report(vec![], error);
+        } else if let Some(delivery) = delivery {
+            self.changes.release(delivery, error);
+        }
+        r
+    }
+
+    /// Claim webhook delivery `guid`, false if it was already processed by this or another replica.
+    pub async fn claim_delivery(&self, guid: &str) -> Result<bool, ChetterError> {
+        self.coordinator.claim_delivery(guid).await
+    }
+
+    /// Release the claim on webhook delivery `guid` after failing to process it, so that it is
+    /// processed when redelivered.
+    pub async fn release_delivery(&self, guid: &str) {
+        if let Err(e) = self.coordinator.release_delivery(guid).await {
+            warn!(delivery = guid, "Failed to release claim: {e}");
+        }
+    }
+
+    /// Mint an access token for installation `id`, or the installation on `org/repo` if `id` is
+    /// None.
+    pub async fn installation_token(
+        &self,
+        id: Option<u64>,
+        repo: Option<(&str, &str)>,
+    ) -> Result<InstallationToken, ChetterError> {
+        let id = match (id, repo) {
+            (Some(id), _) => id,
+            (None, Some((org, repo))) => self.app_client.installation_for(org, repo).await?,
+            (None, None) => {
+                return Err(ChetterError::Config(
+                    "an installation or repository is required".into(),
+                ))
+            }
+        };
+        self.app_client.installation_token(id).await
+    }
+
+    /// Get the most recent changes to references matching `query`, oldest first.
+    #[cfg(feature = "audit")]
+    pub fn audit_entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, ChetterError> {
+        self.audit.query(query)
+    }
+
+    /// Record a webhook delivery with `headers` and `body` if payload recording is configured.
+    ///
+    /// Failures are logged, recording must not keep the delivery from being processed.
+    pub async fn record_payload(&self, headers: BTreeMap<String, String>, body: &str) {
+        let Some(ref recorder) = self.recorder else {
+            return;
+        };
+        let payload = RecordedPayload {
+            received_at: chrono::Utc::now(),
+            headers,
+            body: body.into(),
+        };
+        if let Err(e) = recorder.record(payload).await {
+            warn!("Failed to record payload: {e}");
+        }
+    }
+
+    /// Read the payload recorded at `path`.
+    pub fn read_payload(&self, path: &Path) -> Result<RecordedPayload, ChetterError> {
+        recorder::read(path, self.envelope.as_ref())
+    }
+
+    /// Process a recorded webhook delivery again, returning its event name and repository.
+    ///
+    /// Its signature was verified when it was recorded and is not checked again.  With `dry_run`
+    /// the payload is only parsed.
+    pub async fn replay(
+        &self,
+        payload: &RecordedPayload,
+        dry_run: bool,
+    ) -> Result<String, ChetterError> {
+        let header = |name: &str| payload.headers.get(name).map(String::as_str);
+        let kind = header("x-github-event").ok_or(ChetterError::GithubParseError(
+            "missing X-GitHub-Event header".into(),
+        ))?;
+        let event = self.parse_event(kind, &payload.body)?;
+        let repo = event
+            .repository
+            .as_ref()
+            .and_then(|r| r.full_name.clone())
+            .unwrap_or_default();
+        let summary = format!("{} {repo}", event_name(&event));
+        if !dry_run {
+            self.webhook_dispatcher(event, header("x-github-delivery"))
+                .await?;
+        }
+        Ok(summary)
+    }
+
+    /// Parse the `body` of a webhook event of type `kind`, such as `pull_request`.
+    pub fn parse_event(&self, kind: &str, body: &str) -> Result<WebhookEvent, ChetterError> {
+        self.app_client.parse_event(kind, body)
+    }
+
+    /// Subscribe to webhook events as they finish processing.
+    pub fn subscribe_processed(&self) -> broadcast::Receiver<ProcessedEvent> {
+        self.processed.subscribe()
+    }
+
+    /// Processed webhook events by repository, event and result.
+    #[cfg(feature = "metrics")]
+    pub fn event_counts(&self) -> Vec<EventCount> {
+        metrics().event_counts()
+    }
+
+    /// Render every metric in the Prometheus text format.
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> String {
+        let age = self.scheduler.oldest_task_age().unwrap_or_default();
+        metrics().oldest_task_age.set(age.as_secs_f64());
+        metrics().leader.set(self.coordinator.is_leader().into());
+        metrics().render()
+    }
+
+    async fn dispatch(
+        &self,
+        event: WebhookEvent,
+        delivery: Option<&str>,
+    ) -> Result<(), ChetterError> {
+        if let WebhookEventPayload::Ping(ref payload) = event.specific {
+            self.on_ping(payload);
+            return Ok(());
+        }
+
+        let installation = github::installation_id(&event);
+        if let Some(r) = event.repository.as_ref() {
+            if let Some(name) = r.full_name.as_ref() {
+                let old = self.repo_names.lock().unwrap().insert(r.id.0, name.clone());
+                if let Some(old) = old.filter(|old| old != name) {
+                    self.on_repository_renamed(&old, name).await;
+                }
+            }
+        }
+
+        match (&event.specific, installation) {
+            (WebhookEventPayload::Installation(payload), Some(id)) => {
+                self.on_installation(id, &payload.action);
+                return Ok(());
+            }
+            (WebhookEventPayload::InstallationRepositories(payload), Some(_)) => {
+                self.on_installation_repositories(payload).await;
+                return Ok(());
+            }
+            (WebhookEventPayload::Repository(payload), _) => {
+                if let Some(name) = event.repository.as_ref().and_then(|r| r.full_name.as_ref()) {
+                    self.on_repository(name, &payload.action).await;
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+        // Every API call would be refused until the installation is unsuspended
+        if installation.is_some_and(|id| self.suspended.lock().unwrap().contains(&id)) {
+            debug!("Ignoring event of suspended installation");
+            return Ok(());
+        }
+        if !self.config.filters.accepts(&EventFacts::of(&event)) {
+            debug!("Ignoring event excluded by filters");
+            return Ok(());
+        }
+
+        let event_config = event
+            .repository
+            .as_ref()
+            .and_then(|r| r.full_name.as_deref())
+            .map_or(&self.config.defaults, |n| self.config.repo(n));
+
+        // Early exit to avoid making a repo client when not necessary
+        let head = match event.specific {
+            // Closing or opting out still cleans up the references of opted out pull requests
+            WebhookEventPayload::PullRequest(ref p)
+                if github::opted_out(&p.pull_request)
+                    && !matches!(
+                        p.action,
+                        PullRequestWebhookEventAction::Closed
+                            | PullRequestWebhookEventAction::Edited
+                    ) =>
+            {
+                debug!("Ignoring event of pull request opted out");
+                return Ok(());
+            }
+            WebhookEventPayload::PullRequestReview(ref p) if github::opted_out(&p.pull_request) => {
+                debug!("Ignoring event of pull request opted out");
+                return Ok(());
+            }
+            WebhookEventPayload::PullRequest(ref p) => Some(p.pull_request.head.sha.clone()),
+            WebhookEventPayload::PullRequestReview(ref p) => Some(p.pull_request.head.sha.clone()),
+            // The pull request is fetched for its head, once there is a client to fetch it with
+            WebhookEventPayload::IssueComment(ref p) if !comment_commands(p).is_empty() => None,
+            // Only force-pushed branches matter, and chetter force-pushes its own references
+            WebhookEventPayload::Push(ref p)
+                if p.forced
+                    && !p.deleted
+                    && p.r#ref.starts_with("refs/heads/")
+                    && !event_config.owns_ref(&p.r#ref) =>
+            {
+                Some(p.after.clone())
+            }
+            _ => return Ok(()),
+        };
+
+        let sender = event
+            .sender
+            .as_ref()
+            .map_or("unknown", |s| s.login.as_str());
+        let repo_client = self
+            .namespaced(self.app_client.client_for_event(&event).await?)
+            .triggered_by(sender, delivery);
+        if let Some(ref head) = head {
+            self.probes
+                .ensure_writable(&repo_client, &repo_client.full_name(), head)
+                .await?;
+        }
+        let config = self.config.repo(&repo_client.full_name()).clone();
+        match event.specific {
+            WebhookEventPayload::PullRequest(payload) => {
+                let span = tracing::span!(
+                    tracing::Level::WARN,
+                    "pr",
+                    repo = repo_client.full_name(),
+                    pr = payload.number
+                );
+                let tracked = self.tracker.start(
+                    &repo_client.full_name(),
+                    payload.number,
+                    delivery,
+                    &format!("pull_request.{}", action_name(&payload.action)),
+                );
+                let repo = repo_client.full_name();
+                let pr = payload.number;
+                let scheduler = self.scheduler.clone();
+                let inflight = self.inflight.clone();
+                let policy = self.policy.clone();
+                let work = async move {
+                    on_pull_request(
+                        repo_client,
+                        &config,
+                        policy,
+                        scheduler,
+                        inflight,
+                        tracked,
+                        payload,
+                    )
+                    .await
+                };
+                let work = self.scheduler.run(work.instrument(span));
+                self.coordinator.exclusive(&repo, pr, work).await??;
+            }
+            WebhookEventPayload::PullRequestReview(payload) => {
+                let Some(reviewer) = payload.review.user.as_ref() else {
+                    let msg = "Missing .review.user";
+                    error!(msg);
+                    return Err(ChetterError::GithubParseError(msg.into()));
+                };
+                let login = reviewer.login.clone();
+
+                let span = tracing::span!(
+                    tracing::Level::WARN,
+                    "review",
+                    repo = repo_client.full_name(),
+                    pr = payload.pull_request.number,
+                    reviewer = login,
+                );
+                let tracked = self.tracker.start(
+                    &repo_client.full_name(),
+                    payload.pull_request.number,
+                    delivery,
+                    &format!("pull_request_review.{}", action_name(&payload.action)),
+                );
+                let repo = repo_client.full_name();
+                let pr = payload.pull_request.number;
+                let policy = self.policy.clone();
+                let layout = config.layout.clone();
+                let work = async move {
+                    let r = match disabled(&repo_client, pr, &layout).await {
+                        Ok(true) => {
+                            debug!("Ignoring review of disabled pull request");
+                            Ok(())
+                        }
+                        Ok(false) => {
+                            policy
+                                .on_review(repo_client, &login, &payload, &config)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    tracked.finish(&r);
+                    r
+                };
+                let work = self.scheduler.run(work.instrument(span));
+                self.coordinator.exclusive(&repo, pr, work).await??;
+            }
+            WebhookEventPayload::IssueComment(payload) => {
+                let commands = comment_commands(&payload);
+                let login = payload.comment.user.login.clone();
+                let author = payload.issue.user.login.clone();
+                let repo = repo_client.full_name();
+                let pr = payload.issue.number;
+                let span = tracing::span!(
+                    tracing::Level::WARN,
+                    "commands",
+                    repo = repo,
+                    pr = pr,
+                    user = login,
+                );
+                let pull = repo_client.get_pull(pr).await?;
+                self.probes
+                    .ensure_writable(&repo_client, &repo, &pull.head)
+                    .await?;
+                let tracked = self.tracker.start(
+                    &repo,
+                    pr,
+                    delivery,
+                    &format!("issue_comment.{}", action_name(&payload.action)),
+                );
+
+                // Disabling deletes every reference, which takes long as when closing.  Commands
+                // change references as pull request events do, so they take turns with those
+                // handled by other replicas.
+                let inflight = self.inflight.clone();
+                let coordinator = self.coordinator.clone();
+                let failed = self.scheduler.clone();
+                self.scheduler.spawn(
+                    Priority::High,
+                    async move {
+                        let work =
+                            on_commands(repo_client, &login, &author, &pull, &commands, &config);
+                        let r = match coordinator
+                            .exclusive(&repo, pr, inflight.run(&repo, pr, work))
+                            .await
+                        {
+                            Ok(r) => r.unwrap_or_else(cancelled),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(ref e) = r {
+                            failed.dead_letter(&repo, pr, "issue_comment.created", e);
+                        }
+                        tracked.finish(&r);
+                    }
+                    .instrument(span),
+                );
+            }
+            WebhookEventPayload::Push(payload) => {
+                let branch = payload.r#ref.trim_start_matches("refs/heads/").to_string();
+                let span = tracing::span!(
+                    tracing::Level::WARN,
+                    "push",
+                    repo = repo_client.full_name(),
+                    branch = branch,
+                );
+                let layout = config.layout.clone();
+                async move { on_base_force_push(repo_client, &branch, &payload.after, &layout).await }
+                    .instrument(span)
+                    .await?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Create the router serving webhook events at `/github/events`, along with the administrative
+/// and HTTP APIs, badges, the dashboard, `/version` and `/metrics`, nested below `path_prefix` if
+/// configured.
+///
+/// Applications embedding chetter-app serve it, or merge it into their own router, with their own
+/// middleware.  Background work, such as garbage collection, is started separately by the
+/// `start_*` methods of [State].
+#[cfg(feature = "server")]
+pub fn router(state: State) -> axum::Router {
+    use axum::{extract, routing::get, Json};
+
+    let mut app = axum::Router::new()
+        .merge(webhook::router(state.clone()))
+        .route(
+            "/version",
+            get(|extract::State(state): extract::State<State>| async move { Json(state.version()) }),
+        )
+        .route(
+            "/metrics",
+            get(|extract::State(state): extract::State<State>| async move { state.render_metrics() }),
+        )
+        .merge(badge::router())
+        .merge(admin::router(state.clone()))
+        .merge(api::router(state.clone()))
+        .merge(dashboard::router(state.clone()));
+    if let Some(prefix) = state.path_prefix() {
+        app = axum::Router::new().nest(prefix, app);
+    }
+    app.with_state(state)
+}
+
+/// Whether `ip` is in any of `networks`, treating IPv4-mapped IPv6 addresses as IPv4.
+fn in_networks(networks: &[IpNet], ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    };
+    networks.iter().any(|n| n.contains(&ip))
+}
+
+/// Outcome of reference updates cancelled because the pull request or repository went away.
+fn cancelled<T: Default>() -> Result<T, ChetterError> {
+    info!("cancelled, the pull request was closed or the repository removed");
+    Ok(T::default())
+}
+
+/// Name and action of a webhook event, such as `pull_request.opened`.
+fn event_name(event: &WebhookEvent) -> String {
+    let kind = action_name(&event.kind);
+    let action = match event.specific {
+        WebhookEventPayload::PullRequest(ref p) => action_name(&p.action),
+        WebhookEventPayload::PullRequestReview(ref p) => action_name(&p.action),
+        WebhookEventPayload::Installation(ref p) => action_name(&p.action),
+        WebhookEventPayload::InstallationRepositories(ref p) => action_name(&p.action),
+        WebhookEventPayload::Repository(ref p) => action_name(&p.action),
+        WebhookEventPayload::IssueComment(ref p) => action_name(&p.action),
+        _ => return kind,
+    };
+    format!("{kind}.{action}")
+}
+
+/// Number of the pull request `event` is about, if any.
+fn event_pr(event: &WebhookEvent) -> Option<u64> {
+    match event.specific {
+        WebhookEventPayload::PullRequest(ref p) => Some(p.number),
+        WebhookEventPayload::PullRequestReview(ref p) => Some(p.pull_request.number),
+        WebhookEventPayload::IssueComment(ref p) if p.issue.pull_request.is_some() => {
+            Some(p.issue.number)
+        }
+        _ => None,
+    }
+}
+
+/// Name of a webhook event action as it appears in the payload.
+fn action_name(action: &impl serde::Serialize) -> String {
+    match serde_json::to_value(action) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => "unknown".into(),
+    }
+}
+
+async fn on_pull_request(
+    repo_client: RepositoryClient,
+    config: &RepoConfig,
+    policy: Arc<dyn Policy<RepositoryClient>>,
+    scheduler: Scheduler,
+    inflight: InFlight,
+    tracked: TrackedEvent,
+    payload: Box<PullRequestWebhookEventPayload>,
+) -> Result<(), ChetterError> {
+    let repo = repo_client.full_name();
+    let pr = payload.number;
+    let r = match payload.action {
+        PullRequestWebhookEventAction::Synchronize => {
+            let sub_span = tracing::span!(tracing::Level::INFO, "synchronize");
+            let work = policy.on_synchronize(repo_client, &payload, config);
+            inflight
+                .run(&repo, pr, work)
+                .instrument(sub_span)
+                .await
+                .unwrap_or_else(cancelled)
+        }
+        PullRequestWebhookEventAction::Reopened => {
+            let sub_span = tracing::span!(tracing::Level::INFO, "reopen");
+            // Retained references are reused and versions continue from the last one.
+            let pull = PullRequestInfo::from(payload.pull_request);
+            inflight
+                .run(&repo, pr, resync_pr(repo_client, &pull, config))
+                .instrument(sub_span)
+                .await
+                .unwrap_or_else(cancelled)
+                .map(|_| ())
+        }
+        PullRequestWebhookEventAction::Opened if config.open_delay_secs > 0 => {
+            let sub_span = tracing::span!(tracing::Level::INFO, "open");
+            let delay = tokio::time::Duration::from_secs(config.open_delay_secs);
+            let config = config.clone();
+            let failed = scheduler.clone();
+            scheduler.spawn_after(
+                delay,
+                Priority::High,
+                async move {
+                    let work = Backoff::BACKGROUND
+                        .retry(|| delayed_open(repo_client.clone(), pr, &config));
+                    let r = inflight
+                        .run(&repo, pr, work)
+                        .await
+                        .unwrap_or_else(cancelled);
+                    if let Err(ref e) = r {
+                        failed.dead_letter(&repo, pr, "pull_request.opened", e);
+                    }
+                    tracked.finish(&r);
+                }
+                .instrument(sub_span),
+            );
+            return Ok(());
+        }
+        PullRequestWebhookEventAction::Opened => {
+            let sub_span = tracing::span!(tracing::Level::INFO, "open");
+            let work = policy.on_open(repo_client, &payload, config);
+            inflight
+                .run(&repo, pr, work)
+                .instrument(sub_span)
+                .await
+                .unwrap_or_else(cancelled)
+        }
+        PullRequestWebhookEventAction::ReviewRequested => {
+            let sub_span = tracing::span!(tracing::Level::INFO, "review_requested");
+            async move { on_review_requested(repo_client, config, &payload).await }
+                .instrument(sub_span)
+                .await
+        }
+        PullRequestWebhookEventAction::Edited if github::opted_out(&payload.pull_request) => {
+            let sub_span = tracing::span!(tracing::Level::INFO, "opt_out");
+
+            // Deleting references takes long, as when closing
+            let stopped = inflight.cancel(&repo, pr);
+            let failed = scheduler.clone();
+            let layout = config.layout.clone();
+            scheduler.spawn(
+                Priority::Low,
+                async move {
+                    stopped.await;
+                    let work =
+                        Backoff::BACKGROUND.retry(|| opt_out_pr(repo_client.clone(), pr, &layout));
+                    let r = inflight
+                        .run(&repo, pr, work)
+                        .await
+                        .unwrap_or_else(cancelled);
+                    if let Err(ref e) = r {
+                        failed.dead_letter(&repo, pr, "pull_request.edited", e);
+                    }
+                    tracked.finish(&r);
+                }
+                .instrument(sub_span),
+            );
+            return Ok(());
+        }
+        PullRequestWebhookEventAction::Closed => {
+            let sub_span = tracing::span!(tracing::Level::INFO, "close");
+            let config = config.clone();
+
+            // We can end up with a lot of references to remove.  We can do that in a single API
+            // call using GraphQL, but it still takes over 10s to delete just 50 references.
+            // Given that, we have no real choice but to run this task in the background and
+            // report success to GitHub before it decides to hang up on us.  Anything still
+            // updating references of the pull request is stopped first so the two don't race,
+            // and the deletion is low priority so that mass closes don't hold up other events.
+            let stopped = inflight.cancel(&repo, pr);
+            let failed = scheduler.clone();
+            scheduler.spawn(
+                Priority::Low,
+                async move {
+                    stopped.await;
+                    let work = Backoff::BACKGROUND
+                        .retry(|| policy.on_close(repo_client.clone(), &payload, &config));
+                    let r = inflight
+                        .run(&repo, pr, work)
+                        .await
+                        .unwrap_or_else(cancelled);
+                    if let Err(ref e) = r {
+                        failed.dead_letter(&repo, pr, "pull_request.closed", e);
+                    }
+                    tracked.finish(&r);
+                }
+                .instrument(sub_span),
+            );
+            return Ok(());
+        }
+
+        _ => {
+            debug!("Ignoring PR action: {:?}", payload.action);
+            Ok(())
+        }
+    };
+    tracked.finish(&r);
+    r
+}
+
+/// Record the head of the pull request for the team or reviewer a review was requested from.
+async fn on_review_requested(
+    repo_client: RepositoryClient,
+    config: &RepoConfig,
+    payload: &PullRequestWebhookEventPayload,
+) -> Result<(), ChetterError> {
+    if disabled(&repo_client, payload.number, &config.layout).await? {
+        debug!("Ignoring review request of disabled pull request");
+        return Ok(());
+    }
+    let name = match (&payload.requested_team, &payload.requested_reviewer) {
+        (Some(team), _) => ParsedRef::TeamHead(refname::escape_login(&team.slug)),
+        (None, Some(user)) if config.ignores_reviewer(&user.login, user.r#type == "Bot") => {
+            debug!("Ignoring review request for {}", user.login);
+            return Ok(());
+        }
+        (None, Some(user)) => ParsedRef::ReviewerRequested(refname::escape_login(&user.login)),
+        (None, None) => {
+            let msg = "missing .requested_team and .requested_reviewer";
+            error!(msg);
+            return Err(ChetterError::GithubParseError(msg.into()));
+        }
+    };
+    set_ref(
+        repo_client,
+        payload.number,
+        name,
+        &payload.pull_request.head.sha,
+        &config.layout,
+    )
+    .await
+}
+
+/// How to handle a review in `state`.
+fn review_policy(config: &RepoConfig, state: Option<ReviewState>) -> ReviewPolicy {
+    match state {
+        Some(ReviewState::Approved) => ReviewPolicy::Record,
+        Some(ReviewState::ChangesRequested) if config.approvals_only => ReviewPolicy::Ignore,
+        Some(ReviewState::ChangesRequested) => ReviewPolicy::Record,
+        Some(ReviewState::Commented) => config.commented_review,
+        Some(ReviewState::Dismissed) => config.dismissed_review,
+        Some(ReviewState::Pending) => config.pending_review,
+        _ => ReviewPolicy::Ignore,
+    }
+}
+
+/// Record, forget or ignore the `review` of pull request `pr`, whose base is `base`, according to
+/// the review policies.
+async fn on_pull_request_review(
+    repo_client: impl RepositoryController,
+    config: &RepoConfig,
+    reviewer: &str,
+    pr: u64,
+    base: &str,
+    review: &Review,
+) -> Result<(), ChetterError> {
+    let Some(ref sha) = review.commit_id else {
+        let msg = "missing .review.commit_id";
+        error!(msg);
+        return Err(ChetterError::GithubParseError(msg.into()));
+    };
+
+    let is_bot = review
+        .user
+        .as_ref()
+        .is_some_and(|u| u.r#type == "Bot" || u.login.ends_with("[bot]"));
+    if config.ignores_reviewer(reviewer, is_bot) {
+        debug!("Ignoring review by {reviewer}");
+        return Ok(());
+    }
+    let reviewer = &refname::escape_login(reviewer);
+
+    match review_policy(config, review.state) {
+        ReviewPolicy::Record => {
+            let key = match config.review_naming {
+                ReviewNaming::Counter => None,
+                ReviewNaming::Id => Some(refname::review_id_key(review.id.into_inner())),
+                ReviewNaming::Timestamp => match review.submitted_at {
+                    Some(t) => Some(refname::review_time_key(t)),
+                    None => {
+                        let msg = "missing .review.submitted_at";
+                        error!(msg);
+                        return Err(ChetterError::GithubParseError(msg.into()));
+                    }
+                },
+            };
             bookmark_pr(
                 repo_client,
-                payload.pull_request.number,
+                pr,
                 reviewer,
+                key.as_deref(),
                 sha,
-                &payload.pull_request.base.sha,
+                base,
+                &config.layout,
             )
             .await
         }
-        _ => Ok(()),
+        ReviewPolicy::Cleanup => forget_reviewer(repo_client, pr, reviewer, &config.layout).await,
+        ReviewPolicy::Ignore => {
+            debug!("Ignoring review state: {:?}", review.state);
+            Ok(())
+        }
+    }
+}
+
+/// Refresh the base references of every open pull request targeting `branch` after it was
+/// force-pushed to `sha`.
+async fn on_base_force_push(
+    client: impl RepositoryController,
+    branch: &str,
+    sha: &str,
+    layout: &RefLayout,
+) -> Result<(), ChetterError> {
+    let mut report = BatchReport::default();
+    for pull in client.open_pulls(Some(branch)).await? {
+        let r = refresh_base(&client, &pull, sha, layout).await;
+        report.push(pull.number, r);
+    }
+    report.into_result()
+}
+
+/// Handle `-base` references that are no longer reachable from the rewritten base branch at
+/// `base_sha`.
+///
+/// Each affected `v<N>-base` gains a `v<N>-newbase` reference to the merge-base of the version and
+/// the new base history, preserving the original.  `head-base` is moved to the merge-base of the
+/// pull request head.
+async fn refresh_base(
+    client: &impl RepositoryController,
+    pull: &PullRequestInfo,
+    base_sha: &str,
+    layout: &RefLayout,
+) -> Result<String, ChetterError> {
+    let pr = pull.number;
+    let refs = client.matching_refs(&format!("{pr}/")).await?;
+    let sha_of = |wanted: &ParsedRef| {
+        refs.iter()
+            .find(|r| ParsedRef::parse_full(layout, pr, &r.full_name) == *wanted)
+            .map(|r| r.sha.clone())
+    };
+
+    let mut errors: Vec<ChetterError> = vec![];
+    let mut refreshed = 0;
+    for r in &refs {
+        let (target, name) = match ParsedRef::parse_full(layout, pr, &r.full_name) {
+            ParsedRef::HeadBase => (Some(pull.head.clone()), ParsedRef::HeadBase),
+            ParsedRef::VersionBase(n) => {
+                (sha_of(&ParsedRef::Version(n)), ParsedRef::VersionNewBase(n))
+            }
+            _ => continue,
+        };
+        let Some(target) = target else {
+            continue;
+        };
+
+        let r = async {
+            if client.compare(&r.sha, base_sha).await?.base_is_ancestor() {
+                return Ok(false);
+            }
+            let merge_base = client.compare(base_sha, &target).await?.merge_base;
+
+            let ref_name = name.full_name(layout, pr);
+            if let Some(old) = sha_of(&name) {
+                client
+                    .update_ref(&ref_name, Some(&old), &merge_base)
+                    .await?;
+            } else {
+                client.create_ref(&ref_name, &merge_base).await?;
+            }
+            Ok(true)
+        };
+        match r.await {
+            Ok(true) => refreshed += 1,
+            Ok(false) => (),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    match ChetterError::from_errors(errors) {
+        Some(e) => Err(e),
+        None if refreshed == 0 => Ok("up to date".into()),
+        None => Ok(format!("refreshed {refreshed} base references")),
+    }
+}
+
+async fn open_pr(
+    client: impl RepositoryController,
+    pr: u64,
+    sha: &str,
+    base: &str,
+    config: &RepoConfig,
+) -> Result<(), ChetterError> {
+    let mut errors: Vec<ChetterError> = vec![];
+
+    let latest = match config.latest_refs {
+        true => vec![(ParsedRef::Latest, sha), (ParsedRef::LatestBase, base)],
+        false => vec![],
+    };
+    let refs: Vec<(String, String)> = [
+        (ParsedRef::Head, sha),
+        (ParsedRef::HeadBase, base),
+        (ParsedRef::Version(1), sha),
+        (ParsedRef::VersionBase(1), base),
+    ]
+    .into_iter()
+    .chain(latest)
+    .map(|(name, target)| (name.full_name(&config.layout, pr), target.to_string()))
+    .collect();
+    if let Err(e) = client.create_refs(&refs).await {
+        errors.push(e);
+    }
+
+    if config.merge_base_refs {
+        if let Err(e) = create_merge_base_ref(&client, pr, 1, base, sha, None, &config.layout).await
+        {
+            errors.push(e);
+        }
+    }
+
+    if errors.is_empty() {
+        if config.commit_status {
+            set_snapshot_status(&client, pr, 1, sha, &config.layout).await;
+        }
+        if let Some(ref event_type) = config.dispatch_event {
+            dispatch_version(&client, event_type, pr, 1, sha, base, config).await;
+        }
+    }
+
+    match ChetterError::from_errors(errors) {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+async fn close_pr<T: RepositoryController + Sync + Send + 'static>(
+    client: T,
+    pr: u64,
+    merged: bool,
+    config: RepoConfig,
+) -> Result<(), ChetterError> {
+    // References pushed by hand below the pull request are left alone.
+    let (mut refs, unknown): (Vec<Ref>, Vec<Ref>) = client
+        .matching_refs(&format!("{}/", pr))
+        .await?
+        .into_iter()
+        .partition(|r| {
+            ParsedRef::parse_full(&config.layout, pr, &r.full_name) != ParsedRef::Unknown
+        });
+    for r in &unknown {
+        warn!("{pr}: not deleting unrecognized reference {}", r.full_name);
+    }
+    let mut errors: Vec<ChetterError> = vec![];
+
+    let retained = match &config.retention {
+        Some(retention) => retained_refs(pr, &refs, retention.keep_versions, &config.layout),
+        None => HashSet::new(),
+    };
+
+    if merged && config.archive_on_merge {
+        // Anything that failed to archive is kept rather than losing the history.
+        let mut archived = vec![];
+        for r in refs {
+            match client.archive_ref(&r).await {
+                Ok(()) => archived.push(r),
+                Err(e) => errors.push(e),
+            }
+        }
+        refs = archived;
+    }
+
+    if !retained.is_empty() {
+        info!(
+            "{pr}: keeping {} references until garbage collected",
+            retained.len()
+        );
+        refs.retain(|r| !retained.contains(&r.full_name));
+    }
+
+    // This runs in the background, by now the pull request may have been reopened.  If the
+    // state cannot be checked the references are left for garbage collection.
+    match client.get_pull(pr).await {
+        Ok(pull) if pull.open => info!("{pr}: reopened, keeping references"),
+        Ok(_) => {
+            if let Err(e) = client.delete_refs(&refs).await {
+                errors.push(e);
+            }
+        }
+        Err(e) => errors.push(e),
+    }
+
+    match ChetterError::from_errors(errors) {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+/// Commands given in a new comment on a pull request, none if it is a comment on an issue or by a
+/// bot.
+fn comment_commands(payload: &IssueCommentWebhookEventPayload) -> Vec<Result<Command, String>> {
+    let user = &payload.comment.user;
+    if payload.action != IssueCommentWebhookEventAction::Created
+        || payload.issue.pull_request.is_none()
+        || user.r#type == "Bot"
+        || user.login.ends_with("[bot]")
+    {
+        return vec![];
+    }
+    Command::parse_all(payload.comment.body.as_deref().unwrap_or_default())
+}
+
+/// Carry out the `commands` that `login` gave in a comment on `pull`, opened by `author`,
+/// replying with the outcome of each.
+async fn on_commands(
+    client: impl RepositoryController + Clone,
+    login: &str,
+    author: &str,
+    pull: &PullRequestInfo,
+    commands: &[Result<Command, String>],
+    config: &RepoConfig,
+) -> Result<(), ChetterError> {
+    let allowed = |c: &Command| {
+        !c.requires_push()
+            || (c.open_to_author() && login == author)
+            || (c.open_to_reviewers() && pull.requested_reviewers.iter().any(|r| r == login))
+    };
+    let pusher = match commands
+        .iter()
+        .any(|c| c.as_ref().is_ok_and(|c| !allowed(c)))
+    {
+        true => client.can_push(login).await?,
+        false => false,
+    };
+
+    let mut errors: Vec<ChetterError> = vec![];
+    let mut replies = vec![];
+    for command in commands {
+        replies.push(match command {
+            Err(e) => e.clone(),
+            Ok(c) if !pull.open => format!("`{c}`: the pull request is closed"),
+            Ok(c) if !allowed(c) && !pusher => format!("`{c}`: only {} may do this", c.allowed()),
+            Ok(c) => match run_command(client.clone(), login, pull, c, config).await {
+                Ok(outcome) => format!("`{c}`: {outcome}"),
+                Err(e) => {
+                    errors.push(e);
+                    format!("`{c}` failed, try again later")
+                }
+            },
+        });
+    }
+
+    let mut body = format!("@{login}\n");
+    for reply in replies {
+        // Indented to keep code blocks in the list item
+        body.push_str(&format!("\n- {}", reply.replace('\n', "\n  ")));
+    }
+    if commands.iter().any(Result::is_err) {
+        body.push_str(&format!("\n\nCommands are:\n{}", commands::USAGE));
+    }
+    if let Err(e) = client.post_comment(pull.number, &body).await {
+        errors.push(e);
+    }
+
+    match ChetterError::from_errors(errors) {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+/// Carry out `command`, given by `login`, on `pull`, describing the outcome.
+async fn run_command(
+    client: impl RepositoryController,
+    login: &str,
+    pull: &PullRequestInfo,
+    command: &Command,
+    config: &RepoConfig,
+) -> Result<String, ChetterError> {
+    match command {
+        Command::Disable => disable_pr(client, pull, &config.layout).await,
+        Command::Enable => enable_pr(client, pull, config).await,
+        Command::Snapshot(name) => snapshot_pr(client, pull, name, config).await,
+        Command::Diff(from, to) => diff_pr(client, pull.number, from, to, config).await,
+        Command::Subscribe => subscribe_pr(client, login, pull, &config.layout).await,
+        Command::Unsubscribe => unsubscribe_pr(client, login, pull.number, &config.layout).await,
+    }
+}
+
+/// Move the `<login>-head` reference of `pull` to its head now and to every new version from now
+/// on, until `login` unsubscribes.
+async fn subscribe_pr(
+    client: impl RepositoryController,
+    login: &str,
+    pull: &PullRequestInfo,
+    layout: &RefLayout,
+) -> Result<String, ChetterError> {
+    let pr = pull.number;
+    let reviewer = refname::escape_login(login);
+    let refs = client.matching_refs(&format!("{pr}/")).await?;
+    if disabled_in(pr, &refs, layout) {
+        return Ok("recording is disabled, `/chetter enable` it first".into());
+    }
+    let marker = ParsedRef::ReviewerSubscribed(reviewer.clone());
+    if refs
+        .iter()
+        .any(|r| ParsedRef::parse_full(layout, pr, &r.full_name) == marker)
+    {
+        return Ok("already subscribed".into());
+    }
+
+    client
+        .create_ref(&marker.full_name(layout, pr), &pull.head)
+        .await?;
+    for (name, target) in [
+        (ParsedRef::ReviewerHead(reviewer.clone()), &pull.head),
+        (ParsedRef::ReviewerHeadBase(reviewer.clone()), &pull.base),
+    ] {
+        move_ref(&client, pr, &refs, name, target, layout).await?;
+    }
+    info!("{pr}: {login} subscribed");
+    Ok(format!(
+        "recorded the head as `{}`, which now follows every new version",
+        ParsedRef::ReviewerHead(reviewer).full_name(layout, pr)
+    ))
+}
+
+/// Stop moving the `<login>-head` reference of pull request `pr` to new versions.
+async fn unsubscribe_pr(
+    client: impl RepositoryController,
+    login: &str,
+    pr: u64,
+    layout: &RefLayout,
+) -> Result<String, ChetterError> {
+    let marker = ParsedRef::ReviewerSubscribed(refname::escape_login(login)).full_name(layout, pr);
+    if client.get_ref(&marker).await?.is_none() {
+        return Ok("not subscribed".into());
+    }
+    client.delete_ref(&marker).await?;
+    info!("{pr}: {login} unsubscribed");
+    Ok("new versions are no longer followed".into())
+}
+
+/// Link to the changes of pull request `pr` between the recorded versions `from` and `to`, such as
+/// `v1` and `v3`, and show how to compare them locally.
+async fn diff_pr(
+    client: impl RepositoryController,
+    pr: u64,
+    from: &str,
+    to: &str,
+    config: &RepoConfig,
+) -> Result<String, ChetterError> {
+    let recorded: HashSet<String> = client
+        .matching_refs(&format!("{pr}/"))
+        .await?
+        .into_iter()
+        .map(|r| r.full_name)
+        .collect();
+
+    let ((from_head, from_base), (to_head, to_base)) =
+        match [from, to].map(|given| recorded_version(pr, given, &recorded, &config.layout)) {
+            [Ok(from), Ok(to)] => (from, to),
+            [Err(e), _] | [_, Err(e)] => return Ok(e),
+        };
+
+    let ns = config.ref_namespace();
+    Ok(formatdoc!(
+        r#"
+        [compare on GitHub]({url})
+
+        ```
+        git fetch origin {fetch}
+        git range-diff {from_range} {to_range}
+        ```"#,
+        url = client.compare_url(&from_head, &to_head),
+        fetch = [&from_head, &from_base, &to_head, &to_base]
+            .map(|name| fetch_refspec(ns, name))
+            .join(" "),
+        from_range = tracking_range(&from_base, &from_head),
+        to_range = tracking_range(&to_base, &to_head),
+    ))
+}
+
+/// Refspec fetching reference `name` of namespace `ns` to a remote tracking reference.
+///
+/// Only references below `refs/heads/` have remote tracking references by default, the refspec
+/// names one explicitly so that the commands work whatever the namespace.
+fn fetch_refspec(ns: &str, name: &str) -> String {
+    format!("+{ns}/{name}:refs/remotes/origin/chetter/{name}")
+}
+
+/// Range of the remote tracking references, see [fetch_refspec], from `base` to `head`.
+fn tracking_range(base: &str, head: &str) -> String {
+    format!("origin/chetter/{base}..origin/chetter/{head}")
+}
+
+/// Names of the head and base references of version `given` of pull request `pr`, or why it
+/// cannot be compared.
+fn recorded_version(
+    pr: u64,
+    given: &str,
+    recorded: &HashSet<String>,
+    layout: &RefLayout,
+) -> Result<(String, String), String> {
+    // Snapshots may be given by name alone
+    let head = match ParsedRef::parse(given) {
+        ParsedRef::Unknown if refname::valid_snapshot(layout, pr, given) => {
+            ParsedRef::Snapshot(given.into())
+        }
+        parsed => parsed,
+    };
+    let base = head.base().ok_or_else(|| {
+        format!("`{given}` is not a version, use one such as `v2`, `head` or a snapshot")
+    })?;
+    let (head, base) = (head.full_name(layout, pr), base.full_name(layout, pr));
+    match recorded.contains(&head) && recorded.contains(&base) {
+        true => Ok((head, base)),
+        false => Err(format!("`{given}` was not recorded")),
+    }
+}
+
+/// Record the head and base of `pull` as snapshot `name`, which is never replaced.
+async fn snapshot_pr(
+    client: impl RepositoryController,
+    pull: &PullRequestInfo,
+    name: &str,
+    config: &RepoConfig,
+) -> Result<String, ChetterError> {
+    let pr = pull.number;
+    if !refname::valid_snapshot(&config.layout, pr, name) {
+        return Ok(format!(
+            "`{name}` cannot name a snapshot, use up to 64 letters, digits, `.`, `_` and `-`, \
+             not looking like `v2` or ending in `-base`"
+        ));
+    }
+    let snapshot = ParsedRef::Snapshot(name.into());
+    if client
+        .get_ref(&snapshot.full_name(&config.layout, pr))
+        .await?
+        .is_some()
+    {
+        return Ok(format!("`{}` already exists", snapshot.name()));
+    }
+    if disabled(&client, pr, &config.layout).await? {
+        return Ok("recording is disabled, `/chetter enable` it first".into());
+    }
+
+    client
+        .create_refs(&[
+            (snapshot.full_name(&config.layout, pr), pull.head.clone()),
+            (
+                ParsedRef::SnapshotBase(name.into()).full_name(&config.layout, pr),
+                pull.base.clone(),
+            ),
+        ])
+        .await?;
+    info!("{pr}: snapshot {name} of {}", pull.head);
+    Ok(format!(
+        "recorded {} as `{}/{}`",
+        pull.head,
+        config.ref_namespace(),
+        snapshot.full_name(&config.layout, pr)
+    ))
+}
+
+/// Whether recording pull request `pr` was disabled with `/chetter disable`.
+async fn disabled(
+    client: &impl RepositoryController,
+    pr: u64,
+    layout: &RefLayout,
+) -> Result<bool, ChetterError> {
+    Ok(client
+        .get_ref(&ParsedRef::Disabled.full_name(layout, pr))
+        .await?
+        .is_some())
+}
+
+/// Whether `refs` of pull request `pr` mark it as disabled.
+fn disabled_in(pr: u64, refs: &[Ref], layout: &RefLayout) -> bool {
+    refs.iter()
+        .any(|r| ParsedRef::parse_full(layout, pr, &r.full_name) == ParsedRef::Disabled)
+}
+
+/// Delete the references of `pull` and stop recording it until it is enabled again.
+async fn disable_pr(
+    client: impl RepositoryController,
+    pull: &PullRequestInfo,
+    layout: &RefLayout,
+) -> Result<String, ChetterError> {
+    let pr = pull.number;
+    let refs = client.matching_refs(&format!("{pr}/")).await?;
+    if disabled_in(pr, &refs, layout) {
+        return Ok("already disabled".into());
+    }
+
+    // Marked first so that nothing is recorded while deleting.  As when closing, references
+    // pushed by hand are left alone.
+    client
+        .create_ref(&ParsedRef::Disabled.full_name(layout, pr), &pull.head)
+        .await?;
+    let refs: Vec<Ref> = refs
+        .into_iter()
+        .filter(|r| ParsedRef::parse_full(layout, pr, &r.full_name) != ParsedRef::Unknown)
+        .collect();
+    if !refs.is_empty() {
+        client.delete_refs(&refs).await?;
+    }
+    info!("{pr}: disabled, deleted {} references", refs.len());
+    Ok(format!(
+        "deleted {} references, new versions are not recorded until `/chetter enable`",
+        refs.len()
+    ))
+}
+
+/// Record `pull` again after it was disabled, starting over from `v1`.
+async fn enable_pr(
+    client: impl RepositoryController,
+    pull: &PullRequestInfo,
+    config: &RepoConfig,
+) -> Result<String, ChetterError> {
+    let marker = ParsedRef::Disabled.full_name(&config.layout, pull.number);
+    if client.get_ref(&marker).await?.is_none() {
+        return Ok("already enabled".into());
+    }
+    client.delete_ref(&marker).await?;
+    if pull.opted_out {
+        return Ok("enabled, but the title or body still opts out of chetter-app".into());
+    }
+    resync_pr(client, pull, config).await?;
+    info!("{}: enabled", pull.number);
+    Ok("recorded the head as `v1`".into())
+}
+
+/// Delete the references of pull request `pr` once its author opted out of chetter-app.
+async fn opt_out_pr(
+    client: impl RepositoryController,
+    pr: u64,
+    layout: &RefLayout,
+) -> Result<(), ChetterError> {
+    // As when closing, references pushed by hand are left alone
+    let refs: Vec<Ref> = client
+        .matching_refs(&format!("{pr}/"))
+        .await?
+        .into_iter()
+        .filter(|r| ParsedRef::parse_full(layout, pr, &r.full_name) != ParsedRef::Unknown)
+        .collect();
+    if refs.is_empty() {
+        return Ok(());
+    }
+
+    // This runs in the background, by now the marker may have been removed again
+    if !client.get_pull(pr).await?.opted_out {
+        info!("{pr}: opted back in, keeping references");
+        return Ok(());
+    }
+    info!("{pr}: opted out, deleting {} references", refs.len());
+    client.delete_refs(&refs).await
+}
+
+/// Names of the references kept after pull request `pr` is closed: `head`, `latest`, snapshots,
+/// their bases and those of the last `keep_versions` versions.
+fn retained_refs(pr: u64, refs: &[Ref], keep_versions: u32, layout: &RefLayout) -> HashSet<String> {
+    let last_version = last_version(pr, refs, layout);
+
+    refs.iter()
+        .filter(|r| match ParsedRef::parse_full(layout, pr, &r.full_name) {
+            ParsedRef::Head
+            | ParsedRef::HeadBase
+            | ParsedRef::Latest
+            | ParsedRef::LatestBase
+            | ParsedRef::Snapshot(_)
+            | ParsedRef::SnapshotBase(_) => true,
+            parsed => parsed
+                .version()
+                .is_some_and(|v| v + keep_versions > last_version),
+        })
+        .map(|r| r.full_name.clone())
+        .collect()
+}
+
+/// Latest version of pull request `pr` recorded by `refs`, 0 if there are none.
+fn last_version(pr: u64, refs: &[Ref], layout: &RefLayout) -> u32 {
+    refs.iter()
+        .filter_map(|r| match ParsedRef::parse_full(layout, pr, &r.full_name) {
+            ParsedRef::Version(n) => Some(n),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+async fn synchronize_pr(
+    client: impl RepositoryController,
+    pr: u64,
+    sha: &str,
+    base: &str,
+    config: &RepoConfig,
+) -> Result<(), ChetterError> {
+    let refs = client.matching_refs(&format!("{}/", pr)).await?;
+    if disabled_in(pr, &refs, &config.layout) {
+        info!("{pr}: disabled, not recording {sha}");
+        return Ok(());
+    }
+    let mut errors: Vec<ChetterError> = vec![];
+
+    for (name, target) in [(ParsedRef::Head, sha), (ParsedRef::HeadBase, base)] {
+        if let Err(e) = move_ref(&client, pr, &refs, name, target, &config.layout).await {
+            errors.push(e);
+        }
+    }
+
+    // Subscribers follow every version, whether they reviewed it or not
+    for r in &refs {
+        let ParsedRef::ReviewerSubscribed(reviewer) =
+            ParsedRef::parse_full(&config.layout, pr, &r.full_name)
+        else {
+            continue;
+        };
+        for (name, target) in [
+            (ParsedRef::ReviewerHead(reviewer.clone()), sha),
+            (ParsedRef::ReviewerHeadBase(reviewer), base),
+        ] {
+            if let Err(e) = move_ref(&client, pr, &refs, name, target, &config.layout).await {
+                errors.push(e);
+            }
+        }
+    }
+
+    let last_version = last_version(pr, &refs, &config.layout);
+    let next_ref = last_version + 1;
+
+    // Changes introduced by the previous and new versions, only fetched when needed
+    let comparisons = if config.rebase != RebaseMode::Off || config.range_diff_comment {
+        let find = |name: ParsedRef| {
+            refs.iter()
+                .find(|t| t.full_name == name.full_name(&config.layout, pr))
+        };
+        match (
+            find(ParsedRef::Version(last_version)),
+            find(ParsedRef::VersionBase(last_version)),
+        ) {
+            (Some(prev), Some(prev_base)) => {
+                compare_versions(&client, &prev_base.sha, &prev.sha, base, sha).await
+            }
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    let rebased = config.rebase != RebaseMode::Off
+        && comparisons
+            .as_ref()
+            .is_some_and(|(prev, cur)| same_changes(prev, cur));
+
+    if rebased && config.rebase == RebaseMode::Skip {
+        info!("skipping v{next_ref}, rebase of v{last_version}");
+    } else {
+        let mut created = vec![
+            (
+                ParsedRef::Version(next_ref).full_name(&config.layout, pr),
+                sha.to_string(),
+            ),
+            (
+                ParsedRef::VersionBase(next_ref).full_name(&config.layout, pr),
+                base.to_string(),
+            ),
+        ];
+        if rebased {
+            created.push((
+                ParsedRef::VersionRebase(next_ref).full_name(&config.layout, pr),
+                sha.into(),
+            ));
+        }
+        if let Err(e) = client.create_refs(&created).await {
+            errors.push(e);
+        }
+
+        if config.latest_refs {
+            for (name, target) in [(ParsedRef::Latest, sha), (ParsedRef::LatestBase, base)] {
+                if let Err(e) = move_ref(&client, pr, &refs, name, target, &config.layout).await {
+                    errors.push(e);
+                }
+            }
+        }
+
+        if config.merge_base_refs {
+            let known = comparisons.as_ref().map(|(_, cur)| cur);
+            if let Err(e) =
+                create_merge_base_ref(&client, pr, next_ref, base, sha, known, &config.layout).await
+            {
+                errors.push(e);
+            }
+        }
+
+        if errors.is_empty() {
+            announce_version(&client, pr, next_ref, config).await;
+            if config.commit_status {
+                set_snapshot_status(&client, pr, next_ref, sha, &config.layout).await;
+            }
+            if let (true, Some((prev, cur))) = (config.range_diff_comment, &comparisons) {
+                post_range_diff(&client, pr, next_ref, prev, cur).await;
+            }
+            if let Some(ref event_type) = config.dispatch_event {
+                dispatch_version(&client, event_type, pr, next_ref, sha, base, config).await;
+            }
+        }
+    }
+
+    match ChetterError::from_errors(errors) {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+/// Point `name` at `target`, updating it if it is one of the `existing` references of `pr` and
+/// creating it otherwise.
+async fn move_ref(
+    client: &impl RepositoryController,
+    pr: u64,
+    existing: &[Ref],
+    name: ParsedRef,
+    target: &str,
+    layout: &RefLayout,
+) -> Result<(), ChetterError> {
+    let full_name = name.full_name(layout, pr);
+    match existing
+        .iter()
+        .find(|t| ParsedRef::parse_full(layout, pr, &t.full_name) == name)
+    {
+        Some(old) => client.update_ref(&full_name, Some(&old.sha), target).await,
+        None => client.create_ref(&full_name, target).await,
+    }
+}
+
+/// Record the merge-base of `sha` and `base` as `v<version>-mergebase`.
+///
+/// `known` is a comparison of `base` and `sha` that has already been fetched, if any.
+async fn create_merge_base_ref(
+    client: &impl RepositoryController,
+    pr: u64,
+    version: u32,
+    base: &str,
+    sha: &str,
+    known: Option<&Comparison>,
+    layout: &RefLayout,
+) -> Result<(), ChetterError> {
+    let merge_base = match known {
+        Some(comparison) => comparison.merge_base.clone(),
+        None => client.compare(base, sha).await?.merge_base,
+    };
+    client
+        .create_ref(
+            &ParsedRef::VersionMergeBase(version).full_name(layout, pr),
+            &merge_base,
+        )
+        .await
+}
+
+/// Commit status context used to report recorded versions.
+const SNAPSHOT_STATUS_CONTEXT: &str = "chetter/snapshot";
+
+/// Set a commit status on `sha` linking to the changes since the prior version.
+///
+/// Failures are only logged, the version has already been recorded.
+async fn set_snapshot_status(
+    client: &impl RepositoryController,
+    pr: u64,
+    version: u32,
+    sha: &str,
+    layout: &RefLayout,
+) {
+    let target_url = if version > 1 {
+        client.compare_url(
+            &ParsedRef::Version(version - 1).full_name(layout, pr),
+            &ParsedRef::Version(version).full_name(layout, pr),
+        )
+    } else {
+        client.compare_url(
+            &ParsedRef::VersionBase(1).full_name(layout, pr),
+            &ParsedRef::Version(1).full_name(layout, pr),
+        )
+    };
+
+    if let Err(e) = client
+        .set_status(
+            sha,
+            SNAPSHOT_STATUS_CONTEXT,
+            &format!("Recorded as v{version}"),
+            &target_url,
+        )
+        .await
+    {
+        warn!("Failed to set status for v{version}: {e}");
+    }
+}
+
+/// Fire a `repository_dispatch` event of `event_type` describing the newly created `version` of
+/// `sha` on `base`.
+///
+/// Failures are only logged, the version has already been recorded.
+async fn dispatch_version(
+    client: &impl RepositoryController,
+    event_type: &str,
+    pr: u64,
+    version: u32,
+    sha: &str,
+    base: &str,
+    config: &RepoConfig,
+) {
+    let ns = config.ref_namespace();
+    let payload = serde_json::json!({
+        "pr": pr,
+        "version": version,
+        "sha": sha,
+        "base_sha": base,
+        "ref": format!("{ns}/{}", ParsedRef::Version(version).full_name(&config.layout, pr)),
+        "base_ref": format!("{ns}/{}", ParsedRef::VersionBase(version).full_name(&config.layout, pr)),
+    });
+    if let Err(e) = client.repository_dispatch(event_type, &payload).await {
+        warn!("Failed to dispatch {event_type} for v{version}: {e}");
+    }
+}
+
+/// Marker identifying the comment maintained with `CommentMode::Update`.
+const VERSION_COMMENT_MARKER: &str = "<!-- chetter:version -->";
+
+/// Comment on the pull request describing the newly created `version`.
+///
+/// Failures are only logged, the version has already been recorded.
+async fn announce_version(
+    client: &impl RepositoryController,
+    pr: u64,
+    version: u32,
+    config: &RepoConfig,
+) {
+    let body = version_comment(config.ref_namespace(), pr, version, &config.layout);
+    let r = match config.version_comment {
+        CommentMode::Off => return,
+        CommentMode::Post => client.post_comment(pr, &body).await,
+        CommentMode::Update => {
+            client
+                .upsert_comment(pr, VERSION_COMMENT_MARKER, &body)
+                .await
+        }
+    };
+    if let Err(e) = r {
+        warn!("Failed to comment on v{version}: {e}");
+    }
+}
+
+fn version_comment(ns: &str, pr: u64, version: u32, layout: &RefLayout) -> String {
+    let cur = ParsedRef::Version(version).full_name(layout, pr);
+    let cur_base = ParsedRef::VersionBase(version).full_name(layout, pr);
+
+    let mut body = formatdoc!(
+        r#"
+        {VERSION_COMMENT_MARKER}
+        Recorded version **v{version}** of this pull request as `{ns}/{cur}`.
+
+        ```
+        git fetch origin {} {}
+        ```
+        "#,
+        fetch_refspec(ns, &cur),
+        fetch_refspec(ns, &cur_base),
+    );
+
+    if version > 1 {
+        let prev = ParsedRef::Version(version - 1).full_name(layout, pr);
+        let prev_base = ParsedRef::VersionBase(version - 1).full_name(layout, pr);
+        body.push_str(&formatdoc!(
+            r#"
+
+            Changes since v{prev_version}:
+            ```
+            git fetch origin {} {}
+            git range-diff {} {}
+            ```
+            "#,
+            fetch_refspec(ns, &prev),
+            fetch_refspec(ns, &prev_base),
+            tracking_range(&prev_base, &prev),
+            tracking_range(&cur_base, &cur),
+            prev_version = version - 1,
+        ));
+    }
+    body
+}
+
+/// Get the changes introduced by the previous version, `prev_base..prev_head`, and by the new
+/// version, `base..head`.
+///
+/// Failure to compare is only logged as the comparisons are not required to record a version.
+async fn compare_versions(
+    client: &impl RepositoryController,
+    prev_base: &str,
+    prev_head: &str,
+    base: &str,
+    head: &str,
+) -> Option<(Comparison, Comparison)> {
+    match (
+        client.compare(prev_base, prev_head).await,
+        client.compare(base, head).await,
+    ) {
+        (Ok(prev), Ok(cur)) => Some((prev, cur)),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Failed to compare versions: {e}");
+            None
+        }
     }
 }
 
-async fn open_pr(
-    client: impl RepositoryController,
+/// Determine if two comparisons introduce the same changes, regardless of their base.
+fn same_changes(prev: &Comparison, cur: &Comparison) -> bool {
+    match (patch_id(prev), patch_id(cur)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Comment on the pull request with the differences between `version` and the version before it.
+///
+/// Failures are only logged, the version has already been recorded.
+async fn post_range_diff(
+    client: &impl RepositoryController,
     pr: u64,
-    sha: &str,
-    base: &str,
-) -> Result<(), ChetterError> {
-    let mut errors: Vec<ChetterError> = vec![];
+    version: u32,
+    prev: &Comparison,
+    cur: &Comparison,
+) {
+    let Some(diff) = rangediff::render(prev, cur) else {
+        debug!("v{version} has the same changes as v{}", version - 1);
+        return;
+    };
 
-    for ref_name in ["head", "v1"] {
-        for (suffix, target) in [("", sha), ("-base", base)] {
-            if let Err(e) = client
-                .create_ref(&format!("{}/{}{}", pr, ref_name, suffix), target)
-                .await
-            {
-                errors.push(e);
-            }
-        }
-    }
+    let body = formatdoc!(
+        r#"
+        <details>
+        <summary>Changes from v{prev_version} to v{version}</summary>
 
-    match errors.pop() {
-        None => Ok(()),
-        Some(e) => Err(e),
+        ```diff
+        {diff}
+        ```
+        </details>
+        "#,
+        prev_version = version - 1,
+    );
+    if let Err(e) = client.post_comment(pr, &body).await {
+        warn!("Failed to post range-diff for v{version}: {e}");
     }
 }
 
-async fn close_pr<T: RepositoryController + Sync + Send + 'static>(
-    client: T,
-    pr: u64,
-) -> Result<(), ChetterError> {
-    let refs = client.matching_refs(&format!("{}/", pr)).await?;
-    client.delete_refs(&refs).await?;
-    Ok(())
+/// Compute an identifier for the changes in a comparison, similar to `git patch-id`.
+///
+/// Only added and removed lines contribute, with whitespace ignored, so the same changes applied
+/// to a different base produce the same id.  Returns None if any file is missing a patch as the
+/// changes cannot be fully known.
+fn patch_id(comparison: &Comparison) -> Option<u64> {
+    let mut files: Vec<_> = comparison.files.iter().collect();
+    files.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for file in files {
+        file.filename.hash(&mut hasher);
+        for line in file.patch.as_ref()?.lines() {
+            if line.starts_with('+') || line.starts_with('-') {
+                line.split_whitespace().for_each(|w| w.hash(&mut hasher));
+            }
+        }
+    }
+    Some(hasher.finish())
 }
 
-async fn synchronize_pr(
+/// Bookmark a review of `pr` by `reviewer` of `sha` on top of `base`.
+///
+/// The review is named `review` if set, otherwise it is numbered after the reviewer's prior
+/// reviews.
+async fn bookmark_pr(
     client: impl RepositoryController,
     pr: u64,
+    reviewer: &str,
+    review: Option<&str>,
     sha: &str,
     base: &str,
+    layout: &RefLayout,
 ) -> Result<(), ChetterError> {
-    let refs = client.matching_refs(&format!("{}/", pr)).await?;
+    // Named reviews look up the few references they touch, counted reviews need every version
+    // to find the next one.
+    let refs: Option<Vec<(ParsedRef, String)>> = match review {
+        Some(_) => None,
+        None => Some(
+            client
+                .matching_refs(&format!("{}/", pr))
+                .await?
+                .into_iter()
+                .map(|r| (ParsedRef::parse_full(layout, pr, &r.full_name), r.sha))
+                .filter(|(r, _)| r.reviewer() == Some(reviewer))
+                .collect(),
+        ),
+    };
+
     let mut errors: Vec<ChetterError> = vec![];
 
-    for (name, target) in [("head", sha), ("head-base", base)] {
-        let name = format!("{pr}/{name}");
-        if refs.iter().any(|t| t.full_name.ends_with(&name)) {
-            if let Err(e) = client.update_ref(&name, target).await {
+    for (name, target) in [
+        (ParsedRef::ReviewerHead(reviewer.into()), sha),
+        (ParsedRef::ReviewerHeadBase(reviewer.into()), base),
+    ] {
+        let old = match existing_sha(&client, pr, refs.as_deref(), &name, layout).await {
+            Ok(old) => old,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        let name = name.full_name(layout, pr);
+        if let Some(old) = old {
+            if let Err(e) = client.update_ref(&name, Some(&old), target).await {
                 errors.push(e);
             }
         } else if let Err(e) = client.create_ref(&name, target).await {
@@ -236,76 +2809,254 @@ async fn synchronize_pr(
         }
     }
 
-    let next_ref = if refs.is_empty() {
-        1
-    } else {
-        let last_version: u32 = refs
-            .iter()
-            .filter_map(|t| t.full_name.split('v').last()?.parse::<u32>().ok())
-            .max()
-            .unwrap_or(0);
-        last_version + 1
+    let bookmarks = match review {
+        Some(key) => [
+            ParsedRef::ReviewerReview(reviewer.into(), key.into()),
+            ParsedRef::ReviewerReviewBase(reviewer.into(), key.into()),
+        ],
+        None => {
+            let last_version = refs
+                .iter()
+                .flatten()
+                .filter_map(|(r, _)| match r {
+                    ParsedRef::ReviewerVersion(_, n) => Some(*n),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+            let next_ref = last_version + 1;
+            [
+                ParsedRef::ReviewerVersion(reviewer.into(), next_ref),
+                ParsedRef::ReviewerVersionBase(reviewer.into(), next_ref),
+            ]
+        }
     };
 
-    for (suffix, target) in [("", sha), ("-base", base)] {
-        let name = format!("{pr}/v{next_ref}{suffix}");
-        if let Err(e) = client.create_ref(&name, target).await {
+    for (name, target) in bookmarks.into_iter().zip([sha, base]) {
+        // Named reviews are already bookmarked when the event is redelivered
+        match existing_sha(&client, pr, refs.as_deref(), &name, layout).await {
+            Ok(None) => (),
+            Ok(Some(_)) => continue,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        }
+        if let Err(e) = client.create_ref(&name.full_name(layout, pr), target).await {
             errors.push(e);
         }
     }
 
-    match errors.pop() {
+    match ChetterError::from_errors(errors) {
         None => Ok(()),
         Some(e) => Err(e),
     }
 }
 
-async fn bookmark_pr(
+/// The sha `name` points to if it exists, looked up in `listed` when the references were already
+/// listed.
+async fn existing_sha(
+    client: &impl RepositoryController,
+    pr: u64,
+    listed: Option<&[(ParsedRef, String)]>,
+    name: &ParsedRef,
+    layout: &RefLayout,
+) -> Result<Option<String>, ChetterError> {
+    match listed {
+        Some(refs) => Ok(refs
+            .iter()
+            .find(|(r, _)| r == name)
+            .map(|(_, sha)| sha.clone())),
+        None => Ok(client
+            .get_ref(&name.full_name(layout, pr))
+            .await?
+            .map(|r| r.sha)),
+    }
+}
+
+/// Point `name` at `sha`, creating it if it does not exist yet.
+async fn set_ref(
     client: impl RepositoryController,
     pr: u64,
-    reviewer: &str,
+    name: ParsedRef,
     sha: &str,
-    base: &str,
+    layout: &RefLayout,
 ) -> Result<(), ChetterError> {
-    let refs = client
-        .matching_refs(&format!("{}/{}", pr, reviewer))
-        .await?;
+    let full_name = name.full_name(layout, pr);
+    if let Some(old) = client.get_ref(&full_name).await? {
+        client.update_ref(&full_name, Some(&old.sha), sha).await
+    } else {
+        client.create_ref(&full_name, sha).await
+    }
+}
 
-    let mut errors: Vec<ChetterError> = vec![];
+async fn resync_pr(
+    client: impl RepositoryController,
+    pull: &PullRequestInfo,
+    config: &RepoConfig,
+) -> Result<String, ChetterError> {
+    let pr = pull.number;
+    if pull.opted_out {
+        return Ok("opted out".into());
+    }
+    let refs = client.matching_refs(&format!("{}/", pr)).await?;
+    let head = ParsedRef::Head.full_name(&config.layout, pr);
 
-    for (suffix, target) in [("head", sha), ("head-base", base)] {
-        let name = format!("{pr}/{reviewer}-{suffix}");
-        if refs.iter().any(|t| t.full_name.ends_with(&suffix)) {
-            if let Err(e) = client.update_ref(&name, target).await {
-                errors.push(e);
-            }
-        } else if let Err(e) = client.create_ref(&name, target).await {
+    if disabled_in(pr, &refs, &config.layout) {
+        Ok("disabled".into())
+    } else if refs.is_empty() {
+        open_pr(client, pr, &pull.head, &pull.base, config).await?;
+        Ok("opened".into())
+    } else if refs
+        .iter()
+        .any(|r| r.full_name == head && r.sha == pull.head)
+    {
+        Ok("up to date".into())
+    } else {
+        synchronize_pr(client, pr, &pull.head, &pull.base, config).await?;
+        Ok("synchronized".into())
+    }
+}
+
+/// Bring the references of `pull` up to date and handle its reviews submitted from `since` until
+/// `until`, as their webhook events would have been.
+async fn poll_pr(
+    client: &RepositoryClient,
+    pull: &PullRequestInfo,
+    config: &RepoConfig,
+    since: chrono::DateTime<chrono::Utc>,
+    until: chrono::DateTime<chrono::Utc>,
+) -> Result<String, ChetterError> {
+    let mut summary = resync_pr(client.clone(), pull, config).await?;
+    if pull.opted_out {
+        return Ok(summary);
+    }
+    let reviews = client.pull_reviews(pull.number).await?;
+    let submitted = submitted_between(&reviews, since, until);
+    if !submitted.is_empty() && disabled(client, pull.number, &config.layout).await? {
+        return Ok(summary);
+    }
+
+    let mut errors: Vec<ChetterError> = vec![];
+    for review in &submitted {
+        let Some(ref user) = review.user else {
+            continue;
+        };
+        if let Err(e) = on_pull_request_review(
+            client.clone(),
+            config,
+            &user.login,
+            pull.number,
+            &pull.base,
+            review,
+        )
+        .await
+        {
             errors.push(e);
         }
     }
+    if let Some(e) = ChetterError::from_errors(errors) {
+        return Err(e);
+    }
+    if !submitted.is_empty() {
+        summary.push_str(&format!(", {} new reviews", submitted.len()));
+    }
+    Ok(summary)
+}
 
-    let next_ref = if refs.is_empty() {
-        1
-    } else {
-        let last_version: u32 = refs
-            .iter()
-            .filter_map(|t| t.full_name.split('v').last()?.parse::<u32>().ok())
-            .max()
-            .unwrap_or(0);
-        last_version + 1
-    };
+/// Reviews among `reviews` submitted from `since` until `until`, oldest first.
+fn submitted_between(
+    reviews: &[Review],
+    since: chrono::DateTime<chrono::Utc>,
+    until: chrono::DateTime<chrono::Utc>,
+) -> Vec<&Review> {
+    let mut submitted: Vec<&Review> = reviews
+        .iter()
+        .filter(|r| r.submitted_at.is_some_and(|t| since <= t && t < until))
+        .collect();
+    submitted.sort_by_key(|r| r.submitted_at);
+    submitted
+}
 
-    for (suffix, target) in [("", sha), ("-base", base)] {
-        let name = format!("{pr}/{reviewer}-v{next_ref}{suffix}");
-        if let Err(e) = client.create_ref(&name, target).await {
-            errors.push(e);
+/// Record a newly opened pull request once the open delay has passed.
+///
+/// The pull request is fetched again so that `v1` reflects any pushes made in the meantime.  If a
+/// synchronize event already recorded them, there is nothing left to do.
+async fn delayed_open(
+    client: impl RepositoryController,
+    pr: u64,
+    config: &RepoConfig,
+) -> Result<(), ChetterError> {
+    let pull = client.get_pull(pr).await?;
+    if !pull.open {
+        debug!("closed before the open delay expired");
+        return Ok(());
+    }
+    let r = resync_pr(client, &pull, config).await?;
+    debug!("after open delay: {r}");
+    Ok(())
+}
+
+/// How long references of a closed pull request are kept before garbage collection.
+fn grace_period(config: &RepoConfig) -> chrono::Duration {
+    chrono::Duration::days(config.retention.as_ref().map_or(0, |r| r.grace_days.into()))
+}
+
+async fn gc_refs(
+    client: impl RepositoryController,
+    grace: chrono::Duration,
+    dry_run: bool,
+    layout: &RefLayout,
+) -> Result<BatchReport, ChetterError> {
+    let mut by_pr: BTreeMap<u64, Vec<Ref>> = BTreeMap::new();
+    for r in client.matching_refs("").await? {
+        // As when closing, references not named by chetter-app are left alone
+        if let Some(pr) = refname::pr_number(&r.full_name)
+            .filter(|&pr| ParsedRef::parse_full(layout, pr, &r.full_name) != ParsedRef::Unknown)
+        {
+            by_pr.entry(pr).or_default().push(r);
         }
     }
 
-    match errors.pop() {
-        None => Ok(()),
-        Some(e) => Err(e),
+    let mut report = BatchReport::default();
+    for (pr, refs) in by_pr {
+        let r = match client.get_pull(pr).await {
+            Ok(pull) if pull.open => Ok("open".into()),
+            Ok(pull)
+                if pull
+                    .closed_at
+                    .is_some_and(|t| chrono::Utc::now() - t < grace) =>
+            {
+                Ok("retained".into())
+            }
+            Ok(_) if dry_run => Ok(format!("would delete {} references", refs.len())),
+            Ok(_) => client
+                .delete_refs(&refs)
+                .await
+                .map(|_| format!("deleted {} references", refs.len())),
+            Err(e) => Err(e),
+        };
+        report.push(pr, r);
+    }
+    Ok(report)
+}
+
+async fn forget_reviewer(
+    client: impl RepositoryController,
+    pr: u64,
+    reviewer: &str,
+    layout: &RefLayout,
+) -> Result<(), ChetterError> {
+    let refs: Vec<Ref> = client
+        .matching_refs(&format!("{}/", pr))
+        .await?
+        .into_iter()
+        .filter(|r| ParsedRef::parse_full(layout, pr, &r.full_name).reviewer() == Some(reviewer))
+        .collect();
+    if refs.is_empty() {
+        return Ok(());
     }
+    client.delete_refs(&refs).await
 }
 
 #[cfg(test)]
@@ -313,7 +3064,31 @@ mod tests {
     use mockall::predicate::*;
 
     use super::*;
-    use crate::github::{MockRepositoryController, Ref};
+    use crate::github::{FilePatch, MockRepositoryController};
+
+    fn make_pull(number: u64, open: bool) -> PullRequestInfo {
+        PullRequestInfo {
+            number,
+            open,
+            head: "_".into(),
+            base: "_".into(),
+            base_ref: "main".into(),
+            closed_at: None,
+            opted_out: false,
+            requested_reviewers: vec![],
+        }
+    }
+
+    fn make_refs(names: &[String]) -> Vec<Ref> {
+        names
+            .iter()
+            .map(|r| Ref {
+                node_id: format!("node_{r}"),
+                full_name: r.into(),
+                sha: "_".into(),
+            })
+            .collect()
+    }
 
     #[tokio::test]
     async fn test_open_pr() {
@@ -322,24 +3097,103 @@ mod tests {
         let base = "deaf";
         let num = 1234;
 
-        mock.expect_create_ref()
+        let expected = vec![
+            (format!("{num}/head"), sha.to_string()),
+            (format!("{num}/head-base"), base.to_string()),
+            (format!("{num}/v1"), sha.to_string()),
+            (format!("{num}/v1-base"), base.to_string()),
+        ];
+        mock.expect_create_refs()
             .times(1)
-            .with(eq(format!("{num}/v1")), eq(sha))
-            .returning(|_, _| Ok(()));
-        mock.expect_create_ref()
+            .with(eq(expected))
+            .returning(|_| Ok(()));
+
+        let r = open_pr(mock, num, sha, base, &RepoConfig::default()).await;
+        assert!(r.is_ok())
+    }
+
+    #[tokio::test]
+    async fn test_open_pr_status() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+
+        mock.expect_create_refs().times(1).returning(|_| Ok(()));
+        mock.expect_compare_url()
             .times(1)
-            .with(eq(format!("{num}/head")), eq(sha))
-            .returning(|_, _| Ok(()));
-        mock.expect_create_ref()
+            .with(eq(format!("{num}/v1-base")), eq(format!("{num}/v1")))
+            .return_const("url");
+        mock.expect_set_status()
             .times(1)
-            .with(eq(format!("{num}/v1-base")), eq(base))
+            .with(
+                eq("abcd"),
+                eq(SNAPSHOT_STATUS_CONTEXT),
+                eq("Recorded as v1"),
+                eq("url"),
+            )
+            .returning(|_, _, _, _| Ok(()));
+
+        let config = RepoConfig {
+            commit_status: true,
+            ..Default::default()
+        };
+        let r = open_pr(mock, num, "abcd", "deaf", &config).await;
+        assert!(r.is_ok())
+    }
+
+    #[tokio::test]
+    async fn test_open_pr_dispatch() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+
+        mock.expect_create_refs().times(1).returning(|_| Ok(()));
+        mock.expect_repository_dispatch()
+            .times(1)
+            .with(
+                eq("chetter-version"),
+                eq(serde_json::json!({
+                    "pr": num,
+                    "version": 1,
+                    "sha": "abcd",
+                    "base_sha": "deaf",
+                    "ref": format!("refs/heads/pr/{num}/v1"),
+                    "base_ref": format!("refs/heads/pr/{num}/v1-base"),
+                })),
+            )
             .returning(|_, _| Ok(()));
+
+        let config = RepoConfig {
+            dispatch_event: Some("chetter-version".into()),
+            ..Default::default()
+        };
+        let r = open_pr(mock, num, "abcd", "deaf", &config).await;
+        assert!(r.is_ok())
+    }
+
+    #[tokio::test]
+    async fn test_open_pr_merge_base() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+
+        mock.expect_create_refs().times(1).returning(|_| Ok(()));
+        mock.expect_compare()
+            .times(1)
+            .with(eq("deaf"), eq("abcd"))
+            .returning(|_, _| {
+                Ok(Comparison {
+                    merge_base: "f00d".into(),
+                    ..Default::default()
+                })
+            });
         mock.expect_create_ref()
             .times(1)
-            .with(eq(format!("{num}/head-base")), eq(base))
+            .with(eq(format!("{num}/v1-mergebase")), eq("f00d"))
             .returning(|_, _| Ok(()));
 
-        let r = open_pr(mock, num, sha, base).await;
+        let config = RepoConfig {
+            merge_base_refs: true,
+            ..Default::default()
+        };
+        let r = open_pr(mock, num, "abcd", "deaf", &config).await;
         assert!(r.is_ok())
     }
 
@@ -358,7 +3212,7 @@ mod tests {
             format!("{num}/reviewer-v2-base"),
             format!("{num}/reviewer-head"),
         ];
-        let matches: Vec<Ref> = refs
+        let mut matches: Vec<Ref> = refs
             .iter()
             .map(|r| Ref {
                 node_id: format!("node_{r}"),
@@ -367,19 +3221,321 @@ mod tests {
             })
             .collect();
         let to_delete = matches.clone();
+        matches.extend(make_refs(&[format!("{num}/mine"), format!("{num}/v2-wip")]));
+
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq(format!("{num}/")))
+            .return_once(|_| Ok(matches));
+        mock.expect_get_pull()
+            .times(1)
+            .with(eq(num))
+            .returning(|pr| Ok(make_pull(pr, false)));
+        mock.expect_delete_refs()
+            .times(1)
+            .with(eq(to_delete))
+            .return_once(|_| Ok(()));
+        let r = close_pr(mock, num, true, RepoConfig::default()).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_close_pr_archive() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let matches = make_refs(&[
+            format!("{num}/v1"),
+            format!("{num}/v2"),
+            format!("{num}/head"),
+        ]);
+        let to_delete = make_refs(&[format!("{num}/v1"), format!("{num}/head")]);
+
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq(format!("{num}/")))
+            .return_once(|_| Ok(matches));
+        mock.expect_archive_ref().times(3).returning(|r| {
+            if r.full_name.ends_with("v2") {
+                Err(ChetterError::GithubParseError("failed".into()))
+            } else {
+                Ok(())
+            }
+        });
+        mock.expect_get_pull()
+            .times(1)
+            .with(eq(num))
+            .returning(|pr| Ok(make_pull(pr, false)));
+        mock.expect_delete_refs()
+            .times(1)
+            .with(eq(to_delete))
+            .return_once(|_| Ok(()));
+
+        let config = RepoConfig {
+            archive_on_merge: true,
+            ..Default::default()
+        };
+        let r = close_pr(mock, num, true, config).await;
+        assert!(r.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close_pr_retention() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let matches = make_refs(&[
+            format!("{num}/v1"),
+            format!("{num}/v1-base"),
+            format!("{num}/v2"),
+            format!("{num}/v2-base"),
+            format!("{num}/v3"),
+            format!("{num}/v3-base"),
+            format!("{num}/v3-rebase"),
+            format!("{num}/head"),
+            format!("{num}/head-base"),
+            format!("{num}/reviewer-v3"),
+            format!("{num}/reviewer-head"),
+        ]);
+        let to_delete = make_refs(&[
+            format!("{num}/v1"),
+            format!("{num}/v1-base"),
+            format!("{num}/reviewer-v3"),
+            format!("{num}/reviewer-head"),
+        ]);
 
         mock.expect_matching_refs()
             .times(1)
             .with(eq(format!("{num}/")))
             .return_once(|_| Ok(matches));
+        mock.expect_get_pull()
+            .times(1)
+            .with(eq(num))
+            .returning(|pr| Ok(make_pull(pr, false)));
         mock.expect_delete_refs()
             .times(1)
             .with(eq(to_delete))
             .return_once(|_| Ok(()));
-        let r = close_pr(mock, num).await;
+
+        let config = RepoConfig {
+            retention: Some(config::Retention {
+                keep_versions: 2,
+                grace_days: 7,
+            }),
+            ..Default::default()
+        };
+        let r = close_pr(mock, num, false, config).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_close_pr_reopened() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let matches = make_refs(&[format!("{num}/v1"), format!("{num}/head")]);
+
+        mock.expect_matching_refs()
+            .times(1)
+            .return_once(|_| Ok(matches));
+        mock.expect_get_pull()
+            .times(1)
+            .returning(|pr| Ok(make_pull(pr, true)));
+        mock.expect_delete_refs().never();
+        let r = close_pr(mock, num, false, RepoConfig::default()).await;
         assert!(r.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_opt_out_pr() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let to_delete = make_refs(&[format!("{num}/v1"), format!("{num}/head")]);
+        let mut matches = to_delete.clone();
+        matches.extend(make_refs(&[format!("{num}/mine")]));
+
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq(format!("{num}/")))
+            .return_once(|_| Ok(matches));
+        mock.expect_get_pull().times(1).returning(|pr| {
+            Ok(PullRequestInfo {
+                opted_out: true,
+                ..make_pull(pr, true)
+            })
+        });
+        mock.expect_delete_refs()
+            .times(1)
+            .with(eq(to_delete))
+            .return_once(|_| Ok(()));
+        assert!(opt_out_pr(mock, num, &RefLayout::default()).await.is_ok());
+
+        // Opting back in before the references were deleted keeps them
+        let mut mock = MockRepositoryController::new();
+        let matches = make_refs(&[format!("{num}/v1")]);
+        mock.expect_matching_refs()
+            .times(1)
+            .return_once(|_| Ok(matches));
+        mock.expect_get_pull()
+            .times(1)
+            .returning(|pr| Ok(make_pull(pr, true)));
+        mock.expect_delete_refs().never();
+        assert!(opt_out_pr(mock, num, &RefLayout::default()).await.is_ok());
+
+        // Nothing is recorded for opted out pull requests
+        let mut mock = MockRepositoryController::new();
+        mock.expect_matching_refs().never();
+        mock.expect_create_refs().never();
+        let pull = PullRequestInfo {
+            opted_out: true,
+            ..make_pull(num, true)
+        };
+        let r = resync_pr(mock, &pull, &RepoConfig::default()).await;
+        assert_eq!(r.unwrap(), "opted out");
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        use crate::testing::FakeRepositoryController;
+
+        let num = 12;
+        let pull = PullRequestInfo {
+            head: "c2".into(),
+            base: "b1".into(),
+            requested_reviewers: vec!["nick".into()],
+            ..make_pull(num, true)
+        };
+        let fake = FakeRepositoryController::new()
+            .with_pusher("maintainer")
+            .with_pull(pull.clone())
+            .with_ref("12/head", "c1")
+            .with_ref("12/head-base", "b1")
+            .with_ref("12/v1", "c1")
+            .with_ref("12/v1-base", "b1")
+            .with_ref("12/nick-head", "c1")
+            .with_ref("12/mine", "c0");
+        let config = RepoConfig::default();
+        let run = |login: &'static str, body: &'static str| {
+            let (fake, pull, config) = (fake.clone(), pull.clone(), config.clone());
+            async move {
+                let commands = Command::parse_all(body);
+                on_commands(fake, login, "author", &pull, &commands, &config).await
+            }
+        };
+
+        run("contributor", "/chetter disable").await.unwrap();
+        assert_eq!(fake.refs().len(), 6);
+        assert!(fake.comments(num)[0].contains("only those who can push"));
+
+        run("maintainer", "/chetter disable").await.unwrap();
+        assert_eq!(
+            fake.refs().into_iter().collect::<Vec<_>>(),
+            [
+                ("12/disabled".into(), "c2".into()),
+                ("12/mine".into(), "c0".into())
+            ]
+        );
+        assert!(fake.comments(num)[1].starts_with("@maintainer\n\n- `/chetter disable`: deleted 5"));
+
+        // Nothing is recorded until enabled again
+        synchronize_pr(fake.clone(), num, "c3", "b1", &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            resync_pr(fake.clone(), &pull, &config).await.unwrap(),
+            "disabled"
+        );
+        assert_eq!(fake.refs().len(), 2);
+
+        run(
+            "maintainer",
+            "/chetter enable\n/chetter enable\n/chetter frob",
+        )
+        .await
+        .unwrap();
+        let refs = fake.refs();
+        assert!(!refs.contains_key("12/disabled"));
+        assert_eq!(refs["12/v1"], "c2");
+        assert_eq!(refs["12/v1-base"], "b1");
+        let reply = &fake.comments(num)[2];
+        assert!(reply.contains("- `/chetter enable`: recorded the head as `v1`"));
+        assert!(reply.contains("- `/chetter enable`: already enabled"));
+        assert!(reply.contains("- `/chetter frob` is not a command"));
+        assert!(reply.ends_with(commands::USAGE));
+
+        // The author may take snapshots, which are never replaced
+        run(
+            "author",
+            "/chetter snapshot rc1\n/chetter snapshot rc1\n/chetter snapshot v2",
+        )
+        .await
+        .unwrap();
+        let refs = fake.refs();
+        assert_eq!(refs["12/snapshot-rc1"], "c2");
+        assert_eq!(refs["12/snapshot-rc1-base"], "b1");
+        let reply = &fake.comments(num)[3];
+        assert!(reply.contains("`/chetter snapshot rc1`: recorded c2 as `"));
+        assert!(reply.contains("`/chetter snapshot rc1`: `snapshot-rc1` already exists"));
+        assert!(reply.contains("`/chetter snapshot v2`: `v2` cannot name a snapshot"));
+
+        run("contributor", "/chetter snapshot rc2").await.unwrap();
+        assert!(!fake.refs().contains_key("12/snapshot-rc2"));
+        assert!(fake.comments(num)[4].contains("only the author and those who can push"));
+
+        // Anyone may compare recorded versions
+        run(
+            "contributor",
+            "/chetter diff v1 rc1\n/chetter diff v1 v9\n/chetter diff v1 v1-base",
+        )
+        .await
+        .unwrap();
+        let reply = &fake.comments(num)[5];
+        assert!(reply.contains(
+            "- `/chetter diff v1 rc1`: [compare on GitHub]\
+             (https://github.com/org/repo/compare/pr/12/v1...pr/12/snapshot-rc1)\n  \n  ```\n  \
+             git fetch origin +refs/heads/pr/12/v1:refs/remotes/origin/chetter/12/v1 \
+             +refs/heads/pr/12/v1-base:refs/remotes/origin/chetter/12/v1-base \
+             +refs/heads/pr/12/snapshot-rc1:refs/remotes/origin/chetter/12/snapshot-rc1 \
+             +refs/heads/pr/12/snapshot-rc1-base:refs/remotes/origin/chetter/12/snapshot-rc1-base\n  \
+             git range-diff origin/chetter/12/v1-base..origin/chetter/12/v1 \
+             origin/chetter/12/snapshot-rc1-base..origin/chetter/12/snapshot-rc1\n  ```"
+        ));
+        assert!(reply.contains("- `/chetter diff v1 v9`: `v9` was not recorded"));
+        assert!(reply.contains("- `/chetter diff v1 v1-base`: `v1-base` is not a version"));
+
+        // Snapshots outlive closing
+        let refs = make_refs(&["12/v1".into(), "12/snapshot-rc1".into()]);
+        assert!(retained_refs(num, &refs, 0, &RefLayout::default()).contains("12/snapshot-rc1"));
+
+        // Requested reviewers follow new versions without reviewing them, but nobody else
+        run("contributor", "/chetter subscribe").await.unwrap();
+        assert!(fake.comments(num)[6].contains(
+            "only the author, requested reviewers and those who can push to the repository"
+        ));
+        assert!(!fake.refs().contains_key("12/contributor-subscribed"));
+
+        run("nick", "/chetter subscribe\n/chetter subscribe")
+            .await
+            .unwrap();
+        let reply = &fake.comments(num)[7];
+        assert!(reply.contains(
+            "- `/chetter subscribe`: recorded the head as `12/nick-head`, which now follows"
+        ));
+        assert!(reply.contains("- `/chetter subscribe`: already subscribed"));
+        synchronize_pr(fake.clone(), num, "c3", "b2", &config)
+            .await
+            .unwrap();
+        let refs = fake.refs();
+        assert_eq!(refs["12/nick-subscribed"], "c2");
+        assert_eq!(refs["12/nick-head"], "c3");
+        assert_eq!(refs["12/nick-head-base"], "b2");
+
+        run("nick", "/chetter unsubscribe").await.unwrap();
+        synchronize_pr(fake.clone(), num, "c4", "b2", &config)
+            .await
+            .unwrap();
+        let refs = fake.refs();
+        assert!(!refs.contains_key("12/nick-subscribed"));
+        assert_eq!(refs["12/nick-head"], "c3");
+    }
+
     #[tokio::test]
     async fn test_synchronize_pr() {
         let mut mock = MockRepositoryController::new();
@@ -412,21 +3568,92 @@ mod tests {
             });
         mock.expect_update_ref()
             .times(1)
-            .with(eq(format!("{num}/head")), eq(sha))
-            .returning(|_, _| Ok(()));
+            .withf(move |n, old, s| n == format!("{num}/head") && *old == Some("_") && s == sha)
+            .returning(|_, _, _| Ok(()));
         mock.expect_update_ref()
             .times(1)
-            .with(eq(format!("{num}/head-base")), eq(base))
-            .returning(|_, _| Ok(()));
-        mock.expect_create_ref()
+            .withf(move |n, old, s| {
+                n == format!("{num}/head-base") && *old == Some("_") && s == base
+            })
+            .returning(|_, _, _| Ok(()));
+        mock.expect_create_refs()
             .times(1)
-            .with(eq(format!("{num}/v5")), eq(sha))
-            .returning(|_, _| Ok(()));
+            .with(eq(vec![
+                (format!("{num}/v5"), sha.to_string()),
+                (format!("{num}/v5-base"), base.to_string()),
+            ]))
+            .returning(|_| Ok(()));
+        let r = synchronize_pr(mock, num, sha, base, &RepoConfig::default()).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_pr_latest() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let sha = "abc123";
+        let base = "ba5e";
+
+        let existing = make_refs(&[
+            format!("{num}/head"),
+            format!("{num}/head-base"),
+            format!("{num}/latest"),
+            format!("{num}/v1"),
+            format!("{num}/v1-base"),
+        ]);
+        mock.expect_matching_refs()
+            .times(1)
+            .return_once(|_| Ok(existing));
+        mock.expect_update_ref()
+            .times(1)
+            .withf(move |n, old, s| n == format!("{num}/latest") && *old == Some("_") && s == sha)
+            .returning(|_, _, _| Ok(()));
         mock.expect_create_ref()
             .times(1)
-            .with(eq(format!("{num}/v5-base")), eq(base))
+            .with(eq(format!("{num}/latest-base")), eq(base))
             .returning(|_, _| Ok(()));
-        let r = synchronize_pr(mock, num, sha, base).await;
+        mock.expect_update_ref()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        mock.expect_create_refs().times(1).returning(|_| Ok(()));
+
+        let config = RepoConfig {
+            latest_refs: true,
+            ..Default::default()
+        };
+        let r = synchronize_pr(mock, num, sha, base, &config).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_pr_ignores_reviewer_versions() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let refs = make_refs(&[
+            format!("{num}/head"),
+            format!("{num}/head-base"),
+            format!("{num}/v2"),
+            format!("{num}/v2-base"),
+            format!("{num}/nick-v99"),
+            format!("{num}/nick-v99-base"),
+            format!("{num}/v+7"),
+            format!("{num}/v07"),
+        ]);
+
+        mock.expect_matching_refs()
+            .times(1)
+            .return_once(move |_| Ok(refs));
+        mock.expect_update_ref()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        mock.expect_create_refs()
+            .times(1)
+            .with(eq(vec![
+                (format!("{num}/v3"), "abc123".to_string()),
+                (format!("{num}/v3-base"), "ba5e".to_string()),
+            ]))
+            .returning(|_| Ok(()));
+        let r = synchronize_pr(mock, num, "abc123", "ba5e", &RepoConfig::default()).await;
         assert!(r.is_ok());
     }
 
@@ -466,15 +3693,115 @@ mod tests {
             .times(1)
             .with(eq(format!("{num}/head-base")), eq(base))
             .returning(|_, _| Ok(()));
-        mock.expect_create_ref()
+        mock.expect_create_refs()
             .times(1)
-            .with(eq(format!("{num}/v5")), eq(sha))
-            .returning(|_, _| Ok(()));
-        mock.expect_create_ref()
+            .with(eq(vec![
+                (format!("{num}/v5"), sha.to_string()),
+                (format!("{num}/v5-base"), base.to_string()),
+            ]))
+            .returning(|_| Ok(()));
+        let r = synchronize_pr(mock, num, sha, base, &RepoConfig::default()).await;
+        assert!(r.is_ok());
+    }
+
+    fn rebase_mock(num: u64, prev_patch: &str, patch: &str) -> MockRepositoryController {
+        let mut mock = MockRepositoryController::new();
+        mock.expect_matching_refs()
             .times(1)
-            .with(eq(format!("{num}/v5-base")), eq(base))
-            .returning(|_, _| Ok(()));
-        let r = synchronize_pr(mock, num, sha, base).await;
+            .with(eq(format!("{num}/")))
+            .returning(move |_| {
+                let refs = [
+                    (format!("{num}/head"), "old"),
+                    (format!("{num}/head-base"), "oldbase"),
+                    (format!("{num}/v1"), "old"),
+                    (format!("{num}/v1-base"), "oldbase"),
+                ];
+
+                Ok(refs
+                    .into_iter()
+                    .map(|(r, sha)| Ref {
+                        node_id: format!("node_{r}"),
+                        full_name: r,
+                        sha: sha.into(),
+                    })
+                    .collect())
+            });
+        for (base, head, patch) in [("oldbase", "old", prev_patch), ("ba5e", "abc123", patch)] {
+            let files = vec![FilePatch {
+                filename: "README".into(),
+                patch: Some(patch.into()),
+            }];
+            mock.expect_compare()
+                .times(1)
+                .with(eq(base), eq(head))
+                .return_once(|_, _| {
+                    Ok(Comparison {
+                        files,
+                        ..Default::default()
+                    })
+                });
+        }
+        mock.expect_update_ref()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        mock
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_pr_rebase_skip() {
+        let num = 1234;
+        let mut mock = rebase_mock(num, "@@ -1 +1 @@\n-a\n+b", "@@ -4 +4 @@\n-a\n+b");
+        mock.expect_create_ref().never();
+
+        let config = RepoConfig {
+            rebase: RebaseMode::Skip,
+            ..Default::default()
+        };
+        let r = synchronize_pr(mock, num, "abc123", "ba5e", &config).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_pr_rebase_mark() {
+        let num = 1234;
+        let mut mock = rebase_mock(num, "@@ -1 +1 @@\n-a\n+b", "@@ -4 +4 @@\n-a\n+b");
+        let expected: Vec<(String, String)> = [
+            ("v2", "abc123"),
+            ("v2-base", "ba5e"),
+            ("v2-rebase", "abc123"),
+        ]
+        .map(|(name, target)| (format!("{num}/{name}"), target.into()))
+        .into();
+        mock.expect_create_refs()
+            .times(1)
+            .with(eq(expected))
+            .returning(|_| Ok(()));
+
+        let config = RepoConfig {
+            rebase: RebaseMode::Mark,
+            ..Default::default()
+        };
+        let r = synchronize_pr(mock, num, "abc123", "ba5e", &config).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_pr_not_rebase() {
+        let num = 1234;
+        let mut mock = rebase_mock(num, "@@ -1 +1 @@\n-a\n+b", "@@ -1 +1 @@\n-a\n+c");
+        let expected: Vec<(String, String)> = [("v2", "abc123"), ("v2-base", "ba5e")]
+            .map(|(name, target)| (format!("{num}/{name}"), target.into()))
+            .into();
+        mock.expect_create_refs()
+            .times(1)
+            .with(eq(expected))
+            .returning(|_| Ok(()));
+
+        let config = RepoConfig {
+            rebase: RebaseMode::Skip,
+            ..Default::default()
+        };
+        let r = synchronize_pr(mock, num, "abc123", "ba5e", &config).await;
         assert!(r.is_ok());
     }
 
@@ -488,7 +3815,7 @@ mod tests {
 
         mock.expect_matching_refs()
             .times(1)
-            .with(eq(format!("{num}/{user}")))
+            .with(eq(format!("{num}/")))
             .returning(move |_| {
                 let refs = vec![
                     format!("{num}/{user}-head"),
@@ -511,12 +3838,16 @@ mod tests {
             });
         mock.expect_update_ref()
             .times(1)
-            .with(eq(format!("{num}/{user}-head")), eq(sha))
-            .returning(|_, _| Ok(()));
+            .withf(move |n, old, s| {
+                n == format!("{num}/{user}-head") && *old == Some("_") && s == sha
+            })
+            .returning(|_, _, _| Ok(()));
         mock.expect_update_ref()
             .times(1)
-            .with(eq(format!("{num}/{user}-head-base")), eq(base))
-            .returning(|_, _| Ok(()));
+            .withf(move |n, old, s| {
+                n == format!("{num}/{user}-head-base") && *old == Some("_") && s == base
+            })
+            .returning(|_, _, _| Ok(()));
         mock.expect_create_ref()
             .times(1)
             .with(eq(format!("{num}/{user}-v4")), eq(sha))
@@ -525,7 +3856,45 @@ mod tests {
             .times(1)
             .with(eq(format!("{num}/{user}-v4-base")), eq(base))
             .returning(|_, _| Ok(()));
-        let r = bookmark_pr(mock, num, user, sha, base).await;
+        let r = bookmark_pr(mock, num, user, None, sha, base, &RefLayout::default()).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bookmark_pr_named() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let sha = "abc123";
+        let base = "ba54";
+        let user = "me";
+
+        let existing = [
+            format!("{num}/{user}-head"),
+            format!("{num}/{user}-head-base"),
+            format!("{num}/{user}-r10"),
+        ];
+        mock.expect_get_ref().times(4).returning(move |name| {
+            Ok(make_refs(&existing)
+                .into_iter()
+                .find(|r| r.full_name == name))
+        });
+        mock.expect_update_ref()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        mock.expect_create_ref()
+            .times(1)
+            .with(eq(format!("{num}/{user}-r10-base")), eq(base))
+            .returning(|_, _| Ok(()));
+        let r = bookmark_pr(
+            mock,
+            num,
+            user,
+            Some("r10"),
+            sha,
+            base,
+            &RefLayout::default(),
+        )
+        .await;
         assert!(r.is_ok());
     }
 
@@ -539,7 +3908,7 @@ mod tests {
 
         mock.expect_matching_refs()
             .times(1)
-            .with(eq(format!("{num}/{user}")))
+            .with(eq(format!("{num}/")))
             .returning(move |_| {
                 let refs = vec![
                     format!("{num}/{user}-v3"),
@@ -572,7 +3941,471 @@ mod tests {
             .times(1)
             .with(eq(format!("{num}/{user}-v4-base")), eq(base))
             .returning(|_, _| Ok(()));
-        let r = bookmark_pr(mock, num, user, sha, base).await;
+        let r = bookmark_pr(mock, num, user, None, sha, base, &RefLayout::default()).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_pr_comment() {
+        let num = 1234;
+        let mut mock = rebase_mock(num, "@@ -1 +1 @@\n-a\n+b", "@@ -1 +1 @@\n-a\n+c");
+        mock.expect_create_refs().times(1).returning(|_| Ok(()));
+        mock.expect_upsert_comment()
+            .times(1)
+            .withf(move |pr, marker, body| {
+                *pr == num
+                    && marker == VERSION_COMMENT_MARKER
+                    && body.contains("+refs/heads/pr/1234/v2:refs/remotes/origin/chetter/1234/v2")
+                    && body.contains("origin/chetter/1234/v1-base..origin/chetter/1234/v1")
+            })
+            .returning(|_, _, _| Ok(()));
+
+        let config = RepoConfig {
+            rebase: RebaseMode::Skip,
+            version_comment: CommentMode::Update,
+            ..Default::default()
+        };
+        let r = synchronize_pr(mock, num, "abc123", "ba5e", &config).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_synchronize_pr_range_diff() {
+        let num = 1234;
+        let mut mock = rebase_mock(num, "@@ -1 +1 @@\n-a\n+b", "@@ -1 +1 @@\n-a\n+c");
+        mock.expect_create_refs().times(1).returning(|_| Ok(()));
+        mock.expect_post_comment()
+            .times(1)
+            .withf(move |pr, body| {
+                *pr == num && body.contains("Changes from v1 to v2") && body.contains("-+b\n++c")
+            })
+            .returning(|_, _| Ok(()));
+
+        let config = RepoConfig {
+            range_diff_comment: true,
+            ..Default::default()
+        };
+        let r = synchronize_pr(mock, num, "abc123", "ba5e", &config).await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_forget_reviewer() {
+        let mut mock = MockRepositoryController::new();
+        let num = 1234;
+        let user = "me";
+        let matches: Vec<Ref> = [format!("{num}/{user}-head"), format!("{num}/{user}-v1")]
+            .into_iter()
+            .map(|r| Ref {
+                node_id: format!("node_{r}"),
+                full_name: r,
+                sha: "_".into(),
+            })
+            .collect();
+        let to_delete = matches.clone();
+        // Bookmarks of other reviewers whose login starts with the same name are kept
+        let matches = [matches, make_refs(&[format!("{num}/{user}-too-v1")])].concat();
+
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq(format!("{num}/")))
+            .return_once(|_| Ok(matches));
+        mock.expect_delete_refs()
+            .times(1)
+            .with(eq(to_delete))
+            .return_once(|_| Ok(()));
+        let r = forget_reviewer(mock, num, user, &RefLayout::default()).await;
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn test_review_policy() {
+        let mut config = RepoConfig::default();
+        let states = [
+            ReviewState::Approved,
+            ReviewState::ChangesRequested,
+            ReviewState::Commented,
+        ];
+        let policies = states.map(|s| review_policy(&config, Some(s)));
+        assert_eq!(
+            policies,
+            [
+                ReviewPolicy::Record,
+                ReviewPolicy::Record,
+                ReviewPolicy::Ignore
+            ]
+        );
+
+        config.commented_review = ReviewPolicy::Record;
+        config.approvals_only = true;
+        let policies = states.map(|s| review_policy(&config, Some(s)));
+        assert_eq!(
+            policies,
+            [
+                ReviewPolicy::Record,
+                ReviewPolicy::Ignore,
+                ReviewPolicy::Record
+            ]
+        );
+        assert_eq!(review_policy(&config, None), ReviewPolicy::Ignore);
+    }
+
+    #[tokio::test]
+    async fn test_set_ref() {
+        let num = 1234;
+        let name = format!("{num}/team-core-head");
+
+        let mut mock = MockRepositoryController::new();
+        mock.expect_get_ref()
+            .times(1)
+            .with(eq(name.clone()))
+            .return_once(|_| Ok(None));
+        mock.expect_create_ref()
+            .times(1)
+            .with(eq(name.clone()), eq("abc"))
+            .returning(|_, _| Ok(()));
+        let r = set_ref(
+            mock,
+            num,
+            ParsedRef::TeamHead("core".into()),
+            "abc",
+            &RefLayout::default(),
+        )
+        .await;
+        assert!(r.is_ok());
+
+        let mut mock = MockRepositoryController::new();
+        let existing = make_refs(std::slice::from_ref(&name)).pop();
+        mock.expect_get_ref()
+            .times(1)
+            .with(eq(name.clone()))
+            .return_once(|_| Ok(existing));
+        mock.expect_update_ref()
+            .times(1)
+            .withf(move |n, old, s| n == name && *old == Some("_") && s == "abc")
+            .returning(|_, _, _| Ok(()));
+        let r = set_ref(
+            mock,
+            num,
+            ParsedRef::TeamHead("core".into()),
+            "abc",
+            &RefLayout::default(),
+        )
+        .await;
+        assert!(r.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_resync_pr() {
+        let pull = PullRequestInfo {
+            number: 1234,
+            open: true,
+            head: "abc123".into(),
+            base: "ba5e".into(),
+            base_ref: "main".into(),
+            closed_at: None,
+            opted_out: false,
+            requested_reviewers: vec![],
+        };
+
+        let mut mock = MockRepositoryController::new();
+        mock.expect_matching_refs()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_create_refs().times(1).returning(|_| Ok(()));
+        let r = resync_pr(mock, &pull, &RepoConfig::default()).await;
+        assert_eq!(r.unwrap(), "opened");
+
+        let mut mock = MockRepositoryController::new();
+        mock.expect_matching_refs().times(1).return_once(|_| {
+            Ok(vec![Ref {
+                node_id: "node".into(),
+                full_name: "1234/head".into(),
+                sha: "abc123".into(),
+            }])
+        });
+        let r = resync_pr(mock, &pull, &RepoConfig::default()).await;
+        assert_eq!(r.unwrap(), "up to date");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_base() {
+        let mut mock = MockRepositoryController::new();
+        let refs: Vec<Ref> = [
+            ("1/v1", "c1"),
+            ("1/v1-base", "old1"),
+            ("1/v2", "c2"),
+            ("1/v2-base", "old2"),
+            ("1/v2-newbase", "mb0"),
+            ("1/head", "c2"),
+            ("1/head-base", "kept"),
+            ("1/reviewer-v1-base", "old1"),
+        ]
+        .iter()
+        .map(|(name, sha)| Ref {
+            node_id: format!("node_{name}"),
+            full_name: name.to_string(),
+            sha: sha.to_string(),
+        })
+        .collect();
+        let pull = PullRequestInfo {
+            number: 1,
+            open: true,
+            head: "c2".into(),
+            base: "kept".into(),
+            base_ref: "main".into(),
+            closed_at: None,
+            opted_out: false,
+            requested_reviewers: vec![],
+        };
+
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq("1/"))
+            .return_once(|_| Ok(refs));
+        mock.expect_compare().times(5).returning(|base, head| {
+            let (status, merge_base) = match (base, head) {
+                ("kept", "new") => ("ahead", ""),
+                ("old1" | "old2", "new") => ("diverged", ""),
+                ("new", "c1") => ("diverged", "mb1"),
+                ("new", "c2") => ("diverged", "mb2"),
+                _ => panic!("unexpected compare {base}...{head}"),
+            };
+            Ok(Comparison {
+                status: status.into(),
+                merge_base: merge_base.into(),
+                ..Default::default()
+            })
+        });
+        mock.expect_create_ref()
+            .times(1)
+            .with(eq("1/v1-newbase"), eq("mb1"))
+            .returning(|_, _| Ok(()));
+        mock.expect_update_ref()
+            .times(1)
+            .withf(move |n, old, s| n == "1/v2-newbase" && *old == Some("mb0") && s == "mb2")
+            .returning(|_, _, _| Ok(()));
+
+        let r = refresh_base(&mock, &pull, "new", &RefLayout::default()).await;
+        assert_eq!(r.unwrap(), "refreshed 2 base references");
+    }
+
+    #[tokio::test]
+    async fn test_on_base_force_push() {
+        let mut mock = MockRepositoryController::new();
+        let refs = make_refs(&[
+            "1/v1".into(),
+            "1/v1-base".into(),
+            "1/v2".into(),
+            "1/v2-base".into(),
+        ]);
+        let pull = PullRequestInfo {
+            number: 1,
+            open: true,
+            head: "c2".into(),
+            base: "new".into(),
+            base_ref: "main".into(),
+            closed_at: None,
+            opted_out: false,
+            requested_reviewers: vec![],
+        };
+
+        mock.expect_open_pulls()
+            .times(1)
+            .withf(|base| *base == Some("main"))
+            .return_once(move |_| Ok(vec![pull]));
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq("1/"))
+            .return_once(|_| Ok(refs));
+        // A failure to refresh one version doesn't stop the others
+        let mut seq = mockall::Sequence::new();
+        mock.expect_compare()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Err(ChetterError::GithubParseError("v1".into())));
+        mock.expect_compare()
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(|_, _| {
+                Ok(Comparison {
+                    status: "diverged".into(),
+                    merge_base: "mb2".into(),
+                    ..Default::default()
+                })
+            });
+        mock.expect_create_ref()
+            .times(1)
+            .with(eq("1/v2-newbase"), eq("mb2"))
+            .returning(|_, _| Ok(()));
+
+        let r = on_base_force_push(mock, "main", "new", &RefLayout::default()).await;
+        assert_eq!(r.unwrap_err().to_string(), "v1");
+    }
+
+    #[tokio::test]
+    async fn test_delayed_open() {
+        let mut mock = MockRepositoryController::new();
+        mock.expect_get_pull().times(1).with(eq(1)).returning(|pr| {
+            Ok(PullRequestInfo {
+                number: pr,
+                open: true,
+                head: "abc123".into(),
+                base: "ba5e".into(),
+                base_ref: "main".into(),
+                closed_at: None,
+                opted_out: false,
+                requested_reviewers: vec![],
+            })
+        });
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq("1/"))
+            .returning(|_| Ok(vec![]));
+        let expected: Vec<(String, String)> = [
+            ("1/head", "abc123"),
+            ("1/head-base", "ba5e"),
+            ("1/v1", "abc123"),
+            ("1/v1-base", "ba5e"),
+        ]
+        .map(|(name, sha)| (name.into(), sha.into()))
+        .into();
+        mock.expect_create_refs()
+            .times(1)
+            .with(eq(expected))
+            .returning(|_| Ok(()));
+
+        let r = delayed_open(mock, 1, &RepoConfig::default()).await;
         assert!(r.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_gc_refs() {
+        let mut mock = MockRepositoryController::new();
+        let refs = make_refs(&[
+            "1/head".into(),
+            "1/v1".into(),
+            "2/head".into(),
+            "3/head".into(),
+            "4/head".into(),
+            "junk".into(),
+        ]);
+        let closed = make_refs(&["1/head".into(), "1/v1".into()]);
+
+        mock.expect_matching_refs()
+            .times(1)
+            .with(eq(""))
+            .return_once(|_| Ok(refs));
+        mock.expect_get_pull().times(4).returning(|pr| match pr {
+            1 | 2 | 4 => Ok(PullRequestInfo {
+                number: pr,
+                open: pr == 2,
+                head: "_".into(),
+                base: "_".into(),
+                base_ref: "main".into(),
+                closed_at: match pr {
+                    1 => Some(chrono::Utc::now() - chrono::Duration::days(30)),
+                    4 => Some(chrono::Utc::now() - chrono::Duration::days(1)),
+                    _ => None,
+                },
+                opted_out: false,
+                requested_reviewers: vec![],
+            }),
+            _ => Err(ChetterError::GithubParseError("not found".into())),
+        });
+        mock.expect_delete_refs()
+            .times(1)
+            .with(eq(closed))
+            .return_once(|_| Ok(()));
+
+        let report = gc_refs(
+            mock,
+            chrono::Duration::days(7),
+            false,
+            &RefLayout::default(),
+        )
+        .await
+        .unwrap();
+        let results: Vec<(u64, bool)> = report
+            .items
+            .iter()
+            .map(|i| (i.pr, i.result.is_ok()))
+            .collect();
+        assert_eq!(results, vec![(1, true), (2, true), (3, false), (4, true)]);
+        assert_eq!(report.items[3].result.as_ref().unwrap(), "retained");
+    }
+
+    #[tokio::test]
+    async fn test_gc_refs_dry_run() {
+        let mut mock = MockRepositoryController::new();
+        let refs = make_refs(&["1/head".into(), "1/v1".into()]);
+        mock.expect_matching_refs()
+            .times(1)
+            .return_once(|_| Ok(refs));
+        mock.expect_get_pull().times(1).returning(|pr| {
+            Ok(PullRequestInfo {
+                number: pr,
+                open: false,
+                head: "_".into(),
+                base: "_".into(),
+                base_ref: "main".into(),
+                closed_at: Some(chrono::Utc::now() - chrono::Duration::days(30)),
+                opted_out: false,
+                requested_reviewers: vec![],
+            })
+        });
+        mock.expect_delete_refs().times(0);
+
+        let report = gc_refs(mock, chrono::Duration::days(7), true, &RefLayout::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            report.items[0].result.as_ref().unwrap(),
+            "would delete 2 references"
+        );
+    }
+
+    #[test]
+    fn test_submitted_between() {
+        let review = |id: u64, submitted_at: Option<&str>| -> Review {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "node_id": "",
+                "html_url": "https://github.com/org/repo/pull/1#pullrequestreview-1",
+                "user": null,
+                "commit_id": "c1",
+                "state": "APPROVED",
+                "submitted_at": submitted_at,
+            }))
+            .unwrap()
+        };
+        let reviews = [
+            review(1, Some("2024-01-01T00:00:00Z")),
+            review(4, Some("2024-01-03T00:00:00Z")),
+            review(2, Some("2024-01-02T00:00:00Z")),
+            review(3, None),
+            review(5, Some("2024-01-04T00:00:00Z")),
+        ];
+        let since = "2024-01-02T00:00:00Z".parse().unwrap();
+        let until = "2024-01-04T00:00:00Z".parse().unwrap();
+        let ids: Vec<u64> = submitted_between(&reviews, since, until)
+            .iter()
+            .map(|r| r.id.into_inner())
+            .collect();
+        assert_eq!(ids, [2, 4]);
+    }
+
+    #[test]
+    fn test_in_networks() {
+        let networks: Vec<IpNet> = ["192.30.252.0/22", "2a0a:a440::/29"]
+            .iter()
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let allowed = |ip: &str| in_networks(&networks, ip.parse().unwrap());
+        assert!(allowed("192.30.252.1"));
+        assert!(allowed("::ffff:192.30.255.254"));
+        assert!(allowed("2a0a:a440::1"));
+        assert!(!allowed("192.30.251.255"));
+        assert!(!allowed("10.0.0.1"));
+    }
 }
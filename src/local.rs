@@ -0,0 +1,508 @@
+use async_trait::async_trait;
+use git2::{Cred, CredentialType, Oid, Patch, PushOptions, RemoteCallbacks, Repository};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::{
+    error::ChetterError,
+    github::{
+        Comparison, FilePatch, PullRequestInfo, Ref, RepositoryController, ARCHIVE_NS, REF_NS,
+    },
+};
+
+/// Pull request heads of a mirror of a GitHub repository, as fetched with
+/// `git fetch origin '+refs/pull/*/head:refs/pull/*/head'`.
+const PULL_NS: &str = "refs/pull";
+
+/// A [RepositoryController] acting on a local clone or mirror with libgit2.
+///
+/// References are changed in the local repository and then pushed to its remote, if set, which
+/// keeps chetter references in air-gapped mirrors and runs chetter-app end to end without GitHub.
+/// The commits references point to must already be present locally.
+///
+/// Pull requests are the `refs/pull/<n>/head` references of the repository, which are all open
+/// and merge into the base branch.  Comments and commit statuses have nowhere to go and are only
+/// logged.
+#[derive(Debug, Clone)]
+pub struct LocalRepository {
+    path: PathBuf,
+
+    /// Remote changes to references are pushed to
+    remote: Option<String>,
+
+    /// Namespace references are read from and written to
+    ns: String,
+
+    /// Branch pull requests merge into
+    base_branch: String,
+}
+
+/// A change to a reference, by full name.
+enum Change {
+    Create(String, String),
+    Update(String, String),
+    Delete(String),
+}
+
+impl Change {
+    /// Refspec pushing the change.
+    fn refspec(&self) -> String {
+        match self {
+            Change::Create(name, _) | Change::Update(name, _) => format!("+{name}:{name}"),
+            Change::Delete(name) => format!(":{name}"),
+        }
+    }
+
+    fn apply(&self, repo: &Repository) -> Result<(), ChetterError> {
+        match self {
+            Change::Create(name, sha) => {
+                repo.reference(name, Oid::from_str(sha)?, true, "chetter: create")?;
+                info!("created {} as {}", name, sha.get(..8).unwrap_or(sha));
+            }
+            Change::Update(name, sha) => {
+                repo.find_reference(name)?
+                    .set_target(Oid::from_str(sha)?, "chetter: update")?;
+                info!("updated {} as {}", name, sha.get(..8).unwrap_or(sha));
+            }
+            Change::Delete(name) => {
+                repo.find_reference(name)?.delete()?;
+                info!("deleted {}", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LocalRepository {
+    /// Act on the repository at `path`, which may be bare.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, ChetterError> {
+        let path = path.into();
+        Repository::open(&path)?;
+        Ok(Self {
+            path,
+            remote: None,
+            ns: REF_NS.into(),
+            base_branch: "main".into(),
+        })
+    }
+
+    /// Push every change to references to `remote`, such as `origin`.
+    pub fn with_remote(mut self, remote: &str) -> Self {
+        self.remote = Some(remote.into());
+        self
+    }
+
+    /// Read and write references under `ns` instead of the default namespace.
+    pub fn with_namespace(mut self, ns: &str) -> Self {
+        self.ns = ns.into();
+        self
+    }
+
+    /// Merge pull requests into `branch` rather than `main`.
+    pub fn with_base_branch(mut self, branch: &str) -> Self {
+        self.base_branch = branch.into();
+        self
+    }
+
+    /// Run `f` on the repository without blocking the runtime.
+    async fn with_repo<T, F>(&self, f: F) -> Result<T, ChetterError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Repository) -> Result<T, ChetterError> + Send + 'static,
+    {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || f(&Repository::open(path)?)).await?
+    }
+
+    /// Apply `changes` in order, then push those that were applied.
+    async fn apply(&self, changes: Vec<Change>) -> Result<(), ChetterError> {
+        let remote = self.remote.clone();
+        self.with_repo(move |repo| {
+            let mut refspecs = vec![];
+            let r = changes.iter().try_for_each(|change| {
+                change.apply(repo)?;
+                refspecs.push(change.refspec());
+                Ok(())
+            });
+            if let (Some(remote), false) = (remote, refspecs.is_empty()) {
+                push(repo, &remote, &refspecs)?;
+            }
+            r
+        })
+        .await
+    }
+
+    /// Get the references rooted at `ns` that start with `search`, named relative to `ns`.
+    async fn matching_refs_in(&self, ns: &str, search: &str) -> Result<Vec<Ref>, ChetterError> {
+        let ns = ns.to_string();
+        let prefix = format!("{ns}/{search}");
+        self.with_repo(move |repo| {
+            let mut refs = vec![];
+            for r in repo.references()? {
+                let r = r?;
+                // Symbolic references have no target
+                let (Some(name), Some(oid)) = (r.name(), r.target()) else {
+                    continue;
+                };
+                if name.starts_with(&prefix) {
+                    refs.push(Ref {
+                        full_name: name[ns.len() + 1..].into(),
+                        sha: oid.to_string(),
+                        node_id: String::new(),
+                    });
+                }
+            }
+            refs.sort_by(|a, b| a.full_name.cmp(&b.full_name));
+            Ok(refs)
+        })
+        .await
+    }
+}
+
+/// Push `refspecs` to `remote`, authenticating with the SSH agent if needed.
+fn push(repo: &Repository, remote: &str, refspecs: &[String]) -> Result<(), ChetterError> {
+    let mut rejected = vec![];
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|_, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else {
+            Cred::default()
+        }
+    });
+    callbacks.push_update_reference(|name, status| {
+        if let Some(status) = status {
+            rejected.push(format!("{name}: {status}"));
+        }
+        Ok(())
+    });
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    repo.find_remote(remote)?
+        .push(refspecs, Some(&mut options))?;
+    drop(options);
+
+    if !rejected.is_empty() {
+        return Err(ChetterError::PermissionDenied(format!(
+            "{remote} rejected {}",
+            rejected.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Diff of a file as GitHub reports it, starting at the first hunk.
+fn hunks(patch: &mut Patch) -> Option<String> {
+    let buf = patch.to_buf().ok()?;
+    let text = buf.as_str()?;
+    let start = text.find("@@")?;
+    Some(text[start..].into())
+}
+
+#[async_trait]
+impl RepositoryController for LocalRepository {
+    async fn create_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError> {
+        let name = format!("{}/{ref_name}", self.ns);
+        self.apply(vec![Change::Create(name, sha.into())]).await
+    }
+
+    async fn create_refs(&self, refs: &[(String, String)]) -> Result<(), ChetterError> {
+        let changes = refs
+            .iter()
+            .map(|(name, sha)| Change::Create(format!("{}/{name}", self.ns), sha.clone()))
+            .collect();
+        self.apply(changes).await
+    }
+
+    async fn update_ref<'a>(
+        &self,
+        ref_name: &str,
+        _old_sha: Option<&'a str>,
+        sha: &str,
+    ) -> Result<(), ChetterError> {
+        let name = format!("{}/{ref_name}", self.ns);
+        self.apply(vec![Change::Update(name, sha.into())]).await
+    }
+
+    async fn delete_ref(&self, ref_name: &str) -> Result<(), ChetterError> {
+        let name = format!("{}/{ref_name}", self.ns);
+        self.apply(vec![Change::Delete(name)]).await
+    }
+
+    async fn archive_ref(&self, r: &Ref) -> Result<(), ChetterError> {
+        let name = format!("{ARCHIVE_NS}/{}", r.full_name);
+        self.apply(vec![Change::Create(name, r.sha.clone())]).await
+    }
+
+    async fn delete_refs(&self, refs: &[Ref]) -> Result<(), ChetterError> {
+        let changes = refs
+            .iter()
+            .map(|r| Change::Delete(format!("{}/{}", self.ns, r.full_name)))
+            .collect();
+        self.apply(changes).await
+    }
+
+    async fn get_ref(&self, name: &str) -> Result<Option<Ref>, ChetterError> {
+        let name = name.to_string();
+        let ns = self.ns.clone();
+        self.with_repo(
+            move |repo| match repo.find_reference(&format!("{ns}/{name}")) {
+                Ok(r) => Ok(r.target().map(|oid| Ref {
+                    full_name: name,
+                    sha: oid.to_string(),
+                    node_id: String::new(),
+                })),
+                Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        )
+        .await
+    }
+
+    async fn matching_refs(&self, search: &str) -> Result<Vec<Ref>, ChetterError> {
+        self.matching_refs_in(&self.ns, search).await
+    }
+
+    async fn compare(&self, base: &str, head: &str) -> Result<Comparison, ChetterError> {
+        let (base, head) = (base.to_string(), head.to_string());
+        self.with_repo(move |repo| {
+            let base = repo.revparse_single(&base)?.peel_to_commit()?;
+            let head = repo.revparse_single(&head)?.peel_to_commit()?;
+            let merge_base = repo.merge_base(base.id(), head.id())?;
+            let status = if base.id() == head.id() {
+                "identical"
+            } else if merge_base == base.id() {
+                "ahead"
+            } else if merge_base == head.id() {
+                "behind"
+            } else {
+                "diverged"
+            };
+
+            // Like GitHub, the changes of head since the merge-base
+            let old = repo.find_commit(merge_base)?.tree()?;
+            let diff = repo.diff_tree_to_tree(Some(&old), Some(&head.tree()?), None)?;
+            let mut files = vec![];
+            for (i, delta) in diff.deltas().enumerate() {
+                let file = match delta.new_file().path() {
+                    Some(_) => delta.new_file(),
+                    None => delta.old_file(),
+                };
+                let patch = match Patch::from_diff(&diff, i)? {
+                    Some(ref mut p) if !delta.flags().is_binary() => hunks(p),
+                    _ => None,
+                };
+                files.push(FilePatch {
+                    filename: file
+                        .path()
+                        .map(|p| p.to_string_lossy().into())
+                        .unwrap_or_default(),
+                    patch,
+                });
+            }
+            Ok(Comparison {
+                files,
+                status: status.into(),
+                merge_base: merge_base.to_string(),
+            })
+        })
+        .await
+    }
+
+    async fn post_comment(&self, pr: u64, body: &str) -> Result<(), ChetterError> {
+        info!("comment on #{pr}: {body}");
+        Ok(())
+    }
+
+    async fn upsert_comment(&self, pr: u64, _marker: &str, body: &str) -> Result<(), ChetterError> {
+        self.post_comment(pr, body).await
+    }
+
+    async fn get_pull(&self, pr: u64) -> Result<PullRequestInfo, ChetterError> {
+        let base_ref = self.base_branch.clone();
+        self.with_repo(move |repo| {
+            let head = repo.refname_to_id(&format!("{PULL_NS}/{pr}/head"))?;
+            let base = repo.revparse_single(&base_ref)?.peel_to_commit()?.id();
+            Ok(PullRequestInfo {
+                number: pr,
+                open: true,
+                head: head.to_string(),
+                base: base.to_string(),
+                base_ref,
+                closed_at: None,
+                opted_out: false,
+                requested_reviewers: vec![],
+            })
+        })
+        .await
+    }
+
+    async fn open_pulls<'a>(
+        &self,
+        base: Option<&'a str>,
+    ) -> Result<Vec<PullRequestInfo>, ChetterError> {
+        let heads = self.matching_refs_in(PULL_NS, "").await?;
+        let mut pulls = vec![];
+        for r in heads {
+            let number = r
+                .full_name
+                .strip_suffix("/head")
+                .and_then(|n| n.parse().ok());
+            if let Some(number) = number {
+                let pull = self.get_pull(number).await?;
+                if base.map_or(true, |b| b == pull.base_ref) {
+                    pulls.push(pull);
+                }
+            }
+        }
+        pulls.sort_by_key(|p| p.number);
+        Ok(pulls)
+    }
+
+    fn compare_url(&self, base: &str, head: &str) -> String {
+        format!("{ns}/{base}...{ns}/{head}", ns = self.ns)
+    }
+
+    async fn set_status(
+        &self,
+        sha: &str,
+        context: &str,
+        description: &str,
+        _target_url: &str,
+    ) -> Result<(), ChetterError> {
+        info!(
+            "{context} status on {}: {description}",
+            sha.get(..8).unwrap_or(sha)
+        );
+        Ok(())
+    }
+
+    async fn repository_dispatch(
+        &self,
+        event_type: &str,
+        payload: &serde_json::Value,
+    ) -> Result<(), ChetterError> {
+        info!("{event_type} dispatched: {payload}");
+        Ok(())
+    }
+
+    async fn can_push(&self, _login: &str) -> Result<bool, ChetterError> {
+        // Anyone with the repository at hand can push to it
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+    use std::path::Path;
+
+    /// Commit `content` to `file` on top of `parent`, without updating any reference.
+    fn commit(repo: &Repository, parent: Option<Oid>, file: &str, content: &str) -> String {
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert(file, blob, 0o100644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let sig = Signature::now("chetter", "chetter@example.com").unwrap();
+        let parents: Vec<_> = parent
+            .iter()
+            .map(|p| repo.find_commit(*p).unwrap())
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        repo.commit(None, &sig, &sig, "commit", &tree, &parents)
+            .unwrap()
+            .to_string()
+    }
+
+    fn temp_repo(name: &str, bare: bool) -> (PathBuf, Repository) {
+        let path = std::env::temp_dir().join(format!("chetter-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let repo = match bare {
+            true => Repository::init_bare(&path).unwrap(),
+            false => Repository::init(&path).unwrap(),
+        };
+        (path, repo)
+    }
+
+    fn remote_ref(path: &Path, name: &str) -> Option<String> {
+        let repo = Repository::open(path).unwrap();
+        repo.refname_to_id(name).ok().map(|oid| oid.to_string())
+    }
+
+    #[tokio::test]
+    async fn references() {
+        let (remote_path, _) = temp_repo("local-remote", true);
+        let (path, repo) = temp_repo("local", false);
+        repo.remote("origin", remote_path.to_str().unwrap())
+            .unwrap();
+
+        let base = commit(&repo, None, "a.txt", "a\n");
+        let head = commit(&repo, Some(Oid::from_str(&base).unwrap()), "a.txt", "b\n");
+        repo.reference("refs/heads/main", Oid::from_str(&base).unwrap(), true, "")
+            .unwrap();
+        repo.reference("refs/pull/7/head", Oid::from_str(&head).unwrap(), true, "")
+            .unwrap();
+
+        let local = LocalRepository::open(&path).unwrap().with_remote("origin");
+        let pull = local.get_pull(7).await.unwrap();
+        assert_eq!(
+            (pull.head.as_str(), pull.base.as_str()),
+            (head.as_str(), base.as_str())
+        );
+        assert_eq!(
+            local.open_pulls(Some(&pull.base_ref)).await.unwrap(),
+            vec![pull.clone()]
+        );
+        assert!(local.open_pulls(Some("other")).await.unwrap().is_empty());
+        assert_eq!(local.open_pulls(None).await.unwrap(), [pull]);
+        assert!(local.get_pull(8).await.is_err());
+
+        local
+            .create_refs(&[
+                ("7/v1".into(), base.clone()),
+                ("7/v1-base".into(), base.clone()),
+            ])
+            .await
+            .unwrap();
+        local.update_ref("7/v1", None, &head).await.unwrap();
+        assert!(local.update_ref("7/v2", None, &head).await.is_err());
+        assert_eq!(local.get_ref("7/v1").await.unwrap().unwrap().sha, head);
+        assert_eq!(local.get_ref("7/v2").await.unwrap(), None);
+        assert_eq!(
+            remote_ref(&remote_path, "refs/heads/pr/7/v1").as_ref(),
+            Some(&head)
+        );
+
+        let refs = local.matching_refs("7/").await.unwrap();
+        let names: Vec<&str> = refs.iter().map(|r| r.full_name.as_str()).collect();
+        assert_eq!(names, ["7/v1", "7/v1-base"]);
+
+        local.archive_ref(&refs[0]).await.unwrap();
+        local.delete_refs(&refs).await.unwrap();
+        assert!(local.matching_refs("7/").await.unwrap().is_empty());
+        assert_eq!(remote_ref(&remote_path, "refs/heads/pr/7/v1"), None);
+        assert_eq!(
+            remote_ref(&remote_path, "refs/tags/chetter/7/v1").as_ref(),
+            Some(&head)
+        );
+
+        let comparison = local.compare(&base, "refs/pull/7/head").await.unwrap();
+        assert_eq!(comparison.status, "ahead");
+        assert_eq!(comparison.merge_base, base);
+        assert_eq!(comparison.files.len(), 1);
+        assert_eq!(comparison.files[0].filename, "a.txt");
+        assert_eq!(
+            comparison.files[0].patch.as_deref(),
+            Some("@@ -1 +1 @@\n-a\n+b\n")
+        );
+        assert_eq!(local.compare(&head, &base).await.unwrap().status, "behind");
+        local
+            .set_status("abc", "chetter/snapshot", "v1", "")
+            .await
+            .unwrap();
+
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::remove_dir_all(&remote_path).unwrap();
+    }
+}
@@ -1,51 +1,29 @@
-use axum::{http::header::HeaderMap, routing::post};
-use getopts::Options;
-use octocrab::models::webhook_events::WebhookEvent;
+use axum::http::{Request, Response};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::SocketAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 use tokio::signal;
-use tracing::{debug, error};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-use chetter_app::{error::ChetterError, State};
-
-async fn post_github_events(
-    axum::extract::State(state): axum::extract::State<State>,
-    headers: HeaderMap,
-    body: String,
-) -> Result<(), ChetterError> {
-    let event_type = match headers.get("X-Github-Event") {
-        Some(v) => match v.to_str() {
-            Ok(v) => v,
-            Err(error) => {
-                error!("Failed to parse X-Github-Event: {}", error);
-                headers.iter().for_each(|(k, v)| {
-                    debug!("{} = {}", k, v.to_str().unwrap_or("<error>"));
-                });
-                return Err(ChetterError::GithubParseError(format!(
-                    "Failed to parse X-Github-Event: {error}"
-                )));
-            }
-        },
-        None => {
-            let msg = "No X-Github-Event header";
-            error!(msg);
-            headers.iter().for_each(|(k, v)| {
-                debug!("{} = {}", k, v.to_str().unwrap_or("<error>"));
-            });
-            return Err(ChetterError::GithubParseError(msg.into()));
-        }
-    };
+use tower_http::{classify::ServerErrorsFailureClass, trace::TraceLayer};
+use tracing::{info, warn, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
-    let event = match WebhookEvent::try_from_header_and_body(event_type, &body) {
-        Ok(event) => event,
-        Err(error) => {
-            let msg = format!("Failed to parse event: {}", error);
-            error!(msg);
-            debug!("{}", body);
-            return Err(ChetterError::GithubParseError(msg));
-        }
-    };
+use chetter_app::{batch::BatchReport, error::ChetterError, recorder, setup, tls, State};
+
+/// Tracing target for the HTTP access log, kept apart from application logs so that it can be
+/// filtered independently.
+const ACCESS_LOG: &str = "chetter_app::access";
 
-    state.webhook_dispatcher(event).await
+/// Filter of logged events, set by `RUST_LOG`.
+fn log_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "info,chetter_app=debug,axum::rejection=trace".into())
 }
 
 async fn shutdown_signal() {
@@ -70,50 +48,440 @@ async fn shutdown_signal() {
     }
 }
 
+/// Record every version of pull requests as git references
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path to config file, required by every command but setup
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve webhook events and the HTTP APIs, the default
+    Serve,
+
+    /// Check the configuration and exit
+    VerifyConfig,
+
+    /// Process recorded webhook payloads again, in the order they were received
+    Replay {
+        /// Only parse the payloads
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Recorded payloads, directories are replayed in full
+        #[arg(required = true, value_name = "FILE or DIRECTORY")]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Record open pull requests that were missed, such as those opened before installing
+    Backfill {
+        /// Repository to backfill
+        #[arg(short, long, value_name = "ORG/NAME")]
+        repo: Repo,
+
+        /// Only backfill this pull request
+        #[arg(short, long, value_name = "NUMBER")]
+        pr: Option<u64>,
+    },
+
+    /// Delete the references of closed pull requests, such as after missing their events
+    Gc {
+        /// Repository to garbage collect
+        #[arg(short, long, value_name = "ORG/NAME")]
+        repo: Repo,
+
+        /// Only report what would be deleted
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Show the versions and reviews recorded for a pull request
+    ListRefs {
+        /// Repository of the pull request
+        #[arg(short, long, value_name = "ORG/NAME")]
+        repo: Repo,
+
+        /// Pull request number
+        #[arg(short, long, value_name = "NUMBER")]
+        pr: u64,
+
+        /// Print JSON instead of tables
+        #[arg(short, long)]
+        json: bool,
+    },
+
+    /// Mint an installation access token, to reproduce API calls with the credentials chetter uses
+    Token {
+        /// Installation id
+        #[arg(short, long, value_name = "ID", required_unless_present = "repo")]
+        installation: Option<u64>,
+
+        /// Repository the app is installed on
+        #[arg(short, long, value_name = "ORG/NAME", conflicts_with = "installation")]
+        repo: Option<Repo>,
+
+        /// Also print when the token expires, as JSON
+        #[arg(short, long)]
+        json: bool,
+    },
+
+    /// Register a GitHub App from a browser and write its configuration
+    Setup {
+        /// Name of the app, which must be unique across GitHub
+        #[arg(long, default_value = "chetter")]
+        name: String,
+
+        /// Organization owning the app, the signed in user when not given
+        #[arg(long, value_name = "ORG")]
+        org: Option<String>,
+
+        /// URL GitHub delivers webhook events to, such as https://chetter.example.com/github/events
+        #[arg(long, value_name = "URL")]
+        webhook_url: String,
+
+        /// Local port serving the registration flow
+        #[arg(long, default_value_t = 3334)]
+        port: u16,
+
+        /// Configuration file to write, which must not exist
+        #[arg(short, long, value_name = "FILE", default_value = "chetter-app.toml")]
+        output: PathBuf,
+    },
+}
+
+/// Repository given as `<org>/<name>`.
+#[derive(Clone)]
+struct Repo {
+    org: String,
+    name: String,
+}
+
+impl FromStr for Repo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((org, name)) if !org.is_empty() && !name.is_empty() && !name.contains('/') => {
+                Ok(Self {
+                    org: org.into(),
+                    name: name.into(),
+                })
+            }
+            _ => Err(format!("expected <org>/<name>, not {s}")),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    // Setup runs before a configuration exists
+    if let Command::Setup {
+        name,
+        org,
+        webhook_url,
+        port,
+        output,
+    } = command
+    {
+        let options = setup::SetupOptions {
+            name,
+            org,
+            webhook_url,
+            port,
+        };
+        std::process::exit(run_setup(&options, &output).await);
+    }
+    let Some(config) = cli.config else {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--config <FILE> is required",
+            )
+            .exit();
+    };
 
-    let mut opts = Options::new();
-    opts.optflag("h", "help", "print this help menu");
-    opts.optopt("c", "config", "path to config file", "FILE");
-    let matches = opts.parse(&args[1..]).unwrap_or_else(|err| {
-        eprintln!("Failed to parse commandline arguments: {}", &err);
+    let state = State::new(config.clone()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
         std::process::exit(1);
     });
-
-    if matches.opt_present("h") {
-        println!("{}", opts.usage("Usage: chetter-app [OPTIONS]"));
-        std::process::exit(0);
+    // Errors logged from here on, including the spans they were logged in, are reported
+    #[cfg(feature = "sentry")]
+    let _sentry = state.sentry_config().map(|c| {
+        sentry::init((
+            c.dsn.expose(),
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                environment: c.environment.clone().map(Into::into),
+                ..Default::default()
+            },
+        ))
+    });
+    #[cfg(not(feature = "sentry"))]
+    if state.sentry_config().is_some() {
+        eprintln!("Warning: [sentry] is configured but chetter-app was built without it");
     }
 
-    let Some(config_path) = matches.opt_str("c") else {
-        eprintln!("Error: config file (-c,--config) required");
-        std::process::exit(1);
+    // Filtered per layer, tokio-console needs runtime events that should not be logged.  Logs go
+    // to stderr so that the output of commands, such as tokens, can be captured.
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_filter(log_filter()),
+    );
+    #[cfg(feature = "sentry")]
+    let registry = registry.with(sentry::integrations::tracing::layer().with_filter(log_filter()));
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
+
+    let code = match command {
+        Command::Serve => {
+            serve(state.clone()).await;
+            0
+        }
+        Command::VerifyConfig => verify_config(&state, &config),
+        Command::Replay { dry_run, paths } => replay(&state, &paths, dry_run).await,
+        Command::Backfill { repo, pr } => {
+            print_report(state.backfill(&repo.org, &repo.name, pr).await)
+        }
+        Command::Gc { repo, dry_run } => {
+            print_report(state.gc_command(&repo.org, &repo.name, dry_run).await)
+        }
+        Command::ListRefs { repo, pr, json } => list_refs(&state, &repo, pr, json).await,
+        Command::Token {
+            installation,
+            repo,
+            json,
+        } => token(&state, installation, repo, json).await,
+        Command::Setup { .. } => unreachable!(),
     };
+    state.close().await;
 
-    let state = State::new(config_path).unwrap_or_else(|err| {
-        eprintln!("{}", err);
-        std::process::exit(1);
+    #[cfg(feature = "sentry")]
+    drop(_sentry);
+    std::process::exit(code);
+}
+
+/// Register a GitHub App and write its configuration to `output`.
+async fn run_setup(options: &setup::SetupOptions, output: &Path) -> i32 {
+    // Fail before registering an app whose private key could not be saved
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(output)
+    {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}: {e}", output.display());
+            return 1;
+        }
+    };
+
+    println!(
+        "Open {} in a browser signed in to GitHub to register {}",
+        options.start_url(),
+        options.name
+    );
+    let credentials = match setup::run(options).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{e}");
+            let _ = std::fs::remove_file(output);
+            return 1;
+        }
+    };
+    if let Err(e) = file.write_all(credentials.to_config().as_bytes()) {
+        eprintln!("{}: {e}", output.display());
+        eprint!("{}", credentials.to_config());
+        return 1;
+    }
+
+    println!(
+        "Wrote the configuration of {} to {}",
+        credentials.slug,
+        output.display()
+    );
+    println!(
+        "Install it on repositories from {}",
+        credentials.install_url()
+    );
+    0
+}
+
+/// Check what loading the configuration does not, such as the TLS certificates.
+fn verify_config(state: &State, path: &str) -> i32 {
+    if let Some(Err(e)) = state.tls_config().map(tls::server_config) {
+        eprintln!("{e}");
+        return 1;
+    }
+    println!("{path}: ok");
+    0
+}
+
+/// Print the outcome of a command for each pull request, failing if any failed.
+fn print_report(report: Result<BatchReport, ChetterError>) -> i32 {
+    match report {
+        Ok(report) => {
+            println!("{:#}", report.to_json());
+            (!report.is_ok()).into()
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            1
+        }
+    }
+}
+
+/// Print the versions and review bookmarks recorded for a pull request.
+async fn list_refs(state: &State, repo: &Repo, pr: u64, json: bool) -> i32 {
+    match state.pr_history(&repo.org, &repo.name, pr).await {
+        Ok(history) if json => {
+            println!("{:#}", serde_json::json!(history));
+            0
+        }
+        Ok(history) => {
+            print!("{}", history.to_table());
+            0
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            1
+        }
+    }
+}
+
+/// Print an installation access token.
+async fn token(state: &State, installation: Option<u64>, repo: Option<Repo>, json: bool) -> i32 {
+    let repo = repo.as_ref().map(|r| (r.org.as_str(), r.name.as_str()));
+    match state.installation_token(installation, repo).await {
+        Ok(token) if json => {
+            println!("{:#}", serde_json::json!(token));
+            0
+        }
+        Ok(token) => {
+            println!("{}", token.token);
+            0
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            1
+        }
+    }
+}
+
+/// Process recorded webhook payloads again, in the order they were received.
+async fn replay(state: &State, args: &[PathBuf], dry_run: bool) -> i32 {
+    let mut paths = vec![];
+    for path in args {
+        if !path.is_dir() {
+            paths.push(path.clone());
+            continue;
+        }
+        match recorder::recorded_in(path) {
+            Ok(v) => paths.extend(v),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                return 1;
+            }
+        }
+    }
+
+    let mut failed = false;
+    for path in paths {
+        let r = match state.read_payload(&path) {
+            Ok(payload) => state.replay(&payload, dry_run).await,
+            Err(e) => Err(e),
+        };
+        match r {
+            Ok(summary) => println!("{}: {summary}", path.display()),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                failed = true;
+            }
+        }
+    }
+    failed.into()
+}
+
+/// Serve webhook events and the HTTP APIs until a shutdown signal is received.
+async fn serve(state: State) {
+    let tls_config = state.tls_config().map(|c| {
+        tls::server_config(c).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
     });
 
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "info,chetter_app=debug,axum::rejection=trace".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    state.start_leader_election().await;
+    state.start_scheduled_gc();
+    state.start_hook_network_refresh();
+    state.start_delivery_recovery();
+    state.start_secret_rotation();
+    state.start_polling();
 
-    let app = axum::Router::new()
-        .route("/github/events", post(post_github_events))
-        .with_state(state.clone());
+    let access_log = TraceLayer::new_for_http()
+        .make_span_with(|request: &Request<_>| {
+            let delivery = request
+                .headers()
+                .get("X-GitHub-Delivery")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-");
+            tracing::info_span!(
+                target: ACCESS_LOG,
+                "request",
+                method = %request.method(),
+                path = %request.uri().path(),
+                delivery = %delivery,
+            )
+        })
+        .on_request(())
+        .on_response(|response: &Response<_>, latency: Duration, _span: &Span| {
+            info!(
+                target: ACCESS_LOG,
+                status = response.status().as_u16(),
+                latency_ms = latency.as_millis(),
+                "response"
+            );
+        })
+        .on_failure(
+            |failure: ServerErrorsFailureClass, latency: Duration, _span: &Span| {
+                warn!(
+                    target: ACCESS_LOG,
+                    latency_ms = latency.as_millis(),
+                    "failed: {}",
+                    failure
+                );
+            },
+        );
 
-    axum::Server::bind(&"0.0.0.0:3333".parse().unwrap())
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    let app = chetter_app::router(state.clone()).layer(access_log);
 
-    state.close().await;
+    let addr: SocketAddr = "0.0.0.0:3333".parse().unwrap();
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_config {
+        Some(config) => {
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            let incoming = hyper::server::accept::from_stream(tls::incoming(listener, config));
+            axum::Server::builder(incoming)
+                .serve(service)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+        None => axum::Server::bind(&addr)
+            .serve(service)
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap(),
+    }
 }
@@ -0,0 +1,260 @@
+#[cfg(feature = "metrics")]
+use prometheus::{
+    core::Collector, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+#[cfg(feature = "metrics")]
+use serde::Serialize;
+use std::future::Future;
+#[cfg(feature = "metrics")]
+use std::{sync::OnceLock, time::Instant};
+#[cfg(feature = "metrics")]
+use tracing::error;
+
+use crate::error::ChetterError;
+
+/// Prometheus metrics of the application.
+#[cfg(feature = "metrics")]
+pub struct Metrics {
+    registry: Registry,
+
+    /// Requests an installation has left until its rate limit resets
+    pub rate_limit_remaining: IntGaugeVec,
+
+    /// When the rate limit of an installation resets, in seconds since the epoch
+    pub rate_limit_reset: IntGaugeVec,
+
+    /// Duration of GitHub API calls by call
+    pub call_duration: HistogramVec,
+
+    /// GitHub API calls by call and outcome
+    pub calls: IntCounterVec,
+
+    /// Processed webhook events by repository, event and result
+    pub events: IntCounterVec,
+
+    /// Background tasks by state, pending or running
+    pub background_tasks: IntGaugeVec,
+
+    /// Age of the oldest background task that has not finished yet
+    pub oldest_task_age: Gauge,
+
+    /// 1 if this replica runs scheduled jobs, otherwise 0
+    pub leader: IntGauge,
+}
+
+/// Number of webhook events of a repository that were processed with the same result.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventCount {
+    /// Repository as `<org>/<repo>`, empty for events of no repository
+    pub repo: String,
+
+    /// Event name and action, such as `pull_request.opened`
+    pub event: String,
+
+    /// `ok` or `error`
+    pub result: String,
+
+    /// Events processed since starting
+    pub count: u64,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("chetter".into()), None).unwrap();
+        let rate_limit_remaining = IntGaugeVec::new(
+            Opts::new(
+                "github_rate_limit_remaining",
+                "GitHub API requests left until the rate limit resets",
+            ),
+            &["installation"],
+        )
+        .unwrap();
+        let rate_limit_reset = IntGaugeVec::new(
+            Opts::new(
+                "github_rate_limit_reset_timestamp_seconds",
+                "When the GitHub API rate limit resets",
+            ),
+            &["installation"],
+        )
+        .unwrap();
+        // GraphQL deletions may take up to a minute
+        let call_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "github_call_duration_seconds",
+                "Duration of GitHub API calls",
+            )
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 60.0]),
+            &["call"],
+        )
+        .unwrap();
+        let calls = IntCounterVec::new(
+            Opts::new("github_calls_total", "GitHub API calls by outcome"),
+            &["call", "outcome"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(rate_limit_remaining.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rate_limit_reset.clone()))
+            .unwrap();
+        registry.register(Box::new(call_duration.clone())).unwrap();
+        let events = IntCounterVec::new(
+            Opts::new("webhook_events_total", "Processed webhook events"),
+            &["repo", "event", "result"],
+        )
+        .unwrap();
+        let background_tasks = IntGaugeVec::new(
+            Opts::new(
+                "background_tasks",
+                "Background tasks that have not finished yet",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let oldest_task_age = Gauge::new(
+            "background_oldest_task_age_seconds",
+            "Age of the oldest background task that has not finished yet",
+        )
+        .unwrap();
+        let leader = IntGauge::new("leader", "Whether this replica runs scheduled jobs").unwrap();
+        registry.register(Box::new(calls.clone())).unwrap();
+        registry.register(Box::new(events.clone())).unwrap();
+        registry
+            .register(Box::new(background_tasks.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(oldest_task_age.clone()))
+            .unwrap();
+        registry.register(Box::new(leader.clone())).unwrap();
+
+        Self {
+            registry,
+            rate_limit_remaining,
+            rate_limit_reset,
+            call_duration,
+            calls,
+            events,
+            background_tasks,
+            oldest_task_age,
+            leader,
+        }
+    }
+
+    /// Record GitHub API `call` that took `secs` seconds and failed if `ok` is false.
+    pub fn observe_call(&self, call: &str, secs: f64, ok: bool) {
+        let outcome = match ok {
+            true => "ok",
+            false => "error",
+        };
+        self.call_duration.with_label_values(&[call]).observe(secs);
+        self.calls.with_label_values(&[call, outcome]).inc();
+    }
+
+    /// Record webhook `event` of `repo` that was processed, failing if `ok` is false.
+    pub fn observe_event(&self, repo: &str, event: &str, ok: bool) {
+        let result = match ok {
+            true => "ok",
+            false => "error",
+        };
+        self.events.with_label_values(&[repo, event, result]).inc();
+    }
+
+    /// Processed webhook events by repository, event and result.
+    pub fn event_counts(&self) -> Vec<EventCount> {
+        let mut counts = vec![];
+        for family in self.events.collect() {
+            for metric in family.get_metric() {
+                let label = |name: &str| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .find(|l| l.get_name() == name)
+                        .map(|l| l.get_value().to_string())
+                        .unwrap_or_default()
+                };
+                counts.push(EventCount {
+                    repo: label("repo"),
+                    event: label("event"),
+                    result: label("result"),
+                    count: metric.get_counter().get_value() as u64,
+                });
+            }
+        }
+        counts
+    }
+
+    /// Render every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buf = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            error!("Failed to encode metrics: {e}");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+/// Metrics shared by the whole application.
+#[cfg(feature = "metrics")]
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Run GitHub API `call`, recording how long it took and whether it failed.
+#[cfg(feature = "metrics")]
+pub async fn timed<T, F>(call: &str, work: F) -> Result<T, ChetterError>
+where
+    F: Future<Output = Result<T, ChetterError>>,
+{
+    let started = Instant::now();
+    let r = work.await;
+    metrics().observe_call(call, started.elapsed().as_secs_f64(), r.is_ok());
+    r
+}
+
+/// Run GitHub API `call`, which is not recorded without the `metrics` feature.
+#[cfg(not(feature = "metrics"))]
+pub async fn timed<T, F>(_call: &str, work: F) -> Result<T, ChetterError>
+where
+    F: Future<Output = Result<T, ChetterError>>,
+{
+    work.await
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics
+            .rate_limit_remaining
+            .with_label_values(&["42"])
+            .set(4999);
+        metrics.observe_call("delete_refs", 12.0, false);
+        metrics.observe_event("org/repo", "pull_request.opened", true);
+        metrics.observe_event("org/repo", "pull_request.opened", true);
+        assert_eq!(
+            metrics.event_counts(),
+            [EventCount {
+                repo: "org/repo".into(),
+                event: "pull_request.opened".into(),
+                result: "ok".into(),
+                count: 2,
+            }]
+        );
+        let text = metrics.render();
+        assert!(text.contains("chetter_github_rate_limit_remaining{installation=\"42\"} 4999"));
+        assert!(
+            text.contains("chetter_github_calls_total{call=\"delete_refs\",outcome=\"error\"} 1")
+        );
+        assert!(text.contains(
+            "chetter_github_call_duration_seconds_bucket{call=\"delete_refs\",le=\"20\"} 1"
+        ));
+    }
+}
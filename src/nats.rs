@@ -0,0 +1,161 @@
+use serde_json::json;
+use std::{io::ErrorKind, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Lines},
+    net::TcpStream,
+};
+
+use crate::error::ChetterError;
+
+/// How long to wait for the server before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Publish `payload` to `subject` on the NATS server at `url`, such as `nats://localhost:4222`,
+/// authenticating with `token` if given.
+///
+/// A connection is made for each message, which is only considered published once the server
+/// answers a PING sent after it.  Servers requiring TLS are not supported.
+pub async fn publish(
+    url: &str,
+    subject: &str,
+    token: Option<&str>,
+    payload: &[u8],
+) -> Result<(), ChetterError> {
+    if subject.is_empty() || subject.contains(char::is_whitespace) {
+        return Err(ChetterError::Config(format!(
+            "invalid NATS subject '{subject}'"
+        )));
+    }
+    let address = url
+        .strip_prefix("nats://")
+        .unwrap_or(url)
+        .trim_end_matches('/');
+    let address = match address.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => address.to_string(),
+        _ => format!("{address}:4222"),
+    };
+    tokio::time::timeout(TIMEOUT, exchange(&address, subject, token, payload))
+        .await
+        .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))?
+}
+
+async fn exchange(
+    address: &str,
+    subject: &str,
+    token: Option<&str>,
+    payload: &[u8],
+) -> Result<(), ChetterError> {
+    let (read, mut write) = TcpStream::connect(address).await?.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let greeting = next_line(&mut lines).await?;
+    let info: serde_json::Value = greeting
+        .strip_prefix("INFO ")
+        .and_then(|info| serde_json::from_str(info).ok())
+        .ok_or_else(|| refused(&greeting))?;
+    if info["tls_required"] == true {
+        return Err(ChetterError::Config(format!(
+            "NATS server {address} requires TLS, which is not supported"
+        )));
+    }
+
+    let mut connect = json!({
+        "verbose": false,
+        "pedantic": false,
+        "name": "chetter-app",
+        "lang": "rust",
+        "version": env!("CARGO_PKG_VERSION"),
+        "protocol": 0,
+    });
+    if let Some(token) = token {
+        connect["auth_token"] = token.into();
+    }
+    let mut message =
+        format!("CONNECT {connect}\r\nPUB {subject} {}\r\n", payload.len()).into_bytes();
+    message.extend_from_slice(payload);
+    message.extend_from_slice(b"\r\nPING\r\n");
+    write.write_all(&message).await?;
+
+    loop {
+        match next_line(&mut lines).await?.as_str() {
+            "PONG" => return Ok(()),
+            "PING" => write.write_all(b"PONG\r\n").await?,
+            line if line.starts_with("-ERR") => return Err(refused(line)),
+            // +OK and INFO updating the cluster topology
+            _ => {}
+        }
+    }
+}
+
+/// Read the next line sent by the server, which must not close the connection before.
+async fn next_line<R: AsyncRead + Unpin>(
+    lines: &mut Lines<BufReader<R>>,
+) -> std::io::Result<String> {
+    lines
+        .next_line()
+        .await?
+        .ok_or_else(|| ErrorKind::UnexpectedEof.into())
+}
+
+fn refused(line: &str) -> ChetterError {
+    ChetterError::IOError(std::io::Error::other(format!(
+        "NATS server refused the message: {line}"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    /// Accept one connection as a NATS server would, answering the PING with `answer` and
+    /// returning everything the client sent.
+    async fn server(answer: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n")
+                .await
+                .unwrap();
+            let mut received = vec![];
+            while !received.ends_with(b"PING\r\n") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "client hung up");
+                received.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(answer.as_bytes()).await.unwrap();
+            String::from_utf8(received).unwrap()
+        });
+        (url, task)
+    }
+
+    #[tokio::test]
+    async fn published() {
+        let (url, task) = server("PONG\r\n").await;
+        publish(&url, "chetter.events", Some("s3cret"), b"{\"pr\":12}")
+            .await
+            .unwrap();
+
+        let received = task.await.unwrap();
+        let (connect, publish) = received.split_once("\r\n").unwrap();
+        let connect: serde_json::Value =
+            serde_json::from_str(connect.strip_prefix("CONNECT ").unwrap()).unwrap();
+        assert_eq!(connect["auth_token"], "s3cret");
+        assert_eq!(connect["verbose"], false);
+        assert_eq!(publish, "PUB chetter.events 9\r\n{\"pr\":12}\r\nPING\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejected() {
+        let (url, _) = server("-ERR 'Authorization Violation'\r\n").await;
+        let e = publish(&url, "chetter.events", None, b"{}")
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("Authorization Violation"), "{e}");
+
+        assert!(publish(&url, "chetter events", None, b"{}").await.is_err());
+    }
+}
@@ -0,0 +1,698 @@
+use serde::Deserialize;
+
+#[cfg(feature = "notifications")]
+use {
+    crate::{
+        crypto::sign,
+        error::ChetterError,
+        hooks::RefHook,
+        nats,
+        scheduler::{Backoff, Priority, Scheduler},
+    },
+    async_trait::async_trait,
+    serde_json::json,
+    std::{sync::Arc, time::Duration},
+    tracing::warn,
+};
+
+use crate::{
+    audit::{AuditEntry, Operation},
+    config::{AppConfig, RepoConfig, Secret},
+    crypto::random_token,
+    refname::{pr_number, ParsedRef},
+    tracker::ProcessedEvent,
+};
+use chrono::{SecondsFormat, Utc};
+
+/// Where to announce new versions and reviews of pull requests, in the `notifications` table of
+/// a repository.
+///
+/// Templates may use `{repo}`, `{pr}`, `{ref}`, `{sha}` and `{short_sha}`, along with
+/// `{version}` for versions and `{reviewer}` for reviews.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Notifications {
+    /// Message announcing a new version
+    pub version_template: String,
+
+    /// Message announcing that a reviewer's bookmark moved to what they reviewed
+    pub bookmark_template: String,
+
+    /// Post to a Slack channel through an incoming webhook
+    pub slack: Option<SlackSink>,
+
+    /// Send to a Matrix room
+    pub matrix: Option<MatrixSink>,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self {
+            version_template: "{repo}#{pr}: recorded v{version} at {short_sha} as `{ref}`".into(),
+            bookmark_template:
+                "{repo}#{pr}: {reviewer} reviewed {short_sha}, bookmarked as `{ref}`".into(),
+            slack: None,
+            matrix: None,
+        }
+    }
+}
+
+impl Notifications {
+    /// Template of the message announcing `notification`.
+    pub fn template(&self, notification: &Notification) -> &str {
+        match notification.kind {
+            NotificationKind::Version(_) => &self.version_template,
+            NotificationKind::Bookmark(_) => &self.bookmark_template,
+        }
+    }
+
+    /// Every credential of the sinks, to be fetched when the configuration is loaded.
+    pub(crate) fn secrets_mut(&mut self) -> impl Iterator<Item = &mut Secret> {
+        let slack = self.slack.as_mut().map(|s| &mut s.webhook_url);
+        let matrix = self.matrix.as_mut().map(|m| &mut m.access_token);
+        slack.into_iter().chain(matrix)
+    }
+}
+
+/// Slack incoming webhook
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SlackSink {
+    /// URL of the webhook, which is a credential like any secret
+    pub webhook_url: Secret,
+}
+
+/// Matrix room, messaged as the user of an access token
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct MatrixSink {
+    /// Base URL of the homeserver, such as `https://matrix.org`
+    pub homeserver: String,
+
+    /// Id of the room, such as `!abcdef:matrix.org`, which the user must have joined
+    pub room_id: String,
+
+    /// Access token of the user
+    pub access_token: Secret,
+}
+
+/// Endpoint every processed webhook event is posted to, in an `[[outbound_webhooks]]` table.
+///
+/// The body is the event as streamed by the administrative API, see [ProcessedEvent], or a
+/// CloudEvent holding it.  When `secret` is set it is signed like GitHub signs webhook events,
+/// in the `X-Chetter-Signature-256` header.
+///
+/// [ProcessedEvent]: crate::tracker::ProcessedEvent
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OutboundWebhook {
+    /// URL events are posted to
+    pub url: String,
+
+    /// Secret events are signed with
+    pub secret: Option<Secret>,
+
+    /// How events are posted
+    #[serde(default)]
+    pub format: EventFormat,
+}
+
+/// How processed webhook events are posted to outbound webhooks.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFormat {
+    /// The event as streamed by the administrative API
+    #[default]
+    Chetter,
+
+    /// A CloudEvent in structured mode, see [cloud_event]
+    CloudEvents,
+}
+
+/// NATS subject every processed webhook event is published to as a CloudEvent, see
+/// [cloud_event], in an `[[outbound_nats]]` table.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct NatsSink {
+    /// Address of the server, such as `nats://localhost:4222`
+    pub url: String,
+
+    /// Subject events are published to
+    pub subject: String,
+
+    /// Token authenticating to the server
+    pub token: Option<Secret>,
+}
+
+/// What a notification announces.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotificationKind {
+    /// Version `v<N>` was recorded
+    Version(u32),
+
+    /// The bookmark of a reviewer, named as in references, moved to what they reviewed
+    Bookmark(String),
+}
+
+/// A change to the references of a pull request worth announcing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub kind: NotificationKind,
+
+    /// Repository as `<org>/<repo>`
+    pub repo: String,
+
+    pub pr: u64,
+
+    /// Full name of the reference, such as `refs/heads/pr/12/v3`
+    pub ref_name: String,
+
+    /// SHA-1 the reference points to
+    pub sha: String,
+}
+
+impl Notification {
+    /// The notification for `change` in a repository configured with `config`, if it is worth
+    /// announcing: creating a version or moving the head of a reviewer.
+    ///
+    /// Only changes in the namespace references are read from count, so that references written
+    /// to a migration target as well are announced once.
+    pub fn of(change: &AuditEntry, config: &RepoConfig) -> Option<Self> {
+        let name = change
+            .ref_name
+            .strip_prefix(config.ref_namespace())?
+            .strip_prefix('/')?;
+        let pr = pr_number(name)?;
+        let kind = match (
+            change.operation,
+            ParsedRef::parse_full(&config.layout, pr, name),
+        ) {
+            // Redelivered events update the versions they already created
+            (Operation::Create, ParsedRef::Version(n)) => NotificationKind::Version(n),
+            (Operation::Create | Operation::Update, ParsedRef::ReviewerHead(reviewer)) => {
+                NotificationKind::Bookmark(reviewer)
+            }
+            _ => return None,
+        };
+        Some(Self {
+            kind,
+            repo: change.repo.clone(),
+            pr,
+            ref_name: change.ref_name.clone(),
+            sha: change.new_sha.clone()?,
+        })
+    }
+
+    /// Fill the placeholders of `template`.
+    pub fn render(&self, template: &str) -> String {
+        let (version, reviewer) = match self.kind {
+            NotificationKind::Version(n) => (n.to_string(), ""),
+            NotificationKind::Bookmark(ref reviewer) => (String::new(), reviewer.as_str()),
+        };
+        let short_sha = &self.sha[..self.sha.len().min(8)];
+        template
+            .replace("{repo}", &self.repo)
+            .replace("{pr}", &self.pr.to_string())
+            .replace("{ref}", &self.ref_name)
+            .replace("{short_sha}", short_sha)
+            .replace("{sha}", &self.sha)
+            .replace("{version}", &version)
+            .replace("{reviewer}", reviewer)
+    }
+}
+
+/// Wrap `event` in a [CloudEvent](https://cloudevents.io) of JSON format.
+///
+/// Its type is the event prefixed by `io.github.jsbronder.chetter.`, such as
+/// `io.github.jsbronder.chetter.pull_request.synchronize`, its source the repository as
+/// `/<org>/<repo>` and its subject the pull request as `pr/<number>`.  The GitHub delivery id is
+/// kept as the `delivery` extension, as redelivered webhook events are processed again.
+pub fn cloud_event(event: &ProcessedEvent) -> serde_json::Value {
+    let mut cloud = serde_json::json!({
+        "specversion": "1.0",
+        "id": random_token(),
+        "source": format!("/{}", event.repo),
+        "type": format!("io.github.jsbronder.chetter.{}", event.event),
+        "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "datacontenttype": "application/json",
+        "data": event,
+    });
+    if let Some(pr) = event.pr {
+        cloud["subject"] = format!("pr/{pr}").into();
+    }
+    if let Some(ref delivery) = event.delivery {
+        cloud["delivery"] = delivery.as_str().into();
+    }
+    cloud
+}
+
+/// Whether notifications are configured for any repository, or outbound webhooks or NATS
+/// subjects at all.
+pub fn configured(config: &AppConfig) -> bool {
+    !config.outbound_webhooks.is_empty()
+        || !config.outbound_nats.is_empty()
+        || std::iter::once(&config.defaults)
+            .chain(config.repos.values())
+            .any(|r| r.notifications.is_some())
+}
+
+/// Sends the notifications configured for each repository as references change, and processed
+/// webhook events to the outbound webhooks and NATS subjects.
+///
+/// Installed by [State::from_config] when any are configured.  Notifications are sent in the
+/// background, so that slow sinks do not hold up handling events, and failing to notify is
+/// logged, the change has already been made.
+///
+/// [State::from_config]: crate::State::from_config
+#[cfg(feature = "notifications")]
+#[derive(Clone)]
+pub struct Notifier {
+    config: Arc<AppConfig>,
+    http: reqwest::Client,
+    scheduler: Scheduler,
+}
+
+#[cfg(feature = "notifications")]
+impl Notifier {
+    /// How long to wait for a sink before giving up.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Create a Notifier for the repositories of `config`, sending notifications on `scheduler`.
+    pub fn new(config: Arc<AppConfig>, scheduler: Scheduler) -> Result<Self, ChetterError> {
+        let http = reqwest::Client::builder().timeout(Self::TIMEOUT).build()?;
+        Ok(Self {
+            config,
+            http,
+            scheduler,
+        })
+    }
+
+    /// Post `event` to every outbound webhook and publish it to every NATS subject, retrying
+    /// those that fail for reasons that may pass.
+    pub async fn deliver(&self, event: &ProcessedEvent) {
+        let bodies = serde_json::to_vec(event)
+            .and_then(|plain| Ok((plain, serde_json::to_vec(&cloud_event(event))?)));
+        let (plain, cloud) = match bodies {
+            Ok(bodies) => bodies,
+            Err(e) => {
+                warn!("Failed to serialize {}: {e}", event.event);
+                return;
+            }
+        };
+        for webhook in &self.config.outbound_webhooks {
+            let (body, content_type) = match webhook.format {
+                EventFormat::Chetter => (&plain, "application/json"),
+                EventFormat::CloudEvents => (&cloud, "application/cloudevents+json"),
+            };
+            let send = || async {
+                let mut request = self
+                    .http
+                    .post(&webhook.url)
+                    .header("content-type", content_type)
+                    .header("x-chetter-event", &event.event);
+                if let Some(ref secret) = webhook.secret {
+                    request =
+                        request.header("x-chetter-signature-256", sign(secret.expose(), body));
+                }
+                request
+                    .body(body.clone())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok::<_, ChetterError>(())
+            };
+            if let Err(e) = Backoff::BACKGROUND.retry(send).await {
+                warn!(
+                    repo = event.repo,
+                    "Failed to post {} to {}: {e}", event.event, webhook.url
+                );
+            }
+        }
+        for sink in &self.config.outbound_nats {
+            let token = sink.token.as_ref().map(|t| t.expose());
+            let send = || nats::publish(&sink.url, &sink.subject, token, &cloud);
+            if let Err(e) = Backoff::BACKGROUND.retry(send).await {
+                warn!(
+                    repo = event.repo,
+                    "Failed to publish {} to {} on {}: {e}", event.event, sink.subject, sink.url
+                );
+            }
+        }
+    }
+
+    /// Send `text` to the sinks configured for `repo`.
+    async fn notify(&self, repo: &str, text: &str) {
+        let Some(ref notifications) = self.config.repo(repo).notifications else {
+            return;
+        };
+        if let Some(ref slack) = notifications.slack {
+            let body = json!({"text": text});
+            if let Err(e) = self.post(slack.webhook_url.expose(), &body).await {
+                warn!(repo, "Failed to notify Slack: {e}");
+            }
+        }
+        if let Some(ref matrix) = notifications.matrix {
+            if let Err(e) = self.send_matrix(matrix, text).await {
+                warn!(repo, "Failed to notify Matrix: {e}");
+            }
+        }
+    }
+
+    /// POST `body` as JSON to `url`.
+    async fn post(&self, url: &str, body: &serde_json::Value) -> Result<(), ChetterError> {
+        self.http
+            .post(url)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Send `text` to the Matrix room of `sink`.
+    async fn send_matrix(&self, sink: &MatrixSink, text: &str) -> Result<(), ChetterError> {
+        // Transaction ids must never repeat for the access token, also across restarts, or the
+        // homeserver drops the message as a retry of an earlier one
+        let txn = format!("chetter-{}", random_token());
+        let mut url = reqwest::Url::parse(&sink.homeserver)
+            .map_err(|e| ChetterError::Config(format!("{}: {e}", sink.homeserver)))?;
+        url.path_segments_mut()
+            .map_err(|_| ChetterError::Config(format!("{}: not a base URL", sink.homeserver)))?
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                &sink.room_id,
+                "send",
+                "m.room.message",
+                &txn,
+            ]);
+        self.http
+            .put(url)
+            .bearer_auth(sink.access_token.expose())
+            .json(&json!({"msgtype": "m.text", "body": text}))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "notifications")]
+#[async_trait]
+impl RefHook for Notifier {
+    async fn after(&self, change: &AuditEntry) {
+        let config = self.config.repo(&change.repo);
+        let Some(ref notifications) = config.notifications else {
+            return;
+        };
+        let Some(notification) = Notification::of(change, config) else {
+            return;
+        };
+        let text = notification.render(notifications.template(&notification));
+
+        let (notifier, repo) = (self.clone(), change.repo.clone());
+        self.scheduler.spawn(Priority::High, async move {
+            notifier.notify(&repo, &text).await
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Trigger;
+    use chrono::Utc;
+    #[cfg(feature = "notifications")]
+    use tokio_util::task::TaskTracker;
+
+    fn change(name: &str, operation: Operation) -> AuditEntry {
+        AuditEntry {
+            at: Utc::now(),
+            repo: "org/repo".into(),
+            ref_name: name.into(),
+            operation,
+            old_sha: None,
+            new_sha: Some("c0ffee0123456789".into()),
+            trigger: Trigger::default(),
+        }
+    }
+
+    #[test]
+    fn notified() {
+        let config = RepoConfig {
+            migrate_to: Some("refs/heads/chetter".into()),
+            ..Default::default()
+        };
+        let of = |name, operation| Notification::of(&change(name, operation), &config);
+
+        let version = of("refs/heads/pr/12/v3", Operation::Create).unwrap();
+        assert_eq!(version.kind, NotificationKind::Version(3));
+        assert_eq!(version.pr, 12);
+        let bookmark = of("refs/heads/pr/12/nick-head", Operation::Update).unwrap();
+        assert_eq!(bookmark.kind, NotificationKind::Bookmark("nick".into()));
+
+        assert!(of("refs/heads/pr/12/v3", Operation::Update).is_none());
+        assert!(of("refs/heads/pr/12/v3-base", Operation::Create).is_none());
+        assert!(of("refs/heads/pr/12/head", Operation::Update).is_none());
+        assert!(of("refs/heads/pr/12/nick-head", Operation::Delete).is_none());
+        assert!(of("refs/heads/chetter/12/v3", Operation::Create).is_none());
+        assert!(of("refs/tags/chetter/12/v3", Operation::Create).is_none());
+    }
+
+    #[test]
+    fn rendered() {
+        let defaults = Notifications::default();
+        let version = Notification {
+            kind: NotificationKind::Version(3),
+            repo: "org/repo".into(),
+            pr: 12,
+            ref_name: "refs/heads/pr/12/v3".into(),
+            sha: "c0ffee0123456789".into(),
+        };
+        assert_eq!(
+            version.render(defaults.template(&version)),
+            "org/repo#12: recorded v3 at c0ffee01 as `refs/heads/pr/12/v3`"
+        );
+        let bookmark = Notification {
+            kind: NotificationKind::Bookmark("nick".into()),
+            ref_name: "refs/heads/pr/12/nick-head".into(),
+            ..version
+        };
+        assert_eq!(
+            bookmark.render(defaults.template(&bookmark)),
+            "org/repo#12: nick reviewed c0ffee01, bookmarked as `refs/heads/pr/12/nick-head`"
+        );
+        assert_eq!(
+            bookmark.render("{reviewer}@{sha} v{version}"),
+            "nick@c0ffee0123456789 v"
+        );
+    }
+
+    #[test]
+    fn cloud_events() {
+        let event = ProcessedEvent {
+            repo: "org/repo".into(),
+            pr: Some(12),
+            event: "pull_request_review.submitted".into(),
+            delivery: Some("d1".into()),
+            error: None,
+            duration_ms: 7,
+            version: None,
+            refs: vec![],
+        };
+        let cloud = cloud_event(&event);
+        assert_eq!(cloud["specversion"], "1.0");
+        assert_eq!(cloud["source"], "/org/repo");
+        assert_eq!(
+            cloud["type"],
+            "io.github.jsbronder.chetter.pull_request_review.submitted"
+        );
+        assert_eq!(cloud["subject"], "pr/12");
+        assert_eq!(cloud["delivery"], "d1");
+        assert_eq!(cloud["data"], serde_json::to_value(&event).unwrap());
+        assert_ne!(cloud["id"], cloud_event(&event)["id"]);
+
+        let push = cloud_event(&ProcessedEvent {
+            pr: None,
+            delivery: None,
+            ..event
+        });
+        assert!(push.get("subject").is_none());
+        assert!(push.get("delivery").is_none());
+    }
+
+    /// A Notifier for `config`, along with the tracker of the notifications it sends.
+    #[cfg(feature = "notifications")]
+    fn notifier(config: AppConfig) -> (Notifier, TaskTracker) {
+        let tasks = TaskTracker::new();
+        let scheduler = Scheduler::new(tasks.clone(), 1);
+        (Notifier::new(Arc::new(config), scheduler).unwrap(), tasks)
+    }
+
+    #[cfg(feature = "notifications")]
+    #[tokio::test]
+    async fn slack() {
+        use wiremock::{
+            matchers::{body_json, method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let slack = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/services/hook"))
+            .and(body_json(json!({"text": "v3 of #12"})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&slack)
+            .await;
+
+        let mut config = AppConfig::new(1, "key");
+        config.defaults.notifications = Some(Notifications {
+            version_template: "v{version} of #{pr}".into(),
+            slack: Some(SlackSink {
+                webhook_url: format!("{}/services/hook", slack.uri()).into(),
+            }),
+            ..Default::default()
+        });
+        assert!(configured(&config));
+        let (notifier, tasks) = notifier(config);
+        notifier
+            .after(&change("refs/heads/pr/12/v3", Operation::Create))
+            .await;
+        notifier
+            .after(&change("refs/heads/pr/12/v3-base", Operation::Create))
+            .await;
+        tasks.close();
+        tasks.wait().await;
+    }
+
+    #[cfg(feature = "notifications")]
+    #[tokio::test]
+    async fn matrix() {
+        use wiremock::{
+            matchers::{body_json, header, method, path_regex},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let matrix = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path_regex(
+                r"^/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/chetter-[0-9a-f]+$",
+            ))
+            .and(header("authorization", "Bearer syt_token"))
+            .and(body_json(json!({
+                "msgtype": "m.text",
+                "body": "nick reviewed #12",
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"event_id": "$1"})))
+            .expect(2)
+            .mount(&matrix)
+            .await;
+
+        let mut config = AppConfig::new(1, "key");
+        config.defaults.notifications = Some(Notifications {
+            bookmark_template: "{reviewer} reviewed #{pr}".into(),
+            matrix: Some(MatrixSink {
+                homeserver: format!("{}/", matrix.uri()),
+                room_id: "!room:example.org".into(),
+                access_token: "syt_token".into(),
+            }),
+            ..Default::default()
+        });
+        let (notifier, tasks) = notifier(config);
+        for _ in 0..2 {
+            notifier
+                .after(&change("refs/heads/pr/12/nick-head", Operation::Update))
+                .await;
+        }
+        tasks.close();
+        tasks.wait().await;
+
+        let txns: std::collections::HashSet<_> = matrix
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.url.path().to_string())
+            .collect();
+        assert_eq!(txns.len(), 2, "every message is a new transaction");
+    }
+
+    #[cfg(feature = "notifications")]
+    #[tokio::test]
+    async fn outbound() {
+        use crate::tracker::ChangedRef;
+        use wiremock::{
+            matchers::{body_partial_json, header, method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let event = ProcessedEvent {
+            repo: "org/repo".into(),
+            pr: Some(12),
+            event: "pull_request.synchronize".into(),
+            delivery: Some("d1".into()),
+            error: None,
+            duration_ms: 7,
+            version: Some(3),
+            refs: vec![ChangedRef {
+                name: "refs/heads/pr/12/v3".into(),
+                operation: Operation::Create,
+                sha: Some("c0ffee0123456789".into()),
+            }],
+        };
+        let body = serde_json::to_vec(&event).unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/signed"))
+            .and(header("x-chetter-event", "pull_request.synchronize"))
+            .and(header(
+                "x-chetter-signature-256",
+                sign("s3cret", &body).as_str(),
+            ))
+            .and(body_partial_json(json!({
+                "pr": 12,
+                "version": 3,
+                "refs": [{"ref": "refs/heads/pr/12/v3", "operation": "create"}],
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/cloud"))
+            .and(header("content-type", "application/cloudevents+json"))
+            .and(body_partial_json(json!({
+                "specversion": "1.0",
+                "type": "io.github.jsbronder.chetter.pull_request.synchronize",
+                "subject": "pr/12",
+                "data": {"version": 3},
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut config = AppConfig::new(1, "key");
+        config.outbound_webhooks = vec![
+            OutboundWebhook {
+                url: format!("{}/signed", server.uri()),
+                secret: Some("s3cret".into()),
+                format: EventFormat::Chetter,
+            },
+            OutboundWebhook {
+                url: format!("{}/cloud", server.uri()),
+                secret: None,
+                format: EventFormat::CloudEvents,
+            },
+        ];
+        assert!(configured(&config));
+        notifier(config).0.deliver(&event).await;
+
+        let cloud = &server.received_requests().await.unwrap()[1];
+        assert!(cloud
+            .headers
+            .keys()
+            .all(|name| name.as_str() != "x-chetter-signature-256"));
+    }
+}
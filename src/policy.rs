@@ -0,0 +1,196 @@
+use async_trait::async_trait;
+use octocrab::models::webhook_events::payload::{
+    PullRequestReviewWebhookEventPayload, PullRequestWebhookEventPayload,
+};
+
+use crate::{config::RepoConfig, error::ChetterError, github::RepositoryController};
+
+/// Decides which references are made of pull request events, acting on the repository through a
+/// client of type `C`.
+///
+/// Every hook defaults to what chetter-app does out of the box, so that an organization can change
+/// how versions are recorded by overriding only the hooks it cares about and installing the
+/// policy with [State::with_policy].  Hooks are called for webhook events, within the same
+/// locking, retries and cancellation as the default behavior.  Resynchronizing, polling and
+/// garbage collection are not affected.
+///
+/// ```
+/// use async_trait::async_trait;
+/// use chetter_app::{
+///     config::RepoConfig, error::ChetterError, github::RepositoryController, policy::Policy,
+/// };
+/// use octocrab::models::webhook_events::payload::PullRequestWebhookEventPayload;
+///
+/// /// Only track the head of pull requests, without numbered versions.
+/// struct HeadOnly;
+///
+/// #[async_trait]
+/// impl<C: RepositoryController + Clone + Send + Sync + 'static> Policy<C> for HeadOnly {
+///     async fn on_open(
+///         &self,
+///         client: C,
+///         payload: &PullRequestWebhookEventPayload,
+///         _config: &RepoConfig,
+///     ) -> Result<(), ChetterError> {
+///         let head = &payload.pull_request.head.sha;
+///         client.create_ref(&format!("{}/head", payload.number), head).await
+///     }
+///
+///     async fn on_synchronize(
+///         &self,
+///         client: C,
+///         payload: &PullRequestWebhookEventPayload,
+///         _config: &RepoConfig,
+///     ) -> Result<(), ChetterError> {
+///         let head = &payload.pull_request.head.sha;
+///         client.update_ref(&format!("{}/head", payload.number), None, head).await
+///     }
+/// }
+/// ```
+///
+/// [State::with_policy]: crate::State::with_policy
+#[async_trait]
+pub trait Policy<C>: Send + Sync
+where
+    C: RepositoryController + Clone + Send + Sync + 'static,
+{
+    /// Pull request `payload.number` was opened, by default recording its first version.
+    async fn on_open(
+        &self,
+        client: C,
+        payload: &PullRequestWebhookEventPayload,
+        config: &RepoConfig,
+    ) -> Result<(), ChetterError> {
+        let pull = &payload.pull_request;
+        crate::open_pr(
+            client,
+            payload.number,
+            &pull.head.sha,
+            &pull.base.sha,
+            config,
+        )
+        .await
+    }
+
+    /// Pull request `payload.number` was pushed to, by default recording a new version.
+    async fn on_synchronize(
+        &self,
+        client: C,
+        payload: &PullRequestWebhookEventPayload,
+        config: &RepoConfig,
+    ) -> Result<(), ChetterError> {
+        let pull = &payload.pull_request;
+        crate::synchronize_pr(
+            client,
+            payload.number,
+            &pull.head.sha,
+            &pull.base.sha,
+            config,
+        )
+        .await
+    }
+
+    /// `reviewer` submitted, edited or dismissed a review, by default bookmarking or forgetting
+    /// what they reviewed according to the review settings of the repository.
+    async fn on_review(
+        &self,
+        client: C,
+        reviewer: &str,
+        payload: &PullRequestReviewWebhookEventPayload,
+        config: &RepoConfig,
+    ) -> Result<(), ChetterError> {
+        crate::on_pull_request_review(
+            client,
+            config,
+            reviewer,
+            payload.pull_request.number,
+            &payload.pull_request.base.sha,
+            &payload.review,
+        )
+        .await
+    }
+
+    /// Pull request `payload.number` was closed or merged, by default deleting, archiving or
+    /// retaining its references.
+    ///
+    /// This runs in the background and is retried when it fails.
+    async fn on_close(
+        &self,
+        client: C,
+        payload: &PullRequestWebhookEventPayload,
+        config: &RepoConfig,
+    ) -> Result<(), ChetterError> {
+        let merged = payload.pull_request.merged_at.is_some();
+        crate::close_pr(client, payload.number, merged, config.clone()).await
+    }
+}
+
+/// The behavior of chetter-app out of the box.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultPolicy;
+
+impl<C: RepositoryController + Clone + Send + Sync + 'static> Policy<C> for DefaultPolicy {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        fixtures::{Fixture, PullRequestEvent, PullRequestFixture},
+        FakeRepositoryController,
+    };
+    use octocrab::models::webhook_events::WebhookEventPayload;
+
+    fn payload(action: &str, pull: PullRequestFixture) -> Box<PullRequestWebhookEventPayload> {
+        match PullRequestEvent::new(action, pull).event().specific {
+            WebhookEventPayload::PullRequest(p) => p,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Records the head only, leaving everything else to the default.
+    struct HeadOnly;
+
+    #[async_trait]
+    impl Policy<FakeRepositoryController> for HeadOnly {
+        async fn on_open(
+            &self,
+            client: FakeRepositoryController,
+            payload: &PullRequestWebhookEventPayload,
+            _config: &RepoConfig,
+        ) -> Result<(), ChetterError> {
+            let head = &payload.pull_request.head.sha;
+            client
+                .create_ref(&format!("{}/head", payload.number), head)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn overridden() {
+        let config = RepoConfig::default();
+        let opened = payload("opened", PullRequestFixture::new(1).head("c1").base("b1"));
+
+        let fake = FakeRepositoryController::new();
+        DefaultPolicy
+            .on_open(fake.clone(), &opened, &config)
+            .await
+            .unwrap();
+        assert_eq!(fake.refs().len(), 4);
+
+        let fake = FakeRepositoryController::new();
+        HeadOnly
+            .on_open(fake.clone(), &opened, &config)
+            .await
+            .unwrap();
+        assert_eq!(fake.refs().keys().collect::<Vec<_>>(), ["1/head"]);
+
+        // Hooks that are not overridden keep the default behavior
+        let synchronized = payload("synchronize", PullRequestFixture::new(1).head("c2"));
+        HeadOnly
+            .on_synchronize(fake.clone(), &synchronized, &config)
+            .await
+            .unwrap();
+        assert_eq!(fake.refs()["1/head"], "c2");
+        assert_eq!(fake.refs()["1/v1"], "c2");
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+use crate::{error::ChetterError, github::RepositoryController};
+
+/// Reference (rooted at {REF_NS}/*) created and deleted to probe for write access.
+// Ideally this would be `.chetter-probe`, but git does not allow reference name components that
+// start with a dot.
+pub const PROBE_REF: &str = "chetter-probe";
+
+/// How long to trust a failed probe before trying again, in case permissions were granted.
+const DENIED_TTL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug)]
+enum Probe {
+    Writable,
+    Denied { at: Instant, reason: String },
+}
+
+/// The result of probing a single repository, locked while the probe is in flight.
+type ProbeEntry = Arc<Mutex<Option<Probe>>>;
+
+/// Cached results of probing repositories for permission to manage references.
+///
+/// Without write access every event fails with the same error, the probe lets us report the
+/// problem once clearly and then fail fast.  Probes that fail for other reasons, such as server
+/// errors or rate limits, are not cached.
+#[derive(Clone, Default)]
+pub struct PermissionProbes {
+    results: Arc<StdMutex<HashMap<String, ProbeEntry>>>,
+}
+
+impl PermissionProbes {
+    /// Verify that references can be written in `repo`, probing with a reference to `sha` the
+    /// first time the repository is seen.
+    pub async fn ensure_writable(
+        &self,
+        client: &impl RepositoryController,
+        repo: &str,
+        sha: &str,
+    ) -> Result<(), ChetterError> {
+        let entry = self
+            .results
+            .lock()
+            .unwrap()
+            .entry(repo.into())
+            .or_default()
+            .clone();
+        // Held across the probe so that concurrent events for a new repository don't race on
+        // the probe reference, without holding up those of other repositories.
+        let mut result = entry.lock().await;
+
+        match *result {
+            Some(Probe::Writable) => return Ok(()),
+            Some(Probe::Denied { at, ref reason }) if at.elapsed() < DENIED_TTL => {
+                debug!("{} previously denied write access", repo);
+                return Err(ChetterError::PermissionDenied(reason.clone()));
+            }
+            _ => (),
+        }
+
+        match probe(client, sha).await {
+            Ok(()) => {
+                info!("{} is writable", repo);
+                *result = Some(Probe::Writable);
+                Ok(())
+            }
+            Err(e) if e.is_denied() => {
+                let reason = format!(
+                    "cannot write references in {repo}, verify the application has the \
+                    'Contents (read/write)' permission: {e}"
+                );
+                error!("{}", reason);
+                *result = Some(Probe::Denied {
+                    at: Instant::now(),
+                    reason: reason.clone(),
+                });
+                Err(ChetterError::PermissionDenied(reason))
+            }
+            Err(e) => {
+                warn!("Failed to probe {repo} for write access: {e}");
+                *result = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Move the cached result for `old` to `new` after the repository was renamed.
+    pub async fn rename(&self, old: &str, new: &str) {
+        let mut results = self.results.lock().unwrap();
+        if let Some(probe) = results.remove(old) {
+            results.insert(new.into(), probe);
+        }
+    }
+
+    /// Forget the cached result for `repo`.
+    pub async fn forget(&self, repo: &str) {
+        self.results.lock().unwrap().remove(repo);
+    }
+}
+
+async fn probe(client: &impl RepositoryController, sha: &str) -> Result<(), ChetterError> {
+    // A previous probe may have failed to clean up, updating proves write access just as well.
+    if let Err(e) = client.create_ref(PROBE_REF, sha).await {
+        debug!("probe creation failed, trying update: {e}");
+        client.update_ref(PROBE_REF, None, sha).await?;
+    }
+
+    if let Err(e) = client.delete_ref(PROBE_REF).await {
+        warn!("Failed to delete {}: {}", PROBE_REF, e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::MockRepositoryController;
+    use mockall::predicate::*;
+
+    #[tokio::test]
+    async fn probe_once() {
+        let probes = PermissionProbes::default();
+        let mut mock = MockRepositoryController::new();
+        mock.expect_create_ref()
+            .times(1)
+            .with(eq(PROBE_REF), eq("abc"))
+            .returning(|_, _| Ok(()));
+        mock.expect_delete_ref()
+            .times(1)
+            .with(eq(PROBE_REF))
+            .returning(|_| Ok(()));
+
+        assert!(probes.ensure_writable(&mock, "o/r", "abc").await.is_ok());
+        assert!(probes.ensure_writable(&mock, "o/r", "abc").await.is_ok());
+    }
+
+    fn graphql(message: &str) -> ChetterError {
+        ChetterError::GithubGraphqlError(crate::error::GraphqlErrors {
+            errors: vec![crate::error::GraphqlError {
+                message: message.into(),
+                path: vec![],
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn denied_is_cached() {
+        let probes = PermissionProbes::default();
+        let mut mock = MockRepositoryController::new();
+        mock.expect_create_ref()
+            .times(1)
+            .returning(|_, _| Err(graphql("Resource not accessible by integration")));
+        mock.expect_update_ref()
+            .times(1)
+            .returning(|_, _, _| Err(graphql("Resource not accessible by integration")));
+
+        for _ in 0..2 {
+            let r = probes.ensure_writable(&mock, "o/r", "abc").await;
+            assert!(matches!(r, Err(ChetterError::PermissionDenied(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn failure_is_not_cached() {
+        let probes = PermissionProbes::default();
+        let mut mock = MockRepositoryController::new();
+        mock.expect_create_ref()
+            .times(2)
+            .returning(|_, _| Err(graphql("Something went wrong")));
+        mock.expect_update_ref()
+            .times(2)
+            .returning(|_, _, _| Err(graphql("Something went wrong")));
+
+        for _ in 0..2 {
+            let r = probes.ensure_writable(&mock, "o/r", "abc").await;
+            assert!(matches!(r, Err(ChetterError::GithubGraphqlError(_))));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use similar::TextDiff;
+use std::collections::BTreeMap;
+
+use crate::github::Comparison;
+
+/// GitHub rejects comments longer than 65536 characters, leave room for the surrounding text.
+const MAX_DIFF_LEN: usize = 60000;
+
+/// Render the differences between the changes of two versions of a pull request.
+///
+/// Similar to `git range-diff`, but per file rather than per commit: for each file whose patch
+/// differs between the two comparisons, the patch of the previous version is diffed against the
+/// patch of the current version.  Hunk line numbers are ignored so that a rebase alone produces
+/// no output.  Returns None when the changes are identical.
+pub fn render(prev: &Comparison, cur: &Comparison) -> Option<String> {
+    let mut files: BTreeMap<&str, (Option<String>, Option<String>)> = BTreeMap::new();
+    for f in &prev.files {
+        files.entry(&f.filename).or_default().0 = Some(normalize(f.patch.as_deref()));
+    }
+    for f in &cur.files {
+        files.entry(&f.filename).or_default().1 = Some(normalize(f.patch.as_deref()));
+    }
+
+    let mut out = String::new();
+    for (filename, (old, new)) in files {
+        if old == new {
+            continue;
+        }
+
+        out.push_str(&format!("## {filename}\n"));
+        match (old, new) {
+            (Some(_), None) => out.push_str("no longer changed by the pull request\n"),
+            (None, Some(_)) => out.push_str("newly changed by the pull request\n"),
+            (old, new) => {
+                let (old, new) = (old.unwrap_or_default(), new.unwrap_or_default());
+                let diff = TextDiff::from_lines(&old, &new);
+                for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+                    out.push_str(&hunk.to_string());
+                }
+            }
+        }
+
+        if out.len() > MAX_DIFF_LEN {
+            let mut end = MAX_DIFF_LEN;
+            while !out.is_char_boundary(end) {
+                end -= 1;
+            }
+            out.truncate(end);
+            out.push_str("\n... truncated\n");
+            break;
+        }
+    }
+
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+/// Strip line numbers from hunk headers, keeping any function context.
+fn normalize(patch: Option<&str>) -> String {
+    let Some(patch) = patch else {
+        return "binary or too large to display\n".into();
+    };
+
+    patch
+        .lines()
+        .map(|line| match line.strip_prefix("@@") {
+            Some(rest) => match rest.split_once("@@") {
+                Some((_, context)) => format!("@@{context}\n"),
+                None => format!("{line}\n"),
+            },
+            None => format!("{line}\n"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::FilePatch;
+
+    fn comparison(files: &[(&str, &str)]) -> Comparison {
+        Comparison {
+            files: files
+                .iter()
+                .map(|(filename, patch)| FilePatch {
+                    filename: filename.to_string(),
+                    patch: Some(patch.to_string()),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rebase_only() {
+        let prev = comparison(&[("a", "@@ -1,2 +1,2 @@ fn a\n-x\n+y\n")]);
+        let cur = comparison(&[("a", "@@ -10,2 +10,2 @@ fn a\n-x\n+y\n")]);
+        assert_eq!(render(&prev, &cur), None);
+    }
+
+    #[test]
+    fn changed() {
+        let prev = comparison(&[
+            ("a", "@@ -1,2 +1,2 @@\n-x\n+y\n"),
+            ("b", "@@ -1 +1 @@\n-b\n+c\n"),
+        ]);
+        let cur = comparison(&[
+            ("a", "@@ -1,2 +1,2 @@\n-x\n+z\n"),
+            ("c", "@@ -1 +1 @@\n-c\n+d\n"),
+        ]);
+        let out = render(&prev, &cur).unwrap();
+        assert_eq!(
+            out,
+            indoc::indoc! {"
+                ## a
+                @@ -1,3 +1,3 @@
+                 @@
+                 -x
+                -+y
+                ++z
+                ## b
+                no longer changed by the pull request
+                ## c
+                newly changed by the pull request
+            "}
+        );
+    }
+}
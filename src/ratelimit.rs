@@ -0,0 +1,119 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::metrics;
+
+/// Rate limit of an installation as last reported by GitHub.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Remaining {
+    /// Requests left until the limit resets
+    pub remaining: u32,
+
+    /// When the limit resets
+    pub reset: DateTime<Utc>,
+}
+
+/// GitHub API budget shared by every client of the application.
+///
+/// GitHub reports how many requests an installation has left with every response.  Work that can
+/// wait calls [RateBudget::reserve] so that webhook handling keeps some headroom once fewer than
+/// `floor` requests are left.
+#[derive(Debug, Clone, Default)]
+pub struct RateBudget {
+    floor: u32,
+    installations: Arc<Mutex<HashMap<u64, Remaining>>>,
+}
+
+impl RateBudget {
+    /// Create a budget deferring work that can wait once fewer than `floor` requests are left.
+    pub fn new(floor: u32) -> Self {
+        Self {
+            floor,
+            installations: Arc::default(),
+        }
+    }
+
+    /// Record the `x-ratelimit-remaining` and `x-ratelimit-reset` headers of a response to
+    /// installation `id`.
+    pub fn record(&self, id: u64, remaining: &str, reset: &str) {
+        let (Ok(remaining), Some(reset)) = (
+            remaining.parse::<u32>(),
+            reset
+                .parse()
+                .ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single()),
+        ) else {
+            return;
+        };
+        #[cfg(feature = "metrics")]
+        {
+            let installation = id.to_string();
+            metrics()
+                .rate_limit_remaining
+                .with_label_values(&[&installation])
+                .set(remaining.into());
+            metrics()
+                .rate_limit_reset
+                .with_label_values(&[&installation])
+                .set(reset.timestamp());
+        }
+        self.installations
+            .lock()
+            .unwrap()
+            .insert(id, Remaining { remaining, reset });
+    }
+
+    /// Rate limit of installation `id`, if any response was seen yet.
+    pub fn remaining(&self, id: u64) -> Option<Remaining> {
+        self.installations.lock().unwrap().get(&id).copied()
+    }
+
+    /// Wait until installation `id` has more than the floor of requests left or its limit reset.
+    pub async fn reserve(&self, id: u64) {
+        let Some(wait) = self.wait(id, Utc::now()) else {
+            return;
+        };
+        info!("installation {id}: rate limit budget is low, waiting {wait:?}");
+        tokio::time::sleep(wait).await;
+    }
+
+    fn wait(&self, id: u64, now: DateTime<Utc>) -> Option<std::time::Duration> {
+        let r = self.remaining(id)?;
+        match r.remaining < self.floor {
+            true => (r.reset - now).to_std().ok(),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn wait() {
+        let budget = RateBudget::new(100);
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        assert_eq!(budget.wait(1, now), None);
+
+        budget.record(1, "4000", "1700000600");
+        assert_eq!(budget.wait(1, now), None);
+
+        budget.record(1, "99", "1700000600");
+        assert_eq!(
+            budget.wait(1, now),
+            Some(std::time::Duration::from_secs(600))
+        );
+        assert_eq!(budget.wait(2, now), None);
+        assert_eq!(budget.wait(1, now + Duration::seconds(601)), None);
+
+        budget.record(1, "garbage", "1700000600");
+        assert_eq!(budget.remaining(1).unwrap().remaining, 99);
+    }
+}
@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::warn;
+
+use crate::{config::PayloadRecording, crypto::Envelope, error::ChetterError};
+
+/// Extension of recorded payloads, which are sealed when an encryption key is configured.
+const EXTENSION: &str = "json.gz";
+
+/// A webhook delivery as it was received.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedPayload {
+    /// When the delivery was received
+    pub received_at: DateTime<Utc>,
+
+    /// Request headers, such as `x-github-event` and `x-github-delivery`
+    pub headers: BTreeMap<String, String>,
+
+    /// Request body, exactly as received
+    pub body: String,
+}
+
+/// Writes webhook deliveries to a directory, keeping only the most recent ones.
+///
+/// Each delivery is a gzip compressed JSON file, named so that they sort by when they were
+/// received.  Files are sealed with the storage encryption key when one is configured.
+#[derive(Clone)]
+pub struct PayloadRecorder {
+    directory: PathBuf,
+    max_files: usize,
+    envelope: Option<Envelope>,
+    seq: Arc<AtomicU64>,
+}
+
+impl PayloadRecorder {
+    /// Create a recorder for `settings`, creating its directory if needed.
+    pub fn new(
+        settings: &PayloadRecording,
+        envelope: Option<Envelope>,
+    ) -> Result<Self, ChetterError> {
+        fs::create_dir_all(&settings.directory)?;
+        Ok(Self {
+            directory: settings.directory.clone(),
+            max_files: settings.max_files,
+            envelope,
+            seq: Arc::default(),
+        })
+    }
+
+    /// Write `payload` without blocking the runtime, returning the path it was written to.
+    pub async fn record(&self, payload: RecordedPayload) -> Result<PathBuf, ChetterError> {
+        let recorder = self.clone();
+        tokio::task::spawn_blocking(move || recorder.write(&payload)).await?
+    }
+
+    /// Compress, seal and write `payload`, then rotate.
+    fn write(&self, payload: &RecordedPayload) -> Result<PathBuf, ChetterError> {
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&serde_json::to_vec(payload).map_err(std::io::Error::from)?)?;
+        let mut data = gz.finish()?;
+        if let Some(ref envelope) = self.envelope {
+            data = envelope.seal(&data)?;
+        }
+
+        // Deliveries received within the same microsecond still sort by arrival
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) % 1000;
+        let delivery = payload
+            .headers
+            .get("x-github-delivery")
+            .map(|d| sanitize(d))
+            .unwrap_or_else(|| "unknown".into());
+        let path = self.directory.join(format!(
+            "{}-{seq:03}-{delivery}.{EXTENSION}",
+            payload.received_at.format("%Y%m%dT%H%M%S%.6fZ")
+        ));
+        fs::write(&path, data)?;
+
+        self.rotate();
+        Ok(path)
+    }
+
+    /// Read a payload previously written by [PayloadRecorder::record].
+    pub fn read(&self, path: &Path) -> Result<RecordedPayload, ChetterError> {
+        read(path, self.envelope.as_ref())
+    }
+
+    /// Paths of every recorded payload, oldest first.
+    pub fn recorded(&self) -> Result<Vec<PathBuf>, ChetterError> {
+        recorded_in(&self.directory)
+    }
+
+    /// Delete the oldest payloads beyond `max_files`.
+    fn rotate(&self) {
+        let paths = match self.recorded() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("{}: failed to list payloads: {e}", self.directory.display());
+                return;
+            }
+        };
+        let excess = paths.len().saturating_sub(self.max_files);
+        for path in &paths[..excess] {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("{}: failed to delete: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// Read a recorded payload, opening it with `envelope` if it was sealed.
+pub fn read(path: &Path, envelope: Option<&Envelope>) -> Result<RecordedPayload, ChetterError> {
+    let mut data = fs::read(path)?;
+    if let Some(envelope) = envelope {
+        data = envelope.open(&data)?;
+    }
+    let mut json = vec![];
+    GzDecoder::new(&data[..]).read_to_end(&mut json)?;
+    Ok(serde_json::from_slice(&json).map_err(std::io::Error::from)?)
+}
+
+/// Paths of every payload recorded in `directory`, oldest first.
+pub fn recorded_in(directory: &Path) -> Result<Vec<PathBuf>, ChetterError> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with(EXTENSION))
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Keep delivery ids, which are GUIDs, from escaping the directory.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(delivery: &str) -> RecordedPayload {
+        RecordedPayload {
+            received_at: Utc::now(),
+            headers: BTreeMap::from([
+                ("x-github-event".into(), "ping".into()),
+                ("x-github-delivery".into(), delivery.into()),
+            ]),
+            body: r#"{"zen": "Keep it logically awesome."}"#.into(),
+        }
+    }
+
+    fn recorder(name: &str, envelope: Option<Envelope>) -> PayloadRecorder {
+        let directory = std::env::temp_dir().join(format!("chetter-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let settings = PayloadRecording {
+            directory,
+            max_files: 2,
+        };
+        PayloadRecorder::new(&settings, envelope).unwrap()
+    }
+
+    #[tokio::test]
+    async fn record_and_rotate() {
+        let recorder = recorder("payloads", None);
+        let first = recorder.record(payload("a")).await.unwrap();
+        let second = payload("../b");
+        let path = recorder.record(second.clone()).await.unwrap();
+        assert_eq!(path.parent(), Some(recorder.directory.as_path()));
+        assert_eq!(recorder.read(&path).unwrap(), second);
+
+        recorder.record(payload("c")).await.unwrap();
+        let recorded = recorder.recorded().unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert!(!recorded.contains(&first));
+        fs::remove_dir_all(&recorder.directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sealed() {
+        let envelope = Envelope::from_base64(&Envelope::generate_key()).unwrap();
+        let recorder = recorder("sealed-payloads", Some(envelope));
+        let payload = payload("a");
+        let path = recorder.record(payload.clone()).await.unwrap();
+        assert!(!fs::read(&path).unwrap().starts_with(&[0x1f, 0x8b]));
+        assert_eq!(recorder.read(&path).unwrap(), payload);
+        fs::remove_dir_all(&recorder.directory).unwrap();
+    }
+}
@@ -0,0 +1,920 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+
+/// A reference belonging to a pull request.
+///
+/// Names below are relative to `{REF_NS}/<pull request>/` in the default layout, see `RefLayout`
+/// for changing them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedRef {
+    /// `head`, the most recent version
+    Head,
+
+    /// `head-base`, base of the most recent version
+    HeadBase,
+
+    /// `latest`, the most recently recorded version
+    Latest,
+
+    /// `latest-base`, base of the most recently recorded version
+    LatestBase,
+
+    /// `v<N>`
+    Version(u32),
+
+    /// `v<N>-base`
+    VersionBase(u32),
+
+    /// `v<N>-rebase`, marks a version that only rebased the prior version
+    VersionRebase(u32),
+
+    /// `v<N>-mergebase`, merge-base of a version and its base
+    VersionMergeBase(u32),
+
+    /// `v<N>-newbase`, merge-base of a version and its force-pushed base branch
+    VersionNewBase(u32),
+
+    /// `<reviewer>-head`, the most recent review
+    ReviewerHead(String),
+
+    /// `<reviewer>-head-base`, base of the most recent review
+    ReviewerHeadBase(String),
+
+    /// `<reviewer>-v<N>`
+    ReviewerVersion(String, u32),
+
+    /// `<reviewer>-v<N>-base`
+    ReviewerVersionBase(String, u32),
+
+    /// `<reviewer>-<review>`, where `<review>` is `r<review id>` or `t<submission time>`
+    ReviewerReview(String, String),
+
+    /// `<reviewer>-<review>-base`
+    ReviewerReviewBase(String, String),
+
+    /// `<reviewer>-requested`, the head when a review was last requested from the reviewer
+    ReviewerRequested(String),
+
+    /// `<reviewer>-subscribed`, marks a reviewer whose `<reviewer>-head` follows every version,
+    /// at the head when they subscribed
+    ReviewerSubscribed(String),
+
+    /// `team-<slug>-head`, the head when a review was last requested from a team
+    TeamHead(String),
+
+    /// `disabled`, marks a pull request no longer recorded, at its head when it was disabled
+    Disabled,
+
+    /// `snapshot-<name>`, the head when snapshot `<name>` was taken on demand
+    Snapshot(String),
+
+    /// `snapshot-<name>-base`, the base when snapshot `<name>` was taken
+    SnapshotBase(String),
+
+    /// Anything else
+    Unknown,
+}
+
+impl ParsedRef {
+    /// Parse a reference name relative to its pull request in the default layout, such as
+    /// `v2-base`.
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "head" => return Self::Head,
+            "head-base" => return Self::HeadBase,
+            "latest" => return Self::Latest,
+            "latest-base" => return Self::LatestBase,
+            "disabled" => return Self::Disabled,
+            _ => (),
+        }
+
+        if let Some(rest) = name.strip_prefix('v') {
+            let (number, suffix) = rest.split_once('-').unwrap_or((rest, ""));
+            if let Some(n) = parse_number(number) {
+                match suffix {
+                    "" => return Self::Version(n),
+                    "base" => return Self::VersionBase(n),
+                    "rebase" => return Self::VersionRebase(n),
+                    "mergebase" => return Self::VersionMergeBase(n),
+                    "newbase" => return Self::VersionNewBase(n),
+                    _ => (),
+                }
+            }
+        }
+
+        if let Some(slug) = name
+            .strip_prefix("team-")
+            .and_then(|n| n.strip_suffix("-head"))
+        {
+            if !slug.is_empty() {
+                return Self::TeamHead(slug.into());
+            }
+        }
+
+        // Logins may contain '-', so match the suffix first.
+        if let Some(reviewer) = name.strip_suffix("-head-base") {
+            return Self::for_reviewer(reviewer, Self::ReviewerHeadBase);
+        }
+        if let Some(reviewer) = name.strip_suffix("-head") {
+            return Self::for_reviewer(reviewer, Self::ReviewerHead);
+        }
+        if let Some(reviewer) = name.strip_suffix("-requested") {
+            return Self::for_reviewer(reviewer, Self::ReviewerRequested);
+        }
+        if let Some(reviewer) = name.strip_suffix("-subscribed") {
+            return Self::for_reviewer(reviewer, Self::ReviewerSubscribed);
+        }
+        let (rest, base) = match name.strip_suffix("-base") {
+            Some(rest) => (rest, true),
+            None => (name, false),
+        };
+        if let Some((reviewer, version)) = rest.rsplit_once("-v") {
+            if let Some(n) = parse_number(version) {
+                return Self::for_reviewer(reviewer, |r| match base {
+                    true => Self::ReviewerVersionBase(r, n),
+                    false => Self::ReviewerVersion(r, n),
+                });
+            }
+        }
+        if let Some((reviewer, review)) = rest.rsplit_once('-') {
+            if is_review_key(review) {
+                return Self::for_reviewer(reviewer, |r| match base {
+                    true => Self::ReviewerReviewBase(r, review.into()),
+                    false => Self::ReviewerReview(r, review.into()),
+                });
+            }
+        }
+        // After reviewer references, as logins may start with `snapshot-`
+        if let Some(snapshot) = rest.strip_prefix("snapshot-") {
+            if is_snapshot_name(snapshot) {
+                return match base {
+                    true => Self::SnapshotBase(snapshot.into()),
+                    false => Self::Snapshot(snapshot.into()),
+                };
+            }
+        }
+
+        Self::Unknown
+    }
+
+    /// Parse the name of a reference (rooted at `{REF_NS}/`) if it belongs to pull request `pr`,
+    /// such as `1234/v2-base`, using `layout`.
+    pub fn parse_full(layout: &RefLayout, pr: u64, full_name: &str) -> Self {
+        layout.parse(pr, full_name)
+    }
+
+    /// Name of the reference relative to its pull request in the default layout.
+    pub fn name(&self) -> String {
+        match self {
+            Self::Head => "head".into(),
+            Self::HeadBase => "head-base".into(),
+            Self::Latest => "latest".into(),
+            Self::LatestBase => "latest-base".into(),
+            Self::Version(n) => format!("v{n}"),
+            Self::VersionBase(n) => format!("v{n}-base"),
+            Self::VersionRebase(n) => format!("v{n}-rebase"),
+            Self::VersionMergeBase(n) => format!("v{n}-mergebase"),
+            Self::VersionNewBase(n) => format!("v{n}-newbase"),
+            Self::ReviewerHead(r) => format!("{r}-head"),
+            Self::ReviewerHeadBase(r) => format!("{r}-head-base"),
+            Self::ReviewerVersion(r, n) => format!("{r}-v{n}"),
+            Self::ReviewerVersionBase(r, n) => format!("{r}-v{n}-base"),
+            Self::ReviewerReview(r, k) => format!("{r}-{k}"),
+            Self::ReviewerReviewBase(r, k) => format!("{r}-{k}-base"),
+            Self::ReviewerRequested(r) => format!("{r}-requested"),
+            Self::ReviewerSubscribed(r) => format!("{r}-subscribed"),
+            Self::TeamHead(t) => format!("team-{t}-head"),
+            Self::Disabled => "disabled".into(),
+            Self::Snapshot(s) => format!("snapshot-{s}"),
+            Self::SnapshotBase(s) => format!("snapshot-{s}-base"),
+            Self::Unknown => String::new(),
+        }
+    }
+
+    /// Name of the reference (rooted at `{REF_NS}/`) for pull request `pr` using `layout`.
+    pub fn full_name(&self, layout: &RefLayout, pr: u64) -> String {
+        layout.format(self, pr)
+    }
+
+    /// Version of the pull request this reference belongs to, None for reviewer references.
+    pub fn version(&self) -> Option<u32> {
+        match self {
+            Self::Version(n)
+            | Self::VersionBase(n)
+            | Self::VersionRebase(n)
+            | Self::VersionMergeBase(n)
+            | Self::VersionNewBase(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Login of the reviewer this reference belongs to, if any.
+    pub fn reviewer(&self) -> Option<&str> {
+        match self {
+            Self::ReviewerHead(r)
+            | Self::ReviewerHeadBase(r)
+            | Self::ReviewerVersion(r, _)
+            | Self::ReviewerVersionBase(r, _)
+            | Self::ReviewerReview(r, _)
+            | Self::ReviewerReviewBase(r, _)
+            | Self::ReviewerRequested(r)
+            | Self::ReviewerSubscribed(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// Reference recording the base of this head, None if it is not a head.
+    pub fn base(&self) -> Option<Self> {
+        Some(match self {
+            Self::Head => Self::HeadBase,
+            Self::Latest => Self::LatestBase,
+            Self::Version(n) => Self::VersionBase(*n),
+            Self::ReviewerHead(r) => Self::ReviewerHeadBase(r.clone()),
+            Self::ReviewerVersion(r, n) => Self::ReviewerVersionBase(r.clone(), *n),
+            Self::ReviewerReview(r, k) => Self::ReviewerReviewBase(r.clone(), k.clone()),
+            Self::Snapshot(s) => Self::SnapshotBase(s.clone()),
+            _ => return None,
+        })
+    }
+
+    /// Whether the reference is named by a template of `RefLayout`, or is unknown.
+    fn is_templated(&self) -> bool {
+        matches!(
+            self,
+            Self::Head
+                | Self::HeadBase
+                | Self::Version(_)
+                | Self::VersionBase(_)
+                | Self::VersionRebase(_)
+                | Self::VersionMergeBase(_)
+                | Self::VersionNewBase(_)
+                | Self::ReviewerHead(_)
+                | Self::ReviewerHeadBase(_)
+                | Self::ReviewerVersion(_, _)
+                | Self::ReviewerVersionBase(_, _)
+                | Self::Unknown
+        )
+    }
+
+    fn for_reviewer(reviewer: &str, make: impl FnOnce(String) -> Self) -> Self {
+        if reviewer.is_empty() {
+            Self::Unknown
+        } else {
+            make(reviewer.into())
+        }
+    }
+}
+
+/// Templates naming the references of each pull request.
+///
+/// Every template starts with `{pr}/`, the pull request number, and may use the `{version}` and
+/// `{reviewer}` placeholders as required by the kind of reference.  Base references append
+/// `-base` to the name, and the `-rebase`, `-mergebase` and `-newbase` markers are appended to the
+/// version.  The `latest` aliases and references for team review requests, review requests,
+/// subscriptions and reviews named by id or time, snapshots and the `disabled` marker are always
+/// named as in the default layout.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, try_from = "RawRefLayout")]
+pub struct RefLayout {
+    /// The most recent version, `{pr}/head`
+    pub head: String,
+
+    /// Each version, `{pr}/v{version}`
+    pub version: String,
+
+    /// The most recent review by a reviewer, `{pr}/{reviewer}-head`
+    pub reviewer_head: String,
+
+    /// Each review by a reviewer, `{pr}/{reviewer}-v{version}`
+    pub reviewer_version: String,
+}
+
+impl Default for RefLayout {
+    fn default() -> Self {
+        Self {
+            head: "{pr}/head".into(),
+            version: "{pr}/v{version}".into(),
+            reviewer_head: "{pr}/{reviewer}-head".into(),
+            reviewer_version: "{pr}/{reviewer}-v{version}".into(),
+        }
+    }
+}
+
+/// `RefLayout` as written in the configuration, before it is validated.
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawRefLayout {
+    head: String,
+    version: String,
+    reviewer_head: String,
+    reviewer_version: String,
+}
+
+impl Default for RawRefLayout {
+    fn default() -> Self {
+        let RefLayout {
+            head,
+            version,
+            reviewer_head,
+            reviewer_version,
+        } = RefLayout::default();
+        Self {
+            head,
+            version,
+            reviewer_head,
+            reviewer_version,
+        }
+    }
+}
+
+impl TryFrom<RawRefLayout> for RefLayout {
+    type Error = String;
+
+    fn try_from(raw: RawRefLayout) -> Result<Self, Self::Error> {
+        let layout = Self {
+            head: raw.head,
+            version: raw.version,
+            reviewer_head: raw.reviewer_head,
+            reviewer_version: raw.reviewer_version,
+        };
+        layout.validate()?;
+        Ok(layout)
+    }
+}
+
+/// A piece of a template after `{pr}/`.
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Literal(&'a str),
+    Version,
+    Reviewer,
+}
+
+impl RefLayout {
+    /// Check that every template uses only the placeholders its kind of reference requires, and
+    /// that no two references share a name.
+    pub fn validate(&self) -> Result<(), String> {
+        for (key, template, version, reviewer) in [
+            ("head", &self.head, false, false),
+            ("version", &self.version, true, false),
+            ("reviewer_head", &self.reviewer_head, false, true),
+            ("reviewer_version", &self.reviewer_version, true, true),
+        ] {
+            let tokens = tokenize(template).ok_or_else(|| {
+                format!("layout.{key} must start with '{{pr}}/' followed by a name using only {{version}} and {{reviewer}}: {template}")
+            })?;
+            let count = |t: Token| tokens.iter().filter(|&x| *x == t).count();
+            if count(Token::Version) != usize::from(version)
+                || count(Token::Reviewer) != usize::from(reviewer)
+            {
+                return Err(format!(
+                    "layout.{key} must use {} exactly once: {template}",
+                    match (version, reviewer) {
+                        (false, false) => "no placeholder other than {pr}",
+                        (true, false) => "{version}",
+                        (false, true) => "{reviewer}",
+                        (true, true) => "{version} and {reviewer}",
+                    }
+                ));
+            }
+        }
+
+        // Such as a head named `{pr}/latest` or reviewer heads named `{pr}/{reviewer}-requested`
+        let reviewer = || "nick".to_string();
+        for r in [
+            ParsedRef::Head,
+            ParsedRef::HeadBase,
+            ParsedRef::Latest,
+            ParsedRef::LatestBase,
+            ParsedRef::Version(3),
+            ParsedRef::VersionBase(3),
+            ParsedRef::VersionRebase(3),
+            ParsedRef::VersionMergeBase(3),
+            ParsedRef::VersionNewBase(3),
+            ParsedRef::ReviewerHead(reviewer()),
+            ParsedRef::ReviewerHeadBase(reviewer()),
+            ParsedRef::ReviewerVersion(reviewer(), 3),
+            ParsedRef::ReviewerVersionBase(reviewer(), 3),
+            ParsedRef::ReviewerReview(reviewer(), "r1".into()),
+            ParsedRef::ReviewerReviewBase(reviewer(), "r1".into()),
+            ParsedRef::ReviewerRequested(reviewer()),
+            ParsedRef::ReviewerSubscribed(reviewer()),
+            ParsedRef::TeamHead("core".into()),
+            ParsedRef::Disabled,
+            ParsedRef::Snapshot("rc1".into()),
+            ParsedRef::SnapshotBase("rc1".into()),
+        ] {
+            let name = self.format(&r, 12);
+            // Team heads are told apart from reviewer heads by parsing them first
+            let ambiguous = !matches!(r, ParsedRef::TeamHead(_)) && self.readings(&name) > 1;
+            if ambiguous || self.parse(12, &name) != r {
+                return Err(format!(
+                    "layout names `{}` as `{name}`, which is read back as another reference",
+                    r.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// How many ways `full_name` of pull request 12 can be read: by each template followed by
+    /// the suffixes of its kind of reference, or as a reference that is not templated.
+    fn readings(&self, full_name: &str) -> usize {
+        let name = full_name.strip_prefix("12/").unwrap_or(full_name);
+        let versions = ["", "-base", "-rebase", "-mergebase", "-newbase"];
+        let templated = [
+            (&self.head, &versions[..2]),
+            (&self.version, &versions[..]),
+            (&self.reviewer_head, &versions[..2]),
+            (&self.reviewer_version, &versions[..2]),
+        ]
+        .into_iter()
+        .flat_map(|(t, suffixes)| suffixes.iter().map(move |s| (t, s)))
+        .filter(|(t, s)| matches(t, name, s).is_some())
+        .count();
+        templated + usize::from(!ParsedRef::parse(name).is_templated())
+    }
+
+    /// Parse the name of a reference if it belongs to pull request `pr`.
+    pub fn parse(&self, pr: u64, full_name: &str) -> ParsedRef {
+        let Some(name) = full_name
+            .strip_prefix(&pr.to_string())
+            .and_then(|n| n.strip_prefix('/'))
+        else {
+            return ParsedRef::Unknown;
+        };
+
+        if matches(&self.head, name, "").is_some() {
+            return ParsedRef::Head;
+        }
+        if matches(&self.head, name, "-base").is_some() {
+            return ParsedRef::HeadBase;
+        }
+        for (suffix, make) in [
+            ("", ParsedRef::Version as fn(u32) -> ParsedRef),
+            ("-base", ParsedRef::VersionBase),
+            ("-rebase", ParsedRef::VersionRebase),
+            ("-mergebase", ParsedRef::VersionMergeBase),
+            ("-newbase", ParsedRef::VersionNewBase),
+        ] {
+            if let Some((Some(n), _)) = matches(&self.version, name, suffix) {
+                return make(n);
+            }
+        }
+
+        // Match team references first as logins may start with `team-`
+        let fixed = ParsedRef::parse(name);
+        if let ParsedRef::TeamHead(_) = fixed {
+            return fixed;
+        }
+
+        if let Some((_, Some(r))) = matches(&self.reviewer_head, name, "-base") {
+            return ParsedRef::ReviewerHeadBase(r.into());
+        }
+        if let Some((_, Some(r))) = matches(&self.reviewer_head, name, "") {
+            return ParsedRef::ReviewerHead(r.into());
+        }
+        if let Some((Some(n), Some(r))) = matches(&self.reviewer_version, name, "") {
+            return ParsedRef::ReviewerVersion(r.into(), n);
+        }
+        if let Some((Some(n), Some(r))) = matches(&self.reviewer_version, name, "-base") {
+            return ParsedRef::ReviewerVersionBase(r.into(), n);
+        }
+
+        match fixed.is_templated() {
+            false => fixed,
+            true => ParsedRef::Unknown,
+        }
+    }
+
+    /// Name of the reference `r` for pull request `pr`.
+    pub fn format(&self, r: &ParsedRef, pr: u64) -> String {
+        let fill = |template: &str, version: Option<u32>, reviewer: &str, suffix: &str| {
+            let mut name = template.replacen("{pr}", &pr.to_string(), 1);
+            if let Some(n) = version {
+                name = name.replacen("{version}", &n.to_string(), 1);
+            }
+            format!("{}{suffix}", name.replacen("{reviewer}", reviewer, 1))
+        };
+
+        match r {
+            ParsedRef::Head => fill(&self.head, None, "", ""),
+            ParsedRef::HeadBase => fill(&self.head, None, "", "-base"),
+            ParsedRef::Version(n) => fill(&self.version, Some(*n), "", ""),
+            ParsedRef::VersionBase(n) => fill(&self.version, Some(*n), "", "-base"),
+            ParsedRef::VersionRebase(n) => fill(&self.version, Some(*n), "", "-rebase"),
+            ParsedRef::VersionMergeBase(n) => fill(&self.version, Some(*n), "", "-mergebase"),
+            ParsedRef::VersionNewBase(n) => fill(&self.version, Some(*n), "", "-newbase"),
+            ParsedRef::ReviewerHead(rv) => fill(&self.reviewer_head, None, rv, ""),
+            ParsedRef::ReviewerHeadBase(rv) => fill(&self.reviewer_head, None, rv, "-base"),
+            ParsedRef::ReviewerVersion(rv, n) => fill(&self.reviewer_version, Some(*n), rv, ""),
+            ParsedRef::ReviewerVersionBase(rv, n) => {
+                fill(&self.reviewer_version, Some(*n), rv, "-base")
+            }
+            _ => format!("{pr}/{}", r.name()),
+        }
+    }
+}
+
+/// Split the part of `template` after `{pr}/` into tokens, None if it is malformed.
+fn tokenize(template: &str) -> Option<Vec<Token<'_>>> {
+    let mut rest = template.strip_prefix("{pr}/")?;
+    let mut tokens = vec![];
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("{version}") {
+            tokens.push(Token::Version);
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("{reviewer}") {
+            tokens.push(Token::Reviewer);
+            rest = r;
+        } else {
+            let end = rest.find('{').unwrap_or(rest.len());
+            if end == 0 || rest[..end].contains('}') {
+                return None;
+            }
+            tokens.push(Token::Literal(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    (!tokens.is_empty()).then_some(tokens)
+}
+
+/// Match `name` against `template` followed by `suffix`, returning the version and reviewer.
+fn matches<'a>(
+    template: &str,
+    name: &'a str,
+    suffix: &str,
+) -> Option<(Option<u32>, Option<&'a str>)> {
+    let tokens = tokenize(template)?;
+    let name = name.strip_suffix(suffix)?;
+    let mut captured = (None, None);
+    match_tokens(&tokens, name, &mut captured).then_some(captured)
+}
+
+fn match_tokens<'a>(
+    tokens: &[Token],
+    name: &'a str,
+    captured: &mut (Option<u32>, Option<&'a str>),
+) -> bool {
+    let Some((first, rest)) = tokens.split_first() else {
+        return name.is_empty();
+    };
+    match first {
+        Token::Literal(l) => name
+            .strip_prefix(l)
+            .is_some_and(|n| match_tokens(rest, n, captured)),
+        Token::Version => {
+            let end = name.bytes().take_while(u8::is_ascii_digit).count();
+            match parse_number(&name[..end]) {
+                Some(n) if match_tokens(rest, &name[end..], captured) => {
+                    captured.0 = Some(n);
+                    true
+                }
+                _ => false,
+            }
+        }
+        // Logins may contain '-', so prefer the longest match.
+        Token::Reviewer => {
+            let max = name.find('/').unwrap_or(name.len());
+            for end in (1..=max).rev().filter(|&i| name.is_char_boundary(i)) {
+                if match_tokens(rest, &name[end..], captured) {
+                    captured.1 = Some(&name[..end]);
+                    return true;
+                }
+            }
+            false
+        }
+    }
+}
+
+/// Pull request number of a reference name (rooted at `{REF_NS}/`), such as `1234/v2`.
+pub fn pr_number(full_name: &str) -> Option<u64> {
+    full_name.split_once('/')?.0.parse().ok()
+}
+
+/// Name a review by its id, `r<id>`.
+pub fn review_id_key(id: u64) -> String {
+    format!("r{id}")
+}
+
+/// Name a review by the time it was submitted, `t<YYYYMMDD>T<HHMMSS>Z`.
+pub fn review_time_key(submitted_at: DateTime<Utc>) -> String {
+    format!("t{}", submitted_at.format("%Y%m%dT%H%M%SZ"))
+}
+
+/// Check if `key` was created by `review_id_key` or `review_time_key`.
+fn is_review_key(key: &str) -> bool {
+    if let Some(id) = key.strip_prefix('r') {
+        return parse_number(id).is_some();
+    }
+    key.strip_prefix('t')
+        .is_some_and(|t| NaiveDateTime::parse_from_str(t, "%Y%m%dT%H%M%SZ").is_ok())
+}
+
+/// Whether `name` can name a snapshot of pull request `pr` in `layout`, so that its references
+/// are told apart from any other.
+///
+/// Names are made of letters, digits, `.`, `_` and `-`, such as `rc1` or `bench-2024.05`.
+pub fn valid_snapshot(layout: &RefLayout, pr: u64, name: &str) -> bool {
+    is_snapshot_name(name)
+        && [
+            ParsedRef::Snapshot(name.into()),
+            ParsedRef::SnapshotBase(name.into()),
+        ]
+        .iter()
+        .all(|r| layout.parse(pr, &layout.format(r, pr)) == *r)
+}
+
+fn is_snapshot_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+        && !name.starts_with(['.', '-'])
+        && !name.ends_with('.')
+        && !name.ends_with(".lock")
+        && !name.contains("..")
+}
+
+/// Escape `login` for use as the reviewer in a reference name.
+///
+/// Letters, digits and '-', which are all GitHub allows in user names, are kept as they are.
+/// Everything else, such as the brackets in `dependabot[bot]`, is replaced by '_' and two hex
+/// digits for each byte so that distinct logins never share references.
+pub fn escape_login(login: &str) -> String {
+    let mut escaped = String::with_capacity(login.len());
+    for b in login.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("_{b:02X}"));
+        }
+    }
+    escaped
+}
+
+/// Parse a version number exactly as it is formatted, rejecting signs and leading zeros.
+fn parse_number(s: &str) -> Option<u32> {
+    let digits = !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !digits || (s.len() > 1 && s.starts_with('0')) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        use ParsedRef::*;
+
+        for (name, expected) in [
+            ("head", Head),
+            ("head-base", HeadBase),
+            ("latest", Latest),
+            ("latest-base", LatestBase),
+            ("disabled", Disabled),
+            ("snapshot-rc1", Snapshot("rc1".into())),
+            (
+                "snapshot-bench-2024.05-base",
+                SnapshotBase("bench-2024.05".into()),
+            ),
+            ("snapshot-nick-head", ReviewerHead("snapshot-nick".into())),
+            ("snapshot-v2", ReviewerVersion("snapshot".into(), 2)),
+            ("snapshot-", Unknown),
+            ("snapshot-a..b", Unknown),
+            ("v3", Version(3)),
+            ("v3-base", VersionBase(3)),
+            ("v3-rebase", VersionRebase(3)),
+            ("v3-mergebase", VersionMergeBase(3)),
+            ("v3-newbase", VersionNewBase(3)),
+            ("nick-head", ReviewerHead("nick".into())),
+            ("nick-head-base", ReviewerHeadBase("nick".into())),
+            ("nick-v99", ReviewerVersion("nick".into(), 99)),
+            ("nick-v99-base", ReviewerVersionBase("nick".into(), 99)),
+            ("some-one-v2", ReviewerVersion("some-one".into(), 2)),
+            ("v3-v2", ReviewerVersion("v3".into(), 2)),
+            ("v3-head", ReviewerHead("v3".into())),
+            ("nick-requested", ReviewerRequested("nick".into())),
+            ("nick-subscribed", ReviewerSubscribed("nick".into())),
+            ("nick-r123", ReviewerReview("nick".into(), "r123".into())),
+            (
+                "nick-t20240102T030405Z-base",
+                ReviewerReviewBase("nick".into(), "t20240102T030405Z".into()),
+            ),
+            ("nick-t2024", Unknown),
+            ("nick-r", Unknown),
+            ("team-core-head", TeamHead("core".into())),
+            ("team-head", ReviewerHead("team".into())),
+            ("v3-other", Unknown),
+            ("-head", Unknown),
+            ("nick", Unknown),
+            ("vx", Unknown),
+            ("v", Unknown),
+            ("v+7", Unknown),
+            ("v07", Unknown),
+            ("nick-v+7", Unknown),
+        ] {
+            let parsed = ParsedRef::parse(name);
+            assert_eq!(parsed, expected, "{name}");
+            if parsed != Unknown {
+                assert_eq!(parsed.name(), name);
+            }
+        }
+    }
+
+    #[test]
+    fn default_layout() {
+        let layout = RefLayout::default();
+        assert!(layout.validate().is_ok());
+        for name in [
+            "head",
+            "head-base",
+            "v3-mergebase",
+            "nick-head-base",
+            "some-one-v2",
+            "v3-v2-base",
+            "team-core-head",
+            "nick-requested",
+            "disabled",
+            "nick-r10-base",
+            "v3-other",
+            "nick-v+7",
+        ] {
+            let parsed = layout.parse(12, &format!("12/{name}"));
+            assert_eq!(parsed, ParsedRef::parse(name), "{name}");
+            if parsed != ParsedRef::Unknown {
+                assert_eq!(layout.format(&parsed, 12), format!("12/{name}"));
+            }
+        }
+    }
+
+    #[test]
+    fn custom_layout() {
+        use ParsedRef::*;
+
+        let layout = RefLayout {
+            head: "{pr}/current".into(),
+            version: "{pr}/snapshots-{version}".into(),
+            reviewer_head: "{pr}/{reviewer}/latest".into(),
+            reviewer_version: "{pr}/{reviewer}/{version}".into(),
+        };
+        assert!(layout.validate().is_ok());
+        for (name, expected) in [
+            ("current", Head),
+            ("current-base", HeadBase),
+            ("latest", Latest),
+            ("snapshots-3-rebase", VersionRebase(3)),
+            ("some-one/latest", ReviewerHead("some-one".into())),
+            ("nick/4-base", ReviewerVersionBase("nick".into(), 4)),
+            ("team-core-head", TeamHead("core".into())),
+            ("nick-requested", ReviewerRequested("nick".into())),
+            ("nick-subscribed", ReviewerSubscribed("nick".into())),
+            ("v3", Unknown),
+            ("nick-v4", Unknown),
+            ("a/b/4", Unknown),
+        ] {
+            let parsed = layout.parse(12, &format!("12/{name}"));
+            assert_eq!(parsed, expected, "{name}");
+            if parsed != Unknown {
+                assert_eq!(layout.format(&parsed, 12), format!("12/{name}"));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_layout() {
+        for (head, version) in [
+            ("head", "{pr}/v{version}"),
+            ("{pr}/head", "{pr}/v"),
+            ("{pr}/{version}", "{pr}/v{version}"),
+            ("{pr}/head", "{pr}/{pr}-{version}"),
+            ("{pr}/head", "{pr}/v{version}{version}"),
+            ("{pr}/", "{pr}/v{version}"),
+        ] {
+            let layout = RefLayout {
+                head: head.into(),
+                version: version.into(),
+                ..Default::default()
+            };
+            assert!(layout.validate().is_err(), "{head} {version}");
+        }
+
+        // Names of references that are not templated
+        for (head, reviewer_head) in [
+            ("{pr}/latest", "{pr}/{reviewer}-head"),
+            ("{pr}/disabled", "{pr}/{reviewer}-head"),
+            ("{pr}/head", "{pr}/{reviewer}-requested"),
+            ("{pr}/head", "{pr}/snapshot-{reviewer}"),
+            ("{pr}/v2", "{pr}/{reviewer}-head"),
+        ] {
+            let layout = RefLayout {
+                head: head.into(),
+                reviewer_head: reviewer_head.into(),
+                ..Default::default()
+            };
+            assert!(layout.validate().is_err(), "{head} {reviewer_head}");
+        }
+
+        let toml = "head = \"{pr}/latest\"";
+        let err = toml::from_str::<RefLayout>(toml).unwrap_err();
+        assert!(err.to_string().contains("12/latest"), "{err}");
+        let layout: RefLayout = toml::from_str("version = \"{pr}/snap-{version}\"").unwrap();
+        assert_eq!(layout.head, "{pr}/head");
+    }
+
+    #[test]
+    fn parse_full() {
+        let layout = RefLayout::default();
+        assert_eq!(
+            ParsedRef::parse_full(&layout, 12, "12/v1"),
+            ParsedRef::Version(1)
+        );
+        assert_eq!(
+            ParsedRef::parse_full(&layout, 12, "123/v1"),
+            ParsedRef::Unknown
+        );
+        assert_eq!(ParsedRef::parse_full(&layout, 12, "v1"), ParsedRef::Unknown);
+        assert_eq!(ParsedRef::Version(1).full_name(&layout, 12), "12/v1");
+        assert_eq!(pr_number("12/v1"), Some(12));
+        assert_eq!(pr_number("junk"), None);
+    }
+
+    #[test]
+    fn snapshots() {
+        for name in ["rc1", "bench-2024.05", "v2.1", "my_run"] {
+            assert!(valid_snapshot(&RefLayout::default(), 12, name), "{name}");
+        }
+        // Names that would be read back as another reference, or are not valid in git
+        for name in [
+            "",
+            "v2",
+            "rc-r12",
+            "nick-head",
+            "x-base",
+            "a..b",
+            ".hidden",
+            "-x",
+            "rc1.lock",
+            "a b",
+            "a/b",
+            "rc~1",
+        ] {
+            assert!(!valid_snapshot(&RefLayout::default(), 12, name), "{name}");
+        }
+    }
+
+    #[test]
+    fn bases() {
+        for (head, base) in [
+            ("head", Some("head-base")),
+            ("v3", Some("v3-base")),
+            ("nick-v2", Some("nick-v2-base")),
+            ("nick-r42", Some("nick-r42-base")),
+            ("snapshot-rc1", Some("snapshot-rc1-base")),
+            ("v3-base", None),
+            ("nick-requested", None),
+            ("disabled", None),
+        ] {
+            assert_eq!(
+                ParsedRef::parse(head).base().map(|b| b.name()).as_deref(),
+                base,
+                "{head}"
+            );
+        }
+    }
+
+    #[test]
+    fn review_keys() {
+        use chrono::TimeZone;
+
+        assert_eq!(review_id_key(42), "r42");
+        let t = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(review_time_key(t), "t20240102T030405Z");
+        assert!(is_review_key(&review_time_key(t)));
+    }
+
+    #[test]
+    fn escape_login() {
+        for (login, expected) in [
+            ("nick", "nick"),
+            ("some-one", "some-one"),
+            ("dependabot[bot]", "dependabot_5Bbot_5D"),
+            ("a_b", "a_5Fb"),
+            ("a_5Fb", "a_5F5Fb"),
+            ("..lock", "_2E_2Elock"),
+            ("über", "_C3_BCber"),
+        ] {
+            assert_eq!(super::escape_login(login), expected);
+        }
+
+        let escaped = super::escape_login("renovate[bot]");
+        assert_eq!(
+            ParsedRef::parse(&format!("{escaped}-v2")).reviewer(),
+            Some(escaped.as_str())
+        );
+    }
+}
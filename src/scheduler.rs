@@ -0,0 +1,378 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    sync::{watch, Semaphore},
+    time::{timeout, Duration, Instant},
+};
+use tokio_util::task::TaskTracker;
+use tracing::{error, warn};
+
+use crate::error::ChetterError;
+#[cfg(feature = "metrics")]
+use crate::metrics::metrics;
+
+/// Longest low priority work is deferred while high priority work keeps arriving.
+const MAX_DEFER: Duration = Duration::from_secs(60);
+
+/// Oldest dead letters are forgotten once this many are kept.
+const MAX_DEAD_LETTERS: usize = 1000;
+
+/// Priority of work spawned by the [Scheduler].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Started right away, such as delayed handling of opened pull requests
+    High,
+
+    /// Bulk work such as deleting the references of closed pull requests
+    Low,
+}
+
+/// Runs webhook handling ahead of background work.
+///
+/// Background work runs on a bounded pool of workers.  Low priority work also waits for webhook
+/// handling passed to [Scheduler::run] to finish, up to a minute.
+#[derive(Clone)]
+pub struct Scheduler {
+    tasks: TaskTracker,
+    workers: Arc<Semaphore>,
+    high: Arc<watch::Sender<usize>>,
+    dead_letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+
+    /// When each background task that has not finished yet was spawned, by task id
+    spawned: Arc<Mutex<HashMap<u64, Instant>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// Background work that failed every attempt.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetter {
+    /// Repository as `<org>/<repo>`
+    pub repo: String,
+
+    /// Pull request number
+    pub pr: u64,
+
+    /// Event name and action that spawned the work, such as `pull_request.closed`
+    pub event: String,
+
+    /// Error returned by the last attempt
+    pub error: String,
+
+    /// When the last attempt failed
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Capped exponential backoff between attempts of fallible work.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Attempts made before giving up
+    pub attempts: u32,
+
+    /// Delay after the first failure, doubled after each following one
+    pub first: Duration,
+
+    /// Longest delay between attempts
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Retries of background work, spanning about a minute.
+    pub const BACKGROUND: Backoff = Backoff {
+        attempts: 5,
+        first: Duration::from_secs(4),
+        max: Duration::from_secs(30),
+    };
+
+    /// Run `work` until it succeeds, fails with an error that is not retryable or all attempts
+    /// failed, returning the last error.
+    pub async fn retry<T, F, Fut>(&self, mut work: F) -> Result<T, ChetterError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ChetterError>>,
+    {
+        let mut delay = self.first;
+        let mut attempt = 1;
+        loop {
+            match work().await {
+                Ok(v) => return Ok(v),
+                Err(e) if attempt >= self.attempts || !e.is_retryable() => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "attempt {attempt} of {} failed, retrying in {delay:?}: {e}",
+                        self.attempts
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.max);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    /// Create a scheduler spawning onto `tasks` that runs at most `workers` background tasks at
+    /// once.
+    pub fn new(tasks: TaskTracker, workers: usize) -> Self {
+        Self {
+            tasks,
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            high: Arc::new(watch::Sender::new(0)),
+            dead_letters: Arc::default(),
+            spawned: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    /// How long ago the oldest background task that has not finished yet was spawned.
+    pub fn oldest_task_age(&self) -> Option<Duration> {
+        let spawned = self.spawned.lock().unwrap();
+        spawned.values().min().map(|started| started.elapsed())
+    }
+
+    /// Record background `event` handling of `repo` pull request `pr` that failed for good.
+    pub fn dead_letter(&self, repo: &str, pr: u64, event: &str, error: &ChetterError) {
+        error!("{repo}#{pr}: giving up on {event}: {error}");
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        if dead_letters.len() >= MAX_DEAD_LETTERS {
+            dead_letters.pop_front();
+        }
+        dead_letters.push_back(DeadLetter {
+            repo: repo.into(),
+            pr,
+            event: event.into(),
+            error: error.to_string(),
+            failed_at: Utc::now(),
+        });
+    }
+
+    /// Background work that failed every attempt, oldest first.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Run high priority `work`, deferring low priority work until it is done.
+    pub async fn run<F: Future>(&self, work: F) -> F::Output {
+        let _running = HighGuard::new(&self.high);
+        work.await
+    }
+
+    /// Spawn `work` in the background.
+    pub fn spawn<F>(&self, priority: Priority, work: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn_after(Duration::ZERO, priority, work)
+    }
+
+    /// Spawn `work` in the background once `delay` has passed, without holding a worker while
+    /// waiting.
+    pub fn spawn_after<F>(&self, delay: Duration, priority: Priority, work: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let workers = self.workers.clone();
+        let mut idle = self.high.subscribe();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spawned.lock().unwrap().insert(id, Instant::now());
+        let spawned = Spawned(id, self.spawned.clone());
+        let pending = TaskState::new("pending");
+        self.tasks.spawn(async move {
+            let _spawned = spawned;
+            tokio::time::sleep(delay).await;
+            let Ok(_permit) = workers.acquire().await else {
+                return;
+            };
+            if priority == Priority::Low {
+                let _ = timeout(MAX_DEFER, idle.wait_for(|running| *running == 0)).await;
+            }
+            drop(pending);
+            let _running = TaskState::new("running");
+            work.await
+        });
+    }
+}
+
+/// Forgets when a background task was spawned once it is done.
+struct Spawned(u64, Arc<Mutex<HashMap<u64, Instant>>>);
+
+impl Drop for Spawned {
+    fn drop(&mut self) {
+        self.1.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Counts background tasks in `state`, pending or running, as a metric.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+struct TaskState(&'static str);
+
+impl TaskState {
+    fn new(state: &'static str) -> Self {
+        #[cfg(feature = "metrics")]
+        metrics().background_tasks.with_label_values(&[state]).inc();
+        Self(state)
+    }
+}
+
+impl Drop for TaskState {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        metrics()
+            .background_tasks
+            .with_label_values(&[self.0])
+            .dec();
+    }
+}
+
+/// Counts high priority work while it runs.
+struct HighGuard<'a>(&'a watch::Sender<usize>);
+
+impl<'a> HighGuard<'a> {
+    fn new(high: &'a watch::Sender<usize>) -> Self {
+        high.send_modify(|running| *running += 1);
+        Self(high)
+    }
+}
+
+impl Drop for HighGuard<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|running| *running -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{GraphqlError, GraphqlErrors};
+    use std::sync::Mutex;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn retry() {
+        let backoff = Backoff {
+            attempts: 3,
+            first: Duration::ZERO,
+            max: Duration::ZERO,
+        };
+
+        let timeout = || {
+            ChetterError::GithubGraphqlError(GraphqlErrors {
+                errors: vec![GraphqlError {
+                    message: "timed out".into(),
+                    path: vec![],
+                }],
+            })
+        };
+
+        let mut calls = 0;
+        let r = backoff
+            .retry(|| {
+                calls += 1;
+                let n = calls;
+                async move {
+                    match n {
+                        1 => Err(timeout()),
+                        _ => Ok(n),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(r.unwrap(), 2);
+
+        calls = 0;
+        let r: Result<(), _> = backoff
+            .retry(|| {
+                calls += 1;
+                async move { Err(timeout()) }
+            })
+            .await;
+        assert_eq!(r.unwrap_err().to_string(), "GraphQL Errors: timed out");
+        assert_eq!(calls, 3);
+
+        calls = 0;
+        let r: Result<(), _> = backoff
+            .retry(|| {
+                calls += 1;
+                async { Err(ChetterError::PermissionDenied("denied".into())) }
+            })
+            .await;
+        assert_eq!(r.unwrap_err().to_string(), "denied");
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn dead_letters() {
+        let scheduler = Scheduler::new(TaskTracker::new(), 1);
+        let error = ChetterError::GithubParseError("bad gateway".into());
+        for pr in 0..MAX_DEAD_LETTERS as u64 + 1 {
+            scheduler.dead_letter("org/repo", pr, "pull_request.closed", &error);
+        }
+        let dead_letters = scheduler.dead_letters();
+        assert_eq!(dead_letters.len(), MAX_DEAD_LETTERS);
+        assert_eq!(dead_letters[0].pr, 1);
+        assert_eq!(dead_letters[0].error, "bad gateway");
+    }
+
+    #[tokio::test]
+    async fn oldest_task_age() {
+        let scheduler = Scheduler::new(TaskTracker::new(), 1);
+        assert_eq!(scheduler.oldest_task_age(), None);
+
+        let (done, finished) = oneshot::channel::<()>();
+        scheduler.spawn(Priority::High, async {
+            let _ = finished.await;
+        });
+        assert!(scheduler.oldest_task_age().is_some());
+
+        done.send(()).unwrap();
+        scheduler.tasks.close();
+        scheduler.tasks.wait().await;
+        assert_eq!(scheduler.oldest_task_age(), None);
+    }
+
+    #[tokio::test]
+    async fn low_waits_for_high() {
+        let scheduler = Scheduler::new(TaskTracker::new(), 1);
+        let order = Arc::new(Mutex::new(vec![]));
+        let (done, finished) = oneshot::channel::<()>();
+
+        let high = {
+            let scheduler = scheduler.clone();
+            let order = order.clone();
+            tokio::spawn(async move {
+                scheduler
+                    .run(async {
+                        let _ = finished.await;
+                        order.lock().unwrap().push("high");
+                    })
+                    .await
+            })
+        };
+        while *scheduler.high.borrow() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        for name in ["low1", "low2"] {
+            let order = order.clone();
+            scheduler.spawn(Priority::Low, async move {
+                order.lock().unwrap().push(name);
+            });
+        }
+        tokio::task::yield_now().await;
+        assert!(order.lock().unwrap().is_empty());
+
+        done.send(()).unwrap();
+        high.await.unwrap();
+        scheduler.tasks.close();
+        scheduler.tasks.wait().await;
+        assert_eq!(*order.lock().unwrap(), ["high", "low1", "low2"]);
+    }
+}
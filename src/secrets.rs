@@ -0,0 +1,141 @@
+use serde::Deserialize;
+use std::{path::PathBuf, process::Command};
+
+use crate::error::ChetterError;
+
+/// Where the value of a secret is fetched from.
+///
+/// In the configuration a secret is either given as a plain string or as a table naming its
+/// provider, for instance `private_key = { vault = "secret/chetter", field = "private_key" }`.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SecretSource {
+    /// The value itself
+    Value(String),
+
+    /// Contents of a file, such as one mounted by a secret store
+    File { file: PathBuf },
+
+    /// Output of a command, given as the program followed by its arguments
+    Exec { exec: Vec<String> },
+
+    /// Field of a HashiCorp Vault KV secret, read with the `vault` CLI, which is configured from
+    /// the environment such as `VAULT_ADDR` and `VAULT_TOKEN`
+    Vault { vault: String, field: String },
+
+    /// AWS Secrets Manager secret, read with the `aws` CLI, which is configured from the
+    /// environment.  When `field` is set the secret is a JSON object and the value is that field.
+    Aws { aws: String, field: Option<String> },
+}
+
+impl SecretSource {
+    /// Whether the value is fetched from a provider, and may change when fetched again.
+    pub fn is_provided(&self) -> bool {
+        !matches!(self, Self::Value(_))
+    }
+
+    /// Fetch the value, running the command of the provider if needed.
+    ///
+    /// Trailing whitespace, such as the newline ending the output of a command, is removed.
+    pub fn fetch(&self) -> Result<String, ChetterError> {
+        let value = match self {
+            Self::Value(v) => return Ok(v.clone()),
+            Self::File { file } => std::fs::read_to_string(file)
+                .map_err(|e| ChetterError::Config(format!("{}: {e}", file.display())))?,
+            Self::Exec { exec } => match exec.split_first() {
+                Some((program, args)) => run(program, args)?,
+                None => return Err(ChetterError::Config("secret exec command is empty".into())),
+            },
+            Self::Vault { vault, field } => run(
+                "vault",
+                &["kv", "get", &format!("-field={field}"), vault.as_str()],
+            )?,
+            Self::Aws { aws, field } => {
+                let value = run(
+                    "aws",
+                    &[
+                        "secretsmanager",
+                        "get-secret-value",
+                        "--secret-id",
+                        aws.as_str(),
+                        "--query",
+                        "SecretString",
+                        "--output",
+                        "text",
+                    ],
+                )?;
+                match field {
+                    Some(field) => json_field(&value, field)
+                        .map_err(|e| ChetterError::Config(format!("{aws}: {e}")))?,
+                    None => value,
+                }
+            }
+        };
+        Ok(value.trim_end().into())
+    }
+}
+
+/// Run `program` with `args`, returning what it wrote to stdout.
+fn run<S: AsRef<str>>(program: &str, args: &[S]) -> Result<String, ChetterError> {
+    let output = Command::new(program)
+        .args(args.iter().map(AsRef::as_ref))
+        .output()
+        .map_err(|e| ChetterError::Config(format!("{program}: {e}")))?;
+    if !output.status.success() {
+        return Err(ChetterError::Config(format!(
+            "{program}: {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| ChetterError::Config(format!("{program}: output is not UTF-8")))
+}
+
+/// Get string `field` of the JSON object `value`.
+fn json_field(value: &str, field: &str) -> Result<String, String> {
+    let object: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(value).map_err(|e| format!("not a JSON object: {e}"))?;
+    match object.get(field) {
+        Some(serde_json::Value::String(v)) => Ok(v.clone()),
+        Some(_) => Err(format!("{field} is not a string")),
+        None => Err(format!("no field {field}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn providers() {
+        let source: SecretSource =
+            toml::from_str::<toml::Table>(r#"secret = { exec = ["sh", "-c", "echo s3cret"] }"#)
+                .unwrap()["secret"]
+                .clone()
+                .try_into()
+                .unwrap();
+        assert!(source.is_provided());
+        assert_eq!(source.fetch().unwrap(), "s3cret");
+
+        let path = std::env::temp_dir().join(format!("chetter-secret-{}", std::process::id()));
+        std::fs::write(&path, "from file\n").unwrap();
+        let source = SecretSource::File { file: path.clone() };
+        assert_eq!(source.fetch().unwrap(), "from file");
+        std::fs::remove_file(&path).unwrap();
+        assert!(source.fetch().is_err());
+
+        let source = SecretSource::Exec {
+            exec: vec!["false".into()],
+        };
+        assert!(source.fetch().is_err());
+        assert!(SecretSource::Exec { exec: vec![] }.fetch().is_err());
+
+        assert_eq!(
+            json_field(r#"{"key": "value", "n": 1}"#, "key").as_deref(),
+            Ok("value")
+        );
+        assert!(json_field(r#"{"key": "value", "n": 1}"#, "n").is_err());
+        assert!(json_field("value", "key").is_err());
+    }
+}
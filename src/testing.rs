@@ -0,0 +1,426 @@
+//! Helpers for testing code built on chetter-app without GitHub.
+
+pub mod fixtures;
+
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::ChetterError,
+    github::{Comparison, PullRequestInfo, Ref, RepositoryController},
+};
+
+/// A change made through a [FakeRepositoryController].
+#[derive(Debug, Clone, PartialEq)]
+pub enum FakeOperation {
+    /// A reference was created
+    Create { name: String, sha: String },
+
+    /// An existing reference was updated
+    Update { name: String, sha: String },
+
+    /// A reference was deleted
+    Delete { name: String },
+
+    /// A reference was archived
+    Archive { name: String, sha: String },
+
+    /// A comment was added to, or updated on, a pull request
+    Comment { pr: u64, body: String },
+
+    /// A commit status was set
+    Status {
+        sha: String,
+        context: String,
+        description: String,
+    },
+
+    /// A `repository_dispatch` event was fired
+    Dispatch {
+        event_type: String,
+        payload: serde_json::Value,
+    },
+}
+
+/// An in-memory [RepositoryController] recording every change made through it.
+///
+/// References, archived references and comments behave as on GitHub: creating a reference that
+/// already exists fails, as do updating or deleting one that does not, and listing them matches by
+/// prefix.  Pull requests and
+/// comparisons are whatever was set up with [FakeRepositoryController::with_pull] and
+/// [FakeRepositoryController::with_comparison].  Clones share their state, so that a clone can be
+/// handed to the code under test and the original inspected afterwards.
+///
+/// ```
+/// use chetter_app::{github::RepositoryController, testing::FakeRepositoryController};
+///
+/// # async fn example() {
+/// let fake = FakeRepositoryController::new().with_ref("1/head", "abc");
+/// fake.clone().create_ref("1/v1", "abc").await.unwrap();
+/// assert_eq!(fake.refs()["1/v1"], "abc");
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FakeRepositoryController {
+    state: Arc<Mutex<FakeState>>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    refs: BTreeMap<String, String>,
+    archived: BTreeMap<String, String>,
+    pulls: BTreeMap<u64, PullRequestInfo>,
+    comparisons: HashMap<(String, String), Comparison>,
+    comments: BTreeMap<u64, Vec<String>>,
+    pushers: HashSet<String>,
+    operations: Vec<FakeOperation>,
+}
+
+fn not_found(what: String) -> ChetterError {
+    std::io::Error::new(std::io::ErrorKind::NotFound, what).into()
+}
+
+fn already_exists(name: &str) -> ChetterError {
+    std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("Reference {name} already exists"),
+    )
+    .into()
+}
+
+fn matching(refs: &BTreeMap<String, String>, search: &str) -> Vec<Ref> {
+    refs.iter()
+        .filter(|(name, _)| name.starts_with(search))
+        .map(|(name, sha)| to_ref(name, sha))
+        .collect()
+}
+
+fn to_ref(name: &str, sha: &str) -> Ref {
+    Ref {
+        full_name: name.into(),
+        sha: sha.into(),
+        node_id: format!("node_{name}"),
+    }
+}
+
+impl FakeRepositoryController {
+    /// Create a repository without references or pull requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add reference `name`, relative to the namespace of references, pointing to `sha`.
+    pub fn with_ref(self, name: &str, sha: &str) -> Self {
+        self.lock().refs.insert(name.into(), sha.into());
+        self
+    }
+
+    /// Add pull request `pull`, replacing any with the same number.
+    pub fn with_pull(self, pull: PullRequestInfo) -> Self {
+        self.lock().pulls.insert(pull.number, pull);
+        self
+    }
+
+    /// Let user `login` push to the repository, which nobody else can.
+    pub fn with_pusher(self, login: &str) -> Self {
+        self.lock().pushers.insert(login.into());
+        self
+    }
+
+    /// Answer comparisons of `base` and `head` with `comparison`.
+    ///
+    /// Other comparisons report `head` as ahead of `base`, which is their merge-base, or identical
+    /// when both resolve to the same sha.
+    pub fn with_comparison(self, base: &str, head: &str, comparison: Comparison) -> Self {
+        self.lock()
+            .comparisons
+            .insert((base.into(), head.into()), comparison);
+        self
+    }
+
+    /// References by name, relative to the namespace of references, and sha.
+    pub fn refs(&self) -> BTreeMap<String, String> {
+        self.lock().refs.clone()
+    }
+
+    /// Archived references by name, relative to the archive namespace, and sha.
+    pub fn archived(&self) -> BTreeMap<String, String> {
+        self.lock().archived.clone()
+    }
+
+    /// Comments on pull request `pr`, oldest first.
+    pub fn comments(&self, pr: u64) -> Vec<String> {
+        self.lock().comments.get(&pr).cloned().unwrap_or_default()
+    }
+
+    /// Every change made, in order.
+    pub fn operations(&self) -> Vec<FakeOperation> {
+        self.lock().operations.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl FakeState {
+    fn create(&mut self, name: &str, sha: &str) -> Result<(), ChetterError> {
+        if self.refs.contains_key(name) {
+            return Err(already_exists(name));
+        }
+        self.refs.insert(name.into(), sha.into());
+        self.operations.push(FakeOperation::Create {
+            name: name.into(),
+            sha: sha.into(),
+        });
+        Ok(())
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), ChetterError> {
+        self.refs
+            .remove(name)
+            .ok_or_else(|| not_found(format!("{name} does not exist")))?;
+        self.operations
+            .push(FakeOperation::Delete { name: name.into() });
+        Ok(())
+    }
+
+    /// Replace the comment on `pr` containing `marker`, if any, or add one.
+    fn comment(&mut self, pr: u64, marker: Option<&str>, body: &str) {
+        let comments = self.comments.entry(pr).or_default();
+        match marker.and_then(|m| comments.iter_mut().find(|c| c.contains(m))) {
+            Some(existing) => *existing = body.into(),
+            None => comments.push(body.into()),
+        }
+        self.operations.push(FakeOperation::Comment {
+            pr,
+            body: body.into(),
+        });
+    }
+
+    /// Sha of reference `name`, or `name` itself if there is no such reference.
+    fn resolve(&self, name: &str) -> String {
+        self.refs.get(name).cloned().unwrap_or_else(|| name.into())
+    }
+}
+
+#[async_trait]
+impl RepositoryController for FakeRepositoryController {
+    async fn create_ref(&self, ref_name: &str, sha: &str) -> Result<(), ChetterError> {
+        self.lock().create(ref_name, sha)
+    }
+
+    /// Create `refs`, those that already exist fail without affecting the others.
+    async fn create_refs(&self, refs: &[(String, String)]) -> Result<(), ChetterError> {
+        let mut state = self.lock();
+        let mut errors = vec![];
+        for (name, sha) in refs {
+            if let Err(e) = state.create(name, sha) {
+                errors.push(e);
+            }
+        }
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(ChetterError::Multiple(errors)),
+        }
+    }
+
+    async fn update_ref<'a>(
+        &self,
+        ref_name: &str,
+        _old_sha: Option<&'a str>,
+        sha: &str,
+    ) -> Result<(), ChetterError> {
+        let mut state = self.lock();
+        let Some(current) = state.refs.get_mut(ref_name) else {
+            return Err(not_found(format!("{ref_name} does not exist")));
+        };
+        *current = sha.into();
+        state.operations.push(FakeOperation::Update {
+            name: ref_name.into(),
+            sha: sha.into(),
+        });
+        Ok(())
+    }
+
+    async fn delete_ref(&self, ref_name: &str) -> Result<(), ChetterError> {
+        self.lock().delete(ref_name)
+    }
+
+    async fn archive_ref(&self, r: &Ref) -> Result<(), ChetterError> {
+        let mut state = self.lock();
+        state.archived.insert(r.full_name.clone(), r.sha.clone());
+        state.operations.push(FakeOperation::Archive {
+            name: r.full_name.clone(),
+            sha: r.sha.clone(),
+        });
+        Ok(())
+    }
+
+    async fn delete_refs(&self, refs: &[Ref]) -> Result<(), ChetterError> {
+        let mut state = self.lock();
+        let mut errors = vec![];
+        for r in refs {
+            if let Err(e) = state.delete(&r.full_name) {
+                errors.push(e);
+            }
+        }
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(ChetterError::Multiple(errors)),
+        }
+    }
+
+    async fn get_ref(&self, name: &str) -> Result<Option<Ref>, ChetterError> {
+        Ok(self.lock().refs.get(name).map(|sha| to_ref(name, sha)))
+    }
+
+    async fn matching_refs(&self, search: &str) -> Result<Vec<Ref>, ChetterError> {
+        Ok(matching(&self.lock().refs, search))
+    }
+
+    async fn compare(&self, base: &str, head: &str) -> Result<Comparison, ChetterError> {
+        let state = self.lock();
+        if let Some(comparison) = state.comparisons.get(&(base.into(), head.into())) {
+            return Ok(comparison.clone());
+        }
+        let (base, head) = (state.resolve(base), state.resolve(head));
+        Ok(Comparison {
+            files: vec![],
+            status: if base == head { "identical" } else { "ahead" }.into(),
+            merge_base: base,
+        })
+    }
+
+    async fn post_comment(&self, pr: u64, body: &str) -> Result<(), ChetterError> {
+        self.lock().comment(pr, None, body);
+        Ok(())
+    }
+
+    async fn upsert_comment(&self, pr: u64, marker: &str, body: &str) -> Result<(), ChetterError> {
+        self.lock().comment(pr, Some(marker), body);
+        Ok(())
+    }
+
+    async fn get_pull(&self, pr: u64) -> Result<PullRequestInfo, ChetterError> {
+        self.lock()
+            .pulls
+            .get(&pr)
+            .cloned()
+            .ok_or_else(|| not_found(format!("pull request #{pr} does not exist")))
+    }
+
+    async fn open_pulls<'a>(
+        &self,
+        base: Option<&'a str>,
+    ) -> Result<Vec<PullRequestInfo>, ChetterError> {
+        Ok(self
+            .lock()
+            .pulls
+            .values()
+            .filter(|p| p.open && base.map_or(true, |b| b == p.base_ref))
+            .cloned()
+            .collect())
+    }
+
+    fn compare_url(&self, base: &str, head: &str) -> String {
+        format!("https://github.com/org/repo/compare/pr/{base}...pr/{head}")
+    }
+
+    async fn set_status(
+        &self,
+        sha: &str,
+        context: &str,
+        description: &str,
+        _target_url: &str,
+    ) -> Result<(), ChetterError> {
+        self.lock().operations.push(FakeOperation::Status {
+            sha: sha.into(),
+            context: context.into(),
+            description: description.into(),
+        });
+        Ok(())
+    }
+
+    async fn repository_dispatch(
+        &self,
+        event_type: &str,
+        payload: &serde_json::Value,
+    ) -> Result<(), ChetterError> {
+        self.lock().operations.push(FakeOperation::Dispatch {
+            event_type: event_type.into(),
+            payload: payload.clone(),
+        });
+        Ok(())
+    }
+
+    async fn can_push(&self, login: &str) -> Result<bool, ChetterError> {
+        Ok(self.lock().pushers.contains(login))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RepoConfig;
+
+    #[tokio::test]
+    async fn versions() {
+        let fake = FakeRepositoryController::new();
+        let config = RepoConfig::default();
+        crate::open_pr(fake.clone(), 1, "c1", "b1", &config)
+            .await
+            .unwrap();
+        crate::synchronize_pr(fake.clone(), 1, "c2", "b1", &config)
+            .await
+            .unwrap();
+
+        let refs: Vec<(String, String)> = fake.refs().into_iter().collect();
+        let expected: Vec<(String, String)> = [
+            ("1/head", "c2"),
+            ("1/head-base", "b1"),
+            ("1/v1", "c1"),
+            ("1/v1-base", "b1"),
+            ("1/v2", "c2"),
+            ("1/v2-base", "b1"),
+        ]
+        .iter()
+        .map(|(n, s)| (n.to_string(), s.to_string()))
+        .collect();
+        assert_eq!(refs, expected);
+        assert!(fake.operations().contains(&FakeOperation::Update {
+            name: "1/head".into(),
+            sha: "c2".into(),
+        }));
+
+        // As redelivering the event of an opened pull request would
+        assert!(fake.create_ref("1/v1", "c3").await.is_err());
+        let created = [("1/v1".into(), "c3".into()), ("1/v3".into(), "c3".into())];
+        assert!(fake.create_refs(&created).await.is_err());
+        assert_eq!(fake.refs()["1/v1"], "c1");
+        assert_eq!(fake.refs()["1/v3"], "c3");
+
+        assert!(fake.update_ref("2/head", None, "c3").await.is_err());
+        assert!(fake.delete_ref("2/head").await.is_err());
+        assert!(fake.get_pull(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn comments() {
+        let fake = FakeRepositoryController::new();
+        fake.upsert_comment(1, "<!-- m -->", "<!-- m --> a")
+            .await
+            .unwrap();
+        fake.upsert_comment(1, "<!-- m -->", "<!-- m --> b")
+            .await
+            .unwrap();
+        fake.post_comment(1, "c").await.unwrap();
+        assert_eq!(fake.comments(1), ["<!-- m --> b", "c"]);
+        assert_eq!(fake.operations().len(), 3);
+    }
+}
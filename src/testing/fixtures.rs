@@ -0,0 +1,534 @@
+//! Builders of realistic webhook payloads, which are easier to write than the octocrab models
+//! they parse into.
+//!
+//! ```
+//! use chetter_app::testing::fixtures::{Fixture, PullRequestEvent, PullRequestFixture};
+//!
+//! let event = PullRequestEvent::new("synchronize", PullRequestFixture::new(12).head("c2"));
+//! assert_eq!(event.event().repository.unwrap().name, "repo");
+//! ```
+
+use chrono::{DateTime, Utc};
+use octocrab::models::webhook_events::WebhookEvent;
+use serde_json::{json, Value};
+
+use crate::crypto;
+
+/// Repository events are about unless set otherwise.
+pub const REPO: &str = "org/repo";
+
+/// Installation events are delivered for unless set otherwise.
+pub const INSTALLATION: u64 = 1000;
+
+/// When fixtures were created, updated or submitted.
+const TIMESTAMP: &str = "2024-01-02T03:04:05Z";
+
+/// A webhook event payload.
+pub trait Fixture {
+    /// Event type, sent as the `X-GitHub-Event` header
+    fn kind(&self) -> &'static str;
+
+    /// Payload of the event.
+    fn payload(&self) -> Value;
+
+    /// Body of the request delivering the event.
+    fn body(&self) -> String {
+        self.payload().to_string()
+    }
+
+    /// `X-Hub-Signature-256` header of the request delivering the event with webhook `secret`.
+    fn signature(&self, secret: &str) -> String {
+        crypto::sign(secret, self.body().as_bytes())
+    }
+
+    /// The event as parsed when delivered.
+    ///
+    /// Panics if the payload does not parse, which is a bug in the fixture.
+    fn event(&self) -> WebhookEvent {
+        WebhookEvent::try_from_header_and_body(self.kind(), &self.body())
+            .unwrap_or_else(|e| panic!("{} fixture does not parse: {e}", self.kind()))
+    }
+}
+
+/// Stable id of `name`, so that the same user or repository always has the same id.
+fn id_of(name: &str) -> u64 {
+    // FNV-1a
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    hash % 100_000_000 + 1
+}
+
+/// A user, or a bot if `login` ends with `[bot]`.
+pub fn user(login: &str) -> Value {
+    let id = id_of(login);
+    let api = format!("https://api.github.com/users/{login}");
+    json!({
+        "login": login,
+        "id": id,
+        "node_id": format!("U_{id}"),
+        "avatar_url": format!("https://avatars.githubusercontent.com/u/{id}?v=4"),
+        "gravatar_id": "",
+        "url": api,
+        "html_url": format!("https://github.com/{login}"),
+        "followers_url": format!("{api}/followers"),
+        "following_url": format!("{api}/following{{/other_user}}"),
+        "gists_url": format!("{api}/gists{{/gist_id}}"),
+        "starred_url": format!("{api}/starred{{/owner}}{{/repo}}"),
+        "subscriptions_url": format!("{api}/subscriptions"),
+        "organizations_url": format!("{api}/orgs"),
+        "repos_url": format!("{api}/repos"),
+        "events_url": format!("{api}/events{{/privacy}}"),
+        "received_events_url": format!("{api}/received_events"),
+        "type": if login.ends_with("[bot]") { "Bot" } else { "User" },
+        "site_admin": false,
+    })
+}
+
+/// Repository `full_name`, given as `<org>/<repo>`.
+pub fn repository(full_name: &str) -> Value {
+    let (owner, name) = full_name.split_once('/').unwrap_or(("org", full_name));
+    let id = id_of(full_name);
+    let api = format!("https://api.github.com/repos/{full_name}");
+    json!({
+        "id": id,
+        "node_id": format!("R_{id}"),
+        "name": name,
+        "full_name": full_name,
+        "private": false,
+        "owner": user(owner),
+        "html_url": format!("https://github.com/{full_name}"),
+        "description": null,
+        "fork": false,
+        "url": api,
+        "git_refs_url": format!("{api}/git/refs{{/sha}}"),
+        "pulls_url": format!("{api}/pulls{{/number}}"),
+        "clone_url": format!("https://github.com/{full_name}.git"),
+        "created_at": TIMESTAMP,
+        "updated_at": TIMESTAMP,
+        "pushed_at": TIMESTAMP,
+        "default_branch": "main",
+        "archived": false,
+        "disabled": false,
+        "visibility": "public",
+    })
+}
+
+/// A pull request as embedded in webhook events, open on [REPO] by default.
+#[derive(Debug, Clone)]
+pub struct PullRequestFixture {
+    repo: String,
+    number: u64,
+    author: String,
+    title: String,
+    body: Option<String>,
+    head: String,
+    head_ref: String,
+    base: String,
+    base_ref: String,
+    labels: Vec<String>,
+    draft: bool,
+    closed_at: Option<DateTime<Utc>>,
+    merged: bool,
+}
+
+impl PullRequestFixture {
+    /// Open pull request `number` of [REPO].
+    pub fn new(number: u64) -> Self {
+        Self {
+            repo: REPO.into(),
+            number,
+            author: "author".into(),
+            title: format!("Pull request {number}"),
+            body: None,
+            head: "c0ffee".repeat(6) + "c0ff",
+            head_ref: format!("topic-{number}"),
+            base: "ba5e".repeat(10),
+            base_ref: "main".into(),
+            labels: vec![],
+            draft: false,
+            closed_at: None,
+            merged: false,
+        }
+    }
+
+    /// Open the pull request on repository `<org>/<repo>`.
+    pub fn repo(mut self, full_name: &str) -> Self {
+        self.repo = full_name.into();
+        self
+    }
+
+    /// Login of the user who opened the pull request.
+    pub fn author(mut self, login: &str) -> Self {
+        self.author = login.into();
+        self
+    }
+
+    /// Title of the pull request.
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    /// Description of the pull request.
+    pub fn body(mut self, body: &str) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Sha of the head of the pull request.
+    pub fn head(mut self, sha: &str) -> Self {
+        self.head = sha.into();
+        self
+    }
+
+    /// Sha of the base branch.
+    pub fn base(mut self, sha: &str) -> Self {
+        self.base = sha.into();
+        self
+    }
+
+    /// Name of the branch the pull request merges into.
+    pub fn base_ref(mut self, branch: &str) -> Self {
+        self.base_ref = branch.into();
+        self
+    }
+
+    /// Add label `name`.
+    pub fn label(mut self, name: &str) -> Self {
+        self.labels.push(name.into());
+        self
+    }
+
+    /// Mark the pull request as a draft.
+    pub fn draft(mut self) -> Self {
+        self.draft = true;
+        self
+    }
+
+    /// Close the pull request at `at`, merged or not.
+    pub fn closed(mut self, at: DateTime<Utc>, merged: bool) -> Self {
+        self.closed_at = Some(at);
+        self.merged = merged;
+        self
+    }
+
+    /// The pull request in the REST API format.
+    pub fn to_json(&self) -> Value {
+        let (repo, number) = (&self.repo, self.number);
+        let id = id_of(&format!("{repo}#{number}"));
+        let api = format!("https://api.github.com/repos/{repo}");
+        let html = format!("https://github.com/{repo}/pull/{number}");
+        let owner = repo.split_once('/').map_or("org", |(o, _)| o);
+        let branch = |name: &str, sha: &str| {
+            json!({
+                "label": format!("{owner}:{name}"),
+                "ref": name,
+                "sha": sha,
+                "user": user(owner),
+                "repo": repository(repo),
+            })
+        };
+        let labels: Vec<Value> = self
+            .labels
+            .iter()
+            .map(|name| {
+                let id = id_of(name);
+                json!({
+                    "id": id,
+                    "node_id": format!("LA_{id}"),
+                    "url": format!("{api}/labels/{name}"),
+                    "name": name,
+                    "description": null,
+                    "color": "ededed",
+                    "default": false,
+                })
+            })
+            .collect();
+        let closed_at = self.closed_at.map(|t| t.to_rfc3339());
+        json!({
+            "url": format!("{api}/pulls/{number}"),
+            "id": id,
+            "node_id": format!("PR_{id}"),
+            "html_url": html,
+            "diff_url": format!("{html}.diff"),
+            "patch_url": format!("{html}.patch"),
+            "issue_url": format!("{api}/issues/{number}"),
+            "number": number,
+            "state": if self.closed_at.is_some() { "closed" } else { "open" },
+            "locked": false,
+            "title": self.title,
+            "user": user(&self.author),
+            "body": self.body,
+            "labels": labels,
+            "created_at": TIMESTAMP,
+            "updated_at": TIMESTAMP,
+            "closed_at": closed_at,
+            "merged_at": if self.merged { closed_at.clone() } else { None },
+            "merge_commit_sha": null,
+            "assignees": [],
+            "requested_reviewers": [],
+            "draft": self.draft,
+            "head": branch(&self.head_ref, &self.head),
+            "base": branch(&self.base_ref, &self.base),
+            "author_association": "CONTRIBUTOR",
+            "merged": self.merged,
+            "comments": 0,
+            "review_comments": 0,
+            "commits": 1,
+            "additions": 1,
+            "deletions": 1,
+            "changed_files": 1,
+        })
+    }
+}
+
+/// Fields every event delivered for a repository has.
+fn envelope(repo: &str, sender: &str, installation: u64) -> Value {
+    json!({
+        "repository": repository(repo),
+        "sender": user(sender),
+        "installation": {
+            "id": installation,
+            "node_id": format!("I_{installation}"),
+        },
+    })
+}
+
+/// Merge the fields of `envelope` into `payload`.
+fn with_envelope(mut payload: Value, envelope: Value) -> Value {
+    if let (Value::Object(payload), Value::Object(envelope)) = (&mut payload, envelope) {
+        payload.extend(envelope);
+    }
+    payload
+}
+
+/// A `pull_request` event.
+#[derive(Debug, Clone)]
+pub struct PullRequestEvent {
+    action: String,
+    pull: PullRequestFixture,
+    sender: Option<String>,
+    installation: u64,
+    before: Option<String>,
+    requested_reviewer: Option<String>,
+}
+
+impl PullRequestEvent {
+    /// Event `action`, such as `opened` or `synchronize`, on `pull`.
+    pub fn new(action: &str, pull: PullRequestFixture) -> Self {
+        Self {
+            action: action.into(),
+            pull,
+            sender: None,
+            installation: INSTALLATION,
+            before: None,
+            requested_reviewer: None,
+        }
+    }
+
+    /// Login of the user causing the event, the author of the pull request by default.
+    pub fn sender(mut self, login: &str) -> Self {
+        self.sender = Some(login.into());
+        self
+    }
+
+    /// Installation the event is delivered for.
+    pub fn installation(mut self, id: u64) -> Self {
+        self.installation = id;
+        self
+    }
+
+    /// Head before a `synchronize` event.
+    pub fn before(mut self, sha: &str) -> Self {
+        self.before = Some(sha.into());
+        self
+    }
+
+    /// Reviewer of a `review_requested` event.
+    pub fn requested_reviewer(mut self, login: &str) -> Self {
+        self.requested_reviewer = Some(login.into());
+        self
+    }
+}
+
+impl Fixture for PullRequestEvent {
+    fn kind(&self) -> &'static str {
+        "pull_request"
+    }
+
+    fn payload(&self) -> Value {
+        let mut payload = json!({
+            "action": self.action,
+            "number": self.pull.number,
+            "pull_request": self.pull.to_json(),
+        });
+        if let Some(ref before) = self.before {
+            payload["before"] = json!(before);
+            payload["after"] = json!(self.pull.head);
+        }
+        if let Some(ref reviewer) = self.requested_reviewer {
+            payload["requested_reviewer"] = user(reviewer);
+        }
+        let sender = self.sender.as_ref().unwrap_or(&self.pull.author);
+        with_envelope(
+            payload,
+            envelope(&self.pull.repo, sender, self.installation),
+        )
+    }
+}
+
+/// A `pull_request_review` event.
+#[derive(Debug, Clone)]
+pub struct PullRequestReviewEvent {
+    action: String,
+    pull: PullRequestFixture,
+    id: u64,
+    reviewer: String,
+    state: String,
+    commit_id: String,
+    body: Option<String>,
+    installation: u64,
+}
+
+impl PullRequestReviewEvent {
+    /// Review by `reviewer` of the head of `pull`, approved and submitted by default.
+    pub fn new(pull: PullRequestFixture, reviewer: &str) -> Self {
+        Self {
+            action: "submitted".into(),
+            id: id_of(&format!("{}#{}@{reviewer}", pull.repo, pull.number)),
+            commit_id: pull.head.clone(),
+            pull,
+            reviewer: reviewer.into(),
+            state: "approved".into(),
+            body: None,
+            installation: INSTALLATION,
+        }
+    }
+
+    /// Event action, such as `submitted` or `dismissed`.
+    pub fn action(mut self, action: &str) -> Self {
+        self.action = action.into();
+        self
+    }
+
+    /// Id of the review.
+    pub fn id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// State of the review, such as `approved`, `commented` or `changes_requested`.
+    pub fn state(mut self, state: &str) -> Self {
+        self.state = state.into();
+        self
+    }
+
+    /// Sha that was reviewed, the head of the pull request by default.
+    pub fn commit_id(mut self, sha: &str) -> Self {
+        self.commit_id = sha.into();
+        self
+    }
+
+    /// Body of the review.
+    pub fn body(mut self, body: &str) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Installation the event is delivered for.
+    pub fn installation(mut self, id: u64) -> Self {
+        self.installation = id;
+        self
+    }
+}
+
+impl Fixture for PullRequestReviewEvent {
+    fn kind(&self) -> &'static str {
+        "pull_request_review"
+    }
+
+    fn payload(&self) -> Value {
+        let (repo, number) = (&self.pull.repo, self.pull.number);
+        let html = format!("https://github.com/{repo}/pull/{number}");
+        let payload = json!({
+            "action": self.action,
+            "review": {
+                "id": self.id,
+                "node_id": format!("PRR_{}", self.id),
+                "user": user(&self.reviewer),
+                "body": self.body,
+                "commit_id": self.commit_id,
+                "submitted_at": TIMESTAMP,
+                "state": self.state,
+                "html_url": format!("{html}#pullrequestreview-{}", self.id),
+                "pull_request_url": format!("https://api.github.com/repos/{repo}/pulls/{number}"),
+                "author_association": "MEMBER",
+            },
+            "pull_request": self.pull.to_json(),
+        });
+        with_envelope(payload, envelope(repo, &self.reviewer, self.installation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octocrab::models::{
+        pulls::ReviewState,
+        webhook_events::{
+            payload::{PullRequestWebhookEventAction, WebhookEventPayload},
+            EventInstallation,
+        },
+    };
+
+    #[test]
+    fn pull_request() {
+        let pull = PullRequestFixture::new(12)
+            .repo("acme/widgets")
+            .author("alice")
+            .head("c2")
+            .label("no-chetter")
+            .closed(Utc::now(), true);
+        let fixture = PullRequestEvent::new("closed", pull).sender("bob");
+        let event = fixture.event();
+        assert_eq!(event.sender.unwrap().login, "bob");
+        assert_eq!(
+            event.repository.unwrap().full_name.as_deref(),
+            Some("acme/widgets")
+        );
+        assert!(matches!(
+            event.installation,
+            Some(EventInstallation::Minimal(ref i)) if i.id.0 == INSTALLATION
+        ));
+        let WebhookEventPayload::PullRequest(payload) = event.specific else {
+            panic!("not a pull request event");
+        };
+        assert_eq!(payload.action, PullRequestWebhookEventAction::Closed);
+        assert_eq!(payload.number, 12);
+        assert_eq!(payload.pull_request.head.sha, "c2");
+        assert!(payload.pull_request.merged_at.is_some());
+        assert_eq!(payload.pull_request.user.unwrap().login, "alice");
+        assert_eq!(payload.pull_request.labels.unwrap()[0].name, "no-chetter");
+
+        let signature = fixture.signature("s3cret");
+        assert!(
+            crypto::verify_signature("s3cret", fixture.body().as_bytes(), Some(&signature)).is_ok()
+        );
+    }
+
+    #[test]
+    fn review() {
+        let pull = PullRequestFixture::new(3).head("c1");
+        let event = PullRequestReviewEvent::new(pull, "dependabot[bot]")
+            .state("changes_requested")
+            .event();
+        let WebhookEventPayload::PullRequestReview(payload) = event.specific else {
+            panic!("not a review event");
+        };
+        assert_eq!(payload.review.commit_id.as_deref(), Some("c1"));
+        assert_eq!(payload.review.state, Some(ReviewState::ChangesRequested));
+        assert_eq!(payload.review.user.unwrap().r#type, "Bot");
+        assert_eq!(payload.pull_request.number, 3);
+    }
+}
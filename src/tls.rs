@@ -0,0 +1,154 @@
+use axum::extract::connect_info::Connected;
+use futures::Stream;
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{debug, error};
+
+use crate::{config::TlsConfig, error::ChetterError};
+
+/// Connections waiting to be served once their handshake completed.
+const BACKLOG: usize = 64;
+
+/// A connection that completed the TLS handshake.
+pub struct TlsConnection {
+    stream: TlsStream<TcpStream>,
+    remote: SocketAddr,
+}
+
+impl Connected<&TlsConnection> for SocketAddr {
+    fn connect_info(target: &TlsConnection) -> Self {
+        target.remote
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Build the rustls server configuration, requiring client certificates signed by `client_ca`
+/// if it is set.
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig, ChetterError> {
+    let certs = read_certs(&config.certificate)?;
+    let key = read_key(&config.private_key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match config.client_ca {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| ChetterError::Config(format!("{path}: {e}")))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|e| ChetterError::Config(format!("{}: {e}", config.certificate)))
+}
+
+/// Accept TLS connections on `listener`, completing handshakes concurrently so that a slow or
+/// rejected client does not hold up the others.
+pub fn incoming(
+    listener: TcpListener,
+    config: ServerConfig,
+) -> impl Stream<Item = Result<TlsConnection, std::io::Error>> {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let (tx, rx) = mpsc::channel(BACKLOG);
+
+    tokio::spawn(async move {
+        loop {
+            let (tcp, remote) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let accepted = tx.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(tcp).await {
+                    Ok(stream) => {
+                        let _ = accepted.send(TlsConnection { stream, remote }).await;
+                    }
+                    // Includes clients without an acceptable certificate
+                    Err(e) => debug!("TLS handshake with {remote} failed: {e}"),
+                }
+            });
+            if tx.is_closed() {
+                break;
+            }
+        }
+    });
+
+    futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|conn| (Ok(conn), rx))
+    })
+}
+
+fn read_certs(path: &str) -> Result<Vec<Certificate>, ChetterError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(ChetterError::Config(format!("{path}: no certificates")));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &str) -> Result<PrivateKey, ChetterError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(ChetterError::Config(format!("{path}: no private key"))),
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    audit::{AuditEntry, Operation},
+    error::ChetterError,
+    hooks::RefHook,
+};
+
+/// Idle pull requests are forgotten once this many are tracked.
+const MAX_TRACKED: usize = 10000;
+
+/// A webhook event handled for a pull request.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventRecord {
+    #[serde(skip)]
+    id: u64,
+
+    /// GitHub delivery id from the `X-GitHub-Delivery` header
+    pub delivery: Option<String>,
+
+    /// Event name and action, such as `pull_request.synchronize`
+    pub event: String,
+
+    /// When processing started
+    pub started_at: DateTime<Utc>,
+
+    /// When processing finished, unset while in flight
+    pub finished_at: Option<DateTime<Utc>>,
+
+    /// Error returned by processing, if any
+    pub error: Option<String>,
+}
+
+/// Recent activity on a single pull request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PrActivity {
+    /// Events still being processed, including those running in the background
+    pub in_flight: Vec<EventRecord>,
+
+    /// The most recently finished event
+    pub last_processed: Option<EventRecord>,
+}
+
+impl PrActivity {
+    /// When an event of the pull request last started or finished processing.
+    pub fn last_active(&self) -> Option<DateTime<Utc>> {
+        self.in_flight
+            .iter()
+            .map(|r| r.started_at)
+            .chain(self.last_processed.as_ref().and_then(|r| r.finished_at))
+            .max()
+    }
+}
+
+/// A pull request with recent activity.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackedPr {
+    /// Repository as `<org>/<repo>`
+    pub repo: String,
+
+    /// Pull request number
+    pub pr: u64,
+
+    /// Event processing activity
+    #[serde(flatten)]
+    pub activity: PrActivity,
+}
+
+/// Processing state of a pull request as reported by the administrative API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PrState {
+    /// Pull request number
+    pub pr: u64,
+
+    /// Latest recorded version, 0 if none
+    pub version: u32,
+
+    /// SHA-1 of the `head` reference, if any
+    pub head: Option<String>,
+
+    /// Event processing activity
+    #[serde(flatten)]
+    pub activity: PrActivity,
+}
+
+/// A webhook event that finished processing, as streamed by the administrative API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProcessedEvent {
+    /// Repository as `<org>/<repo>`, empty for events of no repository
+    pub repo: String,
+
+    /// Pull request number, if the event is about one
+    pub pr: Option<u64>,
+
+    /// Event name and action, such as `pull_request.synchronize`
+    pub event: String,
+
+    /// GitHub delivery id from the `X-GitHub-Delivery` header
+    pub delivery: Option<String>,
+
+    /// Error returned by processing, if any
+    pub error: Option<String>,
+
+    /// How long processing took, in milliseconds
+    pub duration_ms: u64,
+
+    /// Version of the pull request recorded by processing, if any
+    pub version: Option<u32>,
+
+    /// References changed by processing, including work done in the background such as
+    /// deleting the references of closed pull requests
+    pub refs: Vec<ChangedRef>,
+}
+
+/// A change made to a reference while processing a webhook event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangedRef {
+    /// Full name of the reference, such as `refs/heads/pr/12/v1`
+    #[serde(rename = "ref")]
+    pub name: String,
+
+    /// Kind of change
+    pub operation: Operation,
+
+    /// SHA-1 the reference points to after the change, None if deleted
+    pub sha: Option<String>,
+}
+
+impl From<&AuditEntry> for ChangedRef {
+    fn from(change: &AuditEntry) -> Self {
+        Self {
+            name: change.ref_name.clone(),
+            operation: change.operation,
+            sha: change.new_sha.clone(),
+        }
+    }
+}
+
+/// Called with the changes made for a delivery and the first error processing it, once done.
+type Report = Box<dyn FnOnce(Vec<AuditEntry>, Option<String>) + Send>;
+
+/// Changes of a delivery, collected until nothing holds it anymore.
+struct Scope {
+    holders: usize,
+    changes: Vec<AuditEntry>,
+    error: Option<String>,
+    report: Report,
+}
+
+/// Collects the changes made to references while processing each webhook delivery.
+///
+/// Changes are collected from [DeliveryChanges::start] until the delivery and every piece of
+/// work holding it, such as deleting references in the background, released it.
+#[derive(Clone, Default)]
+pub struct DeliveryChanges(Arc<Mutex<HashMap<String, Scope>>>);
+
+impl DeliveryChanges {
+    /// Collect the changes attributed to `delivery` from now on, held until released once.
+    ///
+    /// `report` is called with the changes in order and the first error, once every hold on
+    /// `delivery` was released.
+    pub fn start(
+        &self,
+        delivery: &str,
+        report: impl FnOnce(Vec<AuditEntry>, Option<String>) + Send + 'static,
+    ) {
+        let scope = Scope {
+            holders: 1,
+            changes: vec![],
+            error: None,
+            report: Box::new(report),
+        };
+        self.0.lock().unwrap().insert(delivery.into(), scope);
+    }
+
+    /// Keep collecting the changes attributed to `delivery` until released again, false if they
+    /// are not being collected.
+    pub fn hold(&self, delivery: &str) -> bool {
+        match self.0.lock().unwrap().get_mut(delivery) {
+            Some(scope) => {
+                scope.holders += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Release a hold on `delivery`, which failed with `error` if set, reporting its changes if
+    /// it was the last one.
+    pub fn release(&self, delivery: &str, error: Option<String>) {
+        let scope = {
+            let mut scopes = self.0.lock().unwrap();
+            let Some(scope) = scopes.get_mut(delivery) else {
+                return;
+            };
+            scope.holders -= 1;
+            if scope.error.is_none() {
+                scope.error = error;
+            }
+            if scope.holders > 0 {
+                return;
+            }
+            scopes.remove(delivery)
+        };
+        if let Some(scope) = scope {
+            (scope.report)(scope.changes, scope.error);
+        }
+    }
+}
+
+impl std::fmt::Debug for DeliveryChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DeliveryChanges")
+            .field(&self.0.lock().unwrap().len())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl RefHook for DeliveryChanges {
+    async fn after(&self, change: &AuditEntry) {
+        let Some(ref delivery) = change.trigger.delivery else {
+            return;
+        };
+        if let Some(scope) = self.0.lock().unwrap().get_mut(delivery) {
+            scope.changes.push(change.clone());
+        }
+    }
+}
+
+/// The most recent `ping` event, sent when the webhook is created or when redelivered.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HookPing {
+    /// Id of the webhook that sent the ping
+    pub hook_id: Option<u64>,
+
+    /// Events the webhook is subscribed to
+    pub events: Vec<String>,
+
+    /// Random bit of GitHub zen
+    pub zen: Option<String>,
+
+    /// True if the ping was verified against the configured `webhook_secret`
+    pub signed: bool,
+
+    /// When the ping was received
+    pub received_at: DateTime<Utc>,
+}
+
+type Key = (String, u64);
+
+/// Tracks webhook events being processed for each pull request.
+#[derive(Clone, Default)]
+pub struct PrTracker {
+    inner: Arc<Mutex<HashMap<Key, PrActivity>>>,
+    next_id: Arc<AtomicU64>,
+    changes: DeliveryChanges,
+}
+
+impl PrTracker {
+    /// Track events holding the changes of their delivery in `changes` until they finish, even
+    /// when processed in the background.
+    pub fn holding(changes: DeliveryChanges) -> Self {
+        Self {
+            changes,
+            ..Default::default()
+        }
+    }
+
+    /// Record that processing of `event` for `repo` pull request `pr` has started.
+    pub fn start(&self, repo: &str, pr: u64, delivery: Option<&str>, event: &str) -> TrackedEvent {
+        let record = EventRecord {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            delivery: delivery.map(String::from),
+            event: event.into(),
+            started_at: Utc::now(),
+            finished_at: None,
+            error: None,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.len() >= MAX_TRACKED {
+            inner.retain(|_, a| !a.in_flight.is_empty());
+        }
+        let key = (repo.to_string(), pr);
+        let id = record.id;
+        inner.entry(key.clone()).or_default().in_flight.push(record);
+
+        let held = delivery.filter(|d| self.changes.hold(d)).map(String::from);
+        TrackedEvent {
+            tracker: self.clone(),
+            key,
+            id,
+            held,
+            finished: false,
+        }
+    }
+
+    /// Get the activity of `repo` pull request `pr`.
+    pub fn get(&self, repo: &str, pr: u64) -> PrActivity {
+        self.inner
+            .lock()
+            .unwrap()
+            .get(&(repo.to_string(), pr))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get up to `limit` pull requests, most recently active first.
+    pub fn recent(&self, limit: usize) -> Vec<TrackedPr> {
+        let mut prs: Vec<TrackedPr> = self
+            .inner
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((repo, pr), activity)| TrackedPr {
+                repo: repo.clone(),
+                pr: *pr,
+                activity: activity.clone(),
+            })
+            .collect();
+        prs.sort_by_key(|p| std::cmp::Reverse(p.activity.last_active()));
+        prs.truncate(limit);
+        prs
+    }
+
+    /// Move the activity of every pull request of `old` to `new` after the repository was renamed.
+    pub fn rename_repo(&self, old: &str, new: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<Key> = inner.keys().filter(|(r, _)| r == old).cloned().collect();
+        for key in keys {
+            if let Some(activity) = inner.remove(&key) {
+                inner.insert((new.to_string(), key.1), activity);
+            }
+        }
+    }
+
+    /// Forget the activity of every pull request of `repo`.
+    pub fn forget_repo(&self, repo: &str) {
+        self.inner.lock().unwrap().retain(|(r, _), _| r != repo);
+    }
+
+    /// Remove the in-flight record of `tracked`, returning it along with the key it was found
+    /// under, which differs from that of `tracked` if the repository was renamed meanwhile.
+    fn remove_in_flight(&self, tracked: &TrackedEvent) -> Option<(Key, EventRecord)> {
+        let mut inner = self.inner.lock().unwrap();
+        let has_record = |a: &PrActivity| a.in_flight.iter().any(|r| r.id == tracked.id);
+        let key = match inner.get(&tracked.key) {
+            Some(activity) if has_record(activity) => tracked.key.clone(),
+            _ => inner
+                .iter()
+                .find(|(k, a)| k.1 == tracked.key.1 && has_record(a))?
+                .0
+                .clone(),
+        };
+        let activity = inner.get_mut(&key)?;
+        let pos = activity.in_flight.iter().position(|r| r.id == tracked.id)?;
+        let record = activity.in_flight.remove(pos);
+        Some((key, record))
+    }
+}
+
+/// An in-flight event, removed from the tracker when dropped.
+pub struct TrackedEvent {
+    tracker: PrTracker,
+    key: Key,
+    id: u64,
+    /// Delivery whose changes are held until the event finishes
+    held: Option<String>,
+    finished: bool,
+}
+
+impl TrackedEvent {
+    /// Record the outcome of processing the event.
+    pub fn finish<T>(mut self, result: &Result<T, ChetterError>) {
+        self.finished = true;
+        let error = result.as_ref().err().map(ToString::to_string);
+        if let Some((key, mut record)) = self.tracker.remove_in_flight(&self) {
+            record.finished_at = Some(Utc::now());
+            record.error = error.clone();
+            if let Some(activity) = self.tracker.inner.lock().unwrap().get_mut(&key) {
+                activity.last_processed = Some(record);
+            }
+        }
+        if let Some(delivery) = self.held.take() {
+            self.tracker.changes.release(&delivery, error);
+        }
+    }
+}
+
+impl Drop for TrackedEvent {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.tracker.remove_in_flight(self);
+        }
+        if let Some(delivery) = self.held.take() {
+            self.tracker.changes.release(&delivery, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        let tracker = PrTracker::default();
+        let first = tracker.start("org/repo", 1, Some("abc"), "pull_request.opened");
+        let second = tracker.start("org/repo", 1, None, "pull_request.synchronize");
+        assert_eq!(tracker.get("org/repo", 1).in_flight.len(), 2);
+        assert_eq!(tracker.get("org/repo", 2), PrActivity::default());
+
+        first.finish::<()>(&Err(ChetterError::GithubParseError("bad".into())));
+        let activity = tracker.get("org/repo", 1);
+        assert_eq!(activity.in_flight.len(), 1);
+        let last = activity.last_processed.unwrap();
+        assert_eq!(last.delivery.as_deref(), Some("abc"));
+        assert_eq!(last.error.as_deref(), Some("bad"));
+        assert!(last.finished_at.is_some());
+
+        drop(second);
+        let activity = tracker.get("org/repo", 1);
+        assert!(activity.in_flight.is_empty());
+        assert_eq!(
+            activity.last_processed.unwrap().event,
+            "pull_request.opened"
+        );
+    }
+
+    #[test]
+    fn recent() {
+        let tracker = PrTracker::default();
+        tracker
+            .start("org/a", 1, None, "pull_request.opened")
+            .finish::<()>(&Ok(()));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let _second = tracker.start("org/b", 2, None, "pull_request.opened");
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        tracker
+            .start("org/a", 3, None, "pull_request.opened")
+            .finish::<()>(&Ok(()));
+
+        let recent = tracker.recent(10);
+        let keys: Vec<_> = recent.iter().map(|p| (p.repo.as_str(), p.pr)).collect();
+        assert_eq!(keys, [("org/a", 3), ("org/b", 2), ("org/a", 1)]);
+        assert_eq!(tracker.recent(1).len(), 1);
+    }
+
+    #[test]
+    fn rename_repo() {
+        let tracker = PrTracker::default();
+        let tracked = tracker.start("org/old", 1, None, "pull_request.synchronize");
+        tracker.rename_repo("org/old", "org/new");
+        assert_eq!(tracker.get("org/old", 1), PrActivity::default());
+        assert_eq!(tracker.get("org/new", 1).in_flight.len(), 1);
+
+        tracked.finish::<()>(&Ok(()));
+        let activity = tracker.get("org/new", 1);
+        assert!(activity.in_flight.is_empty());
+        assert!(activity.last_processed.is_some());
+    }
+
+    #[tokio::test]
+    async fn delivery_changes() {
+        let change = |name: &str, delivery: Option<&str>| AuditEntry {
+            at: Utc::now(),
+            repo: "org/repo".into(),
+            ref_name: name.into(),
+            operation: Operation::Create,
+            old_sha: None,
+            new_sha: Some("c1".into()),
+            trigger: crate::audit::Trigger {
+                actor: "nick".into(),
+                delivery: delivery.map(String::from),
+            },
+        };
+        let reported = Arc::new(Mutex::new(vec![]));
+        let report = |reported: &Arc<Mutex<Vec<_>>>| {
+            let reported = reported.clone();
+            move |changes: Vec<AuditEntry>, error| reported.lock().unwrap().push((changes, error))
+        };
+        let changes = DeliveryChanges::default();
+        changes.start("abc", report(&reported));
+        changes
+            .after(&change("refs/heads/pr/1/v1", Some("abc")))
+            .await;
+        changes
+            .after(&change("refs/heads/pr/2/v1", Some("def")))
+            .await;
+        changes.after(&change("refs/heads/pr/3/v1", None)).await;
+
+        changes.release("abc", None);
+        let (collected, error) = reported.lock().unwrap().pop().unwrap();
+        assert_eq!(error, None);
+        assert_eq!(collected.len(), 1);
+        assert_eq!(
+            ChangedRef::from(&collected[0]),
+            ChangedRef {
+                name: "refs/heads/pr/1/v1".into(),
+                operation: Operation::Create,
+                sha: Some("c1".into()),
+            }
+        );
+
+        // Work continuing in the background holds the delivery until it finishes
+        let tracker = PrTracker::holding(changes.clone());
+        changes.start("abc", report(&reported));
+        let tracked = tracker.start("org/repo", 1, Some("abc"), "pull_request.closed");
+        changes.release("abc", None);
+        assert!(reported.lock().unwrap().is_empty());
+        changes
+            .after(&change("refs/heads/pr/1/v2", Some("abc")))
+            .await;
+        tracked.finish::<()>(&Err(ChetterError::GithubParseError("bad".into())));
+        let (collected, error) = reported.lock().unwrap().pop().unwrap();
+        assert_eq!(collected.len(), 1);
+        assert_eq!(error.as_deref(), Some("bad"));
+
+        // Deliveries no longer collected are left alone
+        tracker
+            .start("org/repo", 1, Some("abc"), "pull_request.closed")
+            .finish::<()>(&Ok(()));
+        assert!(reported.lock().unwrap().is_empty());
+    }
+}
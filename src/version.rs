@@ -0,0 +1,47 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+/// Build of chetter-app that is running and the GitHub App it acts as.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VersionInfo {
+    /// Version of the crate
+    pub version: &'static str,
+
+    /// Commit chetter-app was built from, `unknown` if it could not be determined
+    pub git_sha: &'static str,
+
+    /// When chetter-app was built
+    pub built_at: Option<DateTime<Utc>>,
+
+    /// GitHub Application id
+    pub app_id: u64,
+}
+
+impl VersionInfo {
+    /// Describe this build acting as GitHub App `app_id`.
+    pub fn new(app_id: u64) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("CHETTER_GIT_SHA"),
+            built_at: env!("CHETTER_BUILD_TIMESTAMP")
+                .parse()
+                .ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single()),
+            app_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version() {
+        let info = VersionInfo::new(1234);
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_sha.is_empty());
+        assert!(info.built_at.is_some());
+        assert_eq!(info.app_id, 1234);
+    }
+}
@@ -0,0 +1,116 @@
+//! Endpoint GitHub delivers webhook events to.
+
+use axum::{
+    extract::{self, ConnectInfo},
+    http::{header::HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use std::net::SocketAddr;
+use tracing::{debug, error, info, warn};
+
+use crate::{error::ChetterError, State};
+
+/// Create the router receiving webhook events at `/github/events`.
+///
+/// Sources of events can only be restricted when served with the address of the peer, such as
+/// with `into_make_service_with_connect_info::<SocketAddr>()`, otherwise every event is refused
+/// when `restrict_webhook_sources` is configured.
+pub fn router(state: State) -> Router<State> {
+    Router::new()
+        .route("/github/events", post(post_github_events))
+        .route_layer(middleware::from_fn_with_state(state, restrict_source))
+}
+
+/// Handle a webhook event delivered by GitHub.
+///
+/// The signature of the event is verified and deliveries already processed, by this or another
+/// replica, are ignored.
+pub async fn post_github_events(
+    extract::State(state): extract::State<State>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(), ChetterError> {
+    let event_type = match headers.get("X-Github-Event") {
+        Some(v) => match v.to_str() {
+            Ok(v) => v,
+            Err(error) => {
+                error!("Failed to parse X-Github-Event: {}", error);
+                headers.iter().for_each(|(k, v)| {
+                    debug!("{} = {}", k, v.to_str().unwrap_or("<error>"));
+                });
+                return Err(ChetterError::GithubParseError(format!(
+                    "Failed to parse X-Github-Event: {error}"
+                )));
+            }
+        },
+        None => {
+            let msg = "No X-Github-Event header";
+            error!(msg);
+            headers.iter().for_each(|(k, v)| {
+                debug!("{} = {}", k, v.to_str().unwrap_or("<error>"));
+            });
+            return Err(ChetterError::GithubParseError(msg.into()));
+        }
+    };
+
+    let signature = headers
+        .get("X-Hub-Signature-256")
+        .and_then(|v| v.to_str().ok());
+    if let Err(e) = state.verify_signature(body.as_bytes(), signature) {
+        warn!("Rejecting {event_type} event: {e}");
+        return Err(e);
+    }
+    state
+        .record_payload(
+            headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("<error>").into()))
+                .collect(),
+            &body,
+        )
+        .await;
+
+    let event = match state.parse_event(event_type, &body) {
+        Ok(event) => event,
+        Err(e) => {
+            error!("{e}");
+            debug!("{}", body);
+            return Err(e);
+        }
+    };
+
+    let delivery = headers
+        .get("X-GitHub-Delivery")
+        .and_then(|v| v.to_str().ok());
+    let Some(guid) = delivery else {
+        return state.webhook_dispatcher(event, delivery).await;
+    };
+    if !state.claim_delivery(guid).await? {
+        info!("Ignoring {event_type} delivery {guid}, it was already processed");
+        return Ok(());
+    }
+    let r = state.webhook_dispatcher(event, delivery).await;
+    if r.is_err() {
+        state.release_delivery(guid).await;
+    }
+    r
+}
+
+/// Refuse webhook events from outside the networks of GitHub when configured to.
+async fn restrict_source<B>(
+    extract::State(state): extract::State<State>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = peer.map(|ConnectInfo(addr)| addr.ip());
+    if state.accepts_webhook_source(ip) {
+        next.run(request).await
+    } else {
+        warn!("Refusing webhook event from {ip:?}");
+        StatusCode::FORBIDDEN.into_response()
+    }
+}
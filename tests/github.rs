@@ -0,0 +1,375 @@
+//! End-to-end tests delivering signed webhook events to chetter-app, acting on a fake GitHub API.
+//!
+//! Unlike the unit tests, which mock [RepositoryController], these exercise the requests actually
+//! sent to GitHub: minting installation tokens, following pagination and chunking GraphQL
+//! mutations.
+//!
+//! [RepositoryController]: chetter_app::github::RepositoryController
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serde_json::{json, Value};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tower::ServiceExt;
+use wiremock::{
+    matchers::{body_string_contains, method, path},
+    Mock, MockServer, Respond, ResponseTemplate,
+};
+
+use chetter_app::{
+    audit::AuditEntry,
+    config::AppConfig,
+    error::ChetterError,
+    hooks::RefHook,
+    secrets::SecretSource,
+    testing::fixtures::{repository, Fixture, PullRequestEvent, PullRequestFixture, REPO},
+    State,
+};
+
+const SECRET: &str = "s3cret";
+const TOKEN: &str = "ghs_installation";
+
+/// chetter-app configured to use the fake GitHub API at `github`.
+fn start(github: &MockServer) -> State {
+    let key = SecretSource::File {
+        file: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/private-key.pem").into(),
+    };
+    let mut config = AppConfig::new(1, key);
+    config.webhook_secret = Some(SECRET.into());
+    config.github.api_url = Some(github.uri());
+    config.github.per_page = 50;
+    State::from_config(config).unwrap()
+}
+
+/// Deliver `fixture` to the webhook endpoint as GitHub would, signed with `secret`.
+async fn deliver(state: &State, fixture: &impl Fixture, secret: &str) -> StatusCode {
+    let request = Request::post("/github/events")
+        .header("X-GitHub-Event", fixture.kind())
+        .header("X-Hub-Signature-256", fixture.signature(secret))
+        .body(Body::from(fixture.body()))
+        .unwrap();
+    chetter_app::router(state.clone())
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+}
+
+/// Reference `name` below the namespace of pull requests, as returned by the REST API.
+fn git_ref(github: &MockServer, name: &str, sha: &str) -> Value {
+    let url = format!("{}/repos/{REPO}/git/refs/heads/pr/{name}", github.uri());
+    json!({
+        "ref": format!("refs/heads/pr/{name}"),
+        "node_id": format!("node_{name}"),
+        "url": url,
+        "object": {"type": "commit", "sha": sha, "url": url},
+    })
+}
+
+/// Mount the endpoints used before handling any event: minting an installation token, expected
+/// `tokens` times, fetching the repository and probing write access with a temporary reference.
+async fn mount_basics(github: &MockServer, tokens: u64) {
+    Mock::given(method("POST"))
+        .and(path("/app/installations/1000/access_tokens"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+            "token": TOKEN,
+            "expires_at": "2099-01-01T00:00:00Z",
+            "permissions": {"contents": "write"},
+        })))
+        .expect(tokens)
+        .mount(github)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/repos/{REPO}")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(repository(REPO)))
+        .mount(github)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("/repos/{REPO}/git/refs")))
+        .respond_with(ResponseTemplate::new(201).set_body_json(git_ref(
+            github,
+            "chetter-probe",
+            "c1",
+        )))
+        .expect(1)
+        .mount(github)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path(format!(
+            "/repos/{REPO}/git/refs/heads/pr/chetter-probe"
+        )))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(github)
+        .await;
+}
+
+/// Requests received by `github` with a body containing `needle`.
+async fn requests_with(github: &MockServer, needle: &str) -> Vec<wiremock::Request> {
+    github
+        .received_requests()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|r| String::from_utf8_lossy(&r.body).contains(needle))
+        .collect()
+}
+
+/// GraphQL query sent in `request`.
+fn graphql(request: &wiremock::Request) -> String {
+    let body: Value = serde_json::from_slice(&request.body).unwrap();
+    body["query"].as_str().unwrap().into()
+}
+
+#[tokio::test]
+async fn opened() {
+    let github = MockServer::start().await;
+    mount_basics(&github, 2).await;
+    Mock::given(method("POST"))
+        .and(path("/graphql"))
+        .and(body_string_contains("createRef"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": {}})))
+        .expect(2)
+        .mount(&github)
+        .await;
+
+    let state = start(&github);
+    for pr in [1, 2] {
+        let pull = PullRequestFixture::new(pr)
+            .head("c1c1c1c1")
+            .base("b1b1b1b1");
+        let event = PullRequestEvent::new("opened", pull);
+        assert_eq!(deliver(&state, &event, SECRET).await, StatusCode::OK);
+    }
+
+    // Each event mints an installation token for its client, used for every request it makes.
+    for request in github.received_requests().await.unwrap() {
+        let authorization = request.headers[&"authorization".into()].as_str();
+        if request.url.path().ends_with("/access_tokens") {
+            assert!(authorization.starts_with("Bearer "));
+            assert!(!authorization.contains(TOKEN));
+        } else {
+            assert!(authorization.contains(TOKEN), "{}", request.url);
+        }
+    }
+
+    let created = requests_with(&github, "createRef").await;
+    let query = graphql(&created[0]);
+    for name in ["1/head", "1/head-base", "1/v1", "1/v1-base"] {
+        assert!(query.contains(&format!("name: \"refs/heads/pr/{name}\"")));
+    }
+    assert!(query.contains(
+        &format!(
+            "repositoryId: \"{}\"",
+            repository(REPO)["node_id"].as_str().unwrap()
+        )[..]
+    ));
+    assert!(graphql(&created[1]).contains("refs/heads/pr/2/v1"));
+
+    // Events signed with another secret are refused before anything is sent to GitHub.
+    let requests = github.received_requests().await.unwrap().len();
+    let forged = PullRequestEvent::new("opened", PullRequestFixture::new(3));
+    assert!(deliver(&state, &forged, "other").await.is_client_error());
+    assert_eq!(github.received_requests().await.unwrap().len(), requests);
+}
+
+#[tokio::test]
+async fn redelivered() {
+    let github = MockServer::start().await;
+    mount_basics(&github, 1).await;
+    // The head was created by an earlier delivery of the same event
+    Mock::given(method("POST"))
+        .and(path("/graphql"))
+        .and(body_string_contains("createRef"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": {"create_0": null, "create_1": {}, "create_2": {}, "create_3": {}},
+            "errors": [{
+                "type": "UNPROCESSABLE",
+                "path": ["create_0"],
+                "message": "A ref named \"refs/heads/pr/1/head\" already exists in the repository.",
+            }],
+        })))
+        .expect(1)
+        .mount(&github)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("/repos/{REPO}/git/refs/heads/pr/1/head")))
+        .and(body_string_contains("c1c1c1c1"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(git_ref(&github, "1/head", "c1c1c1c1")),
+        )
+        .expect(1)
+        .mount(&github)
+        .await;
+
+    let state = start(&github);
+    let pull = PullRequestFixture::new(1).head("c1c1c1c1").base("b1b1b1b1");
+    let event = PullRequestEvent::new("opened", pull);
+    assert_eq!(deliver(&state, &event, SECRET).await, StatusCode::OK);
+}
+
+/// Vetoes changes to versions, recording the changes that were made.
+#[derive(Clone, Default)]
+struct NoVersions(Arc<Mutex<Vec<String>>>);
+
+#[async_trait]
+impl RefHook for NoVersions {
+    async fn before(&self, change: &AuditEntry) -> Result<(), ChetterError> {
+        match change.ref_name.contains("/v") {
+            true => Err(ChetterError::Vetoed(format!("{} vetoed", change.ref_name))),
+            false => Ok(()),
+        }
+    }
+
+    async fn after(&self, change: &AuditEntry) {
+        self.0.lock().unwrap().push(change.ref_name.clone());
+    }
+}
+
+#[tokio::test]
+async fn vetoed() {
+    let github = MockServer::start().await;
+    mount_basics(&github, 1).await;
+    Mock::given(method("POST"))
+        .and(path("/graphql"))
+        .and(body_string_contains("createRef"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": {}})))
+        .expect(1)
+        .mount(&github)
+        .await;
+
+    let made = NoVersions::default();
+    let state = start(&github).with_ref_hook(made.clone());
+    let pull = PullRequestFixture::new(1).head("c1c1c1c1").base("b1b1b1b1");
+    let event = PullRequestEvent::new("opened", pull);
+    assert_eq!(deliver(&state, &event, SECRET).await, StatusCode::FORBIDDEN);
+
+    // The rest of the batch is still created
+    let query = graphql(&requests_with(&github, "createRef").await[0]);
+    assert!(query.contains("refs/heads/pr/1/head\""));
+    assert!(!query.contains("refs/heads/pr/1/v1"));
+    // Along with probing write access, which is a change like any other
+    assert_eq!(
+        *made.0.lock().unwrap(),
+        [
+            "refs/heads/pr/chetter-probe",
+            "refs/heads/pr/chetter-probe",
+            "refs/heads/pr/1/head",
+            "refs/heads/pr/1/head-base"
+        ]
+    );
+}
+
+/// Matching references split in pages of `per_page`, linking to the next on the server at `uri`.
+struct Pages {
+    uri: String,
+    refs: Vec<Value>,
+    per_page: usize,
+}
+
+impl Respond for Pages {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let page: usize = request
+            .url
+            .query_pairs()
+            .find(|(k, _)| k == "page")
+            .map_or(1, |(_, v)| v.parse().unwrap());
+        let start = (page - 1) * self.per_page;
+        let end = (start + self.per_page).min(self.refs.len());
+        let mut response = ResponseTemplate::new(200).set_body_json(&self.refs[start..end]);
+        if end < self.refs.len() {
+            let next = format!(
+                "{}{}?per_page={}&page={}",
+                self.uri,
+                request.url.path(),
+                self.per_page,
+                page + 1
+            );
+            response = response.insert_header("link", format!("<{next}>; rel=\"next\"").as_str());
+        }
+        response
+    }
+}
+
+#[tokio::test]
+async fn closed() {
+    let github = MockServer::start().await;
+    mount_basics(&github, 1).await;
+
+    // Sixty versions and their bases, along with the head, are more than fit in a page or a
+    // single GraphQL mutation.
+    let mut refs = vec![
+        git_ref(&github, "1/head", "c60"),
+        git_ref(&github, "1/head-base", "b1"),
+    ];
+    for v in 1..=60 {
+        refs.push(git_ref(&github, &format!("1/v{v}"), &format!("c{v}")));
+        refs.push(git_ref(&github, &format!("1/v{v}-base"), "b1"));
+    }
+    let names: Vec<String> = refs
+        .iter()
+        .map(|r| r["node_id"].as_str().unwrap().into())
+        .collect();
+
+    Mock::given(method("GET"))
+        .and(path(format!("/repos/{REPO}/git/matching-refs/heads/pr/1/")))
+        .respond_with(Pages {
+            uri: github.uri(),
+            refs,
+            per_page: 50,
+        })
+        .expect(3)
+        .mount(&github)
+        .await;
+    let pull = PullRequestFixture::new(1)
+        .head("c60")
+        .closed("2024-01-03T00:00:00Z".parse().unwrap(), false);
+    Mock::given(method("GET"))
+        .and(path(format!("/repos/{REPO}/pulls/1")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(pull.to_json()))
+        .mount(&github)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/graphql"))
+        .and(body_string_contains("deleteRef"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": {}})))
+        .mount(&github)
+        .await;
+
+    let state = start(&github);
+    let event = PullRequestEvent::new("closed", pull);
+    assert_eq!(deliver(&state, &event, SECRET).await, StatusCode::OK);
+
+    // References are deleted in the background after the event was handled.
+    let mut deleted: Vec<String> = vec![];
+    let mut chunks = 0;
+    for _ in 0..100 {
+        let requests = requests_with(&github, "deleteRef").await;
+        deleted = requests
+            .iter()
+            .flat_map(|r| {
+                graphql(r)
+                    .lines()
+                    .filter_map(|l| l.trim().strip_prefix("refId: \""))
+                    .map(|id| id.trim_end_matches("\",").to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        chunks = requests.len();
+        if deleted.len() >= names.len() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    deleted.sort();
+    let mut expected = names;
+    expected.sort();
+    assert_eq!(deleted, expected);
+    assert_eq!(chunks, 2, "at most 100 references are deleted per mutation");
+}